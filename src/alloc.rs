@@ -1,13 +1,41 @@
 // api
 
-use crate::{mimalloc_internal::mi_page_usable_block_size, mimalloc_types::MI_DEBUG_UNINIT};
-use std::{ffi::c_void, ptr, sync::Arc};
+use crate::{
+    mimalloc_internal::{
+        _mi_page_start, _mi_ptr_cookie, _mi_ptr_segment, _mi_segment_page_of, mi_block_nextx,
+        mi_block_set_next, mi_block_set_nextx, mi_is_in_same_page, mi_page_block_size,
+        mi_page_has_aligned, mi_page_is_in_full, mi_page_thread_free, mi_page_usable_block_size,
+        mi_tf_block, mi_tf_delayed, mi_tf_set_block, mi_tf_set_delayed,
+    },
+    mimalloc_types::{
+        MiDelayed, MiSegment, MI_DEBUG_FREED, MI_DEBUG_UNINIT, MI_ENCODE_FREELIST, MI_INTPTR_SIZE,
+        MI_SECURE,
+    },
+    options::{_mi_error_message, _mi_warning_message},
+    page::{
+        _mi_malloc_generic, _mi_page_free_collect, _mi_page_retire, _mi_page_try_use_delayed_free,
+        _mi_page_unfull,
+    },
+    segment::_mi_segment_huge_page_reset,
+    segment_cache::_mi_segment_of,
+};
+use std::{ffi::c_void, ptr, sync::atomic::Ordering};
 
+use crate::{
+    heap::mi_heap_get_default,
+    mimalloc_internal::mi_heap_is_initialized,
+    page_queue::_mi_bin,
+    stats::{_mi_heap_stats, _mi_stat_counter_increase, _mi_stat_decrease, _mi_stat_increase},
+};
 use crate::{
     mimalloc_internal::{
-        _mi_heap_get_free_small_page, _mi_thread_id, get_default_heap, mi_page_is_huge,
+        _mi_heap_get_free_small_page, _mi_page_segment, _mi_thread_id, get_default_heap,
+        mi_block_next, mi_count_size_overflow, mi_page_is_huge,
+    },
+    mimalloc_types::{
+        MiBlock, MiHeap, MiPage, MiSegmentKind, MI_LARGE_OBJ_SIZE_MAX, MI_MEDIUM_OBJ_SIZE_MAX,
+        MI_PADDING, MI_PADDING_SIZE, MI_SMALL_SIZE_MAX, MI_STAT,
     },
-    mimalloc_types::{MiBlock, MiHeap, MiPage, MI_PADDING, MI_PADDING_SIZE, MI_SMALL_SIZE_MAX},
 };
 
 #[no_mangle]
@@ -15,27 +43,48 @@ pub extern "C" fn mi_malloc(size: usize) -> *mut c_void {
     mi_heap_malloc(get_default_heap(), size)
 }
 
+#[no_mangle]
+pub extern "C" fn mi_zalloc(size: usize) -> *mut c_void {
+    mi_heap_zalloc(get_default_heap(), size)
+}
+
 #[no_mangle]
 pub extern "C" fn mi_calloc(count: usize, size: usize) -> *mut c_void {
-    ptr::null_mut()
+    mi_heap_calloc(get_default_heap(), count, size)
 }
 
 #[no_mangle]
-pub extern "C" fn mi_free(p: *mut c_void) {}
+pub extern "C" fn mi_heap_malloc(heap: *mut MiHeap, size: usize) -> *mut c_void {
+    _mi_heap_malloc_zero(heap, size, false)
+}
 
 #[inline]
-fn mi_heap_malloc(heap: Box<MiHeap>, size: usize) -> *mut c_void {
-    _mi_heap_malloc_zero(heap, size, false)
+pub fn mi_heap_malloc_small(heap: *mut MiHeap, size: usize) -> *mut c_void {
+    mi_heap_malloc_small_zero(heap, size, false)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_zalloc(heap: *mut MiHeap, size: usize) -> *mut c_void {
+    _mi_heap_malloc_zero(heap, size, true)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_calloc(heap: *mut MiHeap, count: usize, size: usize) -> *mut c_void {
+    let mut total: usize = 0;
+    if mi_count_size_overflow(count, size, &mut total) {
+        return ptr::null_mut();
+    }
+    mi_heap_zalloc(heap, total)
 }
 
 #[inline]
-fn _mi_heap_malloc_zero(heap: Box<MiHeap>, size: usize, zero: bool) -> *mut c_void {
+pub fn _mi_heap_malloc_zero(heap: *mut MiHeap, size: usize, zero: bool) -> *mut c_void {
     _mi_heap_malloc_zero_ex(heap, size, zero, 0)
 }
 
 #[inline]
-fn _mi_heap_malloc_zero_ex(
-    heap: Box<MiHeap>,
+pub fn _mi_heap_malloc_zero_ex(
+    heap: *mut MiHeap,
     size: usize,
     zero: bool,
     huge_alignment: usize,
@@ -45,27 +94,57 @@ fn _mi_heap_malloc_zero_ex(
         debug_assert!(huge_alignment == 0);
         mi_heap_malloc_small_zero(heap, size, zero)
     } else {
-        // slow path
-        // TODO To be implemented
-        todo!("slow path does not implement now");
+        debug_assert!(!heap.is_null());
+        if cfg!(debug_assertions) {
+            let tid = _mi_thread_id();
+            debug_assert!(unsafe { (*heap).thread_id == 0 || (*heap).thread_id == tid });
+            // heaps are thread local
+        }
+        // note: size can overflow but it is detected in malloc_generic
+        let p = _mi_malloc_generic(
+            heap,
+            size.wrapping_add(MI_PADDING_SIZE),
+            zero,
+            huge_alignment,
+        );
+        debug_assert!(p.is_null() || mi_usable_size(p) >= size);
+        if MI_STAT > 1 && !p.is_null() {
+            mi_stat_malloc(heap, p);
+        }
+        p
     }
 }
 
 #[inline]
-fn mi_heap_malloc_small_zero(mut heap: Box<MiHeap>, size: usize, zero: bool) -> *mut c_void {
+fn mi_heap_malloc_small_zero(heap: *mut MiHeap, size: usize, zero: bool) -> *mut c_void {
     if cfg!(debug_assertions) {
         let tid = _mi_thread_id();
-        debug_assert!(heap.thread_id == 0 || heap.thread_id == tid);
+        debug_assert!(unsafe { (*heap).thread_id == 0 || (*heap).thread_id == tid });
     }
 
     let size = if MI_PADDING == 1 && size == 0 {
-        std::mem::size_of::<c_void>()
+        std::mem::size_of::<usize>()
     } else {
         size
     };
 
-    let page = _mi_heap_get_free_small_page(&mut heap, size + MI_PADDING_SIZE);
-    _mi_page_malloc(&heap, page, size, zero)
+    let page = _mi_heap_get_free_small_page(heap, size + MI_PADDING_SIZE);
+    let p = _mi_page_malloc(heap, page, size + MI_PADDING_SIZE, zero);
+    debug_assert!(p.is_null() || mi_usable_size(p) >= size);
+    if MI_STAT > 1 && !p.is_null() {
+        mi_stat_malloc(heap, p);
+    }
+    p
+}
+
+fn mi_stat_malloc(mut heap: *mut MiHeap, p: *mut c_void) {
+    if !mi_heap_is_initialized(heap) {
+        heap = get_default_heap();
+    }
+    _mi_stat_increase(
+        unsafe { ptr::addr_of_mut!((*_mi_heap_stats(heap)).malloc) },
+        mi_usable_size(p),
+    );
 }
 
 // ------------------------------------------------------
@@ -74,44 +153,763 @@ fn mi_heap_malloc_small_zero(mut heap: Box<MiHeap>, size: usize, zero: bool) ->
 
 // Fast allocation in a page: just pop from the free list.
 // Fall back to generic allocation only if the list is empty.
-fn _mi_page_malloc<'a>(
-    heap: &Box<MiHeap>,
-    mut page: Box<MiPage>,
+pub fn _mi_page_malloc(
+    heap: *mut MiHeap,
+    page: *mut MiPage,
     size: usize,
     zero: bool,
 ) -> *mut c_void {
-    // TODO check size for huge page
-    debug_assert!(page.xblock_size == 0);
+    unsafe {
+        debug_assert!((*page).xblock_size == 0 || mi_page_block_size(page) >= size);
+        let block = (*page).free;
+        if block.is_null() {
+            return _mi_malloc_generic(heap, size, zero, 0);
+        }
+        debug_assert!(_mi_segment_page_of(_mi_ptr_segment(block.cast()), block.cast()) == page);
 
-    let block = page.free.pop_front();
+        // pop from the free list
+        (*page).used += 1;
+        (*page).free = mi_block_next(page, block);
 
-    if block.is_none() && page.free.is_empty() {
-        // slow path
-        todo!("malloc generic, to be implemented");
-    }
+        // zero the block? note: we need to zero the full block size (issue #63)
+        if zero {
+            debug_assert!((*page).xblock_size != 0); // do not call with zero'ing for huge blocks (see _mi_malloc_generic)
+            let zsize = if (*page).is_zero() != 0 {
+                std::mem::size_of::<MiBlock>() + MI_PADDING_SIZE
+            } else {
+                (*page).xblock_size as usize
+            };
+            ptr::write_bytes(block.cast::<u8>(), 0, zsize - MI_PADDING_SIZE);
+        }
 
-    let mut block = block.unwrap();
+        if MI_STAT > 0 {
+            let bsize = mi_page_usable_block_size(page);
+            if bsize <= MI_MEDIUM_OBJ_SIZE_MAX {
+                let stats = _mi_heap_stats(heap);
+                _mi_stat_increase(ptr::addr_of_mut!((*stats).normal), bsize);
+                _mi_stat_counter_increase(ptr::addr_of_mut!((*stats).normal_count), 1);
+                if MI_STAT > 1 {
+                    let bin = _mi_bin(bsize);
+                    _mi_stat_increase(ptr::addr_of_mut!((*stats).normal_bins[bin]), 1);
+                }
+            }
+        }
 
-    // TODO check is in that block located in page
-    debug_assert!(!page.free.is_empty());
+        if cfg!(debug_assertions) && (*page).is_zero() == 0 && !zero && !mi_page_is_huge(page) {
+            ptr::write_bytes(
+                block.cast::<u8>(),
+                MI_DEBUG_UNINIT,
+                mi_page_usable_block_size(page),
+            );
+        }
+
+        block.cast()
+    }
+}
 
-    page.used += 1;
+// ------------------------------------------------------
+// Free
+// ------------------------------------------------------
 
-    if zero {
-        // TODO zero the block
-        todo!("zero the block, to be implemented");
+// adjust stats on a free (block size is taken from the page to match the stats in `_mi_page_malloc`)
+fn mi_stat_free(page: *const MiPage) {
+    let stats = _mi_heap_stats(mi_heap_get_default());
+    let bsize = mi_page_usable_block_size(page);
+    unsafe {
+        if MI_STAT > 1 {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).malloc), bsize);
+        }
+        if bsize <= MI_MEDIUM_OBJ_SIZE_MAX {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).normal), bsize);
+            if MI_STAT > 1 {
+                _mi_stat_decrease(ptr::addr_of_mut!((*stats).normal_bins[_mi_bin(bsize)]), 1);
+            }
+        } else if bsize <= MI_LARGE_OBJ_SIZE_MAX {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).large), bsize);
+        } else {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).huge), bsize);
+        }
     }
+}
 
-    if page.is_zero() != 0 && !zero && !mi_page_is_huge(page.as_ref()) {
+// multi-threaded free (or free in huge block)
+fn _mi_free_block_mt(page: *mut MiPage, block: *mut MiBlock) {
+    // huge pages are special as they occupy the entire segment
+    // as these are large we reset the memory occupied by the page so it is available to other threads
+    // (as the owning thread needs to actually free the memory later).
+    let segment = _mi_page_segment(page);
+    if unsafe { (*segment).kind } == MiSegmentKind::MiSegmentHuge {
+        _mi_segment_huge_page_reset(segment, page, block);
+    } else if cfg!(debug_assertions) {
+        // not for huge segments as we just reset the content
         unsafe {
             ptr::write_bytes(
-                &mut block,
-                MI_DEBUG_UNINIT,
-                mi_page_usable_block_size(page.as_ref()),
+                block.cast::<u8>(),
+                MI_DEBUG_FREED,
+                mi_page_usable_block_size(page),
+            )
+        };
+    }
+
+    // Try to put the block on either the page-local thread free list, or the heap delayed free list.
+    let mut use_delayed;
+    let mut tfree = unsafe { (*page).xthread_free.load(Ordering::Relaxed) };
+    loop {
+        use_delayed = mi_tf_delayed(tfree) == MiDelayed::MiUseDelayedFree;
+        let tfreex = if use_delayed {
+            // unlikely: this only happens on the first concurrent free in a page that is in the full list
+            mi_tf_set_delayed(tfree, MiDelayed::MiDelayedFreeing)
+        } else {
+            // usual: directly add to page thread_free list
+            mi_block_set_next(page, block, mi_tf_block(tfree));
+            mi_tf_set_block(tfree, block)
+        };
+        match unsafe {
+            (*page).xthread_free.compare_exchange_weak(
+                tfree,
+                tfreex,
+                Ordering::Release,
+                Ordering::Relaxed,
+            )
+        } {
+            Ok(_) => break,
+            Err(current) => tfree = current,
+        }
+    }
+
+    if use_delayed {
+        // racy read on `heap`, but ok because MI_DELAYED_FREEING is set (see `mi_heap_delete` and `mi_heap_collect_abandon`)
+        let heap = unsafe { (*page).xheap.load(Ordering::Acquire) } as *mut MiHeap;
+        debug_assert!(!heap.is_null());
+        if !heap.is_null() {
+            // add to the delayed free list of this heap. (do this atomically as the lock only protects heap memory validity)
+            unsafe {
+                let mut dfree = (*heap).thread_delayed_free.load(Ordering::Relaxed);
+                loop {
+                    mi_block_set_nextx(
+                        heap.cast(),
+                        block,
+                        dfree,
+                        ptr::addr_of!((*heap).keys).cast(),
+                    );
+                    match (*heap).thread_delayed_free.compare_exchange_weak(
+                        dfree,
+                        block,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => break,
+                        Err(current) => dfree = current,
+                    }
+                }
+            }
+        }
+
+        // and reset the MI_DELAYED_FREEING flag
+        tfree = unsafe { (*page).xthread_free.load(Ordering::Relaxed) };
+        loop {
+            debug_assert!(mi_tf_delayed(tfree) == MiDelayed::MiDelayedFreeing);
+            let tfreex = mi_tf_set_delayed(tfree, MiDelayed::MiNoDelayedFree);
+            match unsafe {
+                (*page).xthread_free.compare_exchange_weak(
+                    tfree,
+                    tfreex,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                )
+            } {
+                Ok(_) => break,
+                Err(current) => tfree = current,
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------
+// Check for double free in secure and debug mode
+// This is somewhat expensive so only enabled for secure mode 4
+// ------------------------------------------------------
+
+#[allow(clippy::absurd_extreme_comparisons)] // `MI_SECURE` is a build configuration constant
+const MI_CHECK_DOUBLE_FREE: bool = MI_ENCODE_FREELIST && (MI_SECURE >= 4 || cfg!(debug_assertions));
+
+// linear check if the free list contains a specific element
+fn mi_list_contains(page: *const MiPage, mut list: *const MiBlock, elem: *const MiBlock) -> bool {
+    while !list.is_null() {
+        if elem == list {
+            return true;
+        }
+        list = mi_block_next(page, list);
+    }
+    false
+}
+
+#[inline(never)]
+fn mi_check_is_double_freex(page: *const MiPage, block: *const MiBlock) -> bool {
+    // The decoded value is in the same page (or NULL).
+    // Walk the free lists to verify positively if it is already freed
+    unsafe {
+        if mi_list_contains(page, (*page).free, block)
+            || mi_list_contains(page, (*page).local_free, block)
+            || mi_list_contains(page, mi_page_thread_free(page), block)
+        {
+            _mi_error_message(
+                libc::EAGAIN,
+                format_args!(
+                    "double free detected of block {:p} with size {}\n",
+                    block,
+                    mi_page_block_size(page)
+                ),
+            );
+            return true;
+        }
+    }
+    false
+}
+
+#[inline]
+fn mi_check_is_double_free(page: *const MiPage, block: *const MiBlock) -> bool {
+    if !MI_CHECK_DOUBLE_FREE {
+        return false;
+    }
+    // pretend it is freed, and get the decoded first field
+    let n = mi_block_nextx(page.cast(), block, unsafe { (*page).keys.as_ptr() });
+    // quick checks: is it an aligned pointer in the same page (or NULL)?
+    let is_aligned = (n as usize & (MI_INTPTR_SIZE - 1)) == 0;
+    if is_aligned && (n.is_null() || mi_is_in_same_page(block.cast(), n.cast())) {
+        // Suspicious: decoded value a in block is in the same page (or NULL) -- maybe a double free?
+        // (continue in separate function to improve code generation)
+        mi_check_is_double_freex(page, block)
+    } else {
+        false
+    }
+}
+
+// regular free
+#[inline]
+fn _mi_free_block(page: *mut MiPage, local: bool, block: *mut MiBlock) {
+    // and push it on the free list
+    if local {
+        // owning thread can free a block directly
+        if mi_check_is_double_free(page, block) {
+            return;
+        }
+        mi_free_block_local(page, block);
+    } else {
+        _mi_free_block_mt(page, block);
+    }
+}
+
+// Free a block of the owning thread (that was checked for a double free already)
+fn mi_free_block_local(page: *mut MiPage, block: *mut MiBlock) {
+    unsafe {
+        if cfg!(debug_assertions) && !mi_page_is_huge(page) {
+            // huge page content may be already decommitted
+            ptr::write_bytes(block.cast::<u8>(), MI_DEBUG_FREED, mi_page_block_size(page));
+        }
+        mi_block_set_next(page, block, (*page).local_free);
+        (*page).local_free = block;
+        (*page).used -= 1;
+        if (*page).used == 0 {
+            _mi_page_retire(page);
+        } else if mi_page_is_in_full(page) {
+            _mi_page_unfull(page);
+        }
+    }
+}
+
+// Adjust a block that was allocated aligned, to the actual start of the block in the page.
+pub fn _mi_page_ptr_unalign(
+    segment: *const MiSegment,
+    page: *const MiPage,
+    p: *const c_void,
+) -> *mut MiBlock {
+    debug_assert!(!page.is_null() && !p.is_null());
+    let diff = p as usize - _mi_page_start(segment, page, ptr::null_mut()) as usize;
+    let adjust = diff % mi_page_block_size(page);
+    (p as usize - adjust) as *mut MiBlock
+}
+
+fn _mi_free_generic(segment: *const MiSegment, page: *mut MiPage, is_local: bool, p: *mut c_void) {
+    let block = if mi_page_has_aligned(page) {
+        _mi_page_ptr_unalign(segment, page, p)
+    } else {
+        p as *mut MiBlock
+    };
+    // check for a double free before the statistics are updated
+    if is_local && mi_check_is_double_free(page, block) {
+        return;
+    }
+    if MI_STAT > 0 {
+        mi_stat_free(page);
+    }
+    if is_local {
+        mi_free_block_local(page, block);
+    } else {
+        _mi_free_block_mt(page, block);
+    }
+}
+
+// Get the segment data belonging to a pointer
+// This is just a single `and` in assembly but does further checks in debug mode
+// (and secure mode) if this was a valid pointer.
+#[inline]
+fn mi_checked_ptr_segment(p: *const c_void) -> *mut MiSegment {
+    if (p as usize & (std::mem::size_of::<usize>() - 1)) != 0 {
+        _mi_error_message(
+            libc::EINVAL,
+            format_args!("mi_free: invalid (unaligned) pointer: {:p}\n", p),
+        );
+        return ptr::null_mut();
+    }
+
+    // only accept pointers that point into a segment we allocated
+    let segment = _mi_segment_of(p);
+    if segment.is_null() {
+        _mi_warning_message(format_args!(
+            "mi_free: pointer might not point to a valid heap region: {:p}\n",
+            p
+        ));
+        return ptr::null_mut();
+    }
+    if _mi_ptr_cookie(segment.cast()) != unsafe { (*segment).cookie } {
+        _mi_error_message(
+            libc::EINVAL,
+            format_args!(
+                "mi_free: pointer does not point to a valid heap space: {:p}\n",
+                p
+            ),
+        );
+        return ptr::null_mut();
+    }
+    segment
+}
+
+// Free a block
+#[no_mangle]
+pub extern "C" fn mi_free(p: *mut c_void) {
+    if p.is_null() {
+        return;
+    }
+
+    let segment = mi_checked_ptr_segment(p);
+    if segment.is_null() {
+        return;
+    }
+    let is_local = _mi_thread_id() == unsafe { (*segment).thread_id.load(Ordering::Relaxed) };
+    let page = _mi_segment_page_of(segment, p);
+
+    if is_local {
+        // thread-local free?
+        if unsafe { (*page).flags.full_aligned } == 0 {
+            // and it is not a full page (full pages need to move from the full bin), nor has aligned blocks (aligned blocks need to be unaligned)
+            let block = p as *mut MiBlock;
+            if mi_check_is_double_free(page, block) {
+                return;
+            }
+            if MI_STAT > 0 {
+                mi_stat_free(page);
+            }
+            if cfg!(debug_assertions) && !mi_page_is_huge(page) {
+                // huge page content may be already decommitted
+                unsafe {
+                    ptr::write_bytes(block.cast::<u8>(), MI_DEBUG_FREED, mi_page_block_size(page))
+                };
+            }
+            unsafe {
+                mi_block_set_next(page, block, (*page).local_free);
+                (*page).local_free = block;
+                (*page).used -= 1;
+                if (*page).used == 0 {
+                    // using this expression generates better code than: page->used--; if (mi_page_all_free(page))
+                    _mi_page_retire(page);
+                }
+            }
+        } else {
+            // page is full or contains (inner) aligned blocks; use generic path
+            _mi_free_generic(segment, page, true, p);
+        }
+    } else {
+        // not thread-local; use generic path
+        _mi_free_generic(segment, page, false, p);
+    }
+}
+
+// ------------------------------------------------------
+// Usable size
+// ------------------------------------------------------
+
+// Bytes available in a block
+fn mi_page_usable_aligned_size_of(
+    segment: *const MiSegment,
+    page: *const MiPage,
+    p: *const c_void,
+) -> usize {
+    let block = _mi_page_ptr_unalign(segment, page, p);
+    let size = mi_page_usable_block_size(page);
+    let adjust = p as usize - block as usize;
+    debug_assert!(adjust <= size);
+    size - adjust
+}
+
+#[inline]
+fn _mi_usable_size(p: *const c_void) -> usize {
+    if p.is_null() {
+        return 0;
+    }
+    let segment = mi_checked_ptr_segment(p);
+    if segment.is_null() {
+        return 0;
+    }
+    let page = _mi_segment_page_of(segment, p);
+    if !mi_page_has_aligned(page) {
+        mi_page_usable_block_size(page)
+    } else {
+        // split out to separate routine for improved code generation
+        mi_page_usable_aligned_size_of(segment, page, p)
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mi_usable_size(p: *const c_void) -> usize {
+    _mi_usable_size(p)
+}
+
+// ------------------------------------------------------
+// Reallocation
+// ------------------------------------------------------
+
+pub fn _mi_heap_realloc_zero(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    zero: bool,
+) -> *mut c_void {
+    // if p == NULL then behave as malloc.
+    // else if size == 0 then reallocate to a zero-sized block (and don't return NULL, just as mi_malloc(0)).
+    // (this means that returning NULL always indicates an error, and `p` will not have been freed in that case.)
+    let size = _mi_usable_size(p); // also works if p == NULL (with size 0)
+    if newsize <= size && newsize >= (size / 2) && newsize > 0 {
+        // note: newsize must be > 0 or otherwise we return NULL for realloc(NULL,0)
+        return p; // reallocation still fits and not more than 50% waste
+    }
+    let newp = mi_heap_malloc(heap, newsize);
+    if !newp.is_null() {
+        if zero && newsize > size {
+            // also set last word in the previous allocation to zero to ensure any padding is zero-initialized
+            let start = size.saturating_sub(std::mem::size_of::<usize>());
+            unsafe { ptr::write_bytes(newp.cast::<u8>().add(start), 0, newsize - start) };
+        }
+        if !p.is_null() {
+            if (p as usize).is_multiple_of(std::mem::size_of::<usize>()) {
+                // a client may pass in an arbitrary pointer `p`..
+                let copysize = newsize.min(size);
+                unsafe { ptr::copy_nonoverlapping(p.cast::<u8>(), newp.cast::<u8>(), copysize) };
+            }
+            mi_free(p); // only free the original pointer if successful
+        }
+    }
+    newp
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_realloc(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+) -> *mut c_void {
+    _mi_heap_realloc_zero(heap, p, newsize, false)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_rezalloc(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+) -> *mut c_void {
+    _mi_heap_realloc_zero(heap, p, newsize, true)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_realloc(p: *mut c_void, newsize: usize) -> *mut c_void {
+    mi_heap_realloc(get_default_heap(), p, newsize)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_rezalloc(p: *mut c_void, newsize: usize) -> *mut c_void {
+    mi_heap_rezalloc(get_default_heap(), p, newsize)
+}
+
+// return true if successful
+pub fn _mi_free_delayed_block(block: *mut MiBlock) -> bool {
+    // get segment and page
+    let segment = _mi_ptr_segment(block.cast());
+    debug_assert!(_mi_ptr_cookie(segment.cast()) == unsafe { (*segment).cookie });
+    debug_assert!(_mi_thread_id() == unsafe { (*segment).thread_id.load(Ordering::Relaxed) });
+    let page = _mi_segment_page_of(segment, block.cast());
+
+    // Clear the no-delayed flag so delayed freeing is used again for this page.
+    // This must be done before collecting the free lists on this page -- otherwise
+    // some blocks may end up in the page `thread_free` list with no blocks in the
+    // heap `thread_delayed_free` list which may cause the page to be never freed!
+    // (it would only be freed if we happen to scan it in `mi_page_queue_find_free_ex`)
+    if !_mi_page_try_use_delayed_free(page, MiDelayed::MiUseDelayedFree, false) {
+        return false;
+    }
+
+    // collect all other non-local frees to ensure up-to-date `used` count
+    _mi_page_free_collect(page, false);
+
+    // and free the block (possibly freeing the page as well since used is updated)
+    _mi_free_block(page, true, block);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_void, ptr, sync::atomic::Ordering, thread};
+
+    use crate::{
+        heap::mi_heap_get_default,
+        init::mi_thread_init,
+        mimalloc_internal::{
+            _mi_page_start, _mi_ptr_cookie, _mi_thread_id, mi_block_next, mi_block_nextx,
+            mi_block_set_next, mi_is_in_same_page, mi_page_set_has_aligned, mi_page_set_heap,
+            mi_page_set_in_full, mi_page_thread_free, mi_page_thread_free_flag,
+            mi_page_usable_block_size, mi_tf_make,
+        },
+        mimalloc_types::{
+            MiBlock, MiDelayed, MiHeap, MiPage, MiSegment, MiSegmentKind, MI_ENCODE_FREELIST,
+            MI_SEGMENT_SIZE, MI_SLICES_PER_SEGMENT, MI_STAT,
+        },
+        os::{_mi_os_alloc_aligned, _mi_os_free},
+        segment_cache::{_mi_segment_map_allocated_at, _mi_segment_map_freed_at},
+        stats::_mi_heap_stats,
+    };
+
+    use super::{mi_free, MI_CHECK_DOUBLE_FREE};
+
+    const PAGE_SLICE: usize = 4;
+    const BLOCK_SIZE: usize = 64;
+    const BLOCK_COUNT: usize = 16;
+
+    // A hand-built segment owned by the current thread with a single page of
+    // `BLOCK_COUNT` blocks that are all in use.
+    struct TestSegment {
+        segment: *mut MiSegment,
+        page: *mut MiPage,
+    }
+
+    impl TestSegment {
+        fn new(heap: *mut MiHeap) -> TestSegment {
+            let segment: *mut MiSegment =
+                _mi_os_alloc_aligned(MI_SEGMENT_SIZE, MI_SEGMENT_SIZE, true, ptr::null_mut())
+                    .cast();
+            assert!(!segment.is_null());
+            unsafe {
+                (*segment).cookie = _mi_ptr_cookie(segment.cast());
+                (*segment)
+                    .thread_id
+                    .store(_mi_thread_id(), Ordering::Relaxed);
+                (*segment).kind = MiSegmentKind::MiSegmentNormal;
+                (*segment).segment_slices = MI_SLICES_PER_SEGMENT as u64;
+                (*segment).slice_entries = MI_SLICES_PER_SEGMENT as u64;
+                (*segment).segment_info_slices = 1;
+                (*segment).used = 1;
+
+                let page = ptr::addr_of_mut!((*segment).slices[PAGE_SLICE]);
+                (*page).slice_count = 1;
+                (*page).slice_offset = 0;
+                (*page).xblock_size = BLOCK_SIZE as u32;
+                (*page).capacity = BLOCK_COUNT as u16;
+                (*page).reserved = BLOCK_COUNT as u16;
+                (*page).used = BLOCK_COUNT as u32;
+                mi_page_set_heap(page, heap);
+                _mi_segment_map_allocated_at(segment);
+                TestSegment { segment, page }
+            }
+        }
+
+        fn block(&self, i: usize) -> *mut c_void {
+            assert!(i < BLOCK_COUNT);
+            let start = _mi_page_start(self.segment, self.page, ptr::null_mut());
+            start.wrapping_add(i * BLOCK_SIZE).cast()
+        }
+    }
+
+    impl Drop for TestSegment {
+        fn drop(&mut self) {
+            _mi_segment_map_freed_at(self.segment);
+            _mi_os_free(self.segment.cast(), MI_SEGMENT_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_mi_free_local_pushes_local_free() {
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        let (p0, p1) = (ts.block(0), ts.block(1));
+        mi_free(p0);
+        mi_free(p1);
+        unsafe {
+            assert_eq!((*ts.page).used, BLOCK_COUNT as u32 - 2);
+            assert_eq!((*ts.page).local_free, p1 as *mut MiBlock);
+            assert_eq!(mi_block_next(ts.page, p1.cast()), p0 as *mut MiBlock);
+            assert!(mi_page_thread_free(ts.page).is_null());
+        }
+    }
+
+    #[test]
+    fn test_mi_free_from_other_thread_pushes_thread_free() {
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        unsafe {
+            // a page that is not in the full queue does not use the heap delayed free list
+            (*ts.page).xthread_free.store(
+                mi_tf_make(ptr::null_mut(), MiDelayed::MiNoDelayedFree),
+                Ordering::Relaxed,
+            );
+        }
+        let (p0, p1) = (ts.block(0) as usize, ts.block(1) as usize);
+        thread::spawn(move || {
+            mi_free(p0 as *mut c_void);
+            mi_free(p1 as *mut c_void);
+        })
+        .join()
+        .unwrap();
+        assert!(heap.thread_delayed_free.load(Ordering::Relaxed).is_null());
+        unsafe {
+            // `used` is only updated when the owner collects the thread free list
+            assert_eq!((*ts.page).used, BLOCK_COUNT as u32);
+            assert!((*ts.page).local_free.is_null());
+            assert_eq!(mi_page_thread_free(ts.page), p1 as *mut MiBlock);
+            assert_eq!(
+                mi_block_next(ts.page, p1 as *mut MiBlock),
+                p0 as *mut MiBlock
             );
         }
     }
 
-    let ptr: *mut MiBlock = &mut block;
-    ptr as *mut c_void
+    #[test]
+    fn test_mi_free_full_page_uses_heap_delayed_free() {
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        mi_page_set_in_full(ts.page, true);
+        unsafe {
+            (*ts.page).xthread_free.store(
+                mi_tf_make(ptr::null_mut(), MiDelayed::MiUseDelayedFree),
+                Ordering::Relaxed,
+            );
+        }
+        let (p0, p1) = (ts.block(0) as usize, ts.block(1) as usize);
+        thread::spawn(move || {
+            mi_free(p0 as *mut c_void);
+            mi_free(p1 as *mut c_void);
+        })
+        .join()
+        .unwrap();
+        // only the first free goes to the heap, the next ones use the page thread free list
+        assert_eq!(
+            heap.thread_delayed_free.load(Ordering::Relaxed),
+            p0 as *mut MiBlock
+        );
+        assert_eq!(
+            mi_page_thread_free_flag(ts.page),
+            MiDelayed::MiNoDelayedFree
+        );
+        assert_eq!(mi_page_thread_free(ts.page), p1 as *mut MiBlock);
+    }
+
+    #[test]
+    fn test_mi_free_ignores_foreign_pointers() {
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        let mut foreign = Box::new([0usize; 8]);
+        mi_free(ptr::null_mut());
+        mi_free(foreign.as_mut_ptr().cast());
+        // unaligned pointer into a valid page
+        mi_free((ts.block(0) as usize + 1) as *mut c_void);
+        assert_eq!(foreign[0], 0);
+        unsafe {
+            assert_eq!((*ts.page).used, BLOCK_COUNT as u32);
+            assert!((*ts.page).local_free.is_null());
+        }
+    }
+
+    #[test]
+    fn test_mi_block_next_encoded() {
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        unsafe {
+            (*ts.page).keys = [
+                0x9e37_79b9_7f4a_7c15u64 as usize,
+                0xbf58_476d_1ce4_e5b9u64 as usize,
+            ];
+        }
+        let (b0, b1): (*mut MiBlock, *mut MiBlock) = (ts.block(0).cast(), ts.block(1).cast());
+        mi_block_set_next(ts.page, b1, b0);
+        mi_block_set_next(ts.page, b0, ptr::null());
+        assert_eq!(mi_block_next(ts.page, b1), b0);
+        assert!(mi_block_next(ts.page, b0).is_null());
+        if MI_ENCODE_FREELIST {
+            unsafe {
+                assert_ne!((*b1).next, b0 as usize);
+                assert_ne!((*b0).next, 0);
+                // an overflow that writes a plain pointer does not decode to a block in the page
+                (*b1).next = ts.block(2) as usize;
+                let next = mi_block_nextx(ts.page.cast(), b1, (*ts.page).keys.as_ptr());
+                assert!(!next.is_null() && !mi_is_in_same_page(b1.cast(), next.cast()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_mi_free_detects_double_free() {
+        if !MI_CHECK_DOUBLE_FREE {
+            return;
+        }
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        unsafe {
+            (*ts.page).keys = [
+                0x2545_f491_4f6c_dd1du64 as usize,
+                0x94d0_49bb_1331_11ebu64 as usize,
+            ];
+        }
+        let (p0, p1) = (ts.block(0), ts.block(1));
+        mi_free(p0);
+        mi_free(p1);
+        mi_free(p0); // already on the `local_free` list
+        unsafe {
+            assert_eq!((*ts.page).used, BLOCK_COUNT as u32 - 2);
+            assert_eq!((*ts.page).local_free, p1 as *mut MiBlock);
+            assert_eq!(mi_block_next(ts.page, p1.cast()), p0 as *mut MiBlock);
+            assert!(mi_block_next(ts.page, p0.cast()).is_null());
+        }
+    }
+
+    #[test]
+    fn test_mi_free_generic_double_free_keeps_stats() {
+        if !MI_CHECK_DOUBLE_FREE || MI_STAT == 0 {
+            return;
+        }
+        // make sure the statistics are thread local
+        mi_thread_init();
+        let stats = _mi_heap_stats(mi_heap_get_default());
+        let mut heap = MiHeap::new();
+        let ts = TestSegment::new(&mut heap);
+        // aligned blocks take the generic free path
+        mi_page_set_has_aligned(ts.page, true);
+        let p0 = ts.block(0);
+        let bsize = mi_page_usable_block_size(ts.page) as i64;
+        let freed = unsafe { (*stats).normal.freed };
+        mi_free(p0);
+        assert_eq!(unsafe { (*stats).normal.freed }, freed + bsize);
+        mi_free(p0); // detected as a double free
+        assert_eq!(unsafe { (*stats).normal.freed }, freed + bsize);
+        unsafe {
+            assert_eq!((*ts.page).used, BLOCK_COUNT as u32 - 1);
+            assert_eq!((*ts.page).local_free, p0 as *mut MiBlock);
+        }
+    }
 }
@@ -0,0 +1,475 @@
+/* -----------------------------------------------------------
+  Aligned Allocation
+----------------------------------------------------------- */
+
+use std::{ffi::c_void, ptr};
+
+use crate::{
+    alloc::{
+        _mi_heap_malloc_zero, _mi_heap_malloc_zero_ex, _mi_heap_realloc_zero, _mi_page_malloc,
+        mi_free, mi_heap_malloc_small, mi_usable_size,
+    },
+    mimalloc_internal::{
+        _mi_heap_get_free_small_page, _mi_is_power_of_two, _mi_ptr_page, get_default_heap,
+        mi_count_size_overflow, mi_page_set_has_aligned,
+    },
+    mimalloc_types::{
+        MiHeap, MI_ALIGNMENT_MAX, MI_MAX_ALIGN_GUARANTEE, MI_PADDING_SIZE, MI_SMALL_SIZE_MAX,
+    },
+    options::_mi_error_message,
+};
+
+// Fallback primitive aligned allocation -- split out for better codegen
+#[inline(never)]
+fn mi_heap_malloc_zero_aligned_at_fallback(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+    zero: bool,
+) -> *mut c_void {
+    debug_assert!(size <= isize::MAX as usize);
+    debug_assert!(alignment != 0 && _mi_is_power_of_two(alignment));
+
+    let align_mask = alignment - 1; // for any x, `(x & align_mask) == (x % alignment)`
+    let padsize = size + MI_PADDING_SIZE;
+
+    // use regular allocation if it is guaranteed to fit the alignment constraints
+    if offset == 0
+        && alignment <= padsize
+        && padsize <= MI_MAX_ALIGN_GUARANTEE
+        && (padsize & align_mask) == 0
+    {
+        let p = _mi_heap_malloc_zero(heap, size, zero);
+        debug_assert!(p.is_null() || (p as usize).is_multiple_of(alignment));
+        return p;
+    }
+
+    let p = if alignment > MI_ALIGNMENT_MAX {
+        // use OS allocation for very large alignment and allocate inside a huge page (dedicated segment with 1 page)
+        // This can support alignments >= MI_SEGMENT_SIZE by ensuring the object can be aligned at a point in the
+        // first (and single) page such that the segment info is `MI_SEGMENT_SIZE` bytes before it (so it can be found by aligning the pointer down)
+        if offset != 0 {
+            // todo: cannot support offset alignment for very large alignments yet
+            _mi_error_message(
+                libc::EOVERFLOW,
+                format_args!(
+                    "aligned allocation with a very large alignment cannot be used with an alignment offset (size {}, alignment {}, offset {})\n",
+                    size, alignment, offset
+                ),
+            );
+            return ptr::null_mut();
+        }
+        let oversize = if size <= MI_SMALL_SIZE_MAX {
+            MI_SMALL_SIZE_MAX + 1 // ensure we use generic malloc path
+        } else {
+            size
+        };
+        // the page block size should be large enough to align in the single huge page block
+        // zero afterwards as only the area from the aligned_p may be committed!
+        _mi_heap_malloc_zero_ex(heap, oversize, false, alignment)
+    } else {
+        // otherwise over-allocate
+        _mi_heap_malloc_zero(heap, size + alignment - 1, zero)
+    };
+    if p.is_null() {
+        return ptr::null_mut();
+    }
+
+    // .. and align within the allocation
+    let poffset = (p as usize + offset) & align_mask;
+    let adjust = if poffset == 0 { 0 } else { alignment - poffset };
+    debug_assert!(adjust < alignment);
+    let aligned_p = (p as usize + adjust) as *mut c_void;
+    if aligned_p != p {
+        mi_page_set_has_aligned(_mi_ptr_page(p), true);
+    }
+
+    debug_assert!((aligned_p as usize + offset).is_multiple_of(alignment));
+    debug_assert!(mi_usable_size(aligned_p) >= size);
+    debug_assert!(mi_usable_size(p) == mi_usable_size(aligned_p) + adjust);
+
+    // now zero the block if needed
+    if alignment > MI_ALIGNMENT_MAX && zero {
+        unsafe { ptr::write_bytes(aligned_p.cast::<u8>(), 0, mi_usable_size(aligned_p)) };
+    }
+    aligned_p
+}
+
+// Primitive aligned allocation
+fn mi_heap_malloc_zero_aligned_at(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+    zero: bool,
+) -> *mut c_void {
+    // note: we don't require `size > offset`, we just guarantee that the address at offset is aligned regardless of the allocated size.
+    if alignment == 0 || !_mi_is_power_of_two(alignment) {
+        // require power-of-two (see <https://en.cppreference.com/w/c/memory/aligned_alloc>)
+        _mi_error_message(
+            libc::EOVERFLOW,
+            format_args!(
+                "aligned allocation requires the alignment to be a power-of-two (size {}, alignment {})\n",
+                size, alignment
+            ),
+        );
+        return ptr::null_mut();
+    }
+
+    if size > isize::MAX as usize {
+        // we don't allocate more than PTRDIFF_MAX (see <https://sourceware.org/ml/libc-announce/2019/msg00001.html>)
+        _mi_error_message(
+            libc::EOVERFLOW,
+            format_args!(
+                "aligned allocation request is too large (size {}, alignment {})\n",
+                size, alignment
+            ),
+        );
+        return ptr::null_mut();
+    }
+    let align_mask = alignment - 1; // for any x, `(x & align_mask) == (x % alignment)`
+    let padsize = size + MI_PADDING_SIZE; // note: cannot overflow due to earlier size > PTRDIFF_MAX check
+
+    // try first if there happens to be a small block available with just the right alignment
+    if padsize <= MI_SMALL_SIZE_MAX && alignment <= padsize {
+        let page = _mi_heap_get_free_small_page(heap, padsize);
+        let free = unsafe { (*page).free };
+        let is_aligned = ((free as usize + offset) & align_mask) == 0;
+        if !free.is_null() && is_aligned {
+            let p = _mi_page_malloc(heap, page, padsize, zero);
+            debug_assert!(!p.is_null());
+            debug_assert!((p as usize + offset).is_multiple_of(alignment));
+            return p;
+        }
+    }
+    // fallback
+    mi_heap_malloc_zero_aligned_at_fallback(heap, size, alignment, offset, zero)
+}
+
+// ------------------------------------------------------
+// Optimized mi_heap_malloc_aligned / mi_malloc_aligned
+// ------------------------------------------------------
+
+#[no_mangle]
+pub extern "C" fn mi_heap_malloc_aligned_at(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_malloc_zero_aligned_at(heap, size, alignment, offset, false)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_malloc_aligned(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+) -> *mut c_void {
+    // with padding, we can only guarantee this for word alignment
+    // (note: `MI_PADDING_SIZE` is always reserved so small blocks are not naturally aligned)
+    if alignment == std::mem::size_of::<usize>() && size <= MI_SMALL_SIZE_MAX {
+        // fast path for common alignment and size
+        mi_heap_malloc_small(heap, size)
+    } else {
+        mi_heap_malloc_aligned_at(heap, size, alignment, 0)
+    }
+}
+
+// ------------------------------------------------------
+// Aligned Allocation
+// ------------------------------------------------------
+
+#[no_mangle]
+pub extern "C" fn mi_heap_zalloc_aligned_at(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_malloc_zero_aligned_at(heap, size, alignment, offset, true)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_zalloc_aligned(
+    heap: *mut MiHeap,
+    size: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_zalloc_aligned_at(heap, size, alignment, 0)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_calloc_aligned_at(
+    heap: *mut MiHeap,
+    count: usize,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    let mut total: usize = 0;
+    if mi_count_size_overflow(count, size, &mut total) {
+        return ptr::null_mut();
+    }
+    mi_heap_zalloc_aligned_at(heap, total, alignment, offset)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_calloc_aligned(
+    heap: *mut MiHeap,
+    count: usize,
+    size: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_calloc_aligned_at(heap, count, size, alignment, 0)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_malloc_aligned_at(
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_malloc_aligned_at(get_default_heap(), size, alignment, offset)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_malloc_aligned(size: usize, alignment: usize) -> *mut c_void {
+    mi_heap_malloc_aligned(get_default_heap(), size, alignment)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_zalloc_aligned_at(
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_zalloc_aligned_at(get_default_heap(), size, alignment, offset)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_zalloc_aligned(size: usize, alignment: usize) -> *mut c_void {
+    mi_heap_zalloc_aligned(get_default_heap(), size, alignment)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_calloc_aligned_at(
+    count: usize,
+    size: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_calloc_aligned_at(get_default_heap(), count, size, alignment, offset)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_calloc_aligned(count: usize, size: usize, alignment: usize) -> *mut c_void {
+    mi_heap_calloc_aligned(get_default_heap(), count, size, alignment)
+}
+
+// ------------------------------------------------------
+// Aligned re-allocation
+// ------------------------------------------------------
+
+fn mi_heap_realloc_zero_aligned_at(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+    offset: usize,
+    zero: bool,
+) -> *mut c_void {
+    debug_assert!(alignment > 0);
+    if alignment <= std::mem::size_of::<usize>() {
+        return _mi_heap_realloc_zero(heap, p, newsize, zero);
+    }
+    if p.is_null() {
+        return mi_heap_malloc_zero_aligned_at(heap, newsize, alignment, offset, zero);
+    }
+    let size = mi_usable_size(p);
+    if newsize <= size
+        && newsize >= (size - (size / 2))
+        && (p as usize + offset).is_multiple_of(alignment)
+    {
+        return p; // reallocation still fits, is aligned and not more than 50% waste
+    }
+    // note: we don't zero allocate upfront so we only zero initialize the expanded part
+    let newp = mi_heap_malloc_aligned_at(heap, newsize, alignment, offset);
+    if !newp.is_null() {
+        if zero && newsize > size {
+            // also set last word in the previous allocation to zero to ensure any padding is zero-initialized
+            let start = size.saturating_sub(std::mem::size_of::<usize>());
+            unsafe { ptr::write_bytes(newp.cast::<u8>().add(start), 0, newsize - start) };
+        }
+        unsafe { ptr::copy_nonoverlapping(p.cast::<u8>(), newp.cast::<u8>(), newsize.min(size)) };
+        mi_free(p); // only free if successful
+    }
+    newp
+}
+
+fn mi_heap_realloc_zero_aligned(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+    zero: bool,
+) -> *mut c_void {
+    debug_assert!(alignment > 0);
+    if alignment <= std::mem::size_of::<usize>() {
+        return _mi_heap_realloc_zero(heap, p, newsize, zero);
+    }
+    let offset = p as usize % alignment; // use offset of previous allocation (p can be NULL)
+    mi_heap_realloc_zero_aligned_at(heap, p, newsize, alignment, offset, zero)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_realloc_aligned_at(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_realloc_zero_aligned_at(heap, p, newsize, alignment, offset, false)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_realloc_aligned(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_realloc_zero_aligned(heap, p, newsize, alignment, false)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_rezalloc_aligned(
+    heap: *mut MiHeap,
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_realloc_zero_aligned(heap, p, newsize, alignment, true)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_realloc_aligned_at(
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+    offset: usize,
+) -> *mut c_void {
+    mi_heap_realloc_aligned_at(get_default_heap(), p, newsize, alignment, offset)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_realloc_aligned(
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_realloc_aligned(get_default_heap(), p, newsize, alignment)
+}
+
+#[no_mangle]
+pub extern "C" fn mi_rezalloc_aligned(
+    p: *mut c_void,
+    newsize: usize,
+    alignment: usize,
+) -> *mut c_void {
+    mi_heap_rezalloc_aligned(get_default_heap(), p, newsize, alignment)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_void, ptr};
+
+    use crate::{
+        alloc::{mi_free, mi_usable_size},
+        heap::mi_heap_collect,
+        mimalloc_internal::_mi_ptr_segment,
+        mimalloc_types::{MiSegmentKind, MI_ALIGNMENT_MAX},
+        page::tests::TestHeap,
+    };
+
+    use super::{mi_heap_malloc_aligned, mi_heap_realloc_aligned, mi_heap_zalloc_aligned};
+
+    #[test]
+    fn test_mi_heap_malloc_aligned() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let mut blocks: Vec<*mut c_void> = Vec::new();
+        for shift in 3..=20 {
+            let alignment = 1usize << shift;
+            for size in [1, 24, 100, alignment, 3 * alignment + 5, 200_000] {
+                let p = mi_heap_malloc_aligned(heap, size, alignment);
+                assert!(!p.is_null());
+                assert_eq!(
+                    p as usize % alignment,
+                    0,
+                    "size {size}, alignment {alignment}"
+                );
+                assert!(mi_usable_size(p) >= size);
+                unsafe { ptr::write_bytes(p.cast::<u8>(), 0x5A, size) };
+                blocks.push(p);
+            }
+        }
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_heap_malloc_aligned_invalid() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        assert!(mi_heap_malloc_aligned(heap, 16, 0).is_null());
+        assert!(mi_heap_malloc_aligned(heap, 16, 24).is_null());
+        assert!(mi_heap_malloc_aligned(heap, usize::MAX - 8, 64).is_null());
+    }
+
+    #[test]
+    fn test_mi_heap_malloc_aligned_huge_alignment() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let alignment = 2 * MI_ALIGNMENT_MAX;
+        let p = mi_heap_zalloc_aligned(heap, 1000, alignment);
+        assert!(!p.is_null());
+        assert_eq!(p as usize % alignment, 0);
+        assert_eq!(
+            unsafe { (*_mi_ptr_segment(p)).kind },
+            MiSegmentKind::MiSegmentHuge
+        );
+        let bytes = unsafe { std::slice::from_raw_parts(p.cast::<u8>(), 1000) };
+        assert!(bytes.iter().all(|&b| b == 0));
+        mi_free(p);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_heap_realloc_aligned() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let alignment = 256;
+        let mut p = mi_heap_malloc_aligned(heap, 40, alignment);
+        for i in 0..40u8 {
+            unsafe { *p.cast::<u8>().add(i as usize) = i };
+        }
+        for newsize in [100, 5000, 300_000, 64] {
+            p = mi_heap_realloc_aligned(heap, p, newsize, alignment);
+            assert!(!p.is_null());
+            assert_eq!(p as usize % alignment, 0);
+            assert!(mi_usable_size(p) >= newsize);
+            for i in 0..40u8 {
+                assert_eq!(unsafe { *p.cast::<u8>().add(i as usize) }, i);
+            }
+        }
+        mi_free(p);
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+}
@@ -1,7 +1,339 @@
-use std::{ffi::c_void, ptr};
+/* ----------------------------------------------------------------------------
+"Arenas" are fixed area's of OS memory from which we can allocate
+large blocks (>= MI_ARENA_MIN_BLOCK_SIZE, 4MiB).
+In contrast to the rest of mimalloc, the arenas are shared between
+threads and need to be accessed using atomic operations.
 
-use crate::mimalloc_types::{MiArenaIdT, MiOsTLD};
+Currently arenas are only used to for huge OS page (1GiB) reservations,
+or direct OS memory reservations -- otherwise we delegate allocation to the OS.
 
+In the future, we can expose an API to manually add more kinds of arenas
+which is sometimes needed for embedded devices or shared memory for example.
+(We can also employ this with WASI or `sbrk` systems to reserve large arenas
+ on demand and be able to reuse them efficiently).
+
+The arena allocation needs to be thread safe and we use an atomic bitmap to allocate.
+-----------------------------------------------------------------------------*/
+
+use std::{
+    ffi::c_void,
+    mem::size_of,
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+
+use crate::{
+    bitmap::{
+        _mi_bitmap_claim, _mi_bitmap_claim_across, _mi_bitmap_is_claimed_across,
+        _mi_bitmap_try_find_from_claim_across, _mi_bitmap_unclaim_across, mi_bitmap_index_bit,
+        mi_bitmap_index_create, mi_bitmap_index_field, MiBitmapField, MiBitmapIndex,
+        MI_BITMAP_FIELD_BITS,
+    },
+    mimalloc_internal::_mi_divide_up,
+    mimalloc_types::{MiArenaIdT, MiOption, MiOsTLD, MI_SEGMENT_ALIGN, MI_SEGMENT_SIZE},
+    options::{_mi_error_message, _mi_verbose_message, _mi_warning_message, mi_option_is_enabled},
+    os::{
+        _mi_align_up, _mi_os_alloc, _mi_os_alloc_aligned, _mi_os_alloc_aligned_offset,
+        _mi_os_alloc_huge_os_pages, _mi_os_commit, _mi_os_decommit, _mi_os_free_aligned,
+        _mi_os_free_ex, _mi_os_free_huge_pages, _mi_os_numa_node_count, _mi_os_numa_node_get,
+    },
+};
+
+/* -----------------------------------------------------------
+  Arena allocation
+----------------------------------------------------------- */
+
+// A size of an arena block, and the minimal object size that is allocated from an arena
+pub const MI_ARENA_BLOCK_SIZE: usize = MI_SEGMENT_SIZE; // 32MiB
+const MI_ARENA_MIN_OBJ_SIZE: usize = MI_ARENA_BLOCK_SIZE / 2; // 16MiB
+const MI_MAX_ARENAS: usize = 64; // not more than 126 (since we use 7 bits in the memid and an arena index + 1)
+
+// A memory arena descriptor
+#[repr(C)]
+struct MiArena {
+    id: MiArenaIdT,                       // arena id; 0 for non-specific
+    exclusive: bool,                      // only allow allocations if specifically for this arena
+    start: AtomicPtr<u8>,                 // the start of the memory area
+    block_count: usize, // size of the area in arena blocks (of `MI_ARENA_BLOCK_SIZE`)
+    field_count: usize, // number of bitmap fields (where `field_count * MI_BITMAP_FIELD_BITS >= block_count`)
+    numa_node: i32,     // associated NUMA node
+    is_zero_init: bool, // is the arena zero initialized?
+    allow_decommit: bool, // is decommit allowed? if true, is_large should be false and blocks_committed != NULL
+    is_large: bool,       // large- or huge OS pages (always committed)
+    search_idx: AtomicUsize, // optimization to start the search for free blocks
+    blocks_dirty: *mut MiBitmapField, // are the blocks potentially non-zero?
+    blocks_committed: *mut MiBitmapField, // are the blocks committed? (can be NULL for memory that cannot be decommitted)
+    blocks_inuse: [MiBitmapField; 1], // in-place bitmap of in-use blocks (of size `field_count`)
+}
+
+// The available arenas
+#[allow(clippy::declare_interior_mutable_const)]
+const MI_ARENA_INIT: AtomicPtr<MiArena> = AtomicPtr::new(ptr::null_mut());
+static mi_arenas: [AtomicPtr<MiArena>; MI_MAX_ARENAS] = [MI_ARENA_INIT; MI_MAX_ARENAS];
+static mi_arena_count: AtomicUsize = AtomicUsize::new(0); // = 0
+
+fn mi_arena_blocks_inuse(arena: *mut MiArena) -> *mut MiBitmapField {
+    unsafe { ptr::addr_of_mut!((*arena).blocks_inuse).cast() }
+}
+
+/* -----------------------------------------------------------
+  Arena id's
+  0 is used for non-arena's (like OS memory)
+  id = arena_index + 1
+----------------------------------------------------------- */
+
+fn mi_arena_id_index(id: MiArenaIdT) -> usize {
+    if id <= 0 {
+        MI_MAX_ARENAS
+    } else {
+        id as usize - 1
+    }
+}
+
+fn mi_arena_id_create(arena_index: usize) -> MiArenaIdT {
+    debug_assert!(arena_index < MI_MAX_ARENAS);
+    let id = arena_index as MiArenaIdT + 1;
+    debug_assert!((1..=127).contains(&id));
+    id
+}
+
+pub fn _mi_arena_id_none() -> MiArenaIdT {
+    0
+}
+
+fn mi_arena_id_is_suitable(
+    arena_id: MiArenaIdT,
+    arena_is_exclusive: bool,
+    req_arena_id: MiArenaIdT,
+) -> bool {
+    (!arena_is_exclusive && req_arena_id == _mi_arena_id_none()) || arena_id == req_arena_id
+}
+
+/* -----------------------------------------------------------
+  Arena allocations get a memory id where the lower 8 bits are
+  the arena id, and the upper bits the block index.
+----------------------------------------------------------- */
+
+// Use `0` as a special id for direct OS allocated memory.
+pub const MI_MEMID_OS: usize = 0;
+
+fn mi_arena_memid_create(id: MiArenaIdT, exclusive: bool, bitmap_index: MiBitmapIndex) -> usize {
+    debug_assert!(((bitmap_index << 8) >> 8) == bitmap_index); // no overflow?
+    debug_assert!((0..=0x7F).contains(&id));
+    (bitmap_index << 8) | (id as usize & 0x7F) | if exclusive { 0x80 } else { 0 }
+}
+
+fn mi_arena_memid_indices(
+    arena_memid: usize,
+    arena_index: *mut usize,
+    bitmap_index: *mut MiBitmapIndex,
+) -> bool {
+    unsafe {
+        *bitmap_index = arena_memid >> 8;
+        let id = (arena_memid & 0x7F) as MiArenaIdT;
+        *arena_index = mi_arena_id_index(id);
+    }
+    (arena_memid & 0x80) != 0
+}
+
+// Is a segment with this memory id suitable for a request for `request_arena_id`?
+pub fn _mi_arena_memid_is_suitable(arena_memid: usize, request_arena_id: MiArenaIdT) -> bool {
+    let id = (arena_memid & 0x7F) as MiArenaIdT;
+    let exclusive = (arena_memid & 0x80) != 0;
+    mi_arena_id_is_suitable(id, exclusive, request_arena_id)
+}
+
+pub fn _mi_arena_is_os_allocated(arena_memid: usize) -> bool {
+    arena_memid == MI_MEMID_OS
+}
+
+fn mi_block_count_of_size(size: usize) -> usize {
+    _mi_divide_up(size, MI_ARENA_BLOCK_SIZE)
+}
+
+/* -----------------------------------------------------------
+  Thread safe allocation in an arena
+----------------------------------------------------------- */
+
+fn mi_arena_alloc(arena: *mut MiArena, blocks: usize, bitmap_idx: *mut MiBitmapIndex) -> bool {
+    let idx = 0; // (*arena).search_idx.load(Ordering::Relaxed);  // start from last search; ok to be relaxed as the exact start does not matter
+    unsafe {
+        if _mi_bitmap_try_find_from_claim_across(
+            mi_arena_blocks_inuse(arena),
+            (*arena).field_count,
+            idx,
+            blocks,
+            bitmap_idx,
+        ) {
+            (*arena)
+                .search_idx
+                .store(mi_bitmap_index_field(*bitmap_idx), Ordering::Relaxed); // start search from found location next time around
+            return true;
+        }
+    }
+    false
+}
+
+/* -----------------------------------------------------------
+  Arena Allocation
+----------------------------------------------------------- */
+
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
+fn mi_arena_alloc_from(
+    arena: *mut MiArena,
+    arena_index: usize,
+    needed_bcount: usize,
+    commit: *mut bool,
+    large: *mut bool,
+    is_pinned: *mut bool,
+    is_zero: *mut bool,
+    req_arena_id: MiArenaIdT,
+    memid: *mut usize,
+    _tld: *mut MiOsTLD,
+) -> *mut c_void {
+    unsafe {
+        debug_assert!(mi_arena_id_index((*arena).id) == arena_index);
+        if !mi_arena_id_is_suitable((*arena).id, (*arena).exclusive, req_arena_id) {
+            return ptr::null_mut();
+        }
+
+        let mut bitmap_index: MiBitmapIndex = 0;
+        if !mi_arena_alloc(arena, needed_bcount, &mut bitmap_index) {
+            return ptr::null_mut();
+        }
+
+        // claimed it! set the dirty bits (todo: no need for an atomic op here?)
+        let p: *mut c_void = (*arena)
+            .start
+            .load(Ordering::Relaxed)
+            .add(mi_bitmap_index_bit(bitmap_index) * MI_ARENA_BLOCK_SIZE)
+            .cast();
+        *memid = mi_arena_memid_create((*arena).id, (*arena).exclusive, bitmap_index);
+        *is_zero = _mi_bitmap_claim_across(
+            (*arena).blocks_dirty,
+            (*arena).field_count,
+            needed_bcount,
+            bitmap_index,
+            ptr::null_mut(),
+        );
+        *large = (*arena).is_large;
+        *is_pinned = (*arena).is_large || !(*arena).allow_decommit;
+        if (*arena).blocks_committed.is_null() {
+            // always committed
+            *commit = true;
+        } else if *commit {
+            // arena not committed as a whole, but commit requested: ensure commit now
+            let mut any_uncommitted = false;
+            _mi_bitmap_claim_across(
+                (*arena).blocks_committed,
+                (*arena).field_count,
+                needed_bcount,
+                bitmap_index,
+                &mut any_uncommitted,
+            );
+            if any_uncommitted {
+                let mut commit_zero = false;
+                _mi_os_commit(p, needed_bcount * MI_ARENA_BLOCK_SIZE, &mut commit_zero);
+                if commit_zero {
+                    *is_zero = true;
+                }
+            }
+        } else {
+            // no need to commit, but check if already fully committed
+            *commit = _mi_bitmap_is_claimed_across(
+                (*arena).blocks_committed,
+                (*arena).field_count,
+                needed_bcount,
+                bitmap_index,
+            );
+        }
+        p
+    }
+}
+
+// allocate from an arena with fallback to the OS
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
+fn mi_arena_allocate(
+    numa_node: i32,
+    size: usize,
+    alignment: usize,
+    commit: *mut bool,
+    large: *mut bool,
+    is_pinned: *mut bool,
+    is_zero: *mut bool,
+    req_arena_id: MiArenaIdT,
+    memid: *mut usize,
+    tld: *mut MiOsTLD,
+) -> *mut c_void {
+    debug_assert!(alignment <= MI_SEGMENT_ALIGN);
+    let max_arena = mi_arena_count.load(Ordering::Relaxed);
+    let bcount = mi_block_count_of_size(size);
+    if max_arena == 0 {
+        return ptr::null_mut();
+    }
+    debug_assert!(size <= bcount * MI_ARENA_BLOCK_SIZE);
+
+    // is the arena numa local and are large OS pages allowed (or the arena is not large OS pages)?
+    let is_suitable = |arena: *mut MiArena, numa_local: bool| unsafe {
+        let on_node = (*arena).numa_node < 0 || (*arena).numa_node == numa_node;
+        on_node == numa_local && (*large || !(*arena).is_large)
+    };
+
+    let arena_index = mi_arena_id_index(req_arena_id);
+    if arena_index < MI_MAX_ARENAS {
+        // try a specific arena if requested
+        let arena = mi_arenas[arena_index].load(Ordering::Relaxed);
+        if !arena.is_null() && is_suitable(arena, true) {
+            let p = mi_arena_alloc_from(
+                arena,
+                arena_index,
+                bcount,
+                commit,
+                large,
+                is_pinned,
+                is_zero,
+                req_arena_id,
+                memid,
+                tld,
+            );
+            debug_assert!((p as usize).is_multiple_of(alignment));
+            if !p.is_null() {
+                return p;
+            }
+        }
+    } else {
+        // try numa affine allocation first, then from another numa node instead..
+        for numa_local in [true, false] {
+            for (i, arena) in mi_arenas.iter().enumerate().take(max_arena) {
+                let arena = arena.load(Ordering::Relaxed);
+                if arena.is_null() {
+                    break; // end reached
+                }
+                if is_suitable(arena, numa_local) {
+                    let p = mi_arena_alloc_from(
+                        arena,
+                        i,
+                        bcount,
+                        commit,
+                        large,
+                        is_pinned,
+                        is_zero,
+                        req_arena_id,
+                        memid,
+                        tld,
+                    );
+                    debug_assert!((p as usize).is_multiple_of(alignment));
+                    if !p.is_null() {
+                        return p;
+                    }
+                }
+            }
+        }
+    }
+    ptr::null_mut()
+}
+
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
 pub fn _mi_arena_alloc_aligned(
     size: usize,
     alignment: usize,
@@ -14,5 +346,601 @@ pub fn _mi_arena_alloc_aligned(
     memid: *mut usize,
     tld: *mut MiOsTLD,
 ) -> *mut c_void {
-    ptr::null_mut()
+    debug_assert!(
+        !commit.is_null()
+            && !large.is_null()
+            && !is_pinned.is_null()
+            && !is_zero.is_null()
+            && !memid.is_null()
+            && !tld.is_null()
+    );
+    debug_assert!(size > 0);
+    unsafe {
+        *memid = MI_MEMID_OS;
+        *is_zero = false;
+        *is_pinned = false;
+    }
+    let numa_node = _mi_os_numa_node_get(tld); // current numa node
+
+    // try to allocate in an arena if the alignment is small enough and the object is not too small (as for heap meta data)
+    if size >= MI_ARENA_MIN_OBJ_SIZE && alignment <= MI_SEGMENT_ALIGN && align_offset == 0 {
+        let p = mi_arena_allocate(
+            numa_node,
+            size,
+            alignment,
+            commit,
+            large,
+            is_pinned,
+            is_zero,
+            req_arena_id,
+            memid,
+            tld,
+        );
+        if !p.is_null() {
+            return p;
+        }
+    }
+
+    // finally, fall back to the OS
+    if mi_option_is_enabled(MiOption::MiOptionLimitOsAlloc) || req_arena_id != _mi_arena_id_none() {
+        return ptr::null_mut();
+    }
+    unsafe {
+        *is_zero = true;
+        *memid = MI_MEMID_OS;
+        let p = _mi_os_alloc_aligned_offset(size, alignment, align_offset, *commit, large);
+        if !p.is_null() {
+            *is_pinned = *large;
+        }
+        p
+    }
+}
+
+// Return the start and size of the memory area of an arena
+#[no_mangle]
+pub extern "C" fn mi_arena_area(arena_id: MiArenaIdT, size: *mut usize) -> *mut c_void {
+    if !size.is_null() {
+        unsafe { *size = 0 };
+    }
+    let arena_index = mi_arena_id_index(arena_id);
+    if arena_index >= MI_MAX_ARENAS {
+        return ptr::null_mut();
+    }
+    let arena = mi_arenas[arena_index].load(Ordering::Relaxed);
+    if arena.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe {
+        if !size.is_null() {
+            *size = (*arena).block_count * MI_ARENA_BLOCK_SIZE;
+        }
+        (*arena).start.load(Ordering::Relaxed).cast()
+    }
+}
+
+/* -----------------------------------------------------------
+  Arena free
+----------------------------------------------------------- */
+
+// Free memory allocated by `_mi_arena_alloc_aligned`
+pub fn _mi_arena_free(
+    p: *mut c_void,
+    size: usize,
+    alignment: usize,
+    align_offset: usize,
+    memid: usize,
+    all_committed: bool, /*, mi_stats_t* stats */
+) {
+    debug_assert!(size > 0);
+    if p.is_null() || size == 0 {
+        return;
+    }
+    if memid == MI_MEMID_OS {
+        // was a direct OS allocation, pass through
+        _mi_os_free_aligned(p, size, alignment, align_offset, all_committed);
+    } else {
+        // allocated in an arena
+        debug_assert!(align_offset == 0);
+        let mut arena_idx = 0;
+        let mut bitmap_idx: MiBitmapIndex = 0;
+        mi_arena_memid_indices(memid, &mut arena_idx, &mut bitmap_idx);
+        debug_assert!(arena_idx < MI_MAX_ARENAS);
+        let arena = if arena_idx < MI_MAX_ARENAS {
+            mi_arenas[arena_idx].load(Ordering::Relaxed)
+        } else {
+            ptr::null_mut()
+        };
+        debug_assert!(!arena.is_null());
+        let blocks = mi_block_count_of_size(size);
+        // checks
+        if arena.is_null() {
+            _mi_error_message(
+                libc::EINVAL,
+                format_args!(
+                    "trying to free from non-existent arena: {:p}, size {}, memid: 0x{:x}\n",
+                    p, size, memid
+                ),
+            );
+            return;
+        }
+        unsafe {
+            debug_assert!((*arena).field_count > mi_bitmap_index_field(bitmap_idx));
+            if (*arena).field_count <= mi_bitmap_index_field(bitmap_idx) {
+                _mi_error_message(
+                    libc::EINVAL,
+                    format_args!(
+                        "trying to free from non-existent arena block: {:p}, size {}, memid: 0x{:x}\n",
+                        p, size, memid
+                    ),
+                );
+                return;
+            }
+            // potentially decommit
+            if !(*arena).allow_decommit || (*arena).blocks_committed.is_null() {
+                // note: `all_committed` may be not true as we may "pretend" to be not committed (in segment.rs)
+            } else {
+                _mi_os_decommit(p, blocks * MI_ARENA_BLOCK_SIZE); // ok if this fails
+                _mi_bitmap_unclaim_across(
+                    (*arena).blocks_committed,
+                    (*arena).field_count,
+                    blocks,
+                    bitmap_idx,
+                );
+            }
+            // and make it available to others again
+            let all_inuse = _mi_bitmap_unclaim_across(
+                mi_arena_blocks_inuse(arena),
+                (*arena).field_count,
+                blocks,
+                bitmap_idx,
+            );
+            if !all_inuse {
+                _mi_error_message(
+                    libc::EAGAIN,
+                    format_args!(
+                        "trying to free an already freed block: {:p}, size {}\n",
+                        p, size
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+  Add an arena.
+----------------------------------------------------------- */
+
+fn mi_arena_add(arena: *mut MiArena, arena_id: *mut MiArenaIdT) -> bool {
+    debug_assert!(!arena.is_null());
+    unsafe {
+        debug_assert!(
+            ((*arena).start.load(Ordering::Relaxed) as usize).is_multiple_of(MI_SEGMENT_ALIGN)
+        );
+        debug_assert!((*arena).block_count > 0);
+        if !arena_id.is_null() {
+            *arena_id = -1;
+        }
+
+        let i = mi_arena_count.fetch_add(1, Ordering::AcqRel);
+        if i >= MI_MAX_ARENAS {
+            mi_arena_count.fetch_sub(1, Ordering::AcqRel);
+            return false;
+        }
+        (*arena).id = mi_arena_id_create(i);
+        mi_arenas[i].store(arena, Ordering::Release);
+        if !arena_id.is_null() {
+            *arena_id = (*arena).id;
+        }
+    }
+    true
+}
+
+// Manage a range of OS memory as an arena; `exclusive` arenas are only used by heaps
+// that are created in that arena (see `mi_heap_new_in_arena`).
+#[no_mangle]
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
+pub extern "C" fn mi_manage_os_memory_ex(
+    start: *mut c_void,
+    size: usize,
+    is_committed: bool,
+    is_large: bool,
+    is_zero: bool,
+    numa_node: i32,
+    exclusive: bool,
+    arena_id: *mut MiArenaIdT,
+) -> bool {
+    if !arena_id.is_null() {
+        unsafe { *arena_id = _mi_arena_id_none() };
+    }
+    if size < MI_ARENA_BLOCK_SIZE {
+        return false;
+    }
+
+    let mut is_committed = is_committed;
+    if is_large {
+        debug_assert!(is_committed);
+        is_committed = true;
+    }
+
+    let bcount = size / MI_ARENA_BLOCK_SIZE;
+    let fields = _mi_divide_up(bcount, MI_BITMAP_FIELD_BITS);
+    let bitmaps = if is_committed { 2 } else { 3 };
+    let asize = size_of::<MiArena>() + (bitmaps * fields * size_of::<MiBitmapField>());
+    let arena: *mut MiArena = _mi_os_alloc(asize).cast(); // TODO: can we avoid allocating from the OS?
+    if arena.is_null() {
+        return false;
+    }
+
+    unsafe {
+        (*arena).id = _mi_arena_id_none();
+        (*arena).exclusive = exclusive;
+        (*arena).block_count = bcount;
+        (*arena).field_count = fields;
+        (*arena).start = AtomicPtr::new(start.cast());
+        (*arena).numa_node = numa_node; // TODO: or get the current numa node if -1? (now it allows anyone to allocate on -1)
+        (*arena).is_large = is_large;
+        (*arena).is_zero_init = is_zero;
+        (*arena).allow_decommit = !is_large && !is_committed; // only allow decommit for initially uncommitted memory
+        (*arena).search_idx = AtomicUsize::new(0);
+        (*arena).blocks_dirty = mi_arena_blocks_inuse(arena).add(fields); // just after inuse bitmap
+        (*arena).blocks_committed = if !(*arena).allow_decommit {
+            ptr::null_mut()
+        } else {
+            mi_arena_blocks_inuse(arena).add(2 * fields) // just after dirty bitmap
+        };
+        // the bitmaps are already zero initialized due to os_alloc
+        // and claim leftover blocks if needed (so we never allocate there)
+        let post = (fields * MI_BITMAP_FIELD_BITS) - bcount;
+        if post > 0 {
+            // don't use leftover bits at the end
+            let postidx = mi_bitmap_index_create(fields - 1, MI_BITMAP_FIELD_BITS - post);
+            _mi_bitmap_claim(
+                mi_arena_blocks_inuse(arena),
+                fields,
+                post,
+                postidx,
+                ptr::null_mut(),
+            );
+        }
+    }
+
+    mi_arena_add(arena, arena_id);
+    true
+}
+
+// Reserve a range of regular OS memory
+#[no_mangle]
+pub extern "C" fn mi_reserve_os_memory_ex(
+    size: usize,
+    commit: bool,
+    allow_large: bool,
+    exclusive: bool,
+    arena_id: *mut MiArenaIdT,
+) -> i32 {
+    if !arena_id.is_null() {
+        unsafe { *arena_id = _mi_arena_id_none() };
+    }
+    let size = _mi_align_up(size, MI_ARENA_BLOCK_SIZE); // at least one block
+    let mut large = allow_large;
+    let start = _mi_os_alloc_aligned(size, MI_SEGMENT_ALIGN, commit, &mut large);
+    if start.is_null() {
+        return libc::ENOMEM;
+    }
+    if !mi_manage_os_memory_ex(
+        start,
+        size,
+        large || commit,
+        large,
+        true,
+        -1,
+        exclusive,
+        arena_id,
+    ) {
+        _mi_os_free_ex(start, size, commit);
+        _mi_verbose_message(format_args!(
+            "failed to reserve {} k memory\n",
+            _mi_divide_up(size, 1024)
+        ));
+        return libc::ENOMEM;
+    }
+    _mi_verbose_message(format_args!(
+        "reserved {} KiB memory{}\n",
+        _mi_divide_up(size, 1024),
+        if large { " (in large os pages)" } else { "" }
+    ));
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn mi_manage_os_memory(
+    start: *mut c_void,
+    size: usize,
+    is_committed: bool,
+    is_large: bool,
+    is_zero: bool,
+    numa_node: i32,
+) -> bool {
+    mi_manage_os_memory_ex(
+        start,
+        size,
+        is_committed,
+        is_large,
+        is_zero,
+        numa_node,
+        false,
+        ptr::null_mut(),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn mi_reserve_os_memory(size: usize, commit: bool, allow_large: bool) -> i32 {
+    mi_reserve_os_memory_ex(size, commit, allow_large, false, ptr::null_mut())
+}
+
+/* -----------------------------------------------------------
+  Reserve a huge page arena.
+----------------------------------------------------------- */
+
+// reserve at a specific numa node
+#[no_mangle]
+pub extern "C" fn mi_reserve_huge_os_pages_at_ex(
+    pages: usize,
+    numa_node: i32,
+    timeout_msecs: usize,
+    exclusive: bool,
+    arena_id: *mut MiArenaIdT,
+) -> i32 {
+    if !arena_id.is_null() {
+        unsafe { *arena_id = -1 };
+    }
+    if pages == 0 {
+        return 0;
+    }
+    let mut numa_node = numa_node.max(-1);
+    if numa_node >= 0 {
+        numa_node %= _mi_os_numa_node_count() as i32;
+    }
+    let mut hsize = 0;
+    let mut pages_reserved = 0;
+    let p = _mi_os_alloc_huge_os_pages(
+        pages,
+        numa_node,
+        timeout_msecs as i64,
+        &mut pages_reserved,
+        &mut hsize,
+    );
+    if p.is_null() || pages_reserved == 0 {
+        _mi_warning_message(format_args!("failed to reserve {} GiB huge pages\n", pages));
+        return libc::ENOMEM;
+    }
+    _mi_verbose_message(format_args!(
+        "numa node {}: reserved {} GiB huge pages (of the {} GiB requested)\n",
+        numa_node, pages_reserved, pages
+    ));
+
+    if !mi_manage_os_memory_ex(p, hsize, true, true, true, numa_node, exclusive, arena_id) {
+        _mi_os_free_huge_pages(p, hsize);
+        return libc::ENOMEM;
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn mi_reserve_huge_os_pages_at(
+    pages: usize,
+    numa_node: i32,
+    timeout_msecs: usize,
+) -> i32 {
+    mi_reserve_huge_os_pages_at_ex(pages, numa_node, timeout_msecs, false, ptr::null_mut())
+}
+
+// reserve huge pages evenly among the given number of numa nodes (or use the available ones as detected)
+#[no_mangle]
+pub extern "C" fn mi_reserve_huge_os_pages_interleave(
+    pages: usize,
+    numa_nodes: usize,
+    timeout_msecs: usize,
+) -> i32 {
+    if pages == 0 {
+        return 0;
+    }
+
+    // pages per numa node
+    let numa_count = if numa_nodes > 0 {
+        numa_nodes
+    } else {
+        _mi_os_numa_node_count()
+    };
+    let numa_count = numa_count.max(1);
+    let pages_per = pages / numa_count;
+    let pages_mod = pages % numa_count;
+    let timeout_per = if timeout_msecs == 0 {
+        0
+    } else {
+        (timeout_msecs / numa_count) + 50
+    };
+
+    // reserve evenly among numa nodes
+    let mut pages = pages;
+    for numa_node in 0..numa_count {
+        if pages == 0 {
+            break;
+        }
+        let mut node_pages = pages_per; // can be 0
+        if numa_node < pages_mod {
+            node_pages += 1;
+        }
+        let err = mi_reserve_huge_os_pages_at(node_pages, numa_node as i32, timeout_per);
+        if err != 0 {
+            return err;
+        }
+        if pages < node_pages {
+            pages = 0;
+        } else {
+            pages -= node_pages;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        init::mi_thread_init,
+        mimalloc_types::MiOsTLD,
+        os::{_mi_os_init, _mi_os_with_backend},
+        os_mock::{MiOsCallKind, MiOsMockBackend},
+    };
+
+    use super::{
+        _mi_arena_alloc_aligned, _mi_arena_free, _mi_arena_id_none, mi_arena_area,
+        mi_reserve_huge_os_pages_at, mi_reserve_os_memory_ex, MI_ARENA_BLOCK_SIZE, MI_MEMID_OS,
+    };
+    use crate::mimalloc_types::{MiArenaIdT, MI_SEGMENT_ALIGN, MI_SEGMENT_SIZE};
+
+    fn arena_alloc(size: usize, alignment: usize, memid: &mut usize) -> *mut std::ffi::c_void {
+        arena_alloc_in(size, alignment, _mi_arena_id_none(), memid)
+    }
+
+    fn arena_alloc_in(
+        size: usize,
+        alignment: usize,
+        arena_id: MiArenaIdT,
+        memid: &mut usize,
+    ) -> *mut std::ffi::c_void {
+        let mut commit = true;
+        let mut large = false;
+        let mut is_pinned = false;
+        let mut is_zero = false;
+        let mut tld = MiOsTLD::default();
+        _mi_arena_alloc_aligned(
+            size,
+            alignment,
+            0,
+            &mut commit,
+            &mut large,
+            &mut is_pinned,
+            &mut is_zero,
+            arena_id,
+            memid,
+            &mut tld,
+        )
+    }
+
+    #[test]
+    fn test_mi_arena_alloc_uses_os_backend() {
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        let size = 4 * 1024 * 1024;
+        let alignment = 1024 * 1024;
+        _mi_os_with_backend(mock, || {
+            let mut memid = usize::MAX;
+            let p = arena_alloc(size, alignment, &mut memid);
+            assert!(!p.is_null());
+            assert_eq!(p as usize % alignment, 0);
+            assert_eq!(memid, MI_MEMID_OS);
+            assert_eq!(mock.count(MiOsCallKind::Reserve), 1);
+            assert_eq!(mock.live_regions(), 1);
+
+            _mi_arena_free(p, size, alignment, 0, memid, true);
+            let calls = mock.calls();
+            let last = calls.last().unwrap();
+            assert_eq!(last.kind, MiOsCallKind::Free);
+            assert_eq!(last.addr, p as usize);
+            assert!(last.ok);
+            assert_eq!(mock.live_regions(), 0);
+        });
+    }
+
+    #[test]
+    fn test_mi_arena_alloc_reserve_failure() {
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        mock.fail_nth(MiOsCallKind::Reserve, 0);
+        _mi_os_with_backend(mock, || {
+            let mut memid = usize::MAX;
+            let p = arena_alloc(1024 * 1024, 64 * 1024, &mut memid);
+            assert!(p.is_null());
+            assert_eq!(mock.count(MiOsCallKind::Reserve), 1);
+            assert_eq!(mock.live_regions(), 0);
+        });
+        // the backend is only active within the closure
+        let mut memid = usize::MAX;
+        let p = arena_alloc(1024 * 1024, 64 * 1024, &mut memid);
+        assert!(!p.is_null());
+        assert_eq!(mock.count(MiOsCallKind::Reserve), 1);
+        _mi_arena_free(p, 1024 * 1024, 64 * 1024, 0, memid, true);
+    }
+
+    #[test]
+    fn test_mi_arena_reserve_os_memory_exclusive() {
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        _mi_os_with_backend(mock, || {
+            let mut arena_id = _mi_arena_id_none();
+            let err =
+                mi_reserve_os_memory_ex(2 * MI_ARENA_BLOCK_SIZE, false, false, true, &mut arena_id);
+            assert_eq!(err, 0);
+            assert_ne!(arena_id, _mi_arena_id_none());
+            let mut area_size = 0;
+            let start = mi_arena_area(arena_id, &mut area_size) as usize;
+            assert_eq!(area_size, 2 * MI_ARENA_BLOCK_SIZE);
+            let reserves = mock.count(MiOsCallKind::Reserve);
+
+            // segments in the arena come from the reserved memory
+            let mut memids = [MI_MEMID_OS; 2];
+            let mut ps = [std::ptr::null_mut(); 2];
+            for (p, memid) in ps.iter_mut().zip(memids.iter_mut()) {
+                *p = arena_alloc_in(MI_SEGMENT_SIZE, MI_SEGMENT_ALIGN, arena_id, memid);
+                assert!(!p.is_null());
+                assert_ne!(*memid, MI_MEMID_OS);
+                assert!(*p as usize >= start && (*p as usize) < start + area_size);
+            }
+            assert_ne!(ps[0], ps[1]);
+
+            // a full arena does not fall back to the OS
+            let mut memid = MI_MEMID_OS;
+            let p = arena_alloc_in(MI_SEGMENT_SIZE, MI_SEGMENT_ALIGN, arena_id, &mut memid);
+            assert!(p.is_null());
+            assert_eq!(mock.count(MiOsCallKind::Reserve), reserves);
+
+            // and an exclusive arena is not used for other requests
+            let p = arena_alloc(MI_SEGMENT_SIZE, MI_SEGMENT_ALIGN, &mut memid);
+            assert!(!p.is_null());
+            assert_eq!(memid, MI_MEMID_OS);
+            assert_eq!(mock.count(MiOsCallKind::Reserve), reserves + 1);
+            _mi_arena_free(p, MI_SEGMENT_SIZE, MI_SEGMENT_ALIGN, 0, memid, true);
+
+            // freeing decommits the blocks and makes them available again
+            _mi_arena_free(
+                ps[0],
+                MI_SEGMENT_SIZE,
+                MI_SEGMENT_ALIGN,
+                0,
+                memids[0],
+                false,
+            );
+            assert!(mock
+                .calls()
+                .iter()
+                .any(|c| c.kind == MiOsCallKind::Decommit && c.addr == ps[0] as usize));
+            let p = arena_alloc_in(MI_SEGMENT_SIZE, MI_SEGMENT_ALIGN, arena_id, &mut memid);
+            assert_eq!(p, ps[0]);
+            assert_eq!(memid, memids[0]);
+            assert_eq!(mock.count(MiOsCallKind::Reserve), reserves + 1);
+        });
+    }
+
+    #[test]
+    fn test_mi_reserve_huge_os_pages_unavailable() {
+        mi_thread_init();
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        _mi_os_with_backend(mock, || {
+            assert_eq!(mi_reserve_huge_os_pages_at(1, 0, 0), libc::ENOMEM);
+            assert_eq!(mock.count(MiOsCallKind::ReserveHuge), 1);
+            assert_eq!(mock.live_regions(), 0);
+        });
+    }
 }
@@ -0,0 +1,757 @@
+/* ----------------------------------------------------------------------------
+Concurrent bitmap that can set/reset sequences of bits atomically,
+represented as an array of fields where each field is a machine word (`size_t`)
+
+There are two api's; the standard one cannot have sequences that cross
+between the bitmap fields (and a sequence must be <= MI_BITMAP_FIELD_BITS).
+(this is used in region allocation)
+
+The `_across` postfixed functions do allow sequences that can cross over
+between the fields. (This is used in arena allocation)
+---------------------------------------------------------------------------- */
+
+use std::{
+    ffi::c_void,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    mimalloc_internal::{_mi_divide_up, mi_bsr, mi_clz, mi_ctz},
+    mimalloc_types::MI_SIZE_SIZE,
+};
+
+/* -----------------------------------------------------------
+  Bitmap definition
+----------------------------------------------------------- */
+
+pub const MI_BITMAP_FIELD_BITS: usize = 8 * MI_SIZE_SIZE;
+pub const MI_BITMAP_FIELD_FULL: usize = !0; // all bits set
+
+// An atomic bitmap of `size_t` fields
+pub type MiBitmapField = AtomicUsize;
+pub type MiBitmap = *const MiBitmapField;
+
+// A bitmap index is the index of the bit in a bitmap.
+pub type MiBitmapIndex = usize;
+
+// Create a bit index.
+pub fn mi_bitmap_index_create(idx: usize, bitidx: usize) -> MiBitmapIndex {
+    debug_assert!(bitidx < MI_BITMAP_FIELD_BITS);
+    (idx * MI_BITMAP_FIELD_BITS) + bitidx
+}
+
+// Create a bit index.
+pub fn mi_bitmap_index_create_from_bit(full_bitidx: usize) -> MiBitmapIndex {
+    mi_bitmap_index_create(
+        full_bitidx / MI_BITMAP_FIELD_BITS,
+        full_bitidx % MI_BITMAP_FIELD_BITS,
+    )
+}
+
+// Get the field index from a bit index.
+pub fn mi_bitmap_index_field(bitmap_idx: MiBitmapIndex) -> usize {
+    bitmap_idx / MI_BITMAP_FIELD_BITS
+}
+
+// Get the bit index in a bitmap field
+pub fn mi_bitmap_index_bit_in_field(bitmap_idx: MiBitmapIndex) -> usize {
+    bitmap_idx % MI_BITMAP_FIELD_BITS
+}
+
+// Get the full bit index
+pub fn mi_bitmap_index_bit(bitmap_idx: MiBitmapIndex) -> usize {
+    bitmap_idx
+}
+
+pub type MiBitmapPredFun = fn(bitmap_idx: MiBitmapIndex, pred_arg: *mut c_void) -> bool;
+
+/* -----------------------------------------------------------
+  Bitmap definition
+----------------------------------------------------------- */
+
+// The bit mask for a given number of blocks at a specified bit index.
+fn mi_bitmap_mask_(count: usize, bitidx: usize) -> usize {
+    debug_assert!(count + bitidx <= MI_BITMAP_FIELD_BITS);
+    debug_assert!(count > 0);
+    if count >= MI_BITMAP_FIELD_BITS {
+        return MI_BITMAP_FIELD_FULL;
+    }
+    if count == 0 {
+        return 0;
+    }
+    ((1usize << count) - 1) << bitidx
+}
+
+/* -----------------------------------------------------------
+  Claim a bit sequence atomically
+----------------------------------------------------------- */
+
+// Try to atomically claim a sequence of `count` bits in a single
+// field at `idx` in `bitmap`. Returns `true` on success.
+pub fn _mi_bitmap_try_find_claim_field(
+    bitmap: MiBitmap,
+    idx: usize,
+    count: usize,
+    bitmap_idx: *mut MiBitmapIndex,
+) -> bool {
+    debug_assert!(!bitmap_idx.is_null());
+    debug_assert!(count <= MI_BITMAP_FIELD_BITS);
+    debug_assert!(count > 0);
+    let field = unsafe { &*bitmap.add(idx) };
+    let mut map = field.load(Ordering::Relaxed);
+    if map == MI_BITMAP_FIELD_FULL {
+        return false; // short cut
+    }
+
+    // search for 0-bit sequence of length count
+    let mask = mi_bitmap_mask_(count, 0);
+    let bitidx_max = MI_BITMAP_FIELD_BITS - count;
+
+    let mut bitidx = mi_ctz(!map); // quickly find the first zero bit if possible
+    let mut m = mask << bitidx; // invariant: m == mask shifted by bitidx
+
+    // scan linearly for a free range of zero bits
+    while bitidx <= bitidx_max {
+        let mapm = map & m;
+        if mapm == 0 {
+            // are the mask bits free at bitidx?
+            debug_assert!((m >> bitidx) == mask); // no overflow?
+            let newmap = map | m;
+            debug_assert!((newmap ^ map) >> bitidx == mask);
+            match field.compare_exchange_weak(map, newmap, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    // success, we claimed the bits!
+                    unsafe { *bitmap_idx = mi_bitmap_index_create(idx, bitidx) };
+                    return true;
+                }
+                Err(current) => {
+                    // no success, another thread claimed concurrently.. keep going (with updated `map`)
+                    map = current;
+                    continue;
+                }
+            }
+        } else {
+            // on to the next bit range
+            let shift = if count == 1 {
+                1
+            } else {
+                mi_bsr(mapm) - bitidx + 1
+            };
+            debug_assert!(shift > 0 && shift <= count);
+            bitidx += shift;
+            if bitidx > bitidx_max {
+                break;
+            }
+            m <<= shift;
+        }
+    }
+    // no bits found
+    false
+}
+
+// Find `count` bits of 0 and set them to 1 atomically; returns `true` on success.
+// Starts at idx, and wraps around to search in all `bitmap_fields` fields.
+// `count` can be at most MI_BITMAP_FIELD_BITS and will never cross fields.
+pub fn _mi_bitmap_try_find_from_claim(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    start_field_idx: usize,
+    count: usize,
+    bitmap_idx: *mut MiBitmapIndex,
+) -> bool {
+    let mut idx = start_field_idx;
+    for _ in 0..bitmap_fields {
+        if idx >= bitmap_fields {
+            idx = 0; // wrap
+        }
+        if _mi_bitmap_try_find_claim_field(bitmap, idx, count, bitmap_idx) {
+            return true;
+        }
+        idx += 1;
+    }
+    false
+}
+
+// Like _mi_bitmap_try_find_from_claim but with an extra predicate that must be fullfilled
+pub fn _mi_bitmap_try_find_from_claim_pred(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    start_field_idx: usize,
+    count: usize,
+    pred_fun: Option<MiBitmapPredFun>,
+    pred_arg: *mut c_void,
+    bitmap_idx: *mut MiBitmapIndex,
+) -> bool {
+    let mut idx = start_field_idx;
+    for _ in 0..bitmap_fields {
+        if idx >= bitmap_fields {
+            idx = 0; // wrap
+        }
+        if _mi_bitmap_try_find_claim_field(bitmap, idx, count, bitmap_idx) {
+            match pred_fun {
+                Some(pred_fun) if !pred_fun(unsafe { *bitmap_idx }, pred_arg) => {
+                    // predicate returned false, unclaim and look further
+                    _mi_bitmap_unclaim(bitmap, bitmap_fields, count, unsafe { *bitmap_idx });
+                }
+                _ => return true,
+            }
+        }
+        idx += 1;
+    }
+    false
+}
+
+// Set `count` bits at `bitmap_idx` to 0 atomically
+// Returns `true` if all `count` bits were 1 previously.
+pub fn _mi_bitmap_unclaim(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+) -> bool {
+    let idx = mi_bitmap_index_field(bitmap_idx);
+    let bitidx = mi_bitmap_index_bit_in_field(bitmap_idx);
+    let mask = mi_bitmap_mask_(count, bitidx);
+    debug_assert!(bitmap_fields > idx);
+    let prev = unsafe { (*bitmap.add(idx)).fetch_and(!mask, Ordering::AcqRel) };
+    (prev & mask) == mask
+}
+
+// Set `count` bits at `bitmap_idx` to 1 atomically
+// Returns `true` if all `count` bits were 0 previously. `any_zero` is `true` if there was at least one zero bit.
+pub fn _mi_bitmap_claim(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+    any_zero: *mut bool,
+) -> bool {
+    let idx = mi_bitmap_index_field(bitmap_idx);
+    let bitidx = mi_bitmap_index_bit_in_field(bitmap_idx);
+    let mask = mi_bitmap_mask_(count, bitidx);
+    debug_assert!(bitmap_fields > idx);
+    let prev = unsafe { (*bitmap.add(idx)).fetch_or(mask, Ordering::AcqRel) };
+    if !any_zero.is_null() {
+        unsafe { *any_zero = (prev & mask) != mask };
+    }
+    (prev & mask) == 0
+}
+
+// Returns `true` if all `count` bits were 1. `any_ones` is `true` if there was at least one bit set to one.
+fn mi_bitmap_is_claimedx(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+    any_ones: *mut bool,
+) -> bool {
+    let idx = mi_bitmap_index_field(bitmap_idx);
+    let bitidx = mi_bitmap_index_bit_in_field(bitmap_idx);
+    let mask = mi_bitmap_mask_(count, bitidx);
+    debug_assert!(bitmap_fields > idx);
+    let field = unsafe { (*bitmap.add(idx)).load(Ordering::Relaxed) };
+    if !any_ones.is_null() {
+        unsafe { *any_ones = (field & mask) != 0 };
+    }
+    (field & mask) == mask
+}
+
+pub fn _mi_bitmap_is_claimed(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+) -> bool {
+    mi_bitmap_is_claimedx(
+        bitmap,
+        bitmap_fields,
+        count,
+        bitmap_idx,
+        std::ptr::null_mut(),
+    )
+}
+
+/* -----------------------------------------------------------
+  the `_across` functions work on bitmaps where sequences can cross over
+  between the fields. This is used in arena allocation
+----------------------------------------------------------- */
+
+// Try to atomically claim a sequence of `count` bits starting from the field
+// at `idx` in `bitmap` and crossing into subsequent fields. Returns `true` on success.
+fn mi_bitmap_try_find_claim_field_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    idx: usize,
+    count: usize,
+    retries: usize,
+    bitmap_idx: *mut MiBitmapIndex,
+) -> bool {
+    debug_assert!(!bitmap_idx.is_null());
+
+    // check initial trailing zeros
+    let field = |i: usize| unsafe { &*bitmap.add(i) };
+    let mut map = field(idx).load(Ordering::Relaxed);
+    let initial = mi_clz(map); // count of initial zeros starting at idx
+    debug_assert!(initial <= MI_BITMAP_FIELD_BITS);
+    if initial == 0 {
+        return false;
+    }
+    if initial >= count {
+        return _mi_bitmap_try_find_claim_field(bitmap, idx, count, bitmap_idx); // no need to cross fields
+    }
+    if _mi_divide_up(count - initial, MI_BITMAP_FIELD_BITS) >= (bitmap_fields - idx) {
+        return false; // not enough entries
+    }
+
+    // scan ahead
+    let mut found = initial;
+    let mut mask = 0; // mask bits for the final field
+    let mut fidx = idx;
+    while found < count {
+        fidx += 1;
+        map = field(fidx).load(Ordering::Relaxed);
+        let mask_bits = if found + MI_BITMAP_FIELD_BITS <= count {
+            MI_BITMAP_FIELD_BITS
+        } else {
+            count - found
+        };
+        mask = mi_bitmap_mask_(mask_bits, 0);
+        if (map & mask) != 0 {
+            return false;
+        }
+        found += mask_bits;
+    }
+    debug_assert!(fidx < bitmap_fields);
+
+    // found range of zeros up to the final field; mask contains mask in the final field
+    // now claim it atomically
+    let final_idx = fidx;
+    let final_mask = mask;
+    let initial_idx = idx;
+    let initial_mask = mi_bitmap_mask_(initial, MI_BITMAP_FIELD_BITS - initial);
+
+    // the fields are claimed from the initial to the final one; on failure the fields
+    // before `fidx` are rolled back
+    let claimed = 'claim: {
+        // initial field
+        fidx = initial_idx;
+        map = field(fidx).load(Ordering::Relaxed);
+        loop {
+            if (map & initial_mask) != 0 {
+                break 'claim false;
+            }
+            match field(fidx).compare_exchange(
+                map,
+                map | initial_mask,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => map = current,
+            }
+        }
+
+        // intermediate fields
+        fidx += 1;
+        while fidx < final_idx {
+            if field(fidx)
+                .compare_exchange(0, MI_BITMAP_FIELD_FULL, Ordering::AcqRel, Ordering::Acquire)
+                .is_err()
+            {
+                break 'claim false;
+            }
+            fidx += 1;
+        }
+
+        // final field
+        debug_assert!(fidx == final_idx);
+        map = field(fidx).load(Ordering::Relaxed);
+        loop {
+            if (map & final_mask) != 0 {
+                break 'claim false;
+            }
+            match field(fidx).compare_exchange(
+                map,
+                map | final_mask,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => break,
+                Err(current) => map = current,
+            }
+        }
+        true
+    };
+
+    if claimed {
+        unsafe { *bitmap_idx = mi_bitmap_index_create(idx, MI_BITMAP_FIELD_BITS - initial) };
+        return true;
+    }
+
+    // roll back intermediate fields
+    // (we just failed to claim `fidx` so decrement first)
+    while fidx > initial_idx + 1 {
+        fidx -= 1;
+        debug_assert!(field(fidx).load(Ordering::Relaxed) == MI_BITMAP_FIELD_FULL);
+        field(fidx).store(0, Ordering::Release);
+    }
+    if fidx > initial_idx {
+        // (if we failed on the initial field, nothing was claimed yet)
+        field(initial_idx).fetch_and(!initial_mask, Ordering::AcqRel);
+    }
+    // retry? (we make a recursive call instead of goto to be able to use const declarations)
+    if retries <= 2 {
+        mi_bitmap_try_find_claim_field_across(
+            bitmap,
+            bitmap_fields,
+            idx,
+            count,
+            retries + 1,
+            bitmap_idx,
+        )
+    } else {
+        false
+    }
+}
+
+// Find `count` bits of zeros and set them to 1 atomically; returns `true` on success.
+// Starts at idx, and wraps around to search in all `bitmap_fields` fields.
+pub fn _mi_bitmap_try_find_from_claim_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    start_field_idx: usize,
+    count: usize,
+    bitmap_idx: *mut MiBitmapIndex,
+) -> bool {
+    debug_assert!(count > 0);
+    if count <= 2 {
+        // we don't bother with crossover fields for small counts
+        return _mi_bitmap_try_find_from_claim(
+            bitmap,
+            bitmap_fields,
+            start_field_idx,
+            count,
+            bitmap_idx,
+        );
+    }
+
+    // visit the fields
+    let mut idx = start_field_idx;
+    for _ in 0..bitmap_fields {
+        if idx >= bitmap_fields {
+            idx = 0; // wrap
+        }
+        // first try to claim inside a field
+        if count <= MI_BITMAP_FIELD_BITS
+            && _mi_bitmap_try_find_claim_field(bitmap, idx, count, bitmap_idx)
+        {
+            return true;
+        }
+        // if that fails, then try to claim across fields
+        if mi_bitmap_try_find_claim_field_across(bitmap, bitmap_fields, idx, count, 0, bitmap_idx) {
+            return true;
+        }
+        idx += 1;
+    }
+    false
+}
+
+// Helper for masks across fields; returns the mid count, post_mask may be 0
+fn mi_bitmap_mask_across(
+    bitmap_idx: MiBitmapIndex,
+    bitmap_fields: usize,
+    count: usize,
+    pre_mask: &mut usize,
+    mid_mask: &mut usize,
+    post_mask: &mut usize,
+) -> usize {
+    let bitidx = mi_bitmap_index_bit_in_field(bitmap_idx);
+    if bitidx + count <= MI_BITMAP_FIELD_BITS {
+        *pre_mask = mi_bitmap_mask_(count, bitidx);
+        *mid_mask = 0;
+        *post_mask = 0;
+        debug_assert!(mi_bitmap_index_field(bitmap_idx) < bitmap_fields);
+        0
+    } else {
+        let pre_bits = MI_BITMAP_FIELD_BITS - bitidx;
+        debug_assert!(pre_bits < count);
+        *pre_mask = mi_bitmap_mask_(pre_bits, bitidx);
+        let count = count - pre_bits;
+        let mid_count = count / MI_BITMAP_FIELD_BITS;
+        *mid_mask = MI_BITMAP_FIELD_FULL;
+        let count = count % MI_BITMAP_FIELD_BITS;
+        *post_mask = if count == 0 {
+            0
+        } else {
+            mi_bitmap_mask_(count, 0)
+        };
+        debug_assert!(
+            mi_bitmap_index_field(bitmap_idx) + mid_count + usize::from(count != 0) < bitmap_fields
+        );
+        mid_count
+    }
+}
+
+// Set `count` bits at `bitmap_idx` to 0 atomically
+// Returns `true` if all `count` bits were 1 previously.
+pub fn _mi_bitmap_unclaim_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+) -> bool {
+    let mut idx = mi_bitmap_index_field(bitmap_idx);
+    let (mut pre_mask, mut mid_mask, mut post_mask) = (0, 0, 0);
+    let mut mid_count = mi_bitmap_mask_across(
+        bitmap_idx,
+        bitmap_fields,
+        count,
+        &mut pre_mask,
+        &mut mid_mask,
+        &mut post_mask,
+    );
+    let mut all_one = true;
+    let field = |i: usize| unsafe { &*bitmap.add(i) };
+    let mut prev = field(idx).fetch_and(!pre_mask, Ordering::AcqRel);
+    idx += 1;
+    if (prev & pre_mask) != pre_mask {
+        all_one = false;
+    }
+    while mid_count > 0 {
+        prev = field(idx).fetch_and(!mid_mask, Ordering::AcqRel);
+        idx += 1;
+        if (prev & mid_mask) != mid_mask {
+            all_one = false;
+        }
+        mid_count -= 1;
+    }
+    if post_mask != 0 {
+        prev = field(idx).fetch_and(!post_mask, Ordering::AcqRel);
+        if (prev & post_mask) != post_mask {
+            all_one = false;
+        }
+    }
+    all_one
+}
+
+// Set `count` bits at `bitmap_idx` to 1 atomically
+// Returns `true` if all `count` bits were 0 previously. `any_zero` is `true` if there was at least one zero bit.
+pub fn _mi_bitmap_claim_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+    pany_zero: *mut bool,
+) -> bool {
+    let mut idx = mi_bitmap_index_field(bitmap_idx);
+    let (mut pre_mask, mut mid_mask, mut post_mask) = (0, 0, 0);
+    let mut mid_count = mi_bitmap_mask_across(
+        bitmap_idx,
+        bitmap_fields,
+        count,
+        &mut pre_mask,
+        &mut mid_mask,
+        &mut post_mask,
+    );
+    let mut all_zero = true;
+    let mut any_zero = false;
+    let field = |i: usize| unsafe { &*bitmap.add(i) };
+    let mut prev = field(idx).fetch_or(pre_mask, Ordering::AcqRel);
+    idx += 1;
+    if (prev & pre_mask) != 0 {
+        all_zero = false;
+    }
+    if (prev & pre_mask) != pre_mask {
+        any_zero = true;
+    }
+    while mid_count > 0 {
+        prev = field(idx).fetch_or(mid_mask, Ordering::AcqRel);
+        idx += 1;
+        if (prev & mid_mask) != 0 {
+            all_zero = false;
+        }
+        if (prev & mid_mask) != mid_mask {
+            any_zero = true;
+        }
+        mid_count -= 1;
+    }
+    if post_mask != 0 {
+        prev = field(idx).fetch_or(post_mask, Ordering::AcqRel);
+        if (prev & post_mask) != 0 {
+            all_zero = false;
+        }
+        if (prev & post_mask) != post_mask {
+            any_zero = true;
+        }
+    }
+    if !pany_zero.is_null() {
+        unsafe { *pany_zero = any_zero };
+    }
+    all_zero
+}
+
+// Returns `true` if all `count` bits were 1.
+// `any_ones` is `true` if there was at least one bit set to one.
+fn mi_bitmap_is_claimedx_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+    pany_ones: *mut bool,
+) -> bool {
+    let mut idx = mi_bitmap_index_field(bitmap_idx);
+    let (mut pre_mask, mut mid_mask, mut post_mask) = (0, 0, 0);
+    let mut mid_count = mi_bitmap_mask_across(
+        bitmap_idx,
+        bitmap_fields,
+        count,
+        &mut pre_mask,
+        &mut mid_mask,
+        &mut post_mask,
+    );
+    let mut all_ones = true;
+    let mut any_ones = false;
+    let field = |i: usize| unsafe { &*bitmap.add(i) };
+    let mut prev = field(idx).load(Ordering::Relaxed);
+    idx += 1;
+    if (prev & pre_mask) != pre_mask {
+        all_ones = false;
+    }
+    if (prev & pre_mask) != 0 {
+        any_ones = true;
+    }
+    while mid_count > 0 {
+        prev = field(idx).load(Ordering::Relaxed);
+        idx += 1;
+        if (prev & mid_mask) != mid_mask {
+            all_ones = false;
+        }
+        if (prev & mid_mask) != 0 {
+            any_ones = true;
+        }
+        mid_count -= 1;
+    }
+    if post_mask != 0 {
+        prev = field(idx).load(Ordering::Relaxed);
+        if (prev & post_mask) != post_mask {
+            all_ones = false;
+        }
+        if (prev & post_mask) != 0 {
+            any_ones = true;
+        }
+    }
+    if !pany_ones.is_null() {
+        unsafe { *pany_ones = any_ones };
+    }
+    all_ones
+}
+
+pub fn _mi_bitmap_is_claimed_across(
+    bitmap: MiBitmap,
+    bitmap_fields: usize,
+    count: usize,
+    bitmap_idx: MiBitmapIndex,
+) -> bool {
+    mi_bitmap_is_claimedx_across(
+        bitmap,
+        bitmap_fields,
+        count,
+        bitmap_idx,
+        std::ptr::null_mut(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::c_void,
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::{
+        _mi_bitmap_claim, _mi_bitmap_is_claimed, _mi_bitmap_try_find_from_claim,
+        _mi_bitmap_try_find_from_claim_pred, _mi_bitmap_unclaim, MiBitmapIndex,
+        MI_BITMAP_FIELD_BITS, MI_BITMAP_FIELD_FULL,
+    };
+
+    #[test]
+    fn test_mi_bitmap_claim_and_unclaim() {
+        let bitmap = [
+            AtomicUsize::new(MI_BITMAP_FIELD_FULL),
+            AtomicUsize::new(0b1011),
+        ];
+        let mut idx: MiBitmapIndex = 0;
+        // the first field is full and a run of 3 zero bits starts after the low bits of the second
+        assert!(_mi_bitmap_try_find_from_claim(
+            bitmap.as_ptr(),
+            2,
+            0,
+            3,
+            &mut idx
+        ));
+        assert_eq!(idx, MI_BITMAP_FIELD_BITS + 4);
+        assert_eq!(bitmap[1].load(Ordering::Relaxed), 0b111_1011);
+        assert!(_mi_bitmap_is_claimed(bitmap.as_ptr(), 2, 3, idx));
+
+        // the single free bit in the second field is found next
+        assert!(_mi_bitmap_try_find_from_claim(
+            bitmap.as_ptr(),
+            2,
+            0,
+            1,
+            &mut idx
+        ));
+        assert_eq!(idx, MI_BITMAP_FIELD_BITS + 2);
+
+        assert!(_mi_bitmap_unclaim(
+            bitmap.as_ptr(),
+            2,
+            3,
+            MI_BITMAP_FIELD_BITS + 4
+        ));
+        assert!(!_mi_bitmap_is_claimed(
+            bitmap.as_ptr(),
+            2,
+            1,
+            MI_BITMAP_FIELD_BITS + 5
+        ));
+        let mut any_zero = false;
+        assert!(!_mi_bitmap_claim(
+            bitmap.as_ptr(),
+            2,
+            4,
+            MI_BITMAP_FIELD_BITS + 3,
+            &mut any_zero
+        ));
+        assert!(any_zero);
+        assert_eq!(bitmap[1].load(Ordering::Relaxed), 0b111_1111);
+    }
+
+    #[test]
+    fn test_mi_bitmap_claim_pred_skips_unsuitable() {
+        fn is_suitable(bitmap_idx: MiBitmapIndex, arg: *mut c_void) -> bool {
+            unsafe { *arg.cast::<usize>() += 1 };
+            bitmap_idx != 0
+        }
+        // only bit 0 is free in the first field
+        let bitmap = [AtomicUsize::new(!1), AtomicUsize::new(0)];
+        let mut idx: MiBitmapIndex = 0;
+        let mut calls = 0usize;
+        let arg = ptr::addr_of_mut!(calls).cast();
+        assert!(_mi_bitmap_try_find_from_claim_pred(
+            bitmap.as_ptr(),
+            2,
+            0,
+            1,
+            Some(is_suitable),
+            arg,
+            &mut idx
+        ));
+        assert_eq!(idx, MI_BITMAP_FIELD_BITS);
+        assert_eq!(calls, 2);
+        // the rejected bit was released again
+        assert_eq!(bitmap[0].load(Ordering::Relaxed), !1);
+        assert_eq!(bitmap[1].load(Ordering::Relaxed), 1);
+    }
+}
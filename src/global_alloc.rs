@@ -0,0 +1,73 @@
+// The Rust global allocator on top of the aligned allocation api, use it as:
+//
+//     #[global_allocator]
+//     static GLOBAL: mimalloc_rs::MiMalloc = mimalloc_rs::MiMalloc;
+
+use std::alloc::{GlobalAlloc, Layout};
+
+use crate::{
+    alloc::mi_free,
+    alloc_aligned::{mi_malloc_aligned, mi_realloc_aligned, mi_zalloc_aligned},
+};
+
+/// A zero-sized allocator type that forwards to the mimalloc api.
+pub struct MiMalloc;
+
+unsafe impl GlobalAlloc for MiMalloc {
+    #[inline]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        mi_malloc_aligned(layout.size(), layout.align()).cast()
+    }
+
+    #[inline]
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        mi_zalloc_aligned(layout.size(), layout.align()).cast()
+    }
+
+    #[inline]
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        mi_free(ptr.cast());
+    }
+
+    #[inline]
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        mi_realloc_aligned(ptr.cast(), new_size, layout.align()).cast()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{alloc::GlobalAlloc, alloc::Layout, thread};
+
+    use super::MiMalloc;
+
+    #[test]
+    fn test_global_alloc_on_threads() {
+        let workers: Vec<_> = (0..4)
+            .map(|t| {
+                thread::spawn(move || unsafe {
+                    let layout = Layout::from_size_align(24, 8).unwrap();
+                    let p = MiMalloc.alloc_zeroed(layout);
+                    assert!(!p.is_null());
+                    assert!(std::slice::from_raw_parts(p, 24).iter().all(|&b| b == 0));
+                    p.write_bytes(t as u8, 24);
+
+                    let q = MiMalloc.realloc(p, layout, 5000);
+                    assert!(!q.is_null());
+                    assert!(std::slice::from_raw_parts(q, 24)
+                        .iter()
+                        .all(|&b| b == t as u8));
+                    MiMalloc.dealloc(q, Layout::from_size_align(5000, 8).unwrap());
+
+                    let aligned = Layout::from_size_align(100, 256).unwrap();
+                    let a = MiMalloc.alloc(aligned);
+                    assert_eq!(a as usize % 256, 0);
+                    MiMalloc.dealloc(a, aligned);
+                })
+            })
+            .collect();
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+}
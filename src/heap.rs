@@ -1,51 +1,613 @@
-use crate::mimalloc_types::MiHeap;
+use std::{mem::size_of, ptr, sync::atomic::Ordering};
 
-// Safe delete a heap without freeing any still allocated blocks in that heap.
-pub fn mi_heap_delete(heap: *mut MiHeap) {
-    //   mi_assert(heap != NULL);
-    //   mi_assert(mi_heap_is_initialized(heap));
-    //   mi_assert_expensive(mi_heap_is_valid(heap));
-    //   if (heap==NULL || !mi_heap_is_initialized(heap)) return;
-
-    //   if (!mi_heap_is_backing(heap)) {
-    //     // tranfer still used pages to the backing heap
-    //     mi_heap_absorb(heap->tld->heap_backing, heap);
-    //   }
-    //   else {
-    //     // the backing heap abandons its pages
-    //     _mi_heap_collect_abandon(heap);
-    //   }
-    //   mi_assert_internal(heap->page_count==0);
-    //   mi_heap_free(heap);
+use crate::{
+    alloc::{mi_free, mi_heap_malloc},
+    arena::{_mi_arena_id_none, _mi_arena_memid_is_suitable},
+    init::{_mi_heap_set_default_direct, _mi_is_main_thread, _mi_page_empty, mi_thread_init},
+    mimalloc_internal::{
+        _mi_thread_id, get_default_heap, mi_heap_is_backing, mi_heap_is_default,
+        mi_heap_is_initialized, mi_page_all_free, mi_page_heap, mi_page_thread_free,
+        mi_page_usable_block_size,
+    },
+    mimalloc_types::{
+        MiArenaIdT, MiDelayed, MiHeap, MiPage, MiPageQueue, MI_BIN_FULL, MI_LARGE_OBJ_SIZE_MAX,
+        MI_MEDIUM_OBJ_SIZE_MAX, MI_PAGES_DIRECT, MI_PAGE_QUEUES_EMPTY, MI_STAT,
+    },
+    page::{
+        _mi_deferred_free, _mi_heap_collect_retired, _mi_heap_delayed_free_all,
+        _mi_heap_delayed_free_partial, _mi_page_abandon, _mi_page_free, _mi_page_free_collect,
+        _mi_page_use_delayed_free,
+    },
+    page_queue::{_mi_bin, _mi_page_queue_append},
+    random::{_mi_random_next, _mi_random_split},
+    segment::{_mi_abandoned_collect, _mi_abandoned_reclaim_all, _mi_segment_page_free},
+    segment_cache::_mi_segment_cache_collect,
+    stats::_mi_stat_decrease,
+};
+
+/* -----------------------------------------------------------
+  Helpers
+----------------------------------------------------------- */
+
+// return `true` if ok, `false` to break
+type HeapPageVisitorFun =
+    fn(heap: *mut MiHeap, pq: *mut MiPageQueue, page: *mut MiPage, arg1: *mut MiCollect) -> bool;
+
+// Visit all pages in a heap; returns `false` if break was called.
+fn mi_heap_visit_pages(
+    heap: *mut MiHeap,
+    visitor: HeapPageVisitorFun,
+    arg1: *mut MiCollect,
+) -> bool {
+    if heap.is_null() || unsafe { (*heap).page_count } == 0 {
+        return false;
+    }
+
+    // visit all pages
+    let total = unsafe { (*heap).page_count };
+    let mut count = 0;
+
+    for i in 0..=MI_BIN_FULL {
+        let pq = unsafe { ptr::addr_of_mut!((*heap).pages[i]) };
+        let mut page = unsafe { (*pq).first };
+        while !page.is_null() {
+            let next = unsafe { (*page).next }; // save next in case the page gets removed from the queue
+            debug_assert!(mi_page_heap(page) == heap);
+            count += 1;
+            if !visitor(heap, pq, page, arg1) {
+                return false;
+            }
+            page = next; // and continue
+        }
+    }
+    debug_assert!(count == total);
+    true
+}
+
+/* -----------------------------------------------------------
+  "Collect" pages by migrating `local_free` and `thread_free`
+  lists and freeing empty pages. This is done when a thread
+  stops (and in that case abandons pages if there are still
+  blocks alive)
+----------------------------------------------------------- */
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum MiCollect {
+    MiNormal,
+    MiForce,
+    MiAbandon,
+}
+
+fn mi_heap_page_collect(
+    _heap: *mut MiHeap,
+    pq: *mut MiPageQueue,
+    page: *mut MiPage,
+    arg_collect: *mut MiCollect,
+) -> bool {
+    let collect = unsafe { *arg_collect };
+    _mi_page_free_collect(page, collect >= MiCollect::MiForce);
+    if mi_page_all_free(page) {
+        // no more used blocks, free the page.
+        // note: this will free retired pages as well.
+        _mi_page_free(page, pq, collect >= MiCollect::MiForce);
+    } else if collect == MiCollect::MiAbandon {
+        // still used blocks but the thread is done; abandon the page
+        _mi_page_abandon(page, pq);
+    }
+    true // don't break
+}
+
+fn mi_heap_page_never_delayed_free(
+    _heap: *mut MiHeap,
+    _pq: *mut MiPageQueue,
+    page: *mut MiPage,
+    _arg1: *mut MiCollect,
+) -> bool {
+    _mi_page_use_delayed_free(page, MiDelayed::MiNeverDelayedFree, false);
+    true // don't break
+}
+
+fn mi_heap_collect_ex(heap: *mut MiHeap, mut collect: MiCollect) {
+    if heap.is_null() || !mi_heap_is_initialized(heap) {
+        return;
+    }
+
+    let force = collect >= MiCollect::MiForce;
+    _mi_deferred_free(heap, force);
+
+    // note: never reclaim on collect but leave it to threads that need storage to reclaim
+    if collect == MiCollect::MiForce
+        && _mi_is_main_thread()
+        && mi_heap_is_backing(heap)
+        && !unsafe { (*heap).no_reclaim }
+    {
+        // the main thread is abandoned (end-of-program), try to reclaim all abandoned segments.
+        // if all memory is freed by now, all segments should be freed.
+        _mi_abandoned_reclaim_all(heap, unsafe { ptr::addr_of_mut!((*(*heap).tld).segments) });
+    }
+
+    // if abandoning, mark all pages to no longer add to delayed_free
+    if collect == MiCollect::MiAbandon {
+        mi_heap_visit_pages(heap, mi_heap_page_never_delayed_free, ptr::null_mut());
+    }
+
+    // free all current thread delayed blocks.
+    // (if abandoning, after this there are no more thread-delayed references into the pages.)
+    _mi_heap_delayed_free_all(heap);
+
+    // collect retired pages
+    _mi_heap_collect_retired(heap, force);
+
+    // collect all pages owned by this thread
+    mi_heap_visit_pages(heap, mi_heap_page_collect, &mut collect);
+
+    // collect abandoned segments (in particular, decommit expired parts of segments in the abandoned segment list)
+    // note: forced decommit can be quite expensive if many threads are created/destroyed so we do not force on abandonment
+    _mi_abandoned_collect(
+        heap,
+        collect == MiCollect::MiForce, /* force? */
+        unsafe { ptr::addr_of_mut!((*(*heap).tld).segments) },
+    );
+
+    // decommit in global segment caches
+    // note: forced decommit can be quite expensive if many threads are created/destroyed so we do not force on abandonment
+    _mi_segment_cache_collect(collect == MiCollect::MiForce, unsafe {
+        ptr::addr_of_mut!((*(*heap).tld).os)
+    });
+}
+
+pub fn _mi_heap_collect_abandon(heap: *mut MiHeap) {
+    mi_heap_collect_ex(heap, MiCollect::MiAbandon);
+}
+
+pub fn mi_heap_collect(heap: *mut MiHeap, force: bool) {
+    mi_heap_collect_ex(
+        heap,
+        if force {
+            MiCollect::MiForce
+        } else {
+            MiCollect::MiNormal
+        },
+    );
+}
+
+#[no_mangle]
+pub extern "C" fn mi_collect(force: bool) {
+    mi_heap_collect(get_default_heap(), force);
+}
+
+/* -----------------------------------------------------------
+  Heap new
+----------------------------------------------------------- */
+
+#[no_mangle]
+pub extern "C" fn mi_heap_get_default() -> *mut MiHeap {
+    mi_thread_init();
+    get_default_heap()
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_get_backing() -> *mut MiHeap {
+    let heap = mi_heap_get_default();
+    debug_assert!(!heap.is_null());
+    let bheap = unsafe { (*(*heap).tld).heap_backing };
+    debug_assert!(!bheap.is_null());
+    debug_assert!(unsafe { (*bheap).thread_id } == _mi_thread_id());
+    bheap
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_new_in_arena(arena_id: MiArenaIdT) -> *mut MiHeap {
+    let bheap = mi_heap_get_backing();
+    let heap: *mut MiHeap = mi_heap_malloc(bheap, size_of::<MiHeap>()).cast(); // todo: OS allocate in secure mode?
+    if heap.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe {
+        ptr::write(heap, MiHeap::new());
+        (*heap).tld = (*bheap).tld;
+        (*heap).thread_id = _mi_thread_id();
+        (*heap).arena_id = arena_id;
+        _mi_random_split(
+            ptr::addr_of_mut!((*bheap).random),
+            ptr::addr_of_mut!((*heap).random),
+        );
+        (*heap).cookie = _mi_heap_random_next(heap) | 1;
+        (*heap).keys[0] = _mi_heap_random_next(heap);
+        (*heap).keys[1] = _mi_heap_random_next(heap);
+        (*heap).no_reclaim = true; // don't reclaim abandoned pages or otherwise destroy is unsafe
+                                   // push on the thread local heaps list
+        (*heap).next = (*(*heap).tld).heaps;
+        (*(*heap).tld).heaps = heap;
+    }
+    heap
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_new() -> *mut MiHeap {
+    mi_heap_new_in_arena(_mi_arena_id_none())
+}
+
+pub fn _mi_heap_memid_is_suitable(heap: *mut MiHeap, memid: usize) -> bool {
+    _mi_arena_memid_is_suitable(memid, unsafe { (*heap).arena_id })
+}
+
+pub fn _mi_heap_random_next(heap: *mut MiHeap) -> usize {
+    _mi_random_next(unsafe { ptr::addr_of_mut!((*heap).random) })
+}
+
+// zero out the page queues
+fn mi_heap_reset_pages(heap: *mut MiHeap) {
+    debug_assert!(!heap.is_null());
+    debug_assert!(mi_heap_is_initialized(heap));
+    // TODO: copy full empty heap instead?
+    unsafe {
+        (*heap).pages_free_direct = [ptr::addr_of_mut!(_mi_page_empty); MI_PAGES_DIRECT];
+        (*heap).pages = MI_PAGE_QUEUES_EMPTY;
+        (*heap)
+            .thread_delayed_free
+            .store(ptr::null_mut(), Ordering::Relaxed);
+        (*heap).page_count = 0;
+    }
 }
 
 // called from `mi_heap_destroy` and `mi_heap_delete` to free the internal heap resources.
 fn mi_heap_free(heap: *mut MiHeap) {
-    // mi_assert(heap != NULL);
-    // mi_assert_internal(mi_heap_is_initialized(heap));
-    // if (heap==NULL || !mi_heap_is_initialized(heap)) return;
-    // if (mi_heap_is_backing(heap)) return; // dont free the backing heap
-
-    // // reset default
-    // if (mi_heap_is_default(heap)) {
-    //   _mi_heap_set_default_direct(heap->tld->heap_backing);
-    // }
-
-    // // remove ourselves from the thread local heaps list
-    // // linear search but we expect the number of heaps to be relatively small
-    // mi_heap_t* prev = NULL;
-    // mi_heap_t* curr = heap->tld->heaps;
-    // while (curr != heap && curr != NULL) {
-    //   prev = curr;
-    //   curr = curr->next;
-    // }
-    // mi_assert_internal(curr == heap);
-    // if (curr == heap) {
-    //   if (prev != NULL) { prev->next = heap->next; }
-    //                else { heap->tld->heaps = heap->next; }
-    // }
-    // mi_assert_internal(heap->tld->heaps != NULL);
-
-    // // and free the used memory
-    // mi_free(heap);
+    debug_assert!(!heap.is_null());
+    debug_assert!(mi_heap_is_initialized(heap));
+    if heap.is_null() || !mi_heap_is_initialized(heap) {
+        return;
+    }
+    if mi_heap_is_backing(heap) {
+        return; // dont free the backing heap
+    }
+
+    unsafe {
+        // reset default
+        if mi_heap_is_default(heap) {
+            _mi_heap_set_default_direct((*(*heap).tld).heap_backing);
+        }
+
+        // remove ourselves from the thread local heaps list
+        // linear search but we expect the number of heaps to be relatively small
+        let mut prev: *mut MiHeap = ptr::null_mut();
+        let mut curr = (*(*heap).tld).heaps;
+        while curr != heap && !curr.is_null() {
+            prev = curr;
+            curr = (*curr).next;
+        }
+        debug_assert!(curr == heap);
+        if curr == heap {
+            if !prev.is_null() {
+                (*prev).next = (*heap).next;
+            } else {
+                (*(*heap).tld).heaps = (*heap).next;
+            }
+        }
+        debug_assert!(!(*(*heap).tld).heaps.is_null());
+    }
+
+    // and free the used memory
+    mi_free(heap.cast());
+}
+
+/* -----------------------------------------------------------
+  Heap destroy
+----------------------------------------------------------- */
+
+fn _mi_heap_page_destroy(
+    heap: *mut MiHeap,
+    _pq: *mut MiPageQueue,
+    page: *mut MiPage,
+    _arg1: *mut MiCollect,
+) -> bool {
+    // ensure no more thread_delayed_free will be added
+    _mi_page_use_delayed_free(page, MiDelayed::MiNeverDelayedFree, false);
+
+    // stats
+    let stats = unsafe { ptr::addr_of_mut!((*(*heap).tld).stats) };
+    let bsize = mi_page_usable_block_size(page);
+    unsafe {
+        if MI_STAT > 0 && bsize > MI_MEDIUM_OBJ_SIZE_MAX {
+            if bsize <= MI_LARGE_OBJ_SIZE_MAX {
+                _mi_stat_decrease(ptr::addr_of_mut!((*stats).large), bsize);
+            } else {
+                _mi_stat_decrease(ptr::addr_of_mut!((*stats).huge), bsize);
+            }
+        }
+        if MI_STAT > 0 {
+            _mi_page_free_collect(page, false); // update used count
+            let inuse = (*page).used as usize;
+            if bsize <= MI_MEDIUM_OBJ_SIZE_MAX {
+                _mi_stat_decrease(ptr::addr_of_mut!((*stats).normal), bsize * inuse);
+                if MI_STAT > 1 {
+                    _mi_stat_decrease(
+                        ptr::addr_of_mut!((*stats).normal_bins[_mi_bin(bsize)]),
+                        inuse,
+                    );
+                }
+            }
+            if MI_STAT > 1 {
+                _mi_stat_decrease(ptr::addr_of_mut!((*stats).malloc), bsize * inuse);
+                // todo: off for aligned blocks...
+            }
+        }
+    }
+
+    // pretend it is all free now
+    debug_assert!(mi_page_thread_free(page).is_null());
+    unsafe {
+        (*page).used = 0;
+
+        // and free the page
+        (*page).next = ptr::null_mut();
+        (*page).prev = ptr::null_mut();
+        _mi_segment_page_free(page, false, ptr::addr_of_mut!((*(*heap).tld).segments));
+    }
+
+    true // keep going
+}
+
+pub fn _mi_heap_destroy_pages(heap: *mut MiHeap) {
+    mi_heap_visit_pages(heap, _mi_heap_page_destroy, ptr::null_mut());
+    mi_heap_reset_pages(heap);
+}
+
+// Destroy a heap and free all of its still allocated blocks at once.
+#[no_mangle]
+pub extern "C" fn mi_heap_destroy(heap: *mut MiHeap) {
+    debug_assert!(!heap.is_null());
+    debug_assert!(mi_heap_is_initialized(heap));
+    if heap.is_null() || !mi_heap_is_initialized(heap) {
+        return;
+    }
+    debug_assert!(unsafe { (*heap).no_reclaim });
+    if !unsafe { (*heap).no_reclaim } {
+        // non-standard heap, we cannot destroy it (as some pages may have been reclaimed from other threads); use delete instead
+        mi_heap_delete(heap);
+    } else {
+        // free all pages
+        _mi_heap_destroy_pages(heap);
+        mi_heap_free(heap);
+    }
+}
+
+// forcefully destroy all heaps in the current thread
+pub fn _mi_heap_destroy_all() {
+    let bheap = mi_heap_get_backing();
+    let mut curr = unsafe { (*(*bheap).tld).heaps };
+    while !curr.is_null() {
+        let next = unsafe { (*curr).next };
+        if unsafe { (*curr).no_reclaim } {
+            mi_heap_destroy(curr);
+        } else {
+            _mi_heap_destroy_pages(curr);
+        }
+        curr = next;
+    }
+}
+
+/* -----------------------------------------------------------
+  Safe Heap delete
+----------------------------------------------------------- */
+
+// Transfer the pages from one heap to the other
+fn mi_heap_absorb(heap: *mut MiHeap, from: *mut MiHeap) {
+    debug_assert!(!heap.is_null());
+    if from.is_null() || unsafe { (*from).page_count } == 0 {
+        return;
+    }
+
+    // reduce the size of the delayed frees
+    _mi_heap_delayed_free_partial(from);
+
+    // transfer all pages by appending the queues; this will set a new heap field
+    // so threads may do delayed frees in either heap for a while.
+    // note: appending waits for each page to not be in the `MI_DELAYED_FREEING` state
+    // so after this only the new heap will get delayed frees
+    for i in 0..=MI_BIN_FULL {
+        unsafe {
+            let pq = ptr::addr_of_mut!((*heap).pages[i]);
+            let append = ptr::addr_of_mut!((*from).pages[i]);
+            let pcount = _mi_page_queue_append(heap, pq, append);
+            (*heap).page_count += pcount;
+            (*from).page_count -= pcount;
+        }
+    }
+    debug_assert!(unsafe { (*from).page_count } == 0);
+
+    // and do outstanding delayed frees in the `from` heap
+    // note: be careful here as the `heap` field in all those pages no longer point to `from`,
+    // turns out to be ok as `_mi_heap_delayed_free` only visits the list and calls a
+    // the regular `_mi_free_delayed_block` which is safe.
+    _mi_heap_delayed_free_all(from);
+    debug_assert!(unsafe { (*from).thread_delayed_free.load(Ordering::Relaxed) }.is_null());
+
+    // and reset the `from` heap
+    mi_heap_reset_pages(from);
+}
+
+// Safe delete a heap without freeing any still allocated blocks in that heap.
+#[no_mangle]
+pub extern "C" fn mi_heap_delete(heap: *mut MiHeap) {
+    debug_assert!(!heap.is_null());
+    debug_assert!(mi_heap_is_initialized(heap));
+    if heap.is_null() || !mi_heap_is_initialized(heap) {
+        return;
+    }
+
+    if !mi_heap_is_backing(heap) {
+        // tranfer still used pages to the backing heap
+        mi_heap_absorb(unsafe { (*(*heap).tld).heap_backing }, heap);
+    } else {
+        // the backing heap abandons its pages
+        _mi_heap_collect_abandon(heap);
+    }
+    debug_assert!(unsafe { (*heap).page_count } == 0);
+    mi_heap_free(heap);
+}
+
+#[no_mangle]
+pub extern "C" fn mi_heap_set_default(heap: *mut MiHeap) -> *mut MiHeap {
+    debug_assert!(!heap.is_null() && mi_heap_is_initialized(heap));
+    if heap.is_null() || !mi_heap_is_initialized(heap) {
+        return ptr::null_mut();
+    }
+    let old = get_default_heap();
+    _mi_heap_set_default_direct(heap);
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_void, ptr, sync::atomic::Ordering};
+
+    use crate::{
+        alloc::{mi_free, mi_heap_malloc, mi_malloc},
+        arena::{_mi_arena_id_none, _mi_arena_is_os_allocated, mi_reserve_os_memory_ex},
+        init::_mi_heap_set_default_direct,
+        mimalloc_internal::{_mi_ptr_page, _mi_ptr_segment, get_default_heap, mi_page_heap},
+        mimalloc_types::MI_SEGMENT_SIZE,
+        page::tests::TestHeap,
+        segment::tests::reclaim_abandoned,
+    };
+
+    use super::{
+        _mi_heap_collect_abandon, mi_heap_collect, mi_heap_delete, mi_heap_destroy,
+        mi_heap_get_backing, mi_heap_new, mi_heap_set_default,
+    };
+
+    // Run `f` with a fresh backing heap as the default heap of this thread.
+    fn with_test_heap(f: impl FnOnce(&mut TestHeap)) {
+        let mut th = TestHeap::new();
+        // the previous default may still be the empty heap
+        let old = get_default_heap();
+        _mi_heap_set_default_direct(th.ptr());
+        f(&mut th);
+        _mi_heap_set_default_direct(old);
+        mi_heap_collect(th.ptr(), true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_heap_new_links_into_tld() {
+        with_test_heap(|th| {
+            assert!(mi_heap_get_backing() == th.ptr());
+            let h1 = mi_heap_new();
+            let h2 = mi_heap_new();
+            assert!(!h1.is_null() && !h2.is_null());
+            assert!(th.tld.heaps == h2);
+            unsafe {
+                assert!((*h2).next == h1 && (*h1).next == th.ptr());
+                assert!(ptr::eq((*h1).tld, &*th.tld) && ptr::eq((*h2).tld, &*th.tld));
+                assert!((*h1).no_reclaim);
+            }
+            mi_heap_delete(h1);
+            assert!(unsafe { (*h2).next } == th.ptr());
+            mi_heap_destroy(h2);
+            assert!(th.tld.heaps == th.ptr());
+        });
+    }
+
+    #[test]
+    fn test_mi_heap_destroy_frees_all_blocks() {
+        with_test_heap(|th| {
+            let heap = mi_heap_new();
+            for i in 0..10_000 {
+                let p = mi_heap_malloc(heap, 16 + (i % 300));
+                assert!(!p.is_null());
+            }
+            assert!(!mi_heap_malloc(heap, 1 << 20).is_null());
+            assert!(!mi_heap_malloc(heap, 80 << 20).is_null());
+            assert!(unsafe { (*heap).page_count } > 1);
+            mi_heap_destroy(heap);
+            assert!(th.tld.heaps == th.ptr());
+            // the heap struct itself was freed into the backing heap; collect its page as well
+            mi_heap_collect(th.ptr(), true);
+            assert_eq!(th.heap.page_count, 0);
+        });
+    }
+
+    #[test]
+    fn test_mi_heap_delete_absorbs_pages() {
+        with_test_heap(|th| {
+            let heap = mi_heap_new();
+            let blocks: Vec<*mut c_void> = (0..1000).map(|_| mi_heap_malloc(heap, 64)).collect();
+            let large = mi_heap_malloc(heap, 300_000);
+            mi_heap_delete(heap);
+            for &p in blocks.iter().chain([&large]) {
+                assert!(mi_page_heap(_mi_ptr_page(p)) == th.ptr());
+                unsafe { ptr::write_bytes(p.cast::<u8>(), 1, 64) };
+            }
+            for p in blocks {
+                mi_free(p);
+            }
+            mi_free(large);
+        });
+    }
+
+    #[test]
+    fn test_mi_heap_set_default() {
+        with_test_heap(|th| {
+            let heap = mi_heap_new();
+            let old = mi_heap_set_default(heap);
+            assert!(old == th.ptr());
+            assert!(get_default_heap() == heap);
+            let p = mi_malloc(100);
+            assert!(mi_page_heap(_mi_ptr_page(p)) == heap);
+            // destroying the default heap resets the default to the backing heap
+            mi_heap_destroy(heap);
+            assert!(get_default_heap() == th.ptr());
+        });
+    }
+
+    #[test]
+    fn test_mi_heap_collect_abandon_keeps_live_blocks() {
+        // allocate in an exclusive arena so no concurrently running test reclaims the segment
+        let mut arena_id = _mi_arena_id_none();
+        assert_eq!(
+            mi_reserve_os_memory_ex(MI_SEGMENT_SIZE, false, false, true, &mut arena_id),
+            0
+        );
+        let mut th = TestHeap::new();
+        th.heap.arena_id = arena_id;
+        let blocks: Vec<*mut c_void> = (0..100).map(|_| mi_heap_malloc(th.ptr(), 200)).collect();
+        for &p in &blocks {
+            unsafe { ptr::write_bytes(p.cast::<u8>(), 7, 200) };
+        }
+
+        let segment = _mi_ptr_segment(blocks[0]);
+        assert!(blocks.iter().all(|&p| _mi_ptr_segment(p) == segment));
+
+        // the heap gives up its pages and the segment is abandoned
+        _mi_heap_collect_abandon(th.ptr());
+        assert_eq!(th.heap.page_count, 0);
+        assert_eq!(th.tld.segments.count, 0);
+        unsafe {
+            assert!(!_mi_arena_is_os_allocated((*segment).memid));
+            assert_eq!((*segment).thread_id.load(Ordering::Relaxed), 0);
+            assert!((*segment).used > 0 && (*segment).abandoned == (*segment).used);
+        }
+        for &p in &blocks {
+            assert!(mi_page_heap(_mi_ptr_page(p)).is_null());
+        }
+
+        // reclaim it again; the blocks stayed valid
+        reclaim_abandoned(segment, th.ptr(), &mut th.tld.segments);
+        assert_eq!(th.tld.segments.count, 1);
+        for &p in &blocks {
+            assert!(mi_page_heap(_mi_ptr_page(p)) == th.ptr());
+            assert!(unsafe { std::slice::from_raw_parts(p.cast::<u8>(), 200) }
+                .iter()
+                .all(|&b| b == 7));
+        }
+
+        // freeing the blocks lets a forced collect release the segment
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(th.ptr(), true);
+        assert_eq!(th.heap.page_count, 0);
+        assert_eq!(th.tld.segments.count, 0);
+    }
 }
@@ -0,0 +1,210 @@
+// `Allocator` (nightly `allocator_api`) handles for first-class heaps, so collections
+// can be placed into a specific heap, as in `Vec::new_in(heap)`.
+// A heap is thread local: the handle is neither `Send` nor `Sync`.
+
+use std::{
+    alloc::{AllocError, Allocator, Layout},
+    ptr::{self, NonNull},
+};
+
+use crate::{
+    alloc::{mi_free, mi_usable_size},
+    alloc_aligned::{mi_heap_malloc_aligned, mi_heap_zalloc_aligned},
+    heap::{mi_heap_delete, mi_heap_destroy, mi_heap_new},
+    mimalloc_internal::get_default_heap,
+    mimalloc_types::MiHeap,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MiHeapHandle {
+    heap: NonNull<MiHeap>,
+}
+
+impl MiHeapHandle {
+    /// The default heap of the current thread.
+    pub fn default_heap() -> MiHeapHandle {
+        MiHeapHandle::from_raw(get_default_heap())
+    }
+
+    /// Create a new heap owned by the current thread, or `None` when out of memory.
+    pub fn new() -> Option<MiHeapHandle> {
+        NonNull::new(mi_heap_new()).map(|heap| MiHeapHandle { heap })
+    }
+
+    /// Delete the heap; blocks that are still allocated move to the backing heap of the thread.
+    ///
+    /// # Safety
+    /// The heap (or any copy of this handle) may not be used to allocate afterwards,
+    /// and containers using it as their allocator must not grow or shrink anymore.
+    pub unsafe fn delete(self) {
+        mi_heap_delete(self.as_ptr());
+    }
+
+    /// Destroy the heap and free all of its blocks at once.
+    ///
+    /// # Safety
+    /// No block allocated in this heap may be used (or freed) afterwards.
+    pub unsafe fn destroy(self) {
+        mi_heap_destroy(self.as_ptr());
+    }
+
+    pub(crate) fn from_raw(heap: *mut MiHeap) -> MiHeapHandle {
+        MiHeapHandle {
+            heap: NonNull::new(heap).expect("heap handle to a null heap"),
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut MiHeap {
+        self.heap.as_ptr()
+    }
+
+    // The full usable block is handed out so later growth can stay in place.
+    fn block_slice(p: *mut u8, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let p = NonNull::new(p).ok_or(AllocError)?;
+        let usable = mi_usable_size(p.as_ptr().cast()).max(size);
+        Ok(NonNull::slice_from_raw_parts(p, usable))
+    }
+
+    // Can the block at `ptr` hold `new_layout` without moving?
+    fn fits_in_place(ptr: NonNull<u8>, new_layout: Layout) -> bool {
+        (ptr.as_ptr() as usize).is_multiple_of(new_layout.align())
+            && mi_usable_size(ptr.as_ptr().cast()) >= new_layout.size()
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        zero: bool,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        let new = if zero {
+            self.allocate_zeroed(new_layout)?
+        } else {
+            self.allocate(new_layout)?
+        };
+        let copysize = old_layout.size().min(new_layout.size());
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new.as_ptr().cast::<u8>(), copysize);
+        self.deallocate(ptr, old_layout);
+        Ok(new)
+    }
+}
+
+unsafe impl Allocator for MiHeapHandle {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let p = mi_heap_malloc_aligned(self.as_ptr(), layout.size(), layout.align());
+        MiHeapHandle::block_slice(p.cast(), layout.size())
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let p = mi_heap_zalloc_aligned(self.as_ptr(), layout.size(), layout.align());
+        MiHeapHandle::block_slice(p.cast(), layout.size())
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        mi_free(ptr.as_ptr().cast());
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        if MiHeapHandle::fits_in_place(ptr, new_layout) {
+            return MiHeapHandle::block_slice(ptr.as_ptr(), new_layout.size());
+        }
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() >= old_layout.size());
+        if MiHeapHandle::fits_in_place(ptr, new_layout) {
+            // the tail beyond the old size is not necessarily zero
+            let block = MiHeapHandle::block_slice(ptr.as_ptr(), new_layout.size())?;
+            let old_size = old_layout.size();
+            ptr::write_bytes(ptr.as_ptr().add(old_size), 0, block.len() - old_size);
+            return Ok(block);
+        }
+        self.realloc(ptr, old_layout, new_layout, true)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        debug_assert!(new_layout.size() <= old_layout.size());
+        if MiHeapHandle::fits_in_place(ptr, new_layout) {
+            return MiHeapHandle::block_slice(ptr.as_ptr(), new_layout.size());
+        }
+        self.realloc(ptr, old_layout, new_layout, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{Allocator, Layout};
+
+    use crate::{heap::mi_heap_collect, page::tests::TestHeap};
+
+    use super::MiHeapHandle;
+
+    #[test]
+    fn test_vec_new_in_heap() {
+        let mut th = TestHeap::new();
+        let heap = MiHeapHandle::from_raw(th.ptr());
+        {
+            let mut v: Vec<u64, MiHeapHandle> = Vec::new_in(heap);
+            for i in 0..100_000 {
+                v.push(i);
+            }
+            assert!(v.iter().enumerate().all(|(i, &x)| x == i as u64));
+            let b = Box::new_in([7u8; 3000], heap);
+            assert!(b.iter().all(|&x| x == 7));
+        }
+        mi_heap_collect(th.ptr(), true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_grow_in_place_and_zeroed() {
+        let mut th = TestHeap::new();
+        let heap = MiHeapHandle::from_raw(th.ptr());
+        unsafe {
+            let old = Layout::from_size_align(100, 16).unwrap();
+            let block = heap.allocate(old).unwrap();
+            assert!(block.len() >= 100);
+            let p = block.cast::<u8>();
+            std::ptr::write_bytes(p.as_ptr(), 0xFF, block.len());
+
+            // grows in place within the usable size of the block
+            let new = Layout::from_size_align(block.len(), 16).unwrap();
+            let grown = heap.grow_zeroed(p, old, new).unwrap();
+            assert_eq!(grown.cast::<u8>(), p);
+            let bytes = &*grown.as_ptr();
+            assert!(bytes[..100].iter().all(|&b| b == 0xFF));
+            assert!(bytes[100..].iter().all(|&b| b == 0));
+
+            // and moves otherwise
+            let large = Layout::from_size_align(100_000, 64).unwrap();
+            let moved = heap.grow(p, new, large).unwrap();
+            assert_ne!(moved.cast::<u8>(), p);
+            assert_eq!(moved.cast::<u8>().as_ptr() as usize % 64, 0);
+            assert!((&*moved.as_ptr())[..100].iter().all(|&b| b == 0xFF));
+
+            let small = Layout::from_size_align(10, 64).unwrap();
+            let shrunk = heap.shrink(moved.cast(), large, small).unwrap();
+            heap.deallocate(shrunk.cast(), small);
+        }
+        mi_heap_collect(th.ptr(), true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+}
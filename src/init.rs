@@ -1,65 +1,96 @@
-use ctor::{ctor, dtor};
+use ctor::ctor;
+#[cfg(windows)]
 use windows::Win32::System::Threading::{FlsAlloc, FlsSetValue};
 
-use crate::heap::mi_heap_delete;
-use crate::mimalloc_internal::{_mi_thread_id, get_default_heap, mi_heap_is_initialized};
-use crate::mimalloc_types::{MiHeap, MiTLD, MiThreadData, MiThreadId};
-use crate::os::_mi_os_init;
-use crate::random::_mi_heap_random_next;
-use std::mem::MaybeUninit;
-use std::os::raw::c_void;
+#[cfg(unix)]
+use libc::{c_void, pthread_key_create, pthread_key_t, pthread_setspecific};
+
+use crate::arena::{
+    mi_reserve_huge_os_pages_at, mi_reserve_huge_os_pages_interleave, mi_reserve_os_memory,
+};
+use crate::heap::{
+    _mi_heap_collect_abandon, _mi_heap_destroy_all, _mi_heap_random_next, mi_heap_delete,
+};
+use crate::mimalloc_internal::{
+    _mi_thread_id, get_default_heap, mi_heap_is_backing, mi_heap_is_initialized,
+};
+use crate::mimalloc_types::{MI_KiB, MiHeap, MiOption, MiPage, MiTLD, MiThreadData, MI_SECURE};
+use crate::options::{
+    _mi_error_message, _mi_options_init, _mi_verbose_message, mi_option_get, mi_option_get_clamp,
+    mi_option_is_enabled,
+};
+use crate::os::{_mi_os_alloc, _mi_os_free, _mi_os_init};
+use crate::random::{_mi_random_init, _mi_random_init_weak, _mi_random_reinit_if_weak};
+use crate::segment_cache::_mi_segment_cache_free_all;
+use crate::stats::{
+    _mi_stat_decrease, _mi_stat_increase, _mi_stats_done, _mi_stats_main, mi_stats_print,
+    mi_stats_reset,
+};
+use std::cell::Cell;
+use std::mem::{size_of, MaybeUninit};
 use std::ptr;
 use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
 use std::sync::Once;
 
+// Empty page used to initialize the small free pages array
+pub static mut _mi_page_empty: MiPage = MiPage::new();
+
+// The empty heap every thread starts with; allocating from it initializes the thread
+pub static mut _mi_heap_empty: MiHeap = MiHeap::new();
+
 pub fn get_mi_heap_main() -> &'static mut MiHeap {
     static mut MiHeapMain: MaybeUninit<MiHeap> = MaybeUninit::uninit();
+    static mut TldMain: MaybeUninit<MiTLD> = MaybeUninit::uninit();
     static ONCE: Once = Once::new();
     unsafe {
         ONCE.call_once(|| {
-            MiHeapMain.write(MiHeap::new());
+            let heap: *mut MiHeap = (*ptr::addr_of_mut!(MiHeapMain)).write(MiHeap::new());
+            let tld: *mut MiTLD = (*ptr::addr_of_mut!(TldMain)).write(MiTLD::default());
+            (*heap).tld = tld;
+            (*tld).heap_backing = heap;
+            (*tld).heaps = heap;
+            (*tld).segments.os = ptr::addr_of_mut!((*tld).os);
+            (*tld).segments.stats = ptr::addr_of_mut!((*tld).stats);
+            (*tld).os.stats = ptr::addr_of_mut!((*tld).stats);
         });
-        MiHeapMain.assume_init_mut()
+        (*ptr::addr_of_mut!(MiHeapMain)).assume_init_mut()
     }
 }
 
 // Initialize the thread local default heap, called from `mi_thread_init`
 pub fn _mi_heap_init() -> bool {
-    if mi_heap_is_initialized(get_default_heap().as_ref()) {
+    if mi_heap_is_initialized(get_default_heap()) {
         return true;
     }
 
-    if mi_is_main_thread() {
+    if _mi_is_main_thread() {
         mi_heap_main_init();
         _mi_heap_set_default_direct(get_mi_heap_main());
     } else {
+        // use `_mi_os_alloc` to allocate directly from the OS
         let td: *mut MiThreadData = mi_thread_data_alloc();
-        // TODO should use Option instead?
         if td.is_null() {
             return false;
         }
 
-        // OS allocated so already zero initialized
-        let tld: *mut MiTLD = unsafe { &mut (*td).tld };
-        let heap: *mut MiHeap = unsafe { &mut (*td).heap };
-        // TODO initialize tld and heap by using memcpy
-        // Do I really need to use memcpy?
-
+        // the thread data may come from the cache, so initialize all fields
         unsafe {
+            let tld: *mut MiTLD = ptr::addr_of_mut!((*td).tld);
+            let heap: *mut MiHeap = ptr::addr_of_mut!((*td).heap);
+            ptr::write(tld, MiTLD::default());
+            ptr::write(heap, MiHeap::new());
             (*heap).thread_id = _mi_thread_id();
-
-            // TODO currently, do not random the heap
-            // _mi_random_init(&heap->random);
-            // (*heap).cookie = _mi_heap_random_next(heap) | 1;
-            // heap->keys[0] = _mi_heap_random_next(heap);
-            // heap->keys[1] = _mi_heap_random_next(heap);
+            _mi_random_init(ptr::addr_of_mut!((*heap).random));
+            (*heap).cookie = _mi_heap_random_next(heap) | 1;
+            (*heap).keys[0] = _mi_heap_random_next(heap);
+            (*heap).keys[1] = _mi_heap_random_next(heap);
             (*heap).tld = tld;
             (*tld).heap_backing = heap;
             (*tld).heaps = heap;
-            // (*tld).segments.stats = &(*tld).stats;
-            (*tld).segments.os = &mut (*tld).os;
-            // (*tld).os.stats = &(*tld).stats;
-            // _mi_heap_set_default_direct(heap);
+            (*tld).segments.os = ptr::addr_of_mut!((*tld).os);
+            (*tld).segments.stats = ptr::addr_of_mut!((*tld).stats);
+            (*tld).os.stats = ptr::addr_of_mut!((*tld).stats);
+            _mi_heap_set_default_direct(heap);
         }
     }
 
@@ -72,10 +103,64 @@ pub fn _mi_heap_init() -> bool {
 // per thread so we maintain a small cache of recently freed metadata.
 
 const TD_CACHE_SIZE: usize = 8;
-static TD_CACHE: AtomicPtr<[Option<MiThreadData>; TD_CACHE_SIZE]> = AtomicPtr::new(ptr::null_mut());
+static TD_CACHE: [AtomicPtr<MiThreadData>; TD_CACHE_SIZE] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; TD_CACHE_SIZE];
 
 fn mi_thread_data_alloc() -> *mut MiThreadData {
-    ptr::null_mut()
+    // try to find thread metadata in the cache
+    for slot in TD_CACHE.iter() {
+        if !slot.load(Ordering::Relaxed).is_null() {
+            let td = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !td.is_null() {
+                return td;
+            }
+        }
+    }
+
+    // if that fails, allocate directly from the OS
+    let mut td: *mut MiThreadData = _mi_os_alloc(size_of::<MiThreadData>()).cast();
+    if td.is_null() {
+        // if this fails, try once more. (issue #257)
+        td = _mi_os_alloc(size_of::<MiThreadData>()).cast();
+        if td.is_null() {
+            // really out of memory
+            _mi_error_message(
+                libc::ENOMEM,
+                format_args!(
+                    "unable to allocate thread local heap metadata ({} bytes)\n",
+                    size_of::<MiThreadData>()
+                ),
+            );
+        }
+    }
+    td
+}
+
+fn mi_thread_data_free(tdfree: *mut MiThreadData) {
+    // try to add the thread metadata to the cache
+    for slot in TD_CACHE.iter() {
+        if slot.load(Ordering::Relaxed).is_null()
+            && slot
+                .compare_exchange_weak(ptr::null_mut(), tdfree, Ordering::AcqRel, Ordering::Relaxed)
+                .is_ok()
+        {
+            return;
+        }
+    }
+    // if that fails, just free it directly
+    _mi_os_free(tdfree.cast(), size_of::<MiThreadData>());
+}
+
+fn mi_thread_data_collect() {
+    // free all thread metadata from the cache
+    for slot in TD_CACHE.iter() {
+        if !slot.load(Ordering::Relaxed).is_null() {
+            let td = slot.swap(ptr::null_mut(), Ordering::AcqRel);
+            if !td.is_null() {
+                _mi_os_free(td.cast(), size_of::<MiThreadData>());
+            }
+        }
+    }
 }
 
 static MI_PROCESS_IS_INITIALIZED: AtomicBool = AtomicBool::new(false);
@@ -94,18 +179,44 @@ fn mi_process_init() {
 
     mi_process_setup_auto_thread_done();
 
-    // TODO log here
+    _mi_verbose_message(format_args!("process init: 0x{:x}\n", _mi_thread_id()));
+    _mi_verbose_message(format_args!("secure level: {}\n", MI_SECURE));
+    _mi_verbose_message(format_args!(
+        "debug level : {}\n",
+        if cfg!(debug_assertions) { 2 } else { 0 }
+    ));
     mi_detect_cpu_feature();
     _mi_os_init();
 
     mi_heap_main_init();
     mi_thread_init();
-    if cfg!(Win32) {
+    _mi_random_reinit_if_weak(&mut get_mi_heap_main().random); // now that the OS is initialized, use secure randomness
+    if cfg!(windows) {
         // TODO check lately here
         // FlsSetValue(mi_fls_key, NULL);
     }
-
-    // TODO support option reserve huge os page and reserve os memory
+    mi_stats_reset(); // only call stat reset *after* thread init (or the heap tld == NULL)
+
+    if mi_option_is_enabled(MiOption::MiOptionReserveHugeOsPages) {
+        let pages =
+            mi_option_get_clamp(MiOption::MiOptionReserveHugeOsPages, 0, 128 * 1024) as usize;
+        let reserve_at = mi_option_get(MiOption::MiOptionReserveHugeOsPagesAt);
+        if reserve_at != -1 {
+            mi_reserve_huge_os_pages_at(pages, reserve_at as i32, pages * 500);
+        } else {
+            mi_reserve_huge_os_pages_interleave(pages, 0, pages * 500);
+        }
+    }
+    if mi_option_is_enabled(MiOption::MiOptionReserveOsMemory) {
+        let ksize = mi_option_get(MiOption::MiOptionReserveOsMemory);
+        if ksize > 0 {
+            mi_reserve_os_memory(
+                ksize as usize * MI_KiB as usize,
+                true, /* commit? */
+                true, /* allow large pages? */
+            );
+        }
+    }
 }
 
 fn mi_detect_cpu_feature() {
@@ -129,7 +240,20 @@ extern "C" fn mi_process_done() {
 
     // TODO FlsFree here
 
-    // TODO support feture destroy on exit
+    // Forcefully release all retained memory; this can be dangerous in general if overriding regular malloc/free
+    // since after process_done there might still be other code running that calls `free` (like at_exit routines,
+    // or C-runtime termination code.
+    if mi_option_is_enabled(MiOption::MiOptionDestroyOnExit) {
+        _mi_heap_destroy_all(); // forcefully release all memory held by all heaps (of this thread only!)
+        _mi_segment_cache_free_all(unsafe { ptr::addr_of_mut!((*get_mi_heap_main().tld).os) });
+        // release all cached segments
+    }
+
+    if mi_option_is_enabled(MiOption::MiOptionShowStats)
+        || mi_option_is_enabled(MiOption::MiOptionVerbose)
+    {
+        mi_stats_print(ptr::null_mut());
+    }
 
     mi_allocator_done();
 }
@@ -141,36 +265,42 @@ fn mi_allocator_done() {
 // Called once by the process loader
 fn mi_process_load() {
     mi_heap_main_init();
-    debug_assert!(mi_is_main_thread());
+    debug_assert!(_mi_is_main_thread());
 
     // use libc atexit instead of dtor
     unsafe { libc::atexit(mi_process_done) };
 
-    _mi_option_init();
+    _mi_options_init();
     mi_process_setup_auto_thread_done();
     mi_process_init();
 }
 
 // called from `mi_malloc_generic`
-fn mi_thread_init() {
+pub fn mi_thread_init() {
     // ensure process has started already
     mi_process_init();
 
-    if _mi_heap_init() {}
+    // initialize the thread local default heap
+    // (this will call `_mi_heap_set_default_direct` and thus set the
+    //  fiber/pthread key to a non-zero value, ensuring `_mi_thread_done` is called)
+    if _mi_heap_init() {
+        return; // returns true if already initialized
+    }
+
+    _mi_stat_increase(unsafe { ptr::addr_of_mut!(_mi_stats_main.threads) }, 1);
+    THREAD_COUNT.fetch_add(1, Ordering::Relaxed);
 }
 
 static THREAD_COUNT: AtomicUsize = AtomicUsize::new(1);
 
-// called by DllMain, currently, do not implement
-fn mi_thread_done() {
-    _mi_thread_done(get_default_heap().as_mut());
-}
-
 fn _mi_thread_done(heap: *mut MiHeap) {
-    THREAD_COUNT.fetch_sub(1, Ordering::Relaxed);
+    // the thread never initialized its heap (e.g. the default was only set to the empty heap)
+    if !mi_heap_is_initialized(heap) {
+        return;
+    }
 
-    // mi_atomic_decrement_relaxed(&thread_count);
-    // _mi_stat_decrease(&_mi_stats_main.threads, 1);
+    THREAD_COUNT.fetch_sub(1, Ordering::Relaxed);
+    _mi_stat_decrease(unsafe { ptr::addr_of_mut!(_mi_stats_main.threads) }, 1);
 
     // check thread-id as on Windows shutdown with FLS the main (exit) thread may call this on thread-local heaps...
     unsafe {
@@ -183,63 +313,63 @@ fn _mi_thread_done(heap: *mut MiHeap) {
     _mi_heap_done(heap);
 }
 
-// Free the thread local default heap (called from `mi_thread_done`)
-fn _mi_heap_done(heap: *mut MiHeap) -> bool {
+// Free the thread local default heap (called from `_mi_thread_done`)
+fn _mi_heap_done(mut heap: *mut MiHeap) -> bool {
     if !mi_heap_is_initialized(heap) {
         return true;
     }
 
     // reset default heap
-    if mi_is_main_thread() {
-        _mi_heap_set_default_direct(get_mi_heap_main());
+    _mi_heap_set_default_direct(if _mi_is_main_thread() {
+        get_mi_heap_main()
     } else {
-        // TDOO
-        // _mi_heap_set_default_direct()
+        ptr::addr_of_mut!(_mi_heap_empty)
+    });
+
+    // switch to backing heap
+    heap = unsafe { (*(*heap).tld).heap_backing };
+    if !mi_heap_is_initialized(heap) {
+        return false;
     }
 
     // delete all non-backing heaps in this thread
     let mut curr = unsafe { (*(*heap).tld).heaps };
-
     while !curr.is_null() {
-        let next = unsafe { (*curr).next };
+        let next = unsafe { (*curr).next }; // save `next` as `curr` will be freed
         if curr != heap {
+            debug_assert!(!mi_heap_is_backing(curr));
             mi_heap_delete(curr);
         }
         curr = next;
     }
+    debug_assert!(unsafe { (*(*heap).tld).heaps == heap && (*heap).next.is_null() });
+    debug_assert!(mi_heap_is_backing(heap));
+
+    // collect if not the main thread
+    let is_main = ptr::eq(heap, get_mi_heap_main());
+    if !is_main {
+        _mi_heap_collect_abandon(heap);
+    }
 
-    // mi_assert_internal(heap->tld->heaps == heap && heap->next == NULL);
-    // mi_assert_internal(mi_heap_is_backing(heap));
-
-    //     // collect if not the main thread
-    //   if (heap != &_mi_heap_main) {
-    //     _mi_heap_collect_abandon(heap);
-    //   }
-
-    //   // merge stats
-    //   _mi_stats_done(&heap->tld->stats);
-
-    //   // free if not the main thread
-    //   if (heap != &_mi_heap_main) {
-    //     // the following assertion does not always hold for huge segments as those are always treated
-    //     // as abondened: one may allocate it in one thread, but deallocate in another in which case
-    //     // the count can be too large or negative. todo: perhaps not count huge segments? see issue #363
-    //     // mi_assert_internal(heap->tld->segments.count == 0 || heap->thread_id != _mi_thread_id());
-    //     mi_thread_data_free((mi_thread_data_t*)heap);
-    //   }
-    //   else {
-    //     mi_thread_data_collect(); // free cached thread metadata
-    //     #if 0
-    //     // never free the main thread even in debug mode; if a dll is linked statically with mimalloc,
-    //     // there may still be delete/free calls after the mi_fls_done is called. Issue #207
-    //     _mi_heap_destroy_pages(heap);
-    //     mi_assert_internal(heap->tld->heap_backing == &_mi_heap_main);
-    //     #endif
-    //   }
+    // merge stats
+    _mi_stats_done(unsafe { ptr::addr_of_mut!((*(*heap).tld).stats) });
+
+    // free if not the main thread
+    if !is_main {
+        // the following assertion does not always hold for huge segments as those are always treated
+        // as abondened: one may allocate it in one thread, but deallocate in another in which case
+        // the count can be too large or negative. todo: perhaps not count huge segments? see issue #363
+        // debug_assert!(heap.tld.segments.count == 0 || heap.thread_id != _mi_thread_id());
+        mi_thread_data_free(heap.cast()); // the heap is the first field of `MiThreadData`
+    } else {
+        mi_thread_data_collect(); // free cached thread metadata
+    }
+    // never free the main thread even in debug mode; if a dll is linked statically with mimalloc,
+    // there may still be delete/free calls after the mi_fls_done is called. Issue #207
     false
 }
 
-fn mi_is_main_thread() -> bool {
+pub fn _mi_is_main_thread() -> bool {
     get_mi_heap_main().thread_id == 0 || _mi_thread_id() == get_mi_heap_main().thread_id
 }
 
@@ -247,25 +377,25 @@ fn mi_heap_main_init() {
     if get_mi_heap_main().cookie == 0 {
         get_mi_heap_main().thread_id = _mi_thread_id();
         get_mi_heap_main().cookie = 1;
-        //   #if defined(_WIN32) && !defined(MI_SHARED_LIB)
-        //     _mi_random_init_weak(&_mi_heap_main.random);    // prevent allocation failure during bcrypt dll initialization with static linking
-        //   #else
-        //     _mi_random_init(&_mi_heap_main.random);
-        //   #endif
-        get_mi_heap_main().cookie = _mi_heap_random_next(&mut get_mi_heap_main().random);
-        get_mi_heap_main().keys[0] = _mi_heap_random_next(&mut get_mi_heap_main().random);
-        get_mi_heap_main().keys[1] = _mi_heap_random_next(&mut get_mi_heap_main().random);
+        if cfg!(windows) {
+            _mi_random_init_weak(&mut get_mi_heap_main().random); // prevent allocation failure during bcrypt dll initialization with static linking
+        } else {
+            _mi_random_init(&mut get_mi_heap_main().random);
+        }
+        get_mi_heap_main().cookie = _mi_heap_random_next(get_mi_heap_main());
+        get_mi_heap_main().keys[0] = _mi_heap_random_next(get_mi_heap_main());
+        get_mi_heap_main().keys[1] = _mi_heap_random_next(get_mi_heap_main());
     }
 }
 
-fn _mi_option_init() {}
-
 // TODO should MI_FLS_KEY use thread local?
 //thread_local! (static MI_FLS_KEY: u32 = u32::MAX);
+#[cfg(windows)]
 static mut MI_FLS_KEY: u32 = u32::MAX;
 
 // TODO stdcall or system?
-unsafe extern "system" fn mi_fls_done(value: *const c_void) {
+#[cfg(windows)]
+unsafe extern "system" fn mi_fls_done(value: *const std::os::raw::c_void) {
     let heap: *mut MiHeap = value.cast_mut().cast();
     if !heap.is_null() {
         _mi_thread_done(heap);
@@ -276,6 +406,22 @@ unsafe extern "system" fn mi_fls_done(value: *const c_void) {
     }
 }
 
+// use pthread local storage keys to detect thread ending
+// (the value is the default heap of the thread)
+#[cfg(unix)]
+static MI_HEAP_DEFAULT_KEY: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+#[cfg(unix)]
+unsafe extern "C" fn mi_pthread_done(value: *mut c_void) {
+    let heap: *mut MiHeap = value.cast();
+    if !heap.is_null() {
+        _mi_thread_done(heap);
+        // prevent recursion as `_mi_thread_done` resets the default heap (which would set the key again)
+        let key = MI_HEAP_DEFAULT_KEY.load(Ordering::Relaxed);
+        pthread_setspecific(key as pthread_key_t, ptr::null());
+    }
+}
+
 fn mi_process_setup_auto_thread_done() {
     static TLS_INITIALIZED: AtomicBool = AtomicBool::new(false);
     if TLS_INITIALIZED.load(Ordering::Acquire) {
@@ -284,22 +430,139 @@ fn mi_process_setup_auto_thread_done() {
     TLS_INITIALIZED.store(true, Ordering::Release);
 
     // TODO should check carefully
-    unsafe { MI_FLS_KEY = FlsAlloc(Some(mi_fls_done)) };
+    #[cfg(windows)]
+    unsafe {
+        MI_FLS_KEY = FlsAlloc(Some(mi_fls_done))
+    };
+    #[cfg(unix)]
+    unsafe {
+        let mut key: pthread_key_t = 0;
+        if pthread_key_create(&mut key, Some(mi_pthread_done)) == 0 {
+            MI_HEAP_DEFAULT_KEY.store(key as usize, Ordering::Relaxed);
+        }
+    }
 
     _mi_heap_set_default_direct(get_mi_heap_main());
 }
 
-fn _mi_heap_set_default_direct(heap: *mut MiHeap) {
+// The thread local default heap
+thread_local! {
+    static MI_HEAP_DEFAULT: Cell<*mut MiHeap> = const { Cell::new(ptr::addr_of_mut!(_mi_heap_empty)) };
+}
+
+// The current thread default heap (`_mi_heap_empty` until the thread is initialized)
+#[inline]
+pub fn _mi_heap_default() -> *mut MiHeap {
+    MI_HEAP_DEFAULT.with(|heap| heap.get())
+}
+
+pub fn _mi_heap_set_default_direct(heap: *mut MiHeap) {
+    debug_assert!(!heap.is_null());
+    MI_HEAP_DEFAULT.with(|default| default.set(heap));
+    #[cfg(windows)]
     unsafe {
         FlsSetValue(MI_FLS_KEY, Some(heap.cast()));
     }
+    #[cfg(unix)]
+    {
+        // the key is not yet created when the main heap is set during process initialization
+        let key = MI_HEAP_DEFAULT_KEY.load(Ordering::Relaxed);
+        if key != usize::MAX {
+            unsafe { pthread_setspecific(key as pthread_key_t, heap.cast()) };
+        }
+    }
 }
 
 pub fn _mi_current_thread_count() -> usize {
     THREAD_COUNT.load(Ordering::Relaxed)
 }
 
+#[cfg(test)]
 mod tests {
+    use std::{ffi::c_void, ptr, thread};
+
+    use crate::{
+        alloc::{mi_free, mi_malloc},
+        heap::mi_collect,
+        mimalloc_internal::{get_default_heap, mi_heap_is_initialized},
+    };
+
+    use super::{_mi_heap_empty, get_mi_heap_main};
+
+    #[test]
+    fn test_thread_default_heap_starts_empty() {
+        thread::spawn(|| {
+            assert!(ptr::eq(get_default_heap(), ptr::addr_of!(_mi_heap_empty)));
+            assert!(!mi_heap_is_initialized(get_default_heap()));
+
+            // the first allocation initializes the thread and its own heap
+            let p = mi_malloc(32);
+            assert!(!p.is_null());
+            let heap = get_default_heap();
+            assert!(mi_heap_is_initialized(heap));
+            assert!(!ptr::eq(heap, get_mi_heap_main()));
+            unsafe {
+                assert!((*(*heap).tld).heap_backing == heap);
+                assert!((*heap).cookie & 1 == 1);
+            }
+            mi_free(p);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_thread_exit_abandons_live_blocks() {
+        // threads exit while some of their blocks are still alive
+        let blocks: Vec<usize> = (0..16)
+            .map(|i| {
+                thread::spawn(move || {
+                    let keep = mi_malloc(1000 + i * 100);
+                    unsafe { ptr::write_bytes(keep.cast::<u8>(), i as u8, 1000) };
+                    for size in [16, 1000, 20_000, 200_000] {
+                        mi_free(mi_malloc(size));
+                    }
+                    keep as usize
+                })
+                .join()
+                .unwrap()
+            })
+            .collect();
+
+        // and the blocks can still be used and freed from another thread
+        thread::spawn(move || {
+            for (i, p) in blocks.into_iter().enumerate() {
+                let p = p as *mut c_void;
+                assert!(unsafe { std::slice::from_raw_parts(p.cast::<u8>(), 1000) }
+                    .iter()
+                    .all(|&b| b == i as u8));
+                mi_free(p);
+            }
+            mi_collect(true);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_thread_data_is_recycled() {
+        // short-lived threads reuse the metadata of threads that exited before them
+        let heaps: Vec<usize> = (0..16)
+            .map(|_| {
+                thread::spawn(|| {
+                    mi_free(mi_malloc(8));
+                    get_default_heap() as usize
+                })
+                .join()
+                .unwrap()
+            })
+            .collect();
+        let mut distinct = heaps.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert!(distinct.len() < heaps.len());
+    }
+
     // #[test]
     // fn test___mi_current_thread_count() {
     //     // TODO
@@ -1,13 +1,45 @@
+// The crate is a translation of the mimalloc C sources and item names follow the C code.
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
+#![allow(
+    non_snake_case,
+    non_camel_case_types,
+    non_upper_case_globals,
+    clippy::upper_case_acronyms,
+    clippy::enum_variant_names
+)]
+
 mod alloc;
+mod alloc_aligned;
 mod arena;
+mod bitmap;
+mod global_alloc;
 mod heap;
+#[cfg(feature = "allocator_api")]
+mod heap_alloc;
 mod init;
 mod mimalloc_internal;
 mod mimalloc_types;
 mod options;
 mod os;
+#[cfg(test)]
+mod os_mock;
 mod page;
+mod page_queue;
 mod random;
 mod segment;
 mod segment_cache;
+mod stats;
+#[cfg(test)]
 mod tests;
+
+pub use crate::global_alloc::MiMalloc;
+#[cfg(feature = "allocator_api")]
+pub use crate::heap_alloc::MiHeapHandle;
+pub use crate::mimalloc_types::MiOption;
+pub use crate::options::{mi_register_error, mi_register_output, MiErrorFun, MiOutputFun};
+#[cfg(unix)]
+pub use crate::stats::mi_stats_prometheus_serve_unix;
+pub use crate::stats::{
+    mi_stats_json, mi_stats_prometheus, mi_stats_prometheus_listen, mi_stats_prometheus_serve_tcp,
+    MiProcessInfo,
+};
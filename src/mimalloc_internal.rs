@@ -1,67 +1,77 @@
 use libc::uintptr_t;
 
 use crate::mimalloc_types::{
-    MiCommitMask, MiSegmentKind, MI_HUGE_BLOCK_SIZE, MI_INTPTR_BITS, MI_SEGMENT_MASK,
-    MI_SEGMENT_SIZE, MI_SEGMENT_SLICE_SIZE, MI_SIZE_BITS,
+    MiBlock, MiCommitMask, MiDelayed, MiEncoded, MiSegmentKind, MiSlice, MiThreadFree,
+    MI_ENCODE_FREELIST, MI_HUGE_BLOCK_SIZE, MI_INTPTR_BITS, MI_SEGMENT_MASK, MI_SEGMENT_SIZE,
+    MI_SEGMENT_SLICE_SHIFT, MI_SEGMENT_SLICE_SIZE, MI_SIZE_BITS,
 };
-use std::ffi::c_void;
+use std::{ffi::c_void, ptr, sync::atomic::Ordering};
 
 use crate::{
-    init::get_mi_heap_main,
+    init::{_mi_heap_default, _mi_heap_empty, get_mi_heap_main},
     mimalloc_types::{
         MiHeap, MiPage, MiSegment, MI_PADDING_SIZE, MI_PAGES_DIRECT, MI_SMALL_SIZE_MAX,
     },
+    options::_mi_error_message,
+    segment::_mi_segment_page_start,
 };
 
 #[inline]
-pub fn get_default_heap() -> Box<MiHeap> {
-    // TODO
-    Box::new(MiHeap::new())
+pub fn get_default_heap() -> *mut MiHeap {
+    _mi_heap_default()
 }
 
 type MiThreadid = usize;
 
+// A unique non-zero id for the current thread: the address of a thread local.
+// (`thread_local!` with a const initializer and no destructor never allocates)
 #[inline]
 pub fn _mi_thread_id() -> MiThreadid {
-    0
+    thread_local! {
+        static MI_THREAD_TAG: u8 = const { 0 };
+    }
+    MI_THREAD_TAG.with(|tag| tag as *const u8 as usize)
 }
 
 #[inline]
-pub fn _mi_heap_get_free_small_page<'a>(heap: &mut Box<MiHeap>, size: usize) -> Box<MiPage> {
+pub fn _mi_heap_get_free_small_page(heap: *mut MiHeap, size: usize) -> *mut MiPage {
     debug_assert!(size <= (MI_SMALL_SIZE_MAX + MI_PADDING_SIZE));
 
     let idx = _mi_wsize_from_size(size);
 
-    debug_assert!(idx <= MI_PAGES_DIRECT);
+    debug_assert!(idx < MI_PAGES_DIRECT);
 
-    let ptr: *mut MiPage = heap.pages_free_direct[idx];
+    unsafe { (*heap).pages_free_direct[idx] }
+}
 
-    unsafe { Box::from_raw(ptr) }
+// Yield the processor while spinning on an atomic
+#[inline]
+pub fn mi_atomic_yield() {
+    std::hint::spin_loop();
 }
 
 // Align a byte size to a size in _machine words_,
 // i.e. byte size == `wsize*sizeof(void*)`.
 #[inline]
-fn _mi_wsize_from_size(size: usize) -> usize {
-    debug_assert!(size <= usize::MAX - std::mem::size_of::<c_void>());
-    (size + std::mem::size_of::<c_void>() - 1) / std::mem::size_of::<c_void>()
+pub fn _mi_wsize_from_size(size: usize) -> usize {
+    debug_assert!(size <= usize::MAX - std::mem::size_of::<usize>());
+    size.div_ceil(std::mem::size_of::<usize>())
 }
 
 #[inline]
-fn mi_heap_is_default(heap: *const MiHeap) -> bool {
-    return heap == get_default_heap().as_ref();
+pub fn mi_heap_is_default(heap: *const MiHeap) -> bool {
+    heap == get_default_heap()
 }
 
 #[inline]
-fn mi_heap_is_backing(heap: *mut MiHeap) -> bool {
-    unsafe { (*(*heap).tld).heap_backing == heap }
+pub fn mi_heap_is_backing(heap: *const MiHeap) -> bool {
+    unsafe { ptr::eq((*(*heap).tld).heap_backing, heap) }
 }
 
 #[inline]
 pub fn mi_heap_is_initialized(heap: *const MiHeap) -> bool {
-    // debug_assert!(heap.is_null());
-    // Currently, Heap are initialized by default
-    false
+    debug_assert!(!heap.is_null());
+    !ptr::eq(heap, ptr::addr_of!(_mi_heap_empty))
 }
 
 #[inline]
@@ -76,20 +86,67 @@ pub fn mi_page_is_huge(page: *const MiPage) -> bool {
 }
 
 // Segment belonging to a page
-fn _mi_page_segment(page: *const MiPage) -> *mut MiSegment {
+pub fn _mi_page_segment(page: *const MiPage) -> *mut MiSegment {
     let segment = _mi_ptr_segment(page.cast());
-    // TODO check segment is in range
-    debug_assert!(segment.is_null());
+    debug_assert!(
+        segment.is_null()
+            || (page as usize >= unsafe { ptr::addr_of!((*segment).slices) } as usize
+                && (page as usize)
+                    < unsafe {
+                        ptr::addr_of!((*segment).slices)
+                            .cast::<MiSlice>()
+                            .add((*segment).slice_entries as usize)
+                    } as usize)
+    );
     segment
 }
 
+#[inline]
+pub fn mi_slice_first(slice: *const MiSlice) -> *mut MiSlice {
+    let start = unsafe { (slice as *const u8).sub((*slice).slice_offset as usize) } as *mut MiSlice;
+    debug_assert!(unsafe { (*start).slice_offset } == 0);
+    debug_assert!(unsafe { start.add((*start).slice_count as usize) } > slice as *mut MiSlice);
+    start
+}
+
+// Get the page containing the pointer
+#[inline]
+pub fn _mi_segment_page_of(segment: *const MiSegment, p: *const c_void) -> *mut MiPage {
+    let diff = p as isize - segment as isize;
+    debug_assert!(diff >= 0 && diff <= MI_SEGMENT_SIZE as isize); // can be equal for large alignment
+    let idx = diff as usize >> MI_SEGMENT_SLICE_SHIFT;
+    debug_assert!(idx <= unsafe { (*segment).slice_entries } as usize);
+    let slice0 = unsafe { ptr::addr_of!((*segment).slices).cast::<MiSlice>().add(idx) };
+    let slice = mi_slice_first(slice0); // adjust to the block that holds the page data
+    debug_assert!(unsafe { (*slice).slice_offset } == 0);
+    mi_slice_to_page(slice)
+}
+
+#[inline]
+pub fn mi_slice_to_page(slice: *mut MiSlice) -> *mut MiPage {
+    debug_assert!(unsafe { (*slice).slice_offset == 0 && (*slice).slice_count > 0 });
+    slice
+}
+
+#[inline]
+pub fn mi_page_to_slice(page: *mut MiPage) -> *mut MiSlice {
+    debug_assert!(unsafe { (*page).slice_offset == 0 && (*page).slice_count > 0 });
+    page
+}
+
 // Segment that contains the pointer
 // Large aligned blocks may be aligned at N*MI_SEGMENT_SIZE (inside a huge segment > MI_SEGMENT_SIZE),
 // and we need align "down" to the segment info which is `MI_SEGMENT_SIZE` bytes before it;
 // therefore we align one byte before `p`.
 pub fn _mi_ptr_segment(p: *const c_void) -> *mut MiSegment {
-    debug_assert!(p.is_null());
-    ((p as usize - 1) & MI_SEGMENT_MASK.reverse_bits()) as *mut MiSegment
+    debug_assert!(!p.is_null());
+    ((p as usize).wrapping_sub(1) & !MI_SEGMENT_MASK) as *mut MiSegment
+}
+
+// Get the page containing the pointer
+#[inline]
+pub fn _mi_ptr_page(p: *mut c_void) -> *mut MiPage {
+    _mi_segment_page_of(_mi_ptr_segment(p), p)
 }
 
 // Get the usable block size of a page without fixed padding.
@@ -98,18 +155,26 @@ pub fn mi_page_usable_block_size(page: *const MiPage) -> usize {
     mi_page_block_size(page) - MI_PADDING_SIZE
 }
 
+// Quick page start for initialized pages
+#[inline]
+pub fn _mi_page_start(
+    segment: *const MiSegment,
+    page: *const MiPage,
+    page_size: *mut usize,
+) -> *mut u8 {
+    _mi_segment_page_start(segment, page, page_size)
+}
+
 // Get the block size of a page (special case for huge objects)
-fn mi_page_block_size(page: *const MiPage) -> usize {
+pub fn mi_page_block_size(page: *const MiPage) -> usize {
     let bsize = unsafe { (*page).xblock_size };
     debug_assert!(bsize > 0);
     if (bsize as usize) < MI_HUGE_BLOCK_SIZE {
         bsize as usize
     } else {
-        // TODO
-        todo!("unimplement for huge page");
-        // let psize;
-        // _mi_segment_page_start(_mi_page_segment(page), page, &psize);
-        // return psize;
+        let mut psize: usize = 0;
+        _mi_segment_page_start(_mi_page_segment(page), page, &mut psize);
+        psize
     }
 }
 
@@ -125,7 +190,6 @@ fn mi_page_block_size(page: *const MiPage) -> usize {
 // is still tracked in fine-grained MI_COMMIT_SIZE chunks)
 // ------------------------------------------------------
 
-const MI_MINIMAL_COMMIT_SIZE: usize = 16 * MI_SEGMENT_SLICE_SIZE; // 1MiB
 const MI_COMMIT_SIZE: usize = MI_SEGMENT_SLICE_SIZE; // 64KiB
 const MI_COMMIT_MASK_BITS: usize = MI_SEGMENT_SIZE / MI_COMMIT_SIZE;
 const MI_COMMIT_MASK_FIELD_BITS: usize = MI_SIZE_BITS;
@@ -159,7 +223,7 @@ pub fn mi_commit_mask_is_empty(cm: *const MiCommitMask) -> bool {
             }
         }
     }
-    return true;
+    true
 }
 
 pub fn mi_commit_mask_is_full(cm: *const MiCommitMask) -> bool {
@@ -170,7 +234,43 @@ pub fn mi_commit_mask_is_full(cm: *const MiCommitMask) -> bool {
             }
         }
     }
-    return true;
+    true
+}
+
+// Is `x` a power of two? (0 is considered a power of two)
+#[inline]
+pub fn _mi_is_power_of_two(x: usize) -> bool {
+    (x & x.wrapping_sub(1)) == 0
+}
+
+// Overflow detecting multiply
+#[inline]
+pub fn mi_mul_overflow(count: usize, size: usize, total: *mut usize) -> bool {
+    let (r, overflow) = count.overflowing_mul(size);
+    unsafe { *total = r };
+    overflow
+}
+
+// Safe multiply `count*size` into `total`; return `true` on overflow.
+#[inline]
+pub fn mi_count_size_overflow(count: usize, size: usize, total: *mut usize) -> bool {
+    if count == 1 {
+        // quick check for the case where count is one (common for C++ allocators)
+        unsafe { *total = size };
+        false
+    } else if mi_mul_overflow(count, size, total) {
+        _mi_error_message(
+            libc::EOVERFLOW,
+            format_args!(
+                "allocation request is too large ({} * {} bytes)\n",
+                count, size
+            ),
+        );
+        unsafe { *total = usize::MAX };
+        true
+    } else {
+        false
+    }
 }
 
 // Align downwards
@@ -179,9 +279,9 @@ pub fn _mi_align_down(sz: usize, alignment: usize) -> usize {
     let mask = alignment - 1;
     if (alignment & mask) == 0 {
         // power of two?
-        return sz & !mask;
+        sz & !mask
     } else {
-        return (sz / alignment) * alignment;
+        (sz / alignment) * alignment
     }
 }
 
@@ -191,7 +291,7 @@ pub fn _mi_divide_up(size: usize, divider: usize) -> usize {
     if divider == 0 {
         size
     } else {
-        (size + divider - 1) / divider
+        size.div_ceil(divider)
     }
 }
 
@@ -205,39 +305,23 @@ pub fn _mi_divide_up(size: usize, divider: usize) -> usize {
 
 // "bit scan reverse": Return index of the highest bit (or MI_INTPTR_BITS if `x` is zero)
 pub fn mi_bsr(x: uintptr_t) -> usize {
-    return if x == 0 {
+    if x == 0 {
         MI_INTPTR_BITS
     } else {
         MI_INTPTR_BITS - 1 - mi_clz(x)
-    };
+    }
 }
 
 // #include <limits.h>       // LONG_MAX
 // #define MI_HAVE_FAST_BITSCAN
-fn mi_clz(x: uintptr_t) -> usize {
-    //   if x==0 {return MI_INTPTR_BITS;}
-    //   let idx;
-    // #[cfg(target_pointer_width = "64")]
-    // _BitScanReverse64(&idx, x);
-    // #[cfg(target_pointer_width = "32")]
-    // _BitScanReverse(&idx, x);
-    //   return ((MI_INTPTR_BITS - 1) - idx);
-
-    // TODO: it need compiler intrinsics support, cuz https://doc.rust-lang.org/std/intrinsics/fn.ctlz.html is nightly only api
-
-    0
+// `leading_zeros`/`trailing_zeros` compile to the bit scan instructions (and return
+// MI_INTPTR_BITS for zero)
+pub fn mi_clz(x: uintptr_t) -> usize {
+    x.leading_zeros() as usize
 }
-fn mi_ctz(x: uintptr_t) -> usize {
-    //   if (x==0) return MI_INTPTR_BITS;
-    //   unsigned long idx;
-    // #if (INTPTR_MAX == LONG_MAX)
-    //   _BitScanForward(&idx, x);
-    // #else
-    //   _BitScanForward64(&idx, x);
-    // #endif
-    //   return idx;
 
-    0
+pub fn mi_ctz(x: uintptr_t) -> usize {
+    x.trailing_zeros() as usize
 }
 
 // size of a segment
@@ -247,90 +331,201 @@ pub fn mi_segment_size(segment: *const MiSegment) -> usize {
     // return *(unsafe { *segment }).segment_slices * MI_SEGMENT_SLICE_SIZE;
 }
 
-//   static inline uint8_t* mi_segment_end(mi_segment_t* segment) {
-//     return (uint8_t*)segment + mi_segment_size(segment);
-//   }
+// Thread free access
+#[inline]
+pub fn mi_page_thread_free(page: *const MiPage) -> *mut MiBlock {
+    (unsafe { (*page).xthread_free.load(Ordering::Relaxed) } & !3) as *mut MiBlock
+}
 
-//   // Thread free access
-//   static inline mi_block_t* mi_page_thread_free(const mi_page_t* page) {
-//     return (mi_block_t*)(mi_atomic_load_relaxed(&((mi_page_t*)page)->xthread_free) & ~3);
-//   }
+#[inline]
+pub fn mi_page_thread_free_flag(page: *const MiPage) -> MiDelayed {
+    MiDelayed::from_bits(unsafe { (*page).xthread_free.load(Ordering::Relaxed) })
+}
 
-//   static inline mi_delayed_t mi_page_thread_free_flag(const mi_page_t* page) {
-//     return (mi_delayed_t)(mi_atomic_load_relaxed(&((mi_page_t*)page)->xthread_free) & 3);
-//   }
+// Heap access
+#[inline]
+pub fn mi_page_heap(page: *const MiPage) -> *mut MiHeap {
+    unsafe { (*page).xheap.load(Ordering::Relaxed) as *mut MiHeap }
+}
 
-//   // Heap access
-//   static inline mi_heap_t* mi_page_heap(const mi_page_t* page) {
-//     return (mi_heap_t*)(mi_atomic_load_relaxed(&((mi_page_t*)page)->xheap));
-//   }
+#[inline]
+pub fn mi_page_set_heap(page: *mut MiPage, heap: *mut MiHeap) {
+    debug_assert!(mi_page_thread_free_flag(page) != MiDelayed::MiDelayedFreeing);
+    unsafe { (*page).xheap.store(heap as usize, Ordering::Release) };
+}
 
-//   static inline void mi_page_set_heap(mi_page_t* page, mi_heap_t* heap) {
-//     mi_assert_internal(mi_page_thread_free_flag(page) != MI_DELAYED_FREEING);
-//     mi_atomic_store_release(&page->xheap,(uintptr_t)heap);
-//   }
+// Thread free flag helpers
+#[inline]
+pub fn mi_tf_block(tf: MiThreadFree) -> *mut MiBlock {
+    (tf & !0x03) as *mut MiBlock
+}
 
-//   // Thread free flag helpers
-//   static inline mi_block_t* mi_tf_block(mi_thread_free_t tf) {
-//     return (mi_block_t*)(tf & ~0x03);
-//   }
-//   static inline mi_delayed_t mi_tf_delayed(mi_thread_free_t tf) {
-//     return (mi_delayed_t)(tf & 0x03);
-//   }
-//   static inline mi_thread_free_t mi_tf_make(mi_block_t* block, mi_delayed_t delayed) {
-//     return (mi_thread_free_t)((uintptr_t)block | (uintptr_t)delayed);
-//   }
-//   static inline mi_thread_free_t mi_tf_set_delayed(mi_thread_free_t tf, mi_delayed_t delayed) {
-//     return mi_tf_make(mi_tf_block(tf),delayed);
-//   }
-//   static inline mi_thread_free_t mi_tf_set_block(mi_thread_free_t tf, mi_block_t* block) {
-//     return mi_tf_make(block, mi_tf_delayed(tf));
-//   }
+#[inline]
+pub fn mi_tf_delayed(tf: MiThreadFree) -> MiDelayed {
+    MiDelayed::from_bits(tf)
+}
 
-//   // are all blocks in a page freed?
-//   // note: needs up-to-date used count, (as the `xthread_free` list may not be empty). see `_mi_page_collect_free`.
-//   static inline bool mi_page_all_free(const mi_page_t* page) {
-//     mi_assert_internal(page != NULL);
-//     return (page->used == 0);
-//   }
+#[inline]
+pub fn mi_tf_make(block: *mut MiBlock, delayed: MiDelayed) -> MiThreadFree {
+    (block as usize) | (delayed as usize)
+}
 
-//   // are there any available blocks?
-//   static inline bool mi_page_has_any_available(const mi_page_t* page) {
-//     mi_assert_internal(page != NULL && page->reserved > 0);
-//     return (page->used < page->reserved || (mi_page_thread_free(page) != NULL));
-//   }
+#[inline]
+pub fn mi_tf_set_delayed(tf: MiThreadFree, delayed: MiDelayed) -> MiThreadFree {
+    mi_tf_make(mi_tf_block(tf), delayed)
+}
 
-//   // are there immediately available blocks, i.e. blocks available on the free list.
-//   static inline bool mi_page_immediate_available(const mi_page_t* page) {
-//     mi_assert_internal(page != NULL);
-//     return (page->free != NULL);
-//   }
+#[inline]
+pub fn mi_tf_set_block(tf: MiThreadFree, block: *mut MiBlock) -> MiThreadFree {
+    mi_tf_make(block, mi_tf_delayed(tf))
+}
 
-//   // is more than 7/8th of a page in use?
-//   static inline bool mi_page_mostly_used(const mi_page_t* page) {
-//     if (page==NULL) return true;
-//     uint16_t frac = page->reserved / 8U;
-//     return (page->reserved - page->used <= frac);
-//   }
+// are all blocks in a page freed?
+// note: needs up-to-date used count, (as the `xthread_free` list may not be empty). see `_mi_page_collect_free`.
+#[inline]
+pub fn mi_page_all_free(page: *const MiPage) -> bool {
+    debug_assert!(!page.is_null());
+    unsafe { (*page).used == 0 }
+}
 
-//   static inline mi_page_queue_t* mi_page_queue(const mi_heap_t* heap, size_t size) {
-//     return &((mi_heap_t*)heap)->pages[_mi_bin(size)];
-//   }
+// are there any available blocks?
+#[inline]
+pub fn mi_page_has_any_available(page: *const MiPage) -> bool {
+    debug_assert!(!page.is_null() && unsafe { (*page).reserved } > 0);
+    unsafe { (*page).used < (*page).reserved as u32 || !mi_page_thread_free(page).is_null() }
+}
 
-//   //-----------------------------------------------------------
-//   // Page flags
-//   //-----------------------------------------------------------
-//   static inline bool mi_page_is_in_full(const mi_page_t* page) {
-//     return page->flags.x.in_full;
-//   }
+// are there immediately available blocks, i.e. blocks available on the free list.
+#[inline]
+pub fn mi_page_immediate_available(page: *const MiPage) -> bool {
+    debug_assert!(!page.is_null());
+    unsafe { !(*page).free.is_null() }
+}
 
-//   static inline void mi_page_set_in_full(mi_page_t* page, bool in_full) {
-//     page->flags.x.in_full = in_full;
-//   }
+//-----------------------------------------------------------
+// Page flags
+//-----------------------------------------------------------
+#[inline]
+pub fn mi_page_is_in_full(page: *const MiPage) -> bool {
+    unsafe { (*page).flags.x.in_full() != 0 }
+}
 
-//   static inline bool mi_page_has_aligned(const mi_page_t* page) {
-//     return page->flags.x.has_aligned;
-//   }
+#[inline]
+pub fn mi_page_set_in_full(page: *mut MiPage, in_full: bool) {
+    unsafe { (*page).flags.x.set_in_full(in_full as u8) };
+}
+
+#[inline]
+pub fn mi_page_has_aligned(page: *const MiPage) -> bool {
+    unsafe { (*page).flags.x.has_aligned() != 0 }
+}
+
+#[inline]
+pub fn mi_page_set_has_aligned(page: *mut MiPage, has_aligned: bool) {
+    unsafe { (*page).flags.x.set_has_aligned(has_aligned as u8) };
+}
+
+// -------------------------------------------------------------------
+// Encoding/Decoding the free list next pointers
+// -------------------------------------------------------------------
+
+#[inline]
+pub fn mi_is_in_same_page(p: *const c_void, q: *const c_void) -> bool {
+    let segment = _mi_ptr_segment(p);
+    if _mi_ptr_segment(q) != segment {
+        return false;
+    }
+    // assume q may be invalid // return (_mi_segment_page_of(segment, p) == _mi_segment_page_of(segment, q));
+    let page = _mi_segment_page_of(segment, p);
+    let mut psize: usize = 0;
+    let start = _mi_segment_page_start(segment, page, &mut psize);
+    start as usize <= q as usize && (q as usize) < start as usize + psize
+}
 
-//   static inline void mi_page_set_has_aligned(mi_page_t* page, bool has_aligned) {
-//     page->flags.x.has_aligned = has_aligned;
+#[inline]
+pub fn mi_rotl(x: usize, shift: usize) -> usize {
+    x.rotate_left((shift % MI_INTPTR_BITS) as u32)
+}
+
+#[inline]
+pub fn mi_rotr(x: usize, shift: usize) -> usize {
+    x.rotate_right((shift % MI_INTPTR_BITS) as u32)
+}
+
+#[inline]
+pub fn mi_ptr_decode(null: *const c_void, x: MiEncoded, keys: *const usize) -> *mut c_void {
+    let p = unsafe { mi_rotr(x.wrapping_sub(*keys), *keys) ^ *keys.add(1) } as *mut c_void;
+    if ptr::eq(p, null) {
+        ptr::null_mut()
+    } else {
+        p
+    }
+}
+
+#[inline]
+pub fn mi_ptr_encode(null: *const c_void, p: *const c_void, keys: *const usize) -> MiEncoded {
+    let x = if p.is_null() { null } else { p } as usize;
+    unsafe { mi_rotl(x ^ *keys.add(1), *keys).wrapping_add(*keys) }
+}
+
+#[inline]
+pub fn mi_block_nextx(
+    null: *const c_void,
+    block: *const MiBlock,
+    keys: *const usize,
+) -> *mut MiBlock {
+    if MI_ENCODE_FREELIST {
+        mi_ptr_decode(null, unsafe { (*block).next }, keys).cast()
+    } else {
+        unsafe { (*block).next as *mut MiBlock }
+    }
+}
+
+#[inline]
+pub fn mi_block_set_nextx(
+    null: *const c_void,
+    block: *mut MiBlock,
+    next: *const MiBlock,
+    keys: *const usize,
+) {
+    unsafe {
+        (*block).next = if MI_ENCODE_FREELIST {
+            mi_ptr_encode(null, next.cast(), keys)
+        } else {
+            next as MiEncoded
+        };
+    }
+}
+
+#[inline]
+pub fn mi_block_next(page: *const MiPage, block: *const MiBlock) -> *mut MiBlock {
+    if MI_ENCODE_FREELIST {
+        let mut next = mi_block_nextx(page.cast(), block, unsafe { (*page).keys.as_ptr() });
+        // check for free list corruption: is `next` at least in the same page?
+        // TODO: check if `next` is `page->block_size` aligned?
+        if !next.is_null() && !mi_is_in_same_page(block.cast(), next.cast()) {
+            _mi_error_message(
+                libc::EFAULT,
+                format_args!(
+                    "corrupted free list entry of size {}b at {:p}: value 0x{:x}\n",
+                    mi_page_block_size(page),
+                    block,
+                    next as usize
+                ),
+            );
+            next = ptr::null_mut();
+        }
+        next
+    } else {
+        mi_block_nextx(page.cast(), block, ptr::null())
+    }
+}
+
+#[inline]
+pub fn mi_block_set_next(page: *const MiPage, block: *mut MiBlock, next: *const MiBlock) {
+    if MI_ENCODE_FREELIST {
+        mi_block_set_nextx(page.cast(), block, next, unsafe { (*page).keys.as_ptr() });
+    } else {
+        mi_block_set_nextx(page.cast(), block, next, ptr::null());
+    }
+}
@@ -1,7 +1,12 @@
-use std::{collections::LinkedList, ffi::c_void, ptr, sync::atomic::AtomicPtr};
+use crate::init::_mi_page_empty;
+use crate::stats::_mi_stats_main;
+use std::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize},
+};
 
 pub const MI_SMALL_WSIZE_MAX: usize = 128;
-pub const MI_SMALL_SIZE_MAX: usize = MI_SMALL_WSIZE_MAX * std::mem::size_of::<c_void>();
+pub const MI_SMALL_SIZE_MAX: usize = MI_SMALL_WSIZE_MAX * std::mem::size_of::<usize>();
 
 // for now, only support 32 or 64 bits
 #[cfg(target_pointer_width = "64")]
@@ -12,23 +17,32 @@ pub const MI_INTPTR_SHIFT: usize = 2;
 pub const MI_INTPTR_SIZE: usize = 1 << MI_INTPTR_SHIFT;
 pub const MI_INTPTR_BITS: usize = MI_INTPTR_SIZE * 8;
 
+// minimal alignment guaranteed by the allocator (`alignof(max_align_t)`)
+pub const MI_MAX_ALIGN_SIZE: usize = 16;
+
 #[cfg(debug_assertions)]
 pub const MI_PADDING: usize = 1;
 #[cfg(not(debug_assertions))]
 pub const MI_PADDING: usize = 0;
 
+// Define MI_STAT as 1 to maintain statistics; set it to 2 to have detailed statistics (but costs some performance).
+// (detailed statistics are always kept in debug builds, and in release builds with the `stats` feature)
+#[cfg(any(debug_assertions, feature = "stats"))]
+pub const MI_STAT: i32 = 2;
+#[cfg(not(any(debug_assertions, feature = "stats")))]
+pub const MI_STAT: i32 = 0;
+
 pub const MI_PADDING_SIZE: usize = std::mem::size_of::<MiPadding>();
-pub const MI_PADDING_WSIZE: usize = (MI_PADDING_SIZE + MI_INTPTR_SIZE - 1) / MI_INTPTR_SIZE;
+pub const MI_PADDING_WSIZE: usize = MI_PADDING_SIZE.div_ceil(MI_INTPTR_SIZE);
 pub const MI_PAGES_DIRECT: usize = MI_SMALL_WSIZE_MAX + MI_PADDING_WSIZE + 1;
 pub const MI_BIN_HUGE: usize = 73;
 pub const MI_BIN_FULL: usize = MI_BIN_HUGE + 1;
 
 type MiMsecs = i64;
 pub type SizeT = ::std::os::raw::c_ulonglong;
-pub type MiThreadId = SizeT;
 pub type MiSlice = MiPage;
 
-const MI_SEGMENT_SLICE_SHIFT: usize = 13 + MI_INTPTR_SHIFT; // 64KiB  (32KiB on 32-bit)
+pub const MI_SEGMENT_SLICE_SHIFT: usize = 13 + MI_INTPTR_SHIFT; // 64KiB  (32KiB on 32-bit)
 pub const MI_SEGMENT_SLICE_SIZE: usize = 1 << MI_SEGMENT_SLICE_SHIFT;
 
 #[cfg(target_pointer_width = "32")]
@@ -37,7 +51,7 @@ const MI_SEGMENT_SHIFT: usize = 7 + MI_SEGMENT_SLICE_SHIFT;
 const MI_SEGMENT_SHIFT: usize = 9 + MI_SEGMENT_SLICE_SHIFT;
 
 pub const MI_SEGMENT_SIZE: usize = 1 << MI_SEGMENT_SHIFT;
-const MI_SLICES_PER_SEGMENT: usize = MI_SEGMENT_SIZE / MI_SEGMENT_SLICE_SIZE; // 1024
+pub const MI_SLICES_PER_SEGMENT: usize = MI_SEGMENT_SIZE / MI_SEGMENT_SLICE_SIZE; // 1024
 
 pub const MI_SMALL_PAGE_SHIFT: usize = MI_SEGMENT_SLICE_SHIFT; // 64KiB
 pub const MI_MEDIUM_PAGE_SHIFT: usize = 3 + MI_SMALL_PAGE_SHIFT; // 512KiB
@@ -49,7 +63,6 @@ pub const MI_SMALL_OBJ_SIZE_MAX: usize = MI_SMALL_PAGE_SIZE / 4; // 8KiB on 64-b
 pub const MI_MEDIUM_OBJ_SIZE_MAX: usize = MI_MEDIUM_PAGE_SIZE / 4; // 128KiB on 64-bit
 pub const MI_MEDIUM_OBJ_WSIZE_MAX: usize = MI_MEDIUM_OBJ_SIZE_MAX / MI_INTPTR_SIZE;
 pub const MI_LARGE_OBJ_SIZE_MAX: usize = MI_SEGMENT_SIZE / 2; // 32MiB on 64-bit
-pub const MI_LARGE_OBJ_WSIZE_MAX: usize = MI_LARGE_OBJ_SIZE_MAX / MI_INTPTR_SIZE;
 
 pub type MiArenaIdT = ::std::os::raw::c_int;
 
@@ -57,6 +70,7 @@ pub const MI_SEGMENT_ALIGN: usize = MI_SEGMENT_SIZE;
 pub const MI_SEGMENT_MASK: usize = MI_SEGMENT_ALIGN - 1;
 // may change in other debug mode
 pub const MI_DEBUG_UNINIT: u8 = 0xD0;
+pub const MI_DEBUG_FREED: u8 = 0xDF;
 
 #[cfg(target_pointer_width = "32")]
 pub const MI_SIZE_SHIFT: usize = 2;
@@ -70,11 +84,39 @@ pub const MI_KiB: u32 = 1024;
 pub const MI_MiB: u32 = MI_KiB * MI_KiB;
 pub const MI_GiB: u32 = MI_MiB * MI_KiB;
 
+// Define MI_SECURE to enable security mitigations (through the `secure1`..`secure4` features)
+// MI_SECURE 1  // guard page around metadata
+// MI_SECURE 2  // encode free lists (detect corrupted free list (buffer overflow), and invalid pointer free)
+// MI_SECURE 3  // randomize the allocation order within a page
+// MI_SECURE 4  // checks for double free and guard pages around every page. (may be more expensive)
+#[cfg(feature = "secure4")]
+pub const MI_SECURE: u8 = 4;
+#[cfg(all(feature = "secure3", not(feature = "secure4")))]
+pub const MI_SECURE: u8 = 3;
+#[cfg(all(feature = "secure2", not(feature = "secure3")))]
+pub const MI_SECURE: u8 = 2;
+#[cfg(all(feature = "secure1", not(feature = "secure2")))]
+pub const MI_SECURE: u8 = 1;
+#[cfg(not(feature = "secure1"))]
 pub const MI_SECURE: u8 = 0;
 
+// Encoded free lists allow detection of corrupted free lists
+// and can detect buffer overflows, modify after free, and double `free`s.
+#[allow(clippy::absurd_extreme_comparisons)] // `MI_SECURE` is a build configuration constant
+pub const MI_ENCODE_FREELIST: bool = MI_SECURE >= 2 || cfg!(debug_assertions);
+
 // Used as a special value to encode block sizes in 32 bits.
 pub const MI_HUGE_BLOCK_SIZE: usize = 2 * MI_GiB as usize;
 
+// blocks up to this size are always allocated aligned
+pub const MI_MAX_ALIGN_GUARANTEE: usize = 8 * MI_MAX_ALIGN_SIZE;
+
+// Alignments over MI_ALIGNMENT_MAX are allocated in dedicated huge page segments
+pub const MI_ALIGNMENT_MAX: usize = MI_SEGMENT_SIZE >> 1;
+
+// Maximum slice offset (255)
+pub const MI_MAX_SLICE_OFFSET: usize = (MI_ALIGNMENT_MAX / MI_SEGMENT_SLICE_SIZE) - 1;
+
 // ------------------------------------------------------
 // A segment holds a commit mask where a bit is set if
 // the corresponding MI_COMMIT_SIZE area is committed.
@@ -131,8 +173,8 @@ impl Default for MiHeap {
     fn default() -> Self {
         Self {
             tld: ptr::null_mut(),
-            pages_free_direct: [ptr::null_mut(); MI_PAGES_DIRECT],
-            pages: [Default::default(); MI_BIN_FULL + 1],
+            pages_free_direct: [ptr::addr_of_mut!(_mi_page_empty); MI_PAGES_DIRECT],
+            pages: MI_PAGE_QUEUES_EMPTY,
             thread_delayed_free: Default::default(),
             thread_id: Default::default(),
             arena_id: Default::default(),
@@ -149,19 +191,15 @@ impl Default for MiHeap {
 }
 
 impl MiHeap {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
-            pages_free_direct: [ptr::null_mut(); MI_PAGES_DIRECT],
+            pages_free_direct: [ptr::addr_of_mut!(_mi_page_empty); MI_PAGES_DIRECT],
             page_count: 0,
             page_retired_min: 0,
             page_retired_max: 0,
             no_reclaim: false,
             thread_id: 0,
-            pages: [MiPageQueue {
-                first: ptr::null_mut(),
-                last: ptr::null_mut(),
-                block_size: 0,
-            }; MI_BIN_FULL + 1],
+            pages: MI_PAGE_QUEUES_EMPTY,
             next: ptr::null_mut(),
             thread_delayed_free: AtomicPtr::new(ptr::null_mut()),
             arena_id: 0,
@@ -196,6 +234,99 @@ impl Default for MiPageQueue {
     }
 }
 
+// block sizes (in words) of the page queues; must match the bins in `page_queue.rs:mi_bin`
+const MI_PAGE_QUEUE_WSIZES: [usize; MI_BIN_FULL + 1] = [
+    1,
+    1,
+    2,
+    3,
+    4,
+    5,
+    6,
+    7,
+    8, // 8
+    10,
+    12,
+    14,
+    16,
+    20,
+    24,
+    28,
+    32, // 16
+    40,
+    48,
+    56,
+    64,
+    80,
+    96,
+    112,
+    128, // 24
+    160,
+    192,
+    224,
+    256,
+    320,
+    384,
+    448,
+    512, // 32
+    640,
+    768,
+    896,
+    1024,
+    1280,
+    1536,
+    1792,
+    2048, // 40
+    2560,
+    3072,
+    3584,
+    4096,
+    5120,
+    6144,
+    7168,
+    8192, // 48
+    10240,
+    12288,
+    14336,
+    16384,
+    20480,
+    24576,
+    28672,
+    32768, // 56
+    40960,
+    49152,
+    57344,
+    65536,
+    81920,
+    98304,
+    114688,
+    131072, // 64
+    163840,
+    196608,
+    229376,
+    262144,
+    327680,
+    393216,
+    458752,
+    524288,                      // 72
+    MI_MEDIUM_OBJ_WSIZE_MAX + 1, // Huge queue
+    MI_MEDIUM_OBJ_WSIZE_MAX + 2, // Full queue
+];
+
+pub const MI_PAGE_QUEUES_EMPTY: [MiPageQueue; MI_BIN_FULL + 1] = {
+    let mut queues = [MiPageQueue {
+        first: ptr::null_mut(),
+        last: ptr::null_mut(),
+        block_size: 0,
+    }; MI_BIN_FULL + 1];
+    let mut i = 0;
+    while i < queues.len() {
+        queues[i].block_size = MI_PAGE_QUEUE_WSIZES[i] * std::mem::size_of::<usize>();
+        i += 1;
+    }
+    queues
+};
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub union MiPageFlags {
@@ -233,16 +364,34 @@ impl PageFlag {
     pub fn set_has_aligned(&mut self, val: u8) {
         self.bitfield_1.set(1usize, 1u8, val as u64)
     }
+}
+
+// Thread free list.
+// We use the bottom 2 bits of the pointer for mi_delayed_t flags
+pub type MiThreadFree = usize;
+
+// The delayed flags are used for efficient multi-threaded free-ing
+#[repr(usize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MiDelayed {
+    MiUseDelayedFree = 0,   // push on the owning heap thread delayed list
+    MiDelayedFreeing = 1,   // temporary: another thread is accessing the owning heap
+    MiNoDelayedFree = 2, // optimize: push on page local thread free queue if another block is already in the heap thread delayed free list
+    MiNeverDelayedFree = 3, // sticky, only resets on page reclaim
+}
+
+impl MiDelayed {
     #[inline]
-    pub fn new_bitfield_1(in_full: u8, has_aligned: u8) -> BitfieldUnit<[u8; 1usize], u8> {
-        let mut bitfield_unit: BitfieldUnit<[u8; 1usize], u8> = Default::default();
-        bitfield_unit.set(0usize, 1u8, in_full as u64);
-        bitfield_unit.set(1usize, 1u8, has_aligned as u64);
-        bitfield_unit
+    pub fn from_bits(bits: usize) -> Self {
+        match bits & 0x03 {
+            0 => MiDelayed::MiUseDelayedFree,
+            1 => MiDelayed::MiDelayedFreeing,
+            2 => MiDelayed::MiNoDelayedFree,
+            _ => MiDelayed::MiNeverDelayedFree,
+        }
     }
 }
 
-pub type MiThreadFree = usize;
 #[repr(C)]
 pub struct MiPage {
     // "owned" by the segment
@@ -256,16 +405,14 @@ pub struct MiPage {
     pub flags: MiPageFlags, // `in_full` and `has_aligned` flags (8 bits)
     pub bitfield_2: BitfieldUnit<[u8; 1], u8>,
 
-    pub free: LinkedList<MiBlock>, // list of available free blocks (`malloc` allocates from this list)
+    pub free: *mut MiBlock, // list of available free blocks (`malloc` allocates from this list)
     pub used: u32, // number of blocks in use (including blocks in `local_free` and `thread_free`)
     pub xblock_size: u32, // size available in each block (always `>0`)
     pub local_free: *mut MiBlock, // list of deferred free blocks by this thread (migrates to `free`)
 
-    // #ifdef MI_ENCODE_FREELIST
-    // uintptr_t             keys[2];           // two random keys to encode the free lists (see `_mi_block_next`)
-    // #endif
-    pub xthread_free: AtomicPtr<MiThreadFree>, // list of deferred free blocks freed by other threads
-    pub xheap: AtomicPtr<usize>,
+    pub keys: [usize; 2], // two random keys to encode the free lists (see `mi_block_next`), only used if `MI_ENCODE_FREELIST`
+    pub xthread_free: AtomicUsize, // list of deferred free blocks freed by other threads (`MiThreadFree`)
+    pub xheap: AtomicUsize,
     pub next: *mut MiPage, // next page owned by this thread with the same `block_size`
     pub prev: *mut MiPage, // previous page owned by this thread with the same `block_size`
 
@@ -285,10 +432,11 @@ impl Default for MiPage {
             reserved: Default::default(),
             flags: Default::default(),
             bitfield_2: Default::default(),
-            free: Default::default(),
+            free: ptr::null_mut(),
             used: Default::default(),
             xblock_size: Default::default(),
             local_free: ptr::null_mut(),
+            keys: [0, 0],
             xthread_free: Default::default(),
             xheap: Default::default(),
             next: ptr::null_mut(),
@@ -298,7 +446,7 @@ impl Default for MiPage {
 }
 
 impl MiPage {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             slice_count: 0,
             slice_offset: 0,
@@ -307,12 +455,13 @@ impl MiPage {
             reserved: 0,
             flags: MiPageFlags { full_aligned: 0 },
             bitfield_2: BitfieldUnit::new([0]),
-            free: LinkedList::new(),
+            free: ptr::null_mut(),
             used: 0,
             xblock_size: 0,
             local_free: ptr::null_mut(),
-            xthread_free: AtomicPtr::new(ptr::null_mut()),
-            xheap: AtomicPtr::new(ptr::null_mut()),
+            keys: [0, 0],
+            xthread_free: AtomicUsize::new(0),
+            xheap: AtomicUsize::new(0),
             next: ptr::null_mut(),
             prev: ptr::null_mut(),
         }
@@ -331,7 +480,7 @@ impl MiPage {
     // `true` if the page virtual memory is committed
     #[inline]
     pub fn is_committed(&self) -> u8 {
-        self.bitfield_1.get(0, 1) as u8
+        self.bitfield_1.get(1, 1) as u8
     }
 
     #[inline]
@@ -350,19 +499,6 @@ impl MiPage {
         self.bitfield_1.set(2usize, 1u8, val as u64)
     }
 
-    #[inline]
-    pub fn new_bitfield_1(
-        is_reset: u8,
-        is_committed: u8,
-        is_zero_init: u8,
-    ) -> BitfieldUnit<[u8; 1usize], u8> {
-        let mut bitfield_unitbitfield_unit: BitfieldUnit<[u8; 1usize], u8> = Default::default();
-        bitfield_unitbitfield_unit.set(0usize, 1u8, is_reset as u64);
-        bitfield_unitbitfield_unit.set(1usize, 1u8, is_committed as u64);
-        bitfield_unitbitfield_unit.set(2usize, 1u8, is_zero_init as u64);
-        bitfield_unitbitfield_unit
-    }
-
     // `true` if the blocks in the free list are zero initialized
     #[inline]
     pub fn is_zero(&self) -> u8 {
@@ -384,30 +520,14 @@ impl MiPage {
     pub fn set_retire_expire(&mut self, val: u8) {
         self.bitfield_2.set(1usize, 7u8, val as u64)
     }
-
-    #[inline]
-    pub fn new_bitfield_2(is_zero: u8, retire_expire: u8) -> BitfieldUnit<[u8; 1usize], u8> {
-        let mut bitfield_unitbitfield_unit: BitfieldUnit<[u8; 1usize], u8> = Default::default();
-        bitfield_unitbitfield_unit.set(0usize, 1u8, is_zero as u64);
-        bitfield_unitbitfield_unit.set(1usize, 7u8, retire_expire as u64);
-        bitfield_unitbitfield_unit
-    }
 }
 
-type MiEncoded = usize;
+pub type MiEncoded = usize;
 // free lists contain blocks
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 pub struct MiBlock {
-    next: MiEncoded,
-}
-
-impl Default for MiBlock {
-    fn default() -> Self {
-        Self {
-            next: Default::default(),
-        }
-    }
+    pub next: MiEncoded,
 }
 
 #[repr(C)]
@@ -531,7 +651,8 @@ pub struct MiCommitMask {
 }
 
 #[repr(C)]
-enum MiPageKind {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MiPageKind {
     MiPageSmall,  // small blocks go into 64KiB pages inside a segment
     MiPageMedium, // medium blocks go into medium pages inside a segment
     MiPageLarge,  // larger blocks go into a page of just one block
@@ -539,6 +660,7 @@ enum MiPageKind {
 }
 
 #[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum MiSegmentKind {
     MiSegmentNormal, // MI_SEGMENT_SIZE size with pages inside.
     MiSegmentHuge,   // > MI_LARGE_SIZE_MAX segment with just one huge page inside.
@@ -571,7 +693,7 @@ pub struct MiSegment {
     // // layout like this to optimize access in `mi_free`
     pub kind: MiSegmentKind,
     pub slice_entries: SizeT, // entries in the `slices` array, at most `MI_SLICES_PER_SEGMENT`
-    pub thread_id: AtomicPtr<MiThreadId>, // unique id of the thread owning this segment
+    pub thread_id: AtomicUsize, // unique id of the thread owning this segment
 
     pub slices: [MiSlice; MI_SLICES_PER_SEGMENT + 1], // one more for huge blocks with large alignment
 }
@@ -600,20 +722,122 @@ impl Default for MiSpanQueue {
     }
 }
 
-const MI_SEGMENT_BIN_MAX: usize = 35; // 35 == mi_segment_bin(MI_SLICES_PER_SEGMENT)
+pub const MI_SEGMENT_BIN_MAX: usize = 35; // 35 == mi_segment_bin(MI_SLICES_PER_SEGMENT)
+
+// ------------------------------------------------------
+// Statistics
+// ------------------------------------------------------
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MiStatCount {
+    pub allocated: i64,
+    pub freed: i64,
+    pub peak: i64,
+    pub current: i64,
+}
+
+impl MiStatCount {
+    pub const fn new() -> Self {
+        MiStatCount {
+            allocated: 0,
+            freed: 0,
+            peak: 0,
+            current: 0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MiStatCounter {
+    pub total: i64,
+    pub count: i64,
+}
+
+impl MiStatCounter {
+    pub const fn new() -> Self {
+        MiStatCounter { total: 0, count: 0 }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct MiStats {
+    pub segments: MiStatCount,
+    pub pages: MiStatCount,
+    pub reserved: MiStatCount,
+    pub committed: MiStatCount,
+    pub reset: MiStatCount,
+    pub page_committed: MiStatCount,
+    pub segments_abandoned: MiStatCount,
+    pub pages_abandoned: MiStatCount,
+    pub threads: MiStatCount,
+    pub normal: MiStatCount,
+    pub huge: MiStatCount,
+    pub large: MiStatCount,
+    pub malloc: MiStatCount,
+    pub segments_cache: MiStatCount,
+    pub pages_extended: MiStatCounter,
+    pub mmap_calls: MiStatCounter,
+    pub commit_calls: MiStatCounter,
+    pub page_no_retire: MiStatCounter,
+    pub searches: MiStatCounter,
+    pub normal_count: MiStatCounter,
+    pub huge_count: MiStatCounter,
+    pub large_count: MiStatCounter,
+    pub normal_bins: [MiStatCount; MI_BIN_HUGE + 1], // only maintained if `MI_STAT > 1`
+}
+
+impl MiStats {
+    pub const fn new() -> Self {
+        MiStats {
+            segments: MiStatCount::new(),
+            pages: MiStatCount::new(),
+            reserved: MiStatCount::new(),
+            committed: MiStatCount::new(),
+            reset: MiStatCount::new(),
+            page_committed: MiStatCount::new(),
+            segments_abandoned: MiStatCount::new(),
+            pages_abandoned: MiStatCount::new(),
+            threads: MiStatCount::new(),
+            normal: MiStatCount::new(),
+            huge: MiStatCount::new(),
+            large: MiStatCount::new(),
+            malloc: MiStatCount::new(),
+            segments_cache: MiStatCount::new(),
+            pages_extended: MiStatCounter::new(),
+            mmap_calls: MiStatCounter::new(),
+            commit_calls: MiStatCounter::new(),
+            page_no_retire: MiStatCounter::new(),
+            searches: MiStatCounter::new(),
+            normal_count: MiStatCounter::new(),
+            huge_count: MiStatCounter::new(),
+            large_count: MiStatCounter::new(),
+            normal_bins: [MiStatCount::new(); MI_BIN_HUGE + 1],
+        }
+    }
+}
+
+impl Default for MiStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 // OS thread local data
 #[repr(C)]
 #[derive(Clone)]
 pub struct MiOsTLD {
-    pub region_idx: SizeT, // start point for next allocation
-                           //mi_stats_t*           stats,       // points to tld stats
+    pub region_idx: SizeT,   // start point for next allocation
+    pub stats: *mut MiStats, // points to tld stats
 }
 
 impl Default for MiOsTLD {
     fn default() -> Self {
         Self {
             region_idx: Default::default(),
+            stats: ptr::addr_of_mut!(_mi_stats_main), // until linked to the thread stats
         }
     }
 }
@@ -627,18 +851,42 @@ pub struct MiSegmentsTLD {
     pub peak_count: SizeT,                            // peak number of segments
     pub current_size: SizeT,                          // current size of all segments
     pub peak_size: SizeT,                             // peak size of all segments
-    // pub stats                      : mi_stats_t*      ,                    // points to tld stats
-    pub os: *mut MiOsTLD, // points to os stats
-}
+    pub stats: *mut MiStats,                          // points to tld stats
+    pub os: *mut MiOsTLD,                             // points to os stats
+}
+
+// slice counts of the span queues; must match the bins in `segment.rs:mi_slice_bin`
+const MI_SEGMENT_SPAN_QUEUE_SLICES: [SizeT; MI_SEGMENT_BIN_MAX + 1] = [
+    0, 1, 2, 3, 4, 5, 6, 7, 10, // 8
+    12, 14, 16, 20, 24, 28, 32, 40, // 16
+    48, 56, 64, 80, 96, 112, 128, 160, // 24
+    192, 224, 256, 320, 384, 448, 512, 640, // 32
+    768, 896, 1024, // 35
+];
+
+pub const MI_SEGMENT_SPAN_QUEUES_EMPTY: [MiSpanQueue; MI_SEGMENT_BIN_MAX + 1] = {
+    let mut queues = [MiSpanQueue {
+        first: ptr::null_mut(),
+        last: ptr::null_mut(),
+        slice_count: 0,
+    }; MI_SEGMENT_BIN_MAX + 1];
+    let mut i = 0;
+    while i < queues.len() {
+        queues[i].slice_count = MI_SEGMENT_SPAN_QUEUE_SLICES[i];
+        i += 1;
+    }
+    queues
+};
 
 impl Default for MiSegmentsTLD {
     fn default() -> Self {
         Self {
-            spans: [Default::default(); MI_SEGMENT_BIN_MAX + 1],
+            spans: MI_SEGMENT_SPAN_QUEUES_EMPTY,
             count: Default::default(),
             peak_count: Default::default(),
             current_size: Default::default(),
             peak_size: Default::default(),
+            stats: ptr::addr_of_mut!(_mi_stats_main), // until linked to the thread stats
             os: ptr::null_mut(),
         }
     }
@@ -654,7 +902,7 @@ pub struct MiTLD {
     pub heaps: *mut MiHeap, // list of heaps in this thread (so we can abandon all when the thread terminates)
     pub segments: MiSegmentsTLD, // segment tld
     pub os: MiOsTLD,        // os tld
-                            //pub stats          : mi_stats_t,         // statistics
+    pub stats: MiStats,     // statistics
 }
 
 impl Default for MiTLD {
@@ -666,6 +914,7 @@ impl Default for MiTLD {
             heaps: ptr::null_mut(),
             segments: Default::default(),
             os: Default::default(),
+            stats: MiStats::new(),
         }
     }
 }
@@ -678,41 +927,42 @@ pub struct MiThreadData {
     pub tld: MiTLD,
 }
 
-impl MiThreadData {
-    pub fn new() -> Self {
-        Default::default()
-    }
-}
-
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum MiOption {
     // stable options
-    MiOptionShowErrors,
-    MiOptionShowStats,
-    MiOptionVerbose,
+    MiOptionShowErrors = 0,
+    MiOptionShowStats = 1,
+    MiOptionVerbose = 2,
     // some of the following options are experimental
     // (deprecated options are kept for binary backward compatibility with v1.x versions)
-    // mi_option_eager_commit,
-    // mi_option_deprecated_eager_region_commit,
-    // mi_option_deprecated_reset_decommits,
-    MiOptionLargeOsPages, // use large (2MiB) OS pages, implies eager commit
-    // mi_option_reserve_huge_os_pages, // reserve N huge OS pages (1GiB) at startup
-    // mi_option_reserve_huge_os_pages_at, // reserve huge OS pages at a specific NUMA node
-    // mi_option_reserve_os_memory, // reserve specified amount of OS memory at startup
-    // mi_option_deprecated_segment_cache,
-    // mi_option_page_reset,
-    // mi_option_abandoned_page_decommit,
-    // mi_option_deprecated_segment_reset,
-    MiOptionEagerCommitDelay,
-    // mi_option_decommit_delay,
-    // mi_option_use_numa_nodes, // 0 = use available numa nodes, otherwise use at most N nodes.
-    // mi_option_limit_os_alloc, // 1 = do not use OS memory for allocation (but only reserved arenas)
-    // mi_option_os_tag,
-    // mi_option_max_errors,
-    // mi_option_max_warnings,
-    MiOptionMaxSegmentReclaim,
-    MiOptionAllowDecommit,
-    // mi_option_segment_decommit_delay,
-    // mi_option_decommit_extend_delay,
-    // mi_option_destroy_on_exit,
-    // _mi_option_last,
+    MiOptionEagerCommit = 3,
+    MiOptionDeprecatedEagerRegionCommit = 4,
+    MiOptionDeprecatedResetDecommits = 5,
+    MiOptionLargeOsPages = 6, // use large (2MiB) OS pages, implies eager commit
+    MiOptionReserveHugeOsPages = 7, // reserve N huge OS pages (1GiB) at startup
+    MiOptionReserveHugeOsPagesAt = 8, // reserve huge OS pages at a specific NUMA node
+    MiOptionReserveOsMemory = 9, // reserve specified amount of OS memory at startup
+    MiOptionDeprecatedSegmentCache = 10,
+    MiOptionPageReset = 11,
+    MiOptionAbandonedPageDecommit = 12,
+    MiOptionDeprecatedSegmentReset = 13,
+    MiOptionEagerCommitDelay = 14,
+    MiOptionDecommitDelay = 15,
+    MiOptionUseNumaNodes = 16, // 0 = use available numa nodes, otherwise use at most N nodes.
+    MiOptionLimitOsAlloc = 17, // 1 = do not use OS memory for allocation (but only reserved arenas)
+    MiOptionOsTag = 18,
+    MiOptionMaxErrors = 19,
+    MiOptionMaxWarnings = 20,
+    MiOptionMaxSegmentReclaim = 21,
+    MiOptionAllowDecommit = 22,
+    MiOptionSegmentDecommitDelay = 23,
+    MiOptionDecommitExtendDelay = 24,
+    MiOptionDestroyOnExit = 25,
+    // not in upstream mimalloc
+    /// Where `mi_stats_prometheus_listen` serves the statistics for Prometheus:
+    /// `0` = off (default), `1` (`on`) = on the Unix-domain socket `mimalloc-<pid>.sock`
+    /// in the temporary directory, `1024..=65535` = on that TCP port of localhost.
+    /// Other values are invalid.
+    MiOptionPrometheusListen = 26,
 }
@@ -1,5 +1,18 @@
-use crate::mimalloc_types::MiOption;
+use std::cell::Cell;
+use std::ffi::{c_void, CStr};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::{fmt, mem, process, ptr};
 
+use libc::{c_char, c_int, c_long};
+
+use crate::init::_mi_is_main_thread;
+use crate::mimalloc_internal::_mi_thread_id;
+use crate::mimalloc_types::{MI_KiB, MI_MiB, MiOption, MI_SECURE};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
 enum MiInit {
     UNINIT,      // not yet initialized
     DEFAULTED,   // not found in the environment, use default value
@@ -7,77 +20,206 @@ enum MiInit {
 }
 
 struct MiOptionDesc {
-    pub value: i64,          // the value
-    pub init: MiInit,        // is it initialized yet? (from the environment)
-    pub option: MiOption,    // for debugging: the option index should match the option
-    pub name: String,        // option name without `mimalloc_` prefix
-    pub legacy_name: String, // potential legacy v1.x option name
+    pub value: AtomicI64,                  // the value
+    pub init: AtomicU8,                    // is it initialized yet? (from the environment)
+    pub option: MiOption, // for debugging: the option index should match the option
+    pub name: &'static str, // option name without `mimalloc_` prefix
+    pub legacy_name: Option<&'static str>, // potential legacy v1.x option name
+}
+
+impl MiOptionDesc {
+    const fn new(value: i64, option: MiOption, name: &'static str) -> Self {
+        MiOptionDesc {
+            value: AtomicI64::new(value),
+            init: AtomicU8::new(MiInit::UNINIT as u8),
+            option,
+            name,
+            legacy_name: None,
+        }
+    }
+
+    const fn legacy(
+        value: i64,
+        option: MiOption,
+        name: &'static str,
+        legacy_name: &'static str,
+    ) -> Self {
+        MiOptionDesc {
+            legacy_name: Some(legacy_name),
+            ..MiOptionDesc::new(value, option, name)
+        }
+    }
+
+    fn init(&self) -> MiInit {
+        match self.init.load(Ordering::Acquire) {
+            0 => MiInit::UNINIT,
+            1 => MiInit::DEFAULTED,
+            _ => MiInit::INITIALIZED,
+        }
+    }
+
+    fn set_init(&self, init: MiInit) {
+        self.init.store(init as u8, Ordering::Release);
+    }
 }
 
 // should deprecated if mem::variant_count is stable [https://github.com/rust-lang/rust/issues/73662]
-const MI_OPTION_LAST: usize = 3;
-
-// should carefully design it
-//   static options: [MiOptionDesc; MI_OPTION_LAST] =
-//   {
-//     // stable options
-//     // #if MI_DEBUG || defined(MI_SHOW_ERRORS)
-//     // { 1, UNINIT, MI_OPTION(show_errors) },
-//     // #else
-//     // { 0, UNINIT, MI_OPTION(show_errors) },
-//     // #endif
-//     // { 0, UNINIT, MI_OPTION(show_stats) },
-//     // { 0, UNINIT, MI_OPTION(verbose) },
-
-//     // // Some of the following options are experimental and not all combinations are valid. Use with care.
-//     // { 1, UNINIT, MI_OPTION(eager_commit) },        // commit per segment directly (8MiB)  (but see also `eager_commit_delay`)
-//     // { 0, UNINIT, MI_OPTION(deprecated_eager_region_commit) },
-//     // { 0, UNINIT, MI_OPTION(deprecated_reset_decommits) },
-//     // { 0, UNINIT, MI_OPTION(large_os_pages) },      // use large OS pages, use only with eager commit to prevent fragmentation of VMA's
-//     // { 0, UNINIT, MI_OPTION(reserve_huge_os_pages) },  // per 1GiB huge pages
-//     // { -1, UNINIT, MI_OPTION(reserve_huge_os_pages_at) }, // reserve huge pages at node N
-//     // { 0, UNINIT, MI_OPTION(reserve_os_memory)     },
-//     // { 0, UNINIT, MI_OPTION(deprecated_segment_cache) },  // cache N segments per thread
-//     // { 0, UNINIT, MI_OPTION(page_reset) },          // reset page memory on free
-//     // { 0, UNINIT, MI_OPTION_LEGACY(abandoned_page_decommit, abandoned_page_reset) },// decommit free page memory when a thread terminates
-//     // { 0, UNINIT, MI_OPTION(deprecated_segment_reset) },
-//     // #if defined(__NetBSD__)
-//     // { 0, UNINIT, MI_OPTION(eager_commit_delay) },  // the first N segments per thread are not eagerly committed
-//     // #elif defined(_WIN32)
-//     // { 4, UNINIT, MI_OPTION(eager_commit_delay) },  // the first N segments per thread are not eagerly committed (but per page in the segment on demand)
-//     // #else
-//     // { 1, UNINIT, MI_OPTION(eager_commit_delay) },  // the first N segments per thread are not eagerly committed (but per page in the segment on demand)
-//     // #endif
-//     // { 25,   UNINIT, MI_OPTION_LEGACY(decommit_delay, reset_delay) }, // page decommit delay in milli-seconds
-//     // { 0,    UNINIT, MI_OPTION(use_numa_nodes) },    // 0 = use available numa nodes, otherwise use at most N nodes.
-//     // { 0,    UNINIT, MI_OPTION(limit_os_alloc) },    // 1 = do not use OS memory for allocation (but only reserved arenas)
-//     // { 100,  UNINIT, MI_OPTION(os_tag) },            // only apple specific for now but might serve more or less related purpose
-//     // { 16,   UNINIT, MI_OPTION(max_errors) },        // maximum errors that are output
-//     // { 16,   UNINIT, MI_OPTION(max_warnings) },      // maximum warnings that are output
-//     // { 8,    UNINIT, MI_OPTION(max_segment_reclaim)},// max. number of segment reclaims from the abandoned segments per try.
-//     // { 1,    UNINIT, MI_OPTION(allow_decommit) },    // decommit slices when no longer used (after decommit_delay milli-seconds)
-//     // { 500,  UNINIT, MI_OPTION(segment_decommit_delay) }, // decommit delay in milli-seconds for freed segments
-//     // { 1,    UNINIT, MI_OPTION(decommit_extend_delay) },
-//     // { 0,    UNINIT, MI_OPTION(destroy_on_exit)}     // release all OS memory on process exit; careful with dangling pointer or after-exit frees!
-//   };
-
-pub fn mi_option_get(option: MiOption) -> i64 {
-    // if option < 0 || option >= _mi_option_last {return 0;}
-    // let desc = &options[option];
-    // debug_assert!(desc->option == option);  // index should match the option
-    // if desc->init == UNINIT {
-    //   mi_option_init(desc);
-    // }
-    // return desc->value;
-    0
-}
-
-pub fn mi_option_is_enabled(option: MiOption) -> bool {
-    true
-    // return mi_option_get(option) != 0;
+const MI_OPTION_LAST: usize = MiOption::MiOptionPrometheusListen as usize + 1;
+
+#[cfg(target_os = "netbsd")]
+const MI_EAGER_COMMIT_DELAY: i64 = 0; // the first N segments per thread are not eagerly committed
+#[cfg(windows)]
+const MI_EAGER_COMMIT_DELAY: i64 = 4; // the first N segments per thread are not eagerly committed (but per page in the segment on demand)
+#[cfg(not(any(target_os = "netbsd", windows)))]
+const MI_EAGER_COMMIT_DELAY: i64 = 1; // the first N segments per thread are not eagerly committed (but per page in the segment on demand)
+
+// note: the entries must be in the same order as the `MiOption` variants
+static MI_OPTIONS: [MiOptionDesc; MI_OPTION_LAST] = [
+    // stable options
+    MiOptionDesc::new(
+        if cfg!(debug_assertions) { 1 } else { 0 },
+        MiOption::MiOptionShowErrors,
+        "show_errors",
+    ),
+    MiOptionDesc::new(0, MiOption::MiOptionShowStats, "show_stats"),
+    MiOptionDesc::new(0, MiOption::MiOptionVerbose, "verbose"),
+    // Some of the following options are experimental and not all combinations are valid. Use with care.
+    MiOptionDesc::new(1, MiOption::MiOptionEagerCommit, "eager_commit"), // commit per segment directly (8MiB)  (but see also `eager_commit_delay`)
+    MiOptionDesc::new(
+        0,
+        MiOption::MiOptionDeprecatedEagerRegionCommit,
+        "deprecated_eager_region_commit",
+    ),
+    MiOptionDesc::new(
+        0,
+        MiOption::MiOptionDeprecatedResetDecommits,
+        "deprecated_reset_decommits",
+    ),
+    MiOptionDesc::new(0, MiOption::MiOptionLargeOsPages, "large_os_pages"), // use large OS pages, use only with eager commit to prevent fragmentation of VMA's
+    MiOptionDesc::new(
+        0,
+        MiOption::MiOptionReserveHugeOsPages,
+        "reserve_huge_os_pages",
+    ), // per 1GiB huge pages
+    MiOptionDesc::new(
+        -1,
+        MiOption::MiOptionReserveHugeOsPagesAt,
+        "reserve_huge_os_pages_at",
+    ), // reserve huge pages at node N
+    MiOptionDesc::new(0, MiOption::MiOptionReserveOsMemory, "reserve_os_memory"),
+    MiOptionDesc::new(
+        0,
+        MiOption::MiOptionDeprecatedSegmentCache,
+        "deprecated_segment_cache",
+    ),
+    MiOptionDesc::new(0, MiOption::MiOptionPageReset, "page_reset"), // reset page memory on free
+    MiOptionDesc::legacy(
+        0,
+        MiOption::MiOptionAbandonedPageDecommit,
+        "abandoned_page_decommit",
+        "abandoned_page_reset",
+    ), // decommit free page memory when a thread terminates
+    MiOptionDesc::new(
+        0,
+        MiOption::MiOptionDeprecatedSegmentReset,
+        "deprecated_segment_reset",
+    ),
+    MiOptionDesc::new(
+        MI_EAGER_COMMIT_DELAY,
+        MiOption::MiOptionEagerCommitDelay,
+        "eager_commit_delay",
+    ),
+    MiOptionDesc::legacy(
+        25,
+        MiOption::MiOptionDecommitDelay,
+        "decommit_delay",
+        "reset_delay",
+    ), // page decommit delay in milli-seconds
+    MiOptionDesc::new(0, MiOption::MiOptionUseNumaNodes, "use_numa_nodes"), // 0 = use available numa nodes, otherwise use at most N nodes.
+    MiOptionDesc::new(0, MiOption::MiOptionLimitOsAlloc, "limit_os_alloc"), // 1 = do not use OS memory for allocation (but only reserved arenas)
+    MiOptionDesc::new(100, MiOption::MiOptionOsTag, "os_tag"), // only apple specific for now but might serve more or less related purpose
+    MiOptionDesc::new(16, MiOption::MiOptionMaxErrors, "max_errors"), // maximum errors that are output
+    MiOptionDesc::new(16, MiOption::MiOptionMaxWarnings, "max_warnings"), // maximum warnings that are output
+    MiOptionDesc::new(
+        8,
+        MiOption::MiOptionMaxSegmentReclaim,
+        "max_segment_reclaim",
+    ), // max. number of segment reclaims from the abandoned segments per try.
+    MiOptionDesc::new(1, MiOption::MiOptionAllowDecommit, "allow_decommit"), // decommit slices when no longer used (after decommit_delay milli-seconds)
+    MiOptionDesc::new(
+        500,
+        MiOption::MiOptionSegmentDecommitDelay,
+        "segment_decommit_delay",
+    ), // decommit delay in milli-seconds for freed segments
+    MiOptionDesc::new(
+        1,
+        MiOption::MiOptionDecommitExtendDelay,
+        "decommit_extend_delay",
+    ),
+    MiOptionDesc::new(0, MiOption::MiOptionDestroyOnExit, "destroy_on_exit"), // release all OS memory on process exit; careful with dangling pointer or after-exit frees!
+    MiOptionDesc::new(0, MiOption::MiOptionPrometheusListen, "prometheus_listen"), // where `mi_stats_prometheus_listen` serves the statistics in the Prometheus text format
+];
+
+// Called once by the process loader: initialize all options from the environment
+pub fn _mi_options_init() {
+    mi_add_stderr_output(); // now it safe to use stderr for output
+    for desc in MI_OPTIONS.iter() {
+        let l = mi_option_get(desc.option); // initialize
+        _mi_verbose_message(format_args!("option '{}': {}\n", desc.name, l));
+    }
+    MI_MAX_ERROR_COUNT.store(
+        mi_option_get(MiOption::MiOptionMaxErrors),
+        Ordering::Relaxed,
+    );
+    MI_MAX_WARNING_COUNT.store(
+        mi_option_get(MiOption::MiOptionMaxWarnings),
+        Ordering::Relaxed,
+    );
+}
+
+// Setting an option and initializing it from the environment are serialized
+// so an explicit setting is never overwritten by a concurrent (lazy) initialization.
+static MI_OPTIONS_LOCK: Mutex<()> = Mutex::new(());
+
+fn mi_options_lock() -> MutexGuard<'static, ()> {
+    MI_OPTIONS_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn mi_option_desc(option: MiOption) -> &'static MiOptionDesc {
+    let desc = &MI_OPTIONS[option as usize];
+    debug_assert!(desc.option == option); // index should match the option
+    desc
+}
+
+fn mi_option_desc_get(desc: &MiOptionDesc) -> i64 {
+    if desc.init() == MiInit::UNINIT {
+        mi_option_init(desc);
+    }
+    desc.value.load(Ordering::Relaxed)
+}
+
+fn mi_option_desc_set(desc: &MiOptionDesc, value: i64) {
+    let _lock = mi_options_lock();
+    desc.value.store(value, Ordering::Relaxed);
+    desc.set_init(MiInit::INITIALIZED);
+}
+
+fn mi_option_desc_set_default(desc: &MiOptionDesc, value: i64) {
+    let _lock = mi_options_lock();
+    if desc.init() != MiInit::INITIALIZED {
+        desc.value.store(value, Ordering::Relaxed);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_get(option: MiOption) -> c_long {
+    mi_option_desc_get(mi_option_desc(option)) as c_long
 }
 
-pub fn mi_option_get_clamp(option: MiOption, min: i64, max: i64) -> i64 {
+#[no_mangle]
+pub extern "C" fn mi_option_get_clamp(option: MiOption, min: c_long, max: c_long) -> c_long {
     let x = mi_option_get(option);
 
     if x < min {
@@ -88,3 +230,842 @@ pub fn mi_option_get_clamp(option: MiOption, min: i64, max: i64) -> i64 {
         x
     }
 }
+
+#[no_mangle]
+pub extern "C" fn mi_option_set(option: MiOption, value: c_long) {
+    #[allow(clippy::useless_conversion)] // `c_long` is 32-bit on windows
+    mi_option_desc_set(mi_option_desc(option), i64::from(value));
+}
+
+// Set the default value of an option; this has no effect if the option
+// was already set explicitly or from the environment.
+#[no_mangle]
+pub extern "C" fn mi_option_set_default(option: MiOption, value: c_long) {
+    #[allow(clippy::useless_conversion)] // `c_long` is 32-bit on windows
+    mi_option_desc_set_default(mi_option_desc(option), i64::from(value));
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_is_enabled(option: MiOption) -> bool {
+    mi_option_get(option) != 0
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_set_enabled(option: MiOption, enable: bool) {
+    mi_option_set(option, if enable { 1 } else { 0 });
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_set_enabled_default(option: MiOption, enable: bool) {
+    mi_option_set_default(option, if enable { 1 } else { 0 });
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_enable(option: MiOption) {
+    mi_option_set_enabled(option, true);
+}
+
+#[no_mangle]
+pub extern "C" fn mi_option_disable(option: MiOption) {
+    mi_option_set_enabled(option, false);
+}
+
+/// Read and override allocator options, for example from a configuration system
+/// at startup (before the first allocation). Explicit settings take precedence
+/// over the `MIMALLOC_` environment variables.
+impl MiOption {
+    /// The current value of the option.
+    pub fn get(self) -> i64 {
+        mi_option_get(self) as i64
+    }
+
+    /// The current value clamped to `min..=max`.
+    pub fn get_clamp(self, min: i64, max: i64) -> i64 {
+        self.get().clamp(min, max)
+    }
+
+    pub fn is_enabled(self) -> bool {
+        mi_option_is_enabled(self)
+    }
+
+    /// Set the option, overriding the environment.
+    pub fn set(self, value: i64) {
+        mi_option_set(self, value as c_long);
+    }
+
+    /// Set the default of the option, used unless it is set explicitly or from the environment.
+    pub fn set_default(self, value: i64) {
+        mi_option_set_default(self, value as c_long);
+    }
+
+    pub fn set_enabled(self, enable: bool) {
+        mi_option_set_enabled(self, enable);
+    }
+
+    pub fn set_enabled_default(self, enable: bool) {
+        mi_option_set_enabled_default(self, enable);
+    }
+
+    pub fn enable(self) {
+        mi_option_enable(self);
+    }
+
+    pub fn disable(self) {
+        mi_option_disable(self);
+    }
+}
+
+// --------------------------------------------------------
+// Messages, all end up calling `_mi_fputs`.
+// --------------------------------------------------------
+
+// Type of an output function: `msg` is a zero terminated string, `arg` is the
+// argument given at registration.
+pub type MiOutputFun = unsafe extern "C" fn(msg: *const c_char, arg: *mut c_void);
+
+extern "C" fn mi_out_stderr(msg: *const c_char, _arg: *mut c_void) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = unsafe { CStr::from_ptr(msg) }.to_bytes();
+    let mut written = 0;
+    while written < msg.len() {
+        let rest = &msg[written..];
+        #[cfg(unix)]
+        let n = unsafe { libc::write(libc::STDERR_FILENO, rest.as_ptr().cast(), rest.len()) };
+        #[cfg(windows)]
+        let n = unsafe { libc::write(2, rest.as_ptr().cast(), rest.len() as libc::c_uint) };
+        if n <= 0 {
+            break;
+        }
+        written += n as usize;
+    }
+}
+
+// Since an output function can be registered earliest in the `main`
+// function we also buffer output that happens earlier. When
+// an output function is registered it is called immediately with
+// the output up to that point.
+const MI_MAX_DELAY_OUTPUT: usize = 32 * 1024;
+static mut OUT_BUF: [u8; MI_MAX_DELAY_OUTPUT + 1] = [0; MI_MAX_DELAY_OUTPUT + 1];
+static OUT_LEN: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn mi_out_buf(msg: *const c_char, _arg: *mut c_void) {
+    if msg.is_null() {
+        return;
+    }
+    if OUT_LEN.load(Ordering::Relaxed) >= MI_MAX_DELAY_OUTPUT {
+        return;
+    }
+    let msg = unsafe { CStr::from_ptr(msg) }.to_bytes();
+    let n = msg.len();
+    if n == 0 {
+        return;
+    }
+    // claim space
+    let start = OUT_LEN.fetch_add(n, Ordering::AcqRel);
+    if start >= MI_MAX_DELAY_OUTPUT {
+        return;
+    }
+    // check bound
+    let n = n.min(MI_MAX_DELAY_OUTPUT - start);
+    unsafe {
+        ptr::copy_nonoverlapping(
+            msg.as_ptr(),
+            ptr::addr_of_mut!(OUT_BUF).cast::<u8>().add(start),
+            n,
+        )
+    };
+}
+
+fn mi_out_buf_flush(out: MiOutputFun, no_more_buf: bool, arg: *mut c_void) {
+    // claim (if `no_more_buf == true`, no more output will be added after this point)
+    let mut count = OUT_LEN.fetch_add(
+        if no_more_buf { MI_MAX_DELAY_OUTPUT } else { 1 },
+        Ordering::AcqRel,
+    );
+    // and output the current contents
+    if count > MI_MAX_DELAY_OUTPUT {
+        count = MI_MAX_DELAY_OUTPUT;
+    }
+    let buf = ptr::addr_of_mut!(OUT_BUF).cast::<u8>();
+    unsafe {
+        *buf.add(count) = 0;
+        out(buf.cast(), arg);
+        if !no_more_buf {
+            *buf.add(count) = b'\n'; // if continue with the buffer, insert a newline
+        }
+    }
+}
+
+// Once this module is loaded, switch to this routine
+// which outputs to stderr and the delayed output buffer.
+extern "C" fn mi_out_buf_stderr(msg: *const c_char, arg: *mut c_void) {
+    mi_out_stderr(msg, arg);
+    mi_out_buf(msg, arg);
+}
+
+// --------------------------------------------------------
+// Default output handler
+// --------------------------------------------------------
+
+// The registered output function and its argument; a null output means the delayed output buffer.
+static MI_OUT_DEFAULT: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static MI_OUT_ARG: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+fn mi_out_get_default(parg: Option<&mut *mut c_void>) -> MiOutputFun {
+    if let Some(parg) = parg {
+        *parg = MI_OUT_ARG.load(Ordering::Acquire);
+    }
+    let out = MI_OUT_DEFAULT.load(Ordering::Acquire);
+    if out.is_null() {
+        mi_out_buf
+    } else {
+        unsafe { mem::transmute::<*mut c_void, MiOutputFun>(out) }
+    }
+}
+
+/// Register an output function for statistics, verbose, warning and error messages;
+/// `None` resets to the default output (stderr).
+///
+/// # Safety
+/// The output function is called with `arg` from any thread at any time (also from within
+/// the allocator, so it must not allocate) until another output function is registered:
+/// it must be sound to call it with `arg` and a zero terminated message in all those cases.
+#[no_mangle]
+pub unsafe extern "C" fn mi_register_output(out: Option<MiOutputFun>, arg: *mut c_void) {
+    let fun: MiOutputFun = out.unwrap_or(mi_out_stderr); // stop using the delayed output buffer
+    MI_OUT_DEFAULT.store(fun as *mut c_void, Ordering::Release);
+    MI_OUT_ARG.store(arg, Ordering::Release);
+    if let Some(out) = out {
+        mi_out_buf_flush(out, true, arg); // output all the delayed output now
+    }
+}
+
+// add stderr to the delayed output after the module is loaded
+fn mi_add_stderr_output() {
+    debug_assert!(MI_OUT_DEFAULT.load(Ordering::Relaxed).is_null());
+    mi_out_buf_flush(mi_out_stderr, false, ptr::null_mut()); // flush current contents to stderr
+    let fun: MiOutputFun = mi_out_buf_stderr;
+    MI_OUT_DEFAULT.store(fun as *mut c_void, Ordering::Release); // and add stderr to the delayed output
+}
+
+// --------------------------------------------------------
+// Messages, all end up calling `_mi_fputs`.
+// --------------------------------------------------------
+
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0); // when >= max_error_count stop emitting errors
+static WARNING_COUNT: AtomicUsize = AtomicUsize::new(0); // when >= max_warning_count stop emitting warnings
+
+static MI_MAX_ERROR_COUNT: AtomicI64 = AtomicI64::new(16); // stop outputting errors after this (use < 0 for no limit)
+static MI_MAX_WARNING_COUNT: AtomicI64 = AtomicI64::new(16); // stop outputting warnings after this (use < 0 for no limit)
+
+thread_local! {
+    // Prevent recursion on messages (e.g. when the output function allocates)
+    static RECURSE: Cell<bool> = const { Cell::new(false) };
+}
+
+fn mi_recurse_enter() -> bool {
+    RECURSE.with(|recurse| !recurse.replace(true))
+}
+
+fn mi_recurse_exit() {
+    RECURSE.with(|recurse| recurse.set(false));
+}
+
+pub fn _mi_fputs(
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+    prefix: Option<&CStr>,
+    message: &CStr,
+) {
+    match out {
+        None => {
+            if !mi_recurse_enter() {
+                return;
+            }
+            let mut arg = arg;
+            let out = mi_out_get_default(Some(&mut arg));
+            unsafe {
+                if let Some(prefix) = prefix {
+                    out(prefix.as_ptr(), arg);
+                }
+                out(message.as_ptr(), arg);
+            }
+            mi_recurse_exit();
+        }
+        Some(out) => unsafe {
+            if let Some(prefix) = prefix {
+                out(prefix.as_ptr(), arg);
+            }
+            out(message.as_ptr(), arg);
+        },
+    }
+}
+
+// Messages are formatted into a fixed stack buffer (and truncated if too long)
+// so printing never allocates.
+pub struct MiMsgBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> MiMsgBuf<N> {
+    pub fn new() -> Self {
+        MiMsgBuf {
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn as_cstr(&self) -> &CStr {
+        // the buffer always has a terminating zero after `len` bytes
+        unsafe { CStr::from_bytes_with_nul_unchecked(&self.buf[..=self.len]) }
+    }
+
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> fmt::Write for MiMsgBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // skip embedded zeros and always leave room for the terminating zero
+        for &c in s.as_bytes() {
+            if self.len + 1 >= N {
+                break;
+            }
+            if c != 0 {
+                self.buf[self.len] = c;
+                self.len += 1;
+            }
+        }
+        self.buf[self.len] = 0;
+        Ok(())
+    }
+}
+
+fn mi_vfprintf(
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+    prefix: Option<&CStr>,
+    args: fmt::Arguments,
+) {
+    let mut buf = MiMsgBuf::<512>::new();
+    if !mi_recurse_enter() {
+        return;
+    }
+    let _ = fmt::write(&mut buf, args);
+    mi_recurse_exit();
+    _mi_fputs(out, arg, prefix, buf.as_cstr());
+}
+
+pub fn _mi_fprintf(out: Option<MiOutputFun>, arg: *mut c_void, args: fmt::Arguments) {
+    mi_vfprintf(out, arg, None, args);
+}
+
+fn mi_vfprintf_thread(
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+    prefix: &CStr,
+    args: fmt::Arguments,
+) {
+    if prefix.to_bytes().len() <= 32 && !_mi_is_main_thread() {
+        let mut tprefix = MiMsgBuf::<64>::new();
+        let _ = write!(
+            tprefix,
+            "{}thread 0x{:x}: ",
+            prefix.to_str().unwrap_or(""),
+            _mi_thread_id()
+        );
+        mi_vfprintf(out, arg, Some(tprefix.as_cstr()), args);
+    } else {
+        mi_vfprintf(out, arg, Some(prefix), args);
+    }
+}
+
+pub fn _mi_trace_message(args: fmt::Arguments) {
+    if mi_option_get(MiOption::MiOptionVerbose) <= 1 {
+        return; // only with verbose level 2 or higher
+    }
+    mi_vfprintf_thread(None, ptr::null_mut(), c"mimalloc: ", args);
+}
+
+pub fn _mi_verbose_message(args: fmt::Arguments) {
+    if !mi_option_is_enabled(MiOption::MiOptionVerbose) {
+        return;
+    }
+    mi_vfprintf(None, ptr::null_mut(), Some(c"mimalloc: "), args);
+}
+
+// Count a message against its maximum (if not verbose); returns whether it should be shown
+fn mi_message_allowed(count: &AtomicUsize, max_count: &AtomicI64) -> bool {
+    if !mi_option_is_enabled(MiOption::MiOptionVerbose) {
+        if !mi_option_is_enabled(MiOption::MiOptionShowErrors) {
+            return false;
+        }
+        let max_count = max_count.load(Ordering::Relaxed);
+        if max_count >= 0 && (count.fetch_add(1, Ordering::AcqRel) + 1) as i64 > max_count {
+            return false;
+        }
+    }
+    true
+}
+
+fn mi_show_error_message(args: fmt::Arguments) {
+    if !mi_message_allowed(&ERROR_COUNT, &MI_MAX_ERROR_COUNT) {
+        return;
+    }
+    mi_vfprintf_thread(None, ptr::null_mut(), c"mimalloc: error: ", args);
+}
+
+pub fn _mi_warning_message(args: fmt::Arguments) {
+    if !mi_message_allowed(&WARNING_COUNT, &MI_MAX_WARNING_COUNT) {
+        return;
+    }
+    mi_vfprintf_thread(None, ptr::null_mut(), c"mimalloc: warning: ", args);
+}
+
+// --------------------------------------------------------
+// Errors
+// --------------------------------------------------------
+
+// Type of an error handler: `err` is an errno code (e.g. `EFAULT` for corrupted meta-data,
+// `ENOMEM` when out of memory), `arg` is the argument given at registration.
+pub type MiErrorFun = unsafe extern "C" fn(err: c_int, arg: *mut c_void);
+
+static MI_ERROR_HANDLER: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static MI_ERROR_ARG: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+fn mi_error_default(err: c_int) {
+    if cfg!(debug_assertions) && err == libc::EFAULT {
+        process::abort();
+    }
+    if MI_SECURE != 0 && err == libc::EFAULT {
+        // abort on serious errors in secure mode (corrupted meta-data)
+        process::abort();
+    }
+}
+
+/// Register an error handler that is called on every error (instead of the default
+/// behaviour, which aborts on `EFAULT` in debug and secure mode); `None` resets it.
+///
+/// # Safety
+/// The error handler is called with `arg` from any thread at any time (also from within
+/// the allocator, so it must not allocate) until another handler is registered: it must
+/// be sound to call it with `arg` in all those cases.
+#[no_mangle]
+pub unsafe extern "C" fn mi_register_error(fun: Option<MiErrorFun>, arg: *mut c_void) {
+    let fun = fun.map_or(ptr::null_mut(), |fun| fun as *mut c_void);
+    MI_ERROR_HANDLER.store(fun, Ordering::Release); // can be null
+    MI_ERROR_ARG.store(arg, Ordering::Release);
+}
+
+pub fn _mi_error_message(err: c_int, args: fmt::Arguments) {
+    // show detailed error message
+    mi_show_error_message(args);
+    // and call the error handler which may abort (or return normally)
+    let handler = MI_ERROR_HANDLER.load(Ordering::Acquire);
+    if handler.is_null() {
+        mi_error_default(err);
+    } else {
+        let handler = unsafe { mem::transmute::<*mut c_void, MiErrorFun>(handler) };
+        unsafe { handler(err, MI_ERROR_ARG.load(Ordering::Acquire)) };
+    }
+}
+
+// --------------------------------------------------------
+// Initialize options by checking the environment
+// --------------------------------------------------------
+
+// The environment is read without allocating as options are
+// initialized from within the allocator.
+const MI_ENV_BUF_SIZE: usize = 64 + 1;
+
+struct MiEnvBuf {
+    buf: [u8; MI_ENV_BUF_SIZE],
+    len: usize,
+}
+
+impl MiEnvBuf {
+    fn new() -> Self {
+        MiEnvBuf {
+            buf: [0; MI_ENV_BUF_SIZE],
+            len: 0,
+        }
+    }
+
+    // append as much of `s` as fits (always leaving room for a terminating zero)
+    fn push(&mut self, s: &[u8]) {
+        let n = s.len().min(MI_ENV_BUF_SIZE - 1 - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s[..n]);
+        self.len += n;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+#[cfg(unix)]
+extern "C" {
+    static environ: *const *const libc::c_char;
+}
+
+// On Posix systems use `environ` to access environment variables
+// even before the C runtime is initialized.
+#[cfg(unix)]
+fn mi_getenv(name: &[u8], result: &mut MiEnvBuf) -> bool {
+    if name.is_empty() {
+        return false;
+    }
+    let env = unsafe { environ };
+    if env.is_null() {
+        return false;
+    }
+    // compare up to 256 entries
+    for i in 0..256 {
+        let s = unsafe { *env.add(i) };
+        if s.is_null() {
+            break;
+        }
+        let entry = unsafe { std::ffi::CStr::from_ptr(s) }.to_bytes();
+        if entry.len() > name.len()
+            && entry[..name.len()].eq_ignore_ascii_case(name) // case insensitive
+            && entry[name.len()] == b'='
+        {
+            // found it
+            result.push(&entry[name.len() + 1..]);
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(windows)]
+fn mi_getenv(name: &[u8], result: &mut MiEnvBuf) -> bool {
+    // `getenv` is case insensitive on windows
+    let mut cname = MiEnvBuf::new();
+    cname.push(name);
+    let s = unsafe { libc::getenv(cname.buf.as_ptr().cast()) };
+    if s.is_null() {
+        return false;
+    }
+    result.push(unsafe { std::ffi::CStr::from_ptr(s) }.to_bytes());
+    true
+}
+
+// Parse a leading (signed) decimal number like `strtol`; returns the value and the number of bytes used.
+fn mi_strtol(s: &[u8]) -> (i64, usize) {
+    let mut i = 0;
+    let negative = match s.first() {
+        Some(b'-') => {
+            i += 1;
+            true
+        }
+        Some(b'+') => {
+            i += 1;
+            false
+        }
+        _ => false,
+    };
+    let start = i;
+    let mut value: i64 = 0;
+    while i < s.len() && s[i].is_ascii_digit() {
+        value = value
+            .saturating_mul(10)
+            .saturating_add((s[i] - b'0') as i64);
+        i += 1;
+    }
+    if i == start {
+        return (0, 0); // no digits
+    }
+    (if negative { -value } else { value }, i)
+}
+
+// Parse an (upper case) option value; returns `None` if it is invalid.
+fn mi_option_parse(option: MiOption, s: &[u8]) -> Option<i64> {
+    if s.is_empty() || [&b"1"[..], b"TRUE", b"YES", b"ON"].contains(&s) {
+        return Some(1);
+    }
+    if [&b"0"[..], b"FALSE", b"NO", b"OFF"].contains(&s) {
+        return Some(0);
+    }
+    let (mut value, mut end) = mi_strtol(s);
+    if end == 0 {
+        return None;
+    }
+    if option == MiOption::MiOptionReserveOsMemory {
+        // this option is interpreted in KiB to prevent overflow of `long`
+        match s.get(end) {
+            Some(b'K') => end += 1,
+            Some(b'M') => {
+                value = value.saturating_mul(MI_KiB as i64);
+                end += 1;
+            }
+            Some(b'G') => {
+                value = value.saturating_mul(MI_MiB as i64);
+                end += 1;
+            }
+            _ => value = (value + MI_KiB as i64 - 1) / MI_KiB as i64,
+        }
+        if s[end..].starts_with(b"IB") {
+            end += 2;
+        } else if s.get(end) == Some(&b'B') {
+            end += 1;
+        }
+    }
+    if end == s.len() {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn mi_option_init(desc: &MiOptionDesc) {
+    let lock = mi_options_lock();
+    if desc.init() != MiInit::UNINIT {
+        return; // set explicitly (or initialized by another thread) in the meantime
+    }
+
+    // Read option value from the environment
+    let mut s = MiEnvBuf::new();
+    let mut name = MiEnvBuf::new();
+    name.push(b"mimalloc_");
+    name.push(desc.name.as_bytes());
+    let mut found = mi_getenv(name.as_bytes(), &mut s);
+    let mut deprecated = None;
+    if !found {
+        if let Some(legacy_name) = desc.legacy_name {
+            let mut name = MiEnvBuf::new();
+            name.push(b"mimalloc_");
+            name.push(legacy_name.as_bytes());
+            found = mi_getenv(name.as_bytes(), &mut s);
+            if found {
+                deprecated = Some(legacy_name);
+            }
+        }
+    }
+
+    let mut invalid = false;
+    if found {
+        s.buf[..s.len].make_ascii_uppercase();
+        match mi_option_parse(desc.option, s.as_bytes()) {
+            Some(value) => {
+                desc.value.store(value, Ordering::Relaxed);
+                desc.set_init(MiInit::INITIALIZED);
+            }
+            None => {
+                // set `init` first to avoid recursion through the warning message on mimalloc_verbose.
+                desc.set_init(MiInit::DEFAULTED);
+                invalid = true;
+            }
+        }
+        debug_assert!(desc.init() != MiInit::UNINIT);
+    } else {
+        desc.set_init(MiInit::DEFAULTED);
+    }
+
+    // warn after releasing the lock as messages read the `verbose` and `show_errors` options
+    drop(lock);
+    if let Some(legacy_name) = deprecated {
+        _mi_warning_message(format_args!(
+            "environment option \"mimalloc_{}\" is deprecated -- use \"mimalloc_{}\" instead.\n",
+            legacy_name, desc.name
+        ));
+    }
+    if invalid {
+        if desc.option == MiOption::MiOptionVerbose && desc.value.load(Ordering::Relaxed) == 0 {
+            // if the 'mimalloc_verbose' env var has a bogus value we'd never know
+            // (since the value defaults to 'off') so in that case briefly enable verbose
+            desc.value.store(1, Ordering::Relaxed);
+            _mi_warning_message(format_args!(
+                "environment option mimalloc_{} has an invalid value.\n",
+                desc.name
+            ));
+            desc.value.store(0, Ordering::Relaxed);
+        } else {
+            _mi_warning_message(format_args!(
+                "environment option mimalloc_{} has an invalid value.\n",
+                desc.name
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{c_void, CStr};
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    use libc::{c_char, c_int};
+
+    use crate::alloc_aligned::mi_malloc_aligned;
+    use crate::mimalloc_types::MiOption;
+
+    use super::{
+        _mi_fprintf, mi_option_desc_get, mi_option_desc_set, mi_option_desc_set_default,
+        mi_option_init, mi_option_parse, mi_options_lock, mi_register_error, mi_register_output,
+        MiInit, MiOptionDesc, MI_ERROR_ARG, MI_ERROR_HANDLER, MI_OPTIONS, MI_OPTION_LAST,
+        MI_OUT_ARG, MI_OUT_DEFAULT,
+    };
+
+    // The output and error handlers are process wide; tests that install their own
+    // hold this lock and restore the previous handler afterwards.
+    static HANDLER_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn test_options_table_matches_enum() {
+        for (i, desc) in MI_OPTIONS.iter().enumerate() {
+            assert_eq!(desc.option as usize, i, "{}", desc.name);
+        }
+    }
+
+    #[test]
+    fn test_option_values_match_c_abi() {
+        // the discriminants are part of the C ABI (`mi_option_t` in mimalloc.h)
+        assert_eq!(MiOption::MiOptionEagerCommit as i32, 3);
+        assert_eq!(MiOption::MiOptionLargeOsPages as i32, 6);
+        assert_eq!(MiOption::MiOptionReserveOsMemory as i32, 9);
+        assert_eq!(MiOption::MiOptionEagerCommitDelay as i32, 14);
+        assert_eq!(MiOption::MiOptionMaxErrors as i32, 19);
+        assert_eq!(MiOption::MiOptionAllowDecommit as i32, 22);
+        assert_eq!(MiOption::MiOptionDestroyOnExit as i32, 25);
+        assert_eq!(MiOption::MiOptionPrometheusListen as i32, 26);
+        assert_eq!(MI_OPTION_LAST, 27);
+        assert_eq!(MI_OPTIONS[MiOption::MiOptionOsTag as usize].name, "os_tag");
+    }
+
+    #[test]
+    fn test_mi_option_setters() {
+        // a private option so the global options are not changed
+        let desc = MiOptionDesc::new(0, MiOption::MiOptionVerbose, "test_setters");
+        // the default applies as long as the option is not set explicitly
+        mi_option_desc_set_default(&desc, 3);
+        assert_eq!(mi_option_desc_get(&desc), 3);
+        assert_eq!(desc.init(), MiInit::DEFAULTED);
+
+        mi_option_desc_set(&desc, 5);
+        assert_eq!(mi_option_desc_get(&desc), 5);
+        mi_option_desc_set_default(&desc, 7);
+        assert_eq!(mi_option_desc_get(&desc), 5);
+        mi_option_desc_set(&desc, 1);
+        assert_eq!(mi_option_desc_get(&desc), 1);
+        assert_eq!(desc.init(), MiInit::INITIALIZED);
+    }
+
+    #[test]
+    fn test_mi_option_parse() {
+        let opt = MiOption::MiOptionDecommitDelay;
+        assert_eq!(mi_option_parse(opt, b""), Some(1));
+        assert_eq!(mi_option_parse(opt, b"YES"), Some(1));
+        assert_eq!(mi_option_parse(opt, b"OFF"), Some(0));
+        assert_eq!(mi_option_parse(opt, b"250"), Some(250));
+        assert_eq!(mi_option_parse(opt, b"-1"), Some(-1));
+        assert_eq!(mi_option_parse(opt, b"2M"), None);
+        assert_eq!(mi_option_parse(opt, b"X"), None);
+
+        // sizes are in KiB
+        let opt = MiOption::MiOptionReserveOsMemory;
+        assert_eq!(mi_option_parse(opt, b"4096"), Some(4));
+        assert_eq!(mi_option_parse(opt, b"100K"), Some(100));
+        assert_eq!(mi_option_parse(opt, b"64KIB"), Some(64));
+        assert_eq!(mi_option_parse(opt, b"2MB"), Some(2 * 1024));
+        assert_eq!(mi_option_parse(opt, b"1GIB"), Some(1024 * 1024));
+        assert_eq!(mi_option_parse(opt, b"1GX"), None);
+    }
+
+    const TEST_ENV: [(&str, &str); 3] = [
+        ("mimalloc_Test_Option", "on"),
+        ("MIMALLOC_TEST_LEGACY", "42"),
+        ("MIMALLOC_TEST_INVALID", "fast"),
+    ];
+
+    #[test]
+    fn test_mi_option_init_from_environment() {
+        // the environment is read under the options lock, so hold it while changing it
+        {
+            let _lock = mi_options_lock();
+            for (name, value) in TEST_ENV {
+                std::env::set_var(name, value);
+            }
+        }
+
+        let desc = MiOptionDesc::new(0, MiOption::MiOptionVerbose, "test_option");
+        mi_option_init(&desc);
+        assert_eq!(desc.init(), MiInit::INITIALIZED);
+        assert_eq!(desc.value.load(Ordering::Relaxed), 1);
+
+        let desc = MiOptionDesc::legacy(7, MiOption::MiOptionVerbose, "test_unset", "test_legacy");
+        mi_option_init(&desc);
+        assert_eq!(desc.init(), MiInit::INITIALIZED);
+        assert_eq!(desc.value.load(Ordering::Relaxed), 42);
+
+        let desc = MiOptionDesc::new(3, MiOption::MiOptionVerbose, "test_invalid");
+        mi_option_init(&desc);
+        assert_eq!(desc.init(), MiInit::DEFAULTED);
+        assert_eq!(desc.value.load(Ordering::Relaxed), 3);
+
+        let desc = MiOptionDesc::new(5, MiOption::MiOptionVerbose, "test_missing");
+        mi_option_init(&desc);
+        assert_eq!(desc.init(), MiInit::DEFAULTED);
+        assert_eq!(desc.value.load(Ordering::Relaxed), 5);
+
+        let _lock = mi_options_lock();
+        for (name, _) in TEST_ENV {
+            std::env::remove_var(name);
+        }
+    }
+
+    static OUTPUT: Mutex<String> = Mutex::new(String::new());
+
+    unsafe extern "C" fn collect_output(msg: *const c_char, _arg: *mut c_void) {
+        let msg = CStr::from_ptr(msg).to_str().unwrap();
+        OUTPUT.lock().unwrap().push_str(msg);
+    }
+
+    #[test]
+    fn test_mi_register_output() {
+        let _lock = HANDLER_LOCK.lock().unwrap();
+        let (out, arg) = (
+            MI_OUT_DEFAULT.load(Ordering::Acquire),
+            MI_OUT_ARG.load(Ordering::Acquire),
+        );
+        unsafe { mi_register_output(Some(collect_output), ptr::null_mut()) };
+        _mi_fprintf(None, ptr::null_mut(), format_args!("hello {}\n", 42));
+        unsafe { mi_register_output(None, ptr::null_mut()) }; // back to stderr
+        _mi_fprintf(None, ptr::null_mut(), format_args!("not collected\n"));
+        MI_OUT_DEFAULT.store(out, Ordering::Release);
+        MI_OUT_ARG.store(arg, Ordering::Release);
+
+        let output = OUTPUT.lock().unwrap();
+        assert!(output.contains("hello 42\n"));
+        assert!(!output.contains("not collected"));
+    }
+
+    static OVERFLOW_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn count_errors(err: c_int, _arg: *mut c_void) {
+        if err == libc::EOVERFLOW {
+            OVERFLOW_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_mi_register_error() {
+        let _lock = HANDLER_LOCK.lock().unwrap();
+        let (handler, arg) = (
+            MI_ERROR_HANDLER.load(Ordering::Acquire),
+            MI_ERROR_ARG.load(Ordering::Acquire),
+        );
+        unsafe { mi_register_error(Some(count_errors), ptr::null_mut()) };
+        let p = mi_malloc_aligned(64, 24); // alignment is not a power of two
+        MI_ERROR_HANDLER.store(handler, Ordering::Release);
+        MI_ERROR_ARG.store(arg, Ordering::Release);
+
+        assert!(p.is_null());
+        assert!(OVERFLOW_ERRORS.load(Ordering::Relaxed) >= 1);
+    }
+}
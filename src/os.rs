@@ -1,30 +1,45 @@
 use std::{
+    cell::Cell,
     ffi::c_void,
-    mem::transmute,
-    ptr,
-    sync::atomic::{AtomicU32, AtomicUsize, Ordering},
+    io, ptr,
+    sync::atomic::{AtomicI32, AtomicU32, AtomicUsize, Ordering},
 };
 
+#[cfg(windows)]
+use std::mem::transmute;
+
+#[cfg(windows)]
 use windows::{
-    core::PCSTR,
     s,
     Win32::{
-        Foundation::{self, HINSTANCE},
+        Foundation,
         System::{
-            LibraryLoader::{FreeLibrary, GetProcAddress, LoadLibraryA, LoadLibraryW},
+            LibraryLoader::{FreeLibrary, GetProcAddress, LoadLibraryA},
             Memory::{
-                self, VirtualAlloc, MEM_ADDRESS_REQUIREMENTS, MEM_COMMIT, MEM_EXTENDED_PARAMETER,
-                MEM_LARGE_PAGES, MEM_RESERVE, PAGE_PROTECTION_FLAGS, VIRTUAL_ALLOCATION_TYPE,
+                VirtualAlloc, VirtualFree, VirtualProtect, VirtualQuery, MEMORY_BASIC_INFORMATION,
+                MEM_ADDRESS_REQUIREMENTS, MEM_COMMIT, MEM_DECOMMIT, MEM_EXTENDED_PARAMETER,
+                MEM_LARGE_PAGES, MEM_RELEASE, MEM_RESERVE, MEM_RESET, PAGE_NOACCESS,
+                PAGE_PROTECTION_FLAGS, PAGE_READWRITE, VIRTUAL_ALLOCATION_TYPE,
             },
             SystemInformation::{GetSystemInfo, SYSTEM_INFO},
         },
     },
 };
 
+#[cfg(windows)]
+use crate::mimalloc_types::BitfieldUnit;
 use crate::{
-    mimalloc_types::MiOption::MiOptionLargeOsPages,
-    mimalloc_types::{BitfieldUnit, MI_KiB, MI_MiB},
-    options::mi_option_is_enabled,
+    heap::_mi_heap_random_next,
+    mimalloc_internal::{_mi_align_down, get_default_heap},
+    mimalloc_types::MiOption::{self, MiOptionLargeOsPages},
+    mimalloc_types::{
+        MI_GiB, MI_KiB, MI_MiB, MiOsTLD, MiStats, MI_INTPTR_SIZE, MI_SECURE, MI_SEGMENT_SIZE,
+    },
+    options::{
+        _mi_error_message, _mi_verbose_message, _mi_warning_message, mi_option_get,
+        mi_option_is_enabled,
+    },
+    stats::{_mi_stat_counter_increase, _mi_stat_decrease, _mi_stat_increase, _mi_stats_main},
 };
 
 // page size (initialized properly in `os_init`)
@@ -36,6 +51,7 @@ pub static OS_ALLOC_GRANULARITY: AtomicU32 = AtomicU32::new(4096);
 // if non-zero, use large page allocation
 pub static LARGE_OS_PAGE_SIZE: AtomicU32 = AtomicU32::new(0);
 
+#[cfg(windows)]
 pub type ULONG = ::std::os::raw::c_ulong;
 
 // struct MiMemAddressRequirements {
@@ -74,6 +90,7 @@ pub type ULONG = ::std::os::raw::c_ulong;
 
 // type a = MEM_EXTENDED_PARAMETER;
 
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct MiMemExtendedParameter {
@@ -84,6 +101,7 @@ pub struct MiMemExtendedParameter {
 // #[repr(transparent)]
 // pub struct MiMemExtendedParameter(MEM_EXTENDED_PARAMETER);
 
+#[cfg(windows)]
 #[repr(C)]
 // #[repr(align(8))]
 #[derive(Debug, Copy, Clone)]
@@ -91,6 +109,7 @@ pub struct MiMemExtendedParameterBindgenTy1 {
     pub _bitfield_1: BitfieldUnit<[u8; 8usize], u64>,
 }
 
+#[cfg(windows)]
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub union MiMemExtendedParameterBindgenTy2 {
@@ -102,6 +121,7 @@ pub union MiMemExtendedParameterBindgenTy2 {
     _bindgen_union_align: u64,
 }
 
+#[cfg(windows)]
 impl MiMemExtendedParameterBindgenTy1 {
     #[inline]
     pub fn Type(&self) -> DWORD64 {
@@ -140,14 +160,91 @@ impl MiMemExtendedParameterBindgenTy1 {
     }
 }
 
+#[cfg(windows)]
 pub type DWORD64 = ::std::os::raw::c_ulonglong;
+#[cfg(windows)]
 pub type PVOID = *mut ::std::os::raw::c_void;
+#[cfg(windows)]
 pub type DWORD = ::std::os::raw::c_ulong;
+#[cfg(windows)]
 pub type ULONG_PTR = ::std::os::raw::c_ulonglong;
+#[cfg(windows)]
 pub type SIZE_T = ULONG_PTR;
+#[cfg(windows)]
 pub type HANDLE = *mut ::std::os::raw::c_void;
+#[cfg(windows)]
 pub type PHANDLE = *mut HANDLE;
 
+/* -----------------------------------------------------------
+  OS memory backend. All requests for virtual memory go through
+  a `MiOsBackend`; by default this is the platform backend which
+  calls `mmap`/`VirtualAlloc` and friends. A thread can temporarily
+  install its own backend (see `_mi_os_with_backend`) so segment and
+  arena logic can be tested without touching real OS memory.
+----------------------------------------------------------- */
+
+pub trait MiOsBackend: Sync {
+    // Reserve `size` bytes of virtual memory, preferably aligned to `try_alignment`.
+    // If `commit` is set the memory must be accessible on return. Sets `is_large`
+    // if the memory is backed by large OS pages.
+    fn reserve(
+        &self,
+        size: usize,
+        try_alignment: usize,
+        commit: bool,
+        allow_large: bool,
+        is_large: &mut bool,
+    ) -> *mut c_void;
+
+    // Make an OS page aligned range accessible.
+    fn commit(&self, addr: *mut c_void, size: usize) -> bool;
+
+    // Release the physical memory of an OS page aligned range and make it inaccessible.
+    fn decommit(&self, addr: *mut c_void, size: usize) -> bool;
+
+    // Release the physical memory of an OS page aligned range but keep it accessible.
+    fn reset(&self, addr: *mut c_void, size: usize) -> bool;
+
+    // Make an OS page aligned range inaccessible (`protect`) or accessible again.
+    fn protect(&self, addr: *mut c_void, size: usize, protect: bool) -> bool;
+
+    // Release a range previously returned from `reserve` or `reserve_huge` back to the OS.
+    fn free(&self, addr: *mut c_void, size: usize) -> bool;
+
+    // Reserve and commit `size` bytes of huge (1GiB) OS pages at `addr`, preferably on
+    // NUMA node `numa_node` (if >= 0). Returns NULL if no huge pages are available.
+    fn reserve_huge(&self, addr: *mut c_void, size: usize, numa_node: i32) -> *mut c_void;
+}
+
+// The default backend that allocates directly from the OS
+pub struct MiOsSystemBackend;
+
+static MI_OS_SYSTEM_BACKEND: MiOsSystemBackend = MiOsSystemBackend;
+
+thread_local! {
+    // backend override for the current thread (see `_mi_os_with_backend`)
+    static MI_OS_BACKEND_LOCAL: Cell<Option<&'static dyn MiOsBackend>> = const { Cell::new(None) };
+}
+
+fn mi_os_backend() -> &'static dyn MiOsBackend {
+    MI_OS_BACKEND_LOCAL
+        .with(|b| b.get())
+        .unwrap_or(&MI_OS_SYSTEM_BACKEND)
+}
+
+// Run `f` with all OS memory requests of the current thread routed to `backend`.
+pub fn _mi_os_with_backend<R>(backend: &'static dyn MiOsBackend, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<&'static dyn MiOsBackend>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            MI_OS_BACKEND_LOCAL.with(|b| b.set(self.0));
+        }
+    }
+
+    let _restore = Restore(MI_OS_BACKEND_LOCAL.with(|b| b.replace(Some(backend))));
+    f()
+}
+
 /* -----------------------------------------------------------
   OS aligned allocation with an offset. This is used
   for large alignments > MI_ALIGNMENT_MAX. We use a large mimalloc
@@ -156,37 +253,40 @@ pub type PHANDLE = *mut HANDLE;
   to use the actual start of the memory region.
 ----------------------------------------------------------- */
 
-fn _mi_os_alloc_aligned_offset(
+pub fn _mi_os_alloc_aligned_offset(
     size: usize,
     alignment: usize,
     offset: usize,
     commit: bool,
     large: *mut bool, /*mi_stats_t* tld_stats */
 ) -> *mut c_void {
-    debug_assert!(alignment % _mi_os_page_size() == 0);
+    debug_assert!(offset <= MI_SEGMENT_SIZE);
+    debug_assert!(offset == 0 || alignment.is_multiple_of(_mi_os_page_size()));
+    if offset > MI_SEGMENT_SIZE {
+        return ptr::null_mut();
+    }
     if offset == 0 {
         // regular aligned allocation
         return _mi_os_alloc_aligned(size, alignment, commit, large);
     }
-    // } else {
-    //     // overallocate to align at an offset
-    //     const size_t extra = _mi_align_up(offset, alignment) - offset;
-    //     const size_t oversize = size + extra;
-    //     void* start = _mi_os_alloc_aligned(oversize, alignment, commit, large, tld_stats);
-    //     if (start == NULL) return NULL;
-    //     void* p = (uint8_t*)start + extra;
-    //     mi_assert(_mi_is_aligned((uint8_t*)p + offset, alignment));
-    //     // decommit the overallocation at the start
-    //     if (commit && extra > _mi_os_page_size()) {
-    //       _mi_os_decommit(start, extra, tld_stats);
-    //     }
-    //     return p;
-    // }
 
-    ptr::null_mut()
+    // overallocate to align at an offset
+    let extra = _mi_align_up(offset, alignment) - offset;
+    let oversize = size + extra;
+    let start = _mi_os_alloc_aligned(oversize, alignment, commit, large);
+    if start.is_null() {
+        return ptr::null_mut();
+    }
+    let p = unsafe { (start as *mut u8).add(extra) };
+    debug_assert!((p as usize + offset).is_multiple_of(alignment));
+    // decommit the overallocation at the start
+    if commit && extra > _mi_os_page_size() {
+        _mi_os_decommit(start, extra);
+    }
+    p.cast()
 }
 
-fn _mi_os_alloc_aligned(
+pub fn _mi_os_alloc_aligned(
     size: usize,
     alignment: usize,
     commit: bool,
@@ -212,8 +312,17 @@ fn _mi_os_alloc_aligned(
     }
 }
 
+pub fn _mi_os_alloc(size: usize /*, mi_stats_t* tld_stats */) -> *mut c_void {
+    if size == 0 {
+        return ptr::null_mut();
+    }
+    let size = _mi_os_good_alloc_size(size);
+    let mut is_large = false;
+    mi_os_mem_alloc(size, 0, true, false, &mut is_large)
+}
+
 // OS (small) page size
-fn _mi_os_page_size() -> usize {
+pub fn _mi_os_page_size() -> usize {
     OS_PAGE_SIZE.load(Ordering::Relaxed) as usize
 }
 
@@ -230,7 +339,7 @@ pub fn _mi_align_up(sz: usize, alignment: usize) -> usize {
 }
 
 // round to a good OS allocation size (bounded by max 12.5% waste)
-fn _mi_os_good_alloc_size(size: usize) -> usize {
+pub fn _mi_os_good_alloc_size(size: usize) -> usize {
     let align_size;
     if size < 512 * MI_KiB as usize {
         align_size = _mi_os_page_size();
@@ -263,25 +372,29 @@ fn mi_os_mem_alloc_aligned(
     if !(alignment >= _mi_os_page_size() && ((alignment & (alignment - 1)) == 0)) {
         return ptr::null_mut();
     }
-    let size = _mi_align_up(size, alignment);
+    let size = _mi_align_up(size, _mi_os_page_size());
 
+    // try first with a hint (this will be aligned directly on Win 10+ or BSD)
     let mut p = mi_os_mem_alloc(size, alignment, commit, allow_large, is_large);
     if p.is_null() {
         return ptr::null_mut();
     }
 
     // if not aligned, free it, overallocate, and unmap around it
-    if p as usize & alignment != 0 {
+    if !(p as usize).is_multiple_of(alignment) {
         mi_os_mem_free(p, size, commit);
-        // TODO error log here
+        _mi_warning_message(format_args!(
+            "unable to allocate aligned OS memory directly, fall back to over-allocation ({} bytes, address: {:p}, alignment: {}, commit: {})\n",
+            size, p, alignment, commit
+        ));
         if size >= (usize::MAX - alignment) {
             // overflow
-            // TODO error log here
             return ptr::null_mut();
         }
         let over_size = size + alignment;
 
-        if cfg!(Win32) {
+        #[cfg(windows)]
+        {
             // over-allocate uncommitted (virtual) memory
             p = mi_os_mem_alloc(over_size, 0, false, false, is_large);
             if p.is_null() {
@@ -294,16 +407,89 @@ fn mi_os_mem_alloc_aligned(
             p = mi_align_up_ptr(p, alignment);
 
             if commit {
-                _mi_os_commit(p, over_size, ptr::null_mut());
+                _mi_os_commit(p, size, ptr::null_mut());
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            // overallocate...
+            p = mi_os_mem_alloc(over_size, 1, commit, false, is_large);
+            if p.is_null() {
+                return ptr::null_mut();
             }
+
+            // and selectively unmap parts around the over-allocated area.
+            let aligned_p = mi_align_up_ptr(p, alignment);
+            let pre_size = aligned_p as usize - p as usize;
+            let mid_size = _mi_align_up(size, _mi_os_page_size());
+            let post_size = over_size - pre_size - mid_size;
+            debug_assert!(pre_size < over_size && post_size < over_size && mid_size >= size);
+            if pre_size > 0 {
+                mi_os_mem_free(p, pre_size, commit);
+            }
+            if post_size > 0 {
+                mi_os_mem_free(
+                    unsafe { (aligned_p as *mut u8).add(mid_size) }.cast(),
+                    post_size,
+                    commit,
+                );
+            }
+            // we can return the aligned pointer on `mmap` systems
+            p = aligned_p;
         }
     }
 
-    debug_assert!(p.is_null() || (!p.is_null() && (p as usize % alignment) == 0));
+    debug_assert!(p.is_null() || (!p.is_null() && (p as usize).is_multiple_of(alignment)));
 
     p
 }
 
+/* -----------------------------------------------------------
+  OS memory API: commit, decommit.
+----------------------------------------------------------- */
+
+// OS page align within a given area, either conservative (pages inside the area only),
+// or not (straddling pages outside the area is possible)
+fn mi_os_page_align_areax(
+    conservative: bool,
+    addr: *mut c_void,
+    size: usize,
+    newsize: *mut usize,
+) -> *mut c_void {
+    debug_assert!(!addr.is_null() && size > 0);
+    if !newsize.is_null() {
+        unsafe { newsize.write(0) };
+    }
+    if size == 0 || addr.is_null() {
+        return ptr::null_mut();
+    }
+
+    // page align conservatively within the range
+    let start = if conservative {
+        _mi_align_up(addr as usize, _mi_os_page_size())
+    } else {
+        _mi_align_down(addr as usize, _mi_os_page_size())
+    };
+    let end = if conservative {
+        _mi_align_down(addr as usize + size, _mi_os_page_size())
+    } else {
+        _mi_align_up(addr as usize + size, _mi_os_page_size())
+    };
+    if end <= start {
+        return ptr::null_mut();
+    }
+    if !newsize.is_null() {
+        unsafe { newsize.write(end - start) };
+    }
+    start as *mut c_void
+}
+
+// The OS statistics are always kept in the main statistics (the `tld_stats` parameters are unused).
+fn mi_os_stats() -> *mut MiStats {
+    ptr::addr_of_mut!(_mi_stats_main)
+}
+
 pub fn _mi_os_commit(
     addr: *mut c_void,
     size: usize,
@@ -312,7 +498,17 @@ pub fn _mi_os_commit(
     // MI_UNUSED(tld_stats);
     // mi_stats_t * stats = &_mi_stats_main;
     mi_os_commitx(addr, size, true, false /* liberal */, is_zero)
-    //true
+}
+
+pub fn _mi_os_decommit(addr: *mut c_void, size: usize /*, mi_stats_t* tld_stats */) -> bool {
+    let mut is_zero = false;
+    mi_os_commitx(
+        addr,
+        size,
+        false,
+        true, /* conservative */
+        &mut is_zero,
+    )
 }
 
 fn mi_os_commitx(
@@ -322,7 +518,99 @@ fn mi_os_commitx(
     conservative: bool,
     is_zero: *mut bool, /*, mi_stats_t* stats */
 ) -> bool {
-    true
+    // page align in the range, commit liberally, decommit conservative
+    if !is_zero.is_null() {
+        unsafe { is_zero.write(false) };
+    }
+    let mut csize = 0;
+    let start = mi_os_page_align_areax(conservative, addr, size, &mut csize);
+    if csize == 0 {
+        return true;
+    }
+
+    let stats = mi_os_stats();
+    unsafe {
+        if commit {
+            _mi_stat_increase(ptr::addr_of_mut!((*stats).committed), size); // use size for precise commit vs. decommit
+            _mi_stat_counter_increase(ptr::addr_of_mut!((*stats).commit_calls), 1);
+        } else {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).committed), size);
+        }
+    }
+
+    let ok = if commit {
+        mi_os_backend().commit(start, csize)
+    } else {
+        mi_os_backend().decommit(start, csize)
+    };
+
+    if !ok {
+        let err = mi_os_last_error();
+        _mi_error_message(
+            err,
+            format_args!(
+                "{} error: start: {:p}, csize: 0x{:x}, err: {}\n",
+                if commit { "commit" } else { "decommit" },
+                start,
+                csize,
+                err
+            ),
+        );
+    }
+    ok
+}
+
+// Signal to the OS that the address range is no longer in use
+// but may be used later again. This will release physical memory
+// pages and reduce swapping while keeping the memory committed.
+// We page align to a conservative area inside the range to reset.
+pub fn _mi_os_reset(addr: *mut c_void, size: usize /*, mi_stats_t* stats */) -> bool {
+    // page align conservatively within the range
+    let mut csize = 0;
+    let start = mi_os_page_align_areax(true, addr, size, &mut csize);
+    if csize == 0 {
+        return true;
+    }
+
+    _mi_stat_increase(unsafe { ptr::addr_of_mut!((*mi_os_stats()).reset) }, csize);
+
+    let ok = mi_os_backend().reset(start, csize);
+    if !ok {
+        let err = mi_os_last_error();
+        _mi_warning_message(format_args!(
+            "madvise reset error: start: {:p}, csize: 0x{:x}, errno: {}\n",
+            start, csize, err
+        ));
+    }
+    ok
+}
+
+// Protect a region in memory to be not accessible.
+fn mi_os_protectx(addr: *mut c_void, size: usize, protect: bool) -> bool {
+    // page align conservatively within the range
+    let mut csize = 0;
+    let start = mi_os_page_align_areax(true, addr, size, &mut csize);
+    if csize == 0 {
+        return false;
+    }
+
+    let ok = mi_os_backend().protect(start, csize, protect);
+    if !ok {
+        let err = mi_os_last_error();
+        _mi_warning_message(format_args!(
+            "mprotect error: start: {:p}, csize: 0x{:x}, err: {}\n",
+            start, csize, err
+        ));
+    }
+    ok
+}
+
+pub fn _mi_os_protect(addr: *mut c_void, size: usize) -> bool {
+    mi_os_protectx(addr, size, true)
+}
+
+pub fn _mi_os_unprotect(addr: *mut c_void, size: usize) -> bool {
+    mi_os_protectx(addr, size, false)
 }
 
 fn mi_align_up_ptr(p: *mut c_void, alignment: usize) -> *mut c_void {
@@ -336,31 +624,28 @@ fn mi_os_mem_alloc(
     allow_large: bool,
     is_large: *mut bool, /*mi_stats_t* stats*/
 ) -> *mut c_void {
-    debug_assert!(size > 0 && (size & _mi_os_page_size()) == 0);
+    debug_assert!(size > 0 && size.is_multiple_of(_mi_os_page_size()));
     if size == 0 {
         return ptr::null_mut();
     }
     let allow_large = if !commit { false } else { allow_large };
     let try_alignment = if try_alignment == 0 { 1 } else { try_alignment };
-    let mut p = ptr::null_mut();
 
-    if cfg!(windows) {
-        let mut flags = MEM_RESERVE;
-        if commit {
-            flags |= MEM_COMMIT;
-        }
+    debug_assert!(!is_large.is_null());
+    let p = mi_os_backend().reserve(size, try_alignment, commit, allow_large, unsafe {
+        &mut *is_large
+    });
 
-        p = mi_win_virtual_alloc(
-            ptr::null_mut(),
-            size,
-            try_alignment,
-            flags.0,
-            false,
-            allow_large,
-            is_large,
-        );
+    let stats = mi_os_stats();
+    unsafe {
+        _mi_stat_counter_increase(ptr::addr_of_mut!((*stats).mmap_calls), 1);
+        if !p.is_null() {
+            _mi_stat_increase(ptr::addr_of_mut!((*stats).reserved), size);
+            if commit {
+                _mi_stat_increase(ptr::addr_of_mut!((*stats).committed), size);
+            }
+        }
     }
-
     p
 }
 
@@ -372,10 +657,11 @@ fn use_large_os_page(size: usize, alignment: usize) -> bool {
         return false;
     }
 
-    (size as u32 % LARGE_OS_PAGE_SIZE.load(Ordering::Relaxed)) == 0
-        && (alignment as u32 % LARGE_OS_PAGE_SIZE.load(Ordering::Relaxed)) == 0
+    (size as u32).is_multiple_of(LARGE_OS_PAGE_SIZE.load(Ordering::Relaxed))
+        && (alignment as u32).is_multiple_of(LARGE_OS_PAGE_SIZE.load(Ordering::Relaxed))
 }
 
+#[cfg(windows)]
 fn mi_win_virtual_alloc(
     addr: *mut c_void,
     size: usize,
@@ -427,7 +713,16 @@ fn mi_win_virtual_alloc(
     }
 
     if p.is_null() {
-        // TODO error log here
+        _mi_warning_message(format_args!(
+            "unable to allocate OS memory ({} bytes, error code: 0x{:x}, address: {:p}, alignment: {}, flags: 0x{:x}, large only: {}, allow large: {})\n",
+            size,
+            mi_os_last_error(),
+            addr,
+            try_alignment,
+            flags,
+            large_only,
+            allow_large
+        ));
     }
 
     p
@@ -441,8 +736,7 @@ fn mi_win_virtual_allocx(
     flags: u32,
 ) -> *mut c_void {
     use windows::Win32::System::{
-        Memory::{MemExtendedParameterAddressRequirements, PAGE_READWRITE},
-        Threading::GetCurrentProcess,
+        Memory::MemExtendedParameterAddressRequirements, Threading::GetCurrentProcess,
     };
 
     if cfg!(target_pointer_width = "64") {
@@ -452,7 +746,7 @@ fn mi_win_virtual_allocx(
             if !hint.is_null() {
                 let p = unsafe {
                     VirtualAlloc(
-                        Some(hint),
+                        Some(hint as *const c_void),
                         size,
                         VIRTUAL_ALLOCATION_TYPE(flags),
                         PAGE_READWRITE,
@@ -462,7 +756,15 @@ fn mi_win_virtual_allocx(
                 if !p.is_null() {
                     return p;
                 }
-                // TODO error log here
+                // for robustness always fall through in case of an error
+                _mi_warning_message(format_args!(
+                    "unable to allocate hinted aligned OS memory ({} bytes, error code: 0x{:x}, address: {:p}, alignment: {}, flags: 0x{:x})\n",
+                    size,
+                    mi_os_last_error(),
+                    hint,
+                    try_alignment,
+                    flags
+                ));
             }
         }
     }
@@ -509,9 +811,14 @@ fn mi_win_virtual_allocx(
         if !p.is_null() {
             return p;
         }
-        println!("virtual_alloc2 failed");
-
-        // _mi_warning_message("unable to allocate aligned OS memory (%zu bytes, error code: 0x%x, address: %p, alignment: %zu, flags: 0x%x)\n", size, GetLastError(), addr, try_alignment, flags);
+        _mi_warning_message(format_args!(
+            "unable to allocate aligned OS memory ({} bytes, error code: 0x{:x}, address: {:p}, alignment: {}, flags: 0x{:x})\n",
+            size,
+            mi_os_last_error(),
+            addr,
+            try_alignment,
+            flags
+        ));
         // fall through on error
     }
     // last resort
@@ -525,11 +832,8 @@ fn mi_win_virtual_allocx(
     }
 }
 
-fn mi_os_get_aligned_hint(try_alignment: usize, size: usize) -> *const c_void {
-    ptr::null_mut()
-}
-
-type PVirtualAlloc2 = unsafe extern "stdcall" fn(
+#[cfg(windows)]
+type PVirtualAlloc2 = unsafe extern "system" fn(
     Foundation::HANDLE,
     PVOID,
     SIZE_T,
@@ -539,8 +843,10 @@ type PVirtualAlloc2 = unsafe extern "stdcall" fn(
     ULONG,
 ) -> PVOID;
 
+#[cfg(windows)]
 pub static mut P_VIRTUAL_ALLOC2: Option<PVirtualAlloc2> = None;
 
+#[cfg(windows)]
 pub fn _mi_os_init() {
     let mut si = SYSTEM_INFO::default();
     unsafe {
@@ -569,6 +875,457 @@ pub fn _mi_os_init() {
     }
 }
 
+/* -----------------------------------------------------------
+  Use the virtual address area after 2TiB for 4MiB aligned allocations
+----------------------------------------------------------- */
+
+#[cfg(target_pointer_width = "64")]
+static ALIGNED_BASE: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(target_pointer_width = "64")]
+const MI_HINT_BASE: usize = 2 << 40; // 2TiB start, the hint area is upto 6TiB (since before win8 there is "only" 8TiB available to processes)
+#[cfg(target_pointer_width = "64")]
+const MI_HINT_MAX: usize = 30 << 40; // wrap after 30TiB (area after 32TiB is used for huge OS pages)
+
+// Return a MI_SEGMENT_SIZE aligned address that is probably available.
+// If this returns NULL, the OS will determine the address but on some OS's that may not be
+// properly aligned which can be more costly as it needs to be adjusted afterwards.
+// For a size > 1GiB this always returns NULL in order to guarantee good ASLR randomization;
+// (otherwise an initial large allocation of say 2TiB has a 50% chance to include (known) addresses
+//  in the middle of the 2TiB - 6TiB address range (see issue #372))
+#[cfg(target_pointer_width = "64")]
+fn mi_os_get_aligned_hint(try_alignment: usize, size: usize) -> *mut c_void {
+    if try_alignment <= 1 || try_alignment > MI_SEGMENT_SIZE {
+        return ptr::null_mut();
+    }
+    let size = _mi_align_up(size, MI_SEGMENT_SIZE);
+    if size > MI_GiB as usize {
+        // guarantee the chance of fixed valid address is at most 1/(4TiB / 1<<30) = 1/4096.
+        return ptr::null_mut();
+    }
+
+    let mut hint = ALIGNED_BASE.fetch_add(size, Ordering::AcqRel);
+    if hint == 0 || hint > MI_HINT_MAX {
+        // wrap or initialize
+        let init = MI_HINT_BASE;
+        let _ =
+            ALIGNED_BASE.compare_exchange(hint + size, init, Ordering::AcqRel, Ordering::Acquire);
+        // this may still give 0 or > MI_HINT_MAX but that is ok, it is a hint after all
+        hint = ALIGNED_BASE.fetch_add(size, Ordering::AcqRel);
+    }
+    if !hint.is_multiple_of(try_alignment) {
+        return ptr::null_mut();
+    }
+    hint as *mut c_void
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+fn mi_os_get_aligned_hint(_try_alignment: usize, _size: usize) -> *mut c_void {
+    ptr::null_mut()
+}
+
+/* -----------------------------------------------------------
+  Raw allocation on Unix's (mmap)
+----------------------------------------------------------- */
+
+#[cfg(unix)]
+fn mi_unix_mmapx(
+    addr: *mut c_void,
+    size: usize,
+    try_alignment: usize,
+    protect_flags: i32,
+    flags: i32,
+    fd: i32,
+) -> *mut c_void {
+    if cfg!(target_pointer_width = "64") && addr.is_null() {
+        // on 64-bit systems, use the virtual address area after 2TiB for 4MiB aligned allocations
+        let hint = mi_os_get_aligned_hint(try_alignment, size);
+        if !hint.is_null() {
+            let p = unsafe { libc::mmap(hint, size, protect_flags, flags, fd, 0) };
+            if p == libc::MAP_FAILED || !(p as usize).is_multiple_of(try_alignment) {
+                let err = mi_os_last_error();
+                _mi_warning_message(format_args!(
+                    "unable to directly request hinted aligned OS memory (error: {} (0x{:x}), size: 0x{:x} bytes, alignment: 0x{:x}, hint address: {:p})\n",
+                    err, err, size, try_alignment, hint
+                ));
+            }
+            if p != libc::MAP_FAILED {
+                return p;
+            }
+            // fall back to regular mmap
+        }
+    }
+    // regular mmap
+    let p = unsafe { libc::mmap(addr, size, protect_flags, flags, fd, 0) };
+    if p != libc::MAP_FAILED {
+        return p;
+    }
+    // failed to allocate
+    ptr::null_mut()
+}
+
+#[cfg(unix)]
+fn mi_unix_mmap(
+    addr: *mut c_void,
+    size: usize,
+    try_alignment: usize,
+    protect_flags: i32,
+    large_only: bool,
+    allow_large: bool,
+    is_large: *mut bool,
+) -> *mut c_void {
+    static LARGE_PAGE_TRY_OK: AtomicUsize = AtomicUsize::new(0);
+    #[cfg(target_os = "linux")]
+    static HUGE_PAGES_AVAILABLE: std::sync::atomic::AtomicBool =
+        std::sync::atomic::AtomicBool::new(true);
+
+    let mut p = ptr::null_mut();
+    #[cfg(target_os = "macos")]
+    let fd = {
+        // macOS: tracking anonymous page with a specific ID. (All up to 98 are taken officially but LLVM sanitizers had taken 99)
+        let mut os_tag = mi_option_get(MiOption::MiOptionOsTag) as i32;
+        if !(100..=255).contains(&os_tag) {
+            os_tag = 100;
+        }
+        os_tag << 24 // VM_MAKE_TAG(os_tag)
+    };
+    #[cfg(not(target_os = "macos"))]
+    let fd = -1;
+    #[cfg(target_os = "linux")]
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE;
+    #[cfg(not(target_os = "linux"))]
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANON;
+
+    if (large_only || use_large_os_page(size, try_alignment)) && allow_large {
+        let try_ok = LARGE_PAGE_TRY_OK.load(Ordering::Acquire);
+        if !large_only && try_ok > 0 {
+            // If the OS is not configured for large OS pages, or the user does not have
+            // enough permission, the `mmap` will always fail (but it might also fail for other reasons).
+            // Therefore, once a large page allocation failed, we don't try again for `large_page_try_ok` times
+            // to avoid too many failing calls to mmap.
+            let _ = LARGE_PAGE_TRY_OK.compare_exchange(
+                try_ok,
+                try_ok - 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            );
+        } else {
+            #[cfg(target_os = "linux")]
+            {
+                // using NORESERVE on huge pages seems to fail on Linux
+                let mut lflags = (flags & !libc::MAP_NORESERVE) | libc::MAP_HUGETLB;
+                if size.is_multiple_of(MI_GiB as usize)
+                    && HUGE_PAGES_AVAILABLE.load(Ordering::Relaxed)
+                {
+                    lflags |= libc::MAP_HUGE_1GB;
+                } else {
+                    lflags |= libc::MAP_HUGE_2MB;
+                }
+
+                // try large OS page allocation
+                unsafe { is_large.write(true) };
+                p = mi_unix_mmapx(addr, size, try_alignment, protect_flags, lflags, fd);
+                if p.is_null() && (lflags & libc::MAP_HUGE_1GB) != 0 {
+                    // don't try huge 1GiB pages again
+                    HUGE_PAGES_AVAILABLE.store(false, Ordering::Relaxed);
+                    _mi_warning_message(format_args!(
+                        "unable to allocate huge (1GiB) page, trying large (2MiB) pages instead (error {})\n",
+                        mi_os_last_error()
+                    ));
+                    lflags = (lflags & !libc::MAP_HUGE_1GB) | libc::MAP_HUGE_2MB;
+                    p = mi_unix_mmapx(addr, size, try_alignment, protect_flags, lflags, fd);
+                }
+                if large_only {
+                    return p;
+                }
+                if p.is_null() {
+                    // on error, don't try again for the next N allocations
+                    LARGE_PAGE_TRY_OK.store(8, Ordering::Release);
+                }
+            }
+        }
+    }
+
+    // regular allocation
+    if p.is_null() {
+        unsafe { is_large.write(false) };
+        p = mi_unix_mmapx(addr, size, try_alignment, protect_flags, flags, fd);
+        #[cfg(target_os = "linux")]
+        if !p.is_null() && allow_large && use_large_os_page(size, try_alignment) {
+            // Many Linux systems don't allow MAP_HUGETLB but they support instead
+            // transparent huge pages (THP). Generally, it is not required to call `madvise` with MADV_HUGE
+            // though since properly aligned allocations will already use large pages if available
+            // in that case. However, some systems only allow THP if called with explicit `madvise`, so
+            // when large OS pages are enabled for mimalloc, we call `madvise` anyways.
+            if unsafe { libc::madvise(p, size, libc::MADV_HUGEPAGE) } == 0 {
+                unsafe { is_large.write(true) }; // possibly
+            }
+        }
+    }
+
+    if p.is_null() {
+        _mi_warning_message(format_args!(
+            "unable to allocate OS memory ({} bytes, error code: {}, address: {:p}, large only: {}, allow large: {})\n",
+            size,
+            mi_os_last_error(),
+            addr,
+            large_only,
+            allow_large
+        ));
+    }
+
+    p
+}
+
+#[cfg(unix)]
+pub fn _mi_os_init() {
+    // get the page size
+    let result = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if result > 0 {
+        OS_PAGE_SIZE.store(result as u32, Ordering::Relaxed);
+        OS_ALLOC_GRANULARITY.store(result as u32, Ordering::Relaxed);
+    }
+    LARGE_OS_PAGE_SIZE.store(2 * MI_MiB, Ordering::Relaxed); // TODO: can we query the OS for this?
+}
+
+/* -----------------------------------------------------------
+  The platform backend
+----------------------------------------------------------- */
+
+impl MiOsBackend for MiOsSystemBackend {
+    #[cfg(windows)]
+    fn reserve(
+        &self,
+        size: usize,
+        try_alignment: usize,
+        commit: bool,
+        allow_large: bool,
+        is_large: &mut bool,
+    ) -> *mut c_void {
+        let mut flags = MEM_RESERVE;
+        if commit {
+            flags |= MEM_COMMIT;
+        }
+
+        mi_win_virtual_alloc(
+            ptr::null_mut(),
+            size,
+            try_alignment,
+            flags.0,
+            false,
+            allow_large,
+            is_large,
+        )
+    }
+
+    #[cfg(unix)]
+    fn reserve(
+        &self,
+        size: usize,
+        try_alignment: usize,
+        commit: bool,
+        allow_large: bool,
+        is_large: &mut bool,
+    ) -> *mut c_void {
+        let protect_flags = if commit {
+            libc::PROT_WRITE | libc::PROT_READ
+        } else {
+            libc::PROT_NONE
+        };
+        mi_unix_mmap(
+            ptr::null_mut(),
+            size,
+            try_alignment,
+            protect_flags,
+            false,
+            allow_large,
+            is_large,
+        )
+    }
+
+    #[cfg(windows)]
+    fn commit(&self, addr: *mut c_void, size: usize) -> bool {
+        // note: if the memory was already committed, the call succeeds but the memory is not zero'd
+        let p = unsafe { VirtualAlloc(Some(addr), size, MEM_COMMIT, PAGE_READWRITE) };
+        p == addr
+    }
+
+    #[cfg(unix)]
+    fn commit(&self, addr: *mut c_void, size: usize) -> bool {
+        // commit: ensure we can access the area
+        unsafe { libc::mprotect(addr, size, libc::PROT_READ | libc::PROT_WRITE) == 0 }
+    }
+
+    #[cfg(windows)]
+    fn decommit(&self, addr: *mut c_void, size: usize) -> bool {
+        unsafe { VirtualFree(addr, size, MEM_DECOMMIT).as_bool() }
+    }
+
+    #[cfg(unix)]
+    fn decommit(&self, addr: *mut c_void, size: usize) -> bool {
+        if cfg!(debug_assertions) || MI_SECURE != 0 {
+            // decommit: just disable access (also used in debug and secure mode to trap on illegal access)
+            unsafe { libc::mprotect(addr, size, libc::PROT_NONE) == 0 }
+        } else {
+            // decommit: use MADV_DONTNEED as it decreases rss immediately (unlike MADV_FREE)
+            unsafe { libc::madvise(addr, size, libc::MADV_DONTNEED) == 0 }
+        }
+    }
+
+    #[cfg(windows)]
+    fn reset(&self, addr: *mut c_void, size: usize) -> bool {
+        let p = unsafe { VirtualAlloc(Some(addr), size, MEM_RESET, PAGE_READWRITE) };
+        p == addr
+    }
+
+    #[cfg(unix)]
+    fn reset(&self, addr: *mut c_void, size: usize) -> bool {
+        #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+        {
+            static ADVICE: AtomicI32 = AtomicI32::new(libc::MADV_FREE);
+            let advice = ADVICE.load(Ordering::Relaxed);
+            if unsafe { libc::madvise(addr, size, advice) } == 0 {
+                return true;
+            }
+            if advice == libc::MADV_FREE
+                && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL)
+            {
+                // if MADV_FREE is not supported, fall back to MADV_DONTNEED from now on
+                ADVICE.store(libc::MADV_DONTNEED, Ordering::Relaxed);
+                return unsafe { libc::madvise(addr, size, libc::MADV_DONTNEED) } == 0;
+            }
+            false
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+        unsafe {
+            libc::madvise(addr, size, libc::MADV_DONTNEED) == 0
+        }
+    }
+
+    #[cfg(windows)]
+    fn protect(&self, addr: *mut c_void, size: usize, protect: bool) -> bool {
+        let mut oldprotect = PAGE_PROTECTION_FLAGS(0);
+        let newprotect = if protect {
+            PAGE_NOACCESS
+        } else {
+            PAGE_READWRITE
+        };
+        unsafe { VirtualProtect(addr, size, newprotect, &mut oldprotect).as_bool() }
+    }
+
+    #[cfg(unix)]
+    fn protect(&self, addr: *mut c_void, size: usize, protect: bool) -> bool {
+        let prot = if protect {
+            libc::PROT_NONE
+        } else {
+            libc::PROT_READ | libc::PROT_WRITE
+        };
+        unsafe { libc::mprotect(addr, size, prot) == 0 }
+    }
+
+    #[cfg(windows)]
+    fn free(&self, addr: *mut c_void, _size: usize) -> bool {
+        let mut ok = unsafe { VirtualFree(addr, 0, MEM_RELEASE).as_bool() };
+        if !ok {
+            // In mi_os_mem_alloc_aligned the fallback path may have returned a pointer inside
+            // the memory region returned by VirtualAlloc; in that case we need to free using
+            // the start of the region.
+            let mut info = MEMORY_BASIC_INFORMATION::default();
+            unsafe {
+                VirtualQuery(
+                    Some(addr),
+                    &mut info,
+                    std::mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+                );
+            }
+            if (info.AllocationBase as usize) < addr as usize
+                && addr as usize - (info.AllocationBase as usize) < MI_SEGMENT_SIZE
+            {
+                ok = unsafe { VirtualFree(info.AllocationBase, 0, MEM_RELEASE).as_bool() };
+            }
+        }
+        ok
+    }
+
+    #[cfg(unix)]
+    fn free(&self, addr: *mut c_void, size: usize) -> bool {
+        unsafe { libc::munmap(addr, size) == 0 }
+    }
+
+    #[cfg(windows)]
+    fn reserve_huge(&self, addr: *mut c_void, size: usize, _numa_node: i32) -> *mut c_void {
+        debug_assert!(size.is_multiple_of(MI_GiB as usize));
+        debug_assert!(!addr.is_null());
+        // TODO: use `VirtualAlloc2` with a `MemExtendedParameterNumaNode` to allocate on the numa node
+        let flags = MEM_LARGE_PAGES.0 | MEM_COMMIT.0 | MEM_RESERVE.0;
+        let mut is_large = true;
+        mi_win_virtual_alloc(
+            addr,
+            size,
+            MI_SEGMENT_SIZE,
+            flags,
+            true,
+            true,
+            &mut is_large,
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reserve_huge(&self, addr: *mut c_void, size: usize, numa_node: i32) -> *mut c_void {
+        debug_assert!(size.is_multiple_of(MI_GiB as usize));
+        let mut is_large = true;
+        let p = mi_unix_mmap(
+            addr,
+            size,
+            MI_SEGMENT_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            true,
+            true,
+            &mut is_large,
+        );
+        if p.is_null() {
+            return ptr::null_mut();
+        }
+        if numa_node >= 0 && (numa_node as usize) < 8 * MI_INTPTR_SIZE {
+            // at most 64 nodes
+            const MPOL_PREFERRED: libc::c_ulong = 1;
+            let numa_mask: libc::c_ulong = 1 << numa_node;
+            // TODO: does `mbind` work correctly for huge OS pages? should we
+            // use `set_mempolicy` before calling mmap instead?
+            // see: <https://lkml.org/lkml/2017/2/9/875>
+            let err = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    p,
+                    size as libc::c_ulong,
+                    MPOL_PREFERRED,
+                    &numa_mask as *const libc::c_ulong,
+                    (8 * MI_INTPTR_SIZE) as libc::c_ulong,
+                    0 as libc::c_uint,
+                )
+            };
+            if err != 0 {
+                _mi_warning_message(format_args!(
+                    "failed to bind huge (1GiB) pages to numa node {}: {}\n",
+                    numa_node,
+                    io::Error::last_os_error()
+                ));
+            }
+        }
+        p
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn reserve_huge(&self, _addr: *mut c_void, _size: usize, _numa_node: i32) -> *mut c_void {
+        ptr::null_mut()
+    }
+}
+
+// The error code of the last failed OS call (`errno` or `GetLastError()`)
+fn mi_os_last_error() -> i32 {
+    io::Error::last_os_error().raw_os_error().unwrap_or(0)
+}
+
 /* -----------------------------------------------------------
   Free memory
 -------------------------------------------------------------- */
@@ -578,25 +1335,341 @@ fn mi_os_mem_free(
     size: usize,
     was_committed: bool, /* , mi_stats_t* stats*/
 ) -> bool {
-    true
+    if addr.is_null() || size == 0 {
+        return true;
+    }
+
+    let ok = mi_os_backend().free(addr, size);
+    let stats = mi_os_stats();
+    unsafe {
+        if was_committed {
+            _mi_stat_decrease(ptr::addr_of_mut!((*stats).committed), size);
+        }
+        _mi_stat_decrease(ptr::addr_of_mut!((*stats).reserved), size);
+    }
+    if !ok {
+        let err = mi_os_last_error();
+        _mi_warning_message(format_args!(
+            "unable to free OS memory (error: {} (0x{:x}), size: 0x{:x} bytes, address: {:p})\n",
+            err, err, size, addr
+        ));
+    }
+    ok
 }
 
-#[cfg(test)]
-mod tests {
-    use std::{mem::size_of, os::raw::c_void, ptr};
+pub fn _mi_os_free_ex(
+    p: *mut c_void,
+    size: usize,
+    was_committed: bool, /*, mi_stats_t* tld_stats */
+) {
+    let size = _mi_os_good_alloc_size(size);
+    mi_os_mem_free(p, size, was_committed);
+}
 
-    use windows::Win32::System::{
-        Memory::{MEM_COMMIT, MEM_EXTENDED_PARAMETER, MEM_RESERVE},
-        Threading::GetCurrentProcess,
+pub fn _mi_os_free(p: *mut c_void, size: usize /*, mi_stats_t* tld_stats */) {
+    _mi_os_free_ex(p, size, true);
+}
+
+// free memory allocated by `_mi_os_alloc_aligned_offset`
+pub fn _mi_os_free_aligned(
+    p: *mut c_void,
+    size: usize,
+    alignment: usize,
+    align_offset: usize,
+    was_committed: bool, /*, mi_stats_t* tld_stats */
+) {
+    debug_assert!(align_offset <= MI_SEGMENT_SIZE);
+    let extra = _mi_align_up(align_offset, alignment) - align_offset;
+    let start = unsafe { (p as *mut u8).sub(extra) };
+    _mi_os_free_ex(start.cast(), size + extra, was_committed);
+}
+
+/* ----------------------------------------------------------------------------
+  Support for allocating huge OS pages (1Gib) that are reserved up-front
+  and possibly associated with a specific NUMA node. (use `numa_node>=0`)
+-----------------------------------------------------------------------------*/
+
+const MI_HUGE_OS_PAGE_SIZE: usize = MI_GiB as usize;
+
+// To ensure proper alignment, use our own area for huge OS pages
+#[cfg(target_pointer_width = "64")]
+static MI_HUGE_START: AtomicUsize = AtomicUsize::new(0); // = 0
+
+// Claim an aligned address range for huge pages
+#[cfg(target_pointer_width = "64")]
+fn mi_os_claim_huge_pages(pages: usize, total_size: *mut usize) -> *mut u8 {
+    if !total_size.is_null() {
+        unsafe { *total_size = 0 };
+    }
+    let size = pages * MI_HUGE_OS_PAGE_SIZE;
+
+    let mut huge_start = MI_HUGE_START.load(Ordering::Relaxed);
+    loop {
+        let mut start = huge_start;
+        if start == 0 {
+            // Initialize the start address after the 32TiB area
+            start = 32 << 40; // 32TiB virtual start address
+            if MI_SECURE != 0 || !cfg!(debug_assertions) {
+                // security: randomize start of huge pages unless in debug mode
+                let r = _mi_heap_random_next(get_default_heap());
+                start += MI_HUGE_OS_PAGE_SIZE * ((r >> 17) & 0x0FFF); // (randomly 12bits)*1GiB == between 0 to 4TiB
+            }
+        }
+        let end = start + size;
+        debug_assert!(end.is_multiple_of(MI_SEGMENT_SIZE));
+        match MI_HUGE_START.compare_exchange(huge_start, end, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => {
+                if !total_size.is_null() {
+                    unsafe { *total_size = size };
+                }
+                return start as *mut u8;
+            }
+            Err(current) => huge_start = current,
+        }
+    }
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+fn mi_os_claim_huge_pages(_pages: usize, total_size: *mut usize) -> *mut u8 {
+    if !total_size.is_null() {
+        unsafe { *total_size = 0 };
+    }
+    ptr::null_mut()
+}
+
+// Allocate MI_SEGMENT_SIZE aligned huge pages
+pub fn _mi_os_alloc_huge_os_pages(
+    pages: usize,
+    numa_node: i32,
+    max_msecs: i64,
+    pages_reserved: *mut usize,
+    psize: *mut usize,
+) -> *mut c_void {
+    if !psize.is_null() {
+        unsafe { *psize = 0 };
+    }
+    if !pages_reserved.is_null() {
+        unsafe { *pages_reserved = 0 };
+    }
+    let mut size = 0;
+    let start = mi_os_claim_huge_pages(pages, &mut size);
+    if start.is_null() {
+        return ptr::null_mut(); // or 32-bit systems
+    }
+
+    // Allocate one page at the time but try to place them contiguously
+    // We allocate one page at the time to be able to abort if it takes too long
+    // or to at least allocate as many as available on the system.
+    let start_t = _mi_clock_now();
+    let mut page = 0;
+    while page < pages {
+        // allocate a page
+        let addr: *mut c_void = unsafe { start.add(page * MI_HUGE_OS_PAGE_SIZE) }.cast();
+        let p = mi_os_backend().reserve_huge(addr, MI_HUGE_OS_PAGE_SIZE, numa_node);
+
+        // Did we succeed at a contiguous address?
+        if p != addr {
+            // no success, issue a warning and break
+            if !p.is_null() {
+                _mi_warning_message(format_args!(
+                    "could not allocate contiguous huge page {} at {:p}\n",
+                    page, addr
+                ));
+                _mi_os_free(p, MI_HUGE_OS_PAGE_SIZE);
+            }
+            break;
+        }
+
+        // success, record it
+        let stats = mi_os_stats();
+        unsafe {
+            _mi_stat_increase(ptr::addr_of_mut!((*stats).committed), MI_HUGE_OS_PAGE_SIZE);
+            _mi_stat_increase(ptr::addr_of_mut!((*stats).reserved), MI_HUGE_OS_PAGE_SIZE);
+        }
+        page += 1;
+
+        // check for timeout
+        if max_msecs > 0 {
+            let mut elapsed = _mi_clock_now() - start_t;
+            if page >= 2 {
+                let estimate = (elapsed / page as i64) * pages as i64;
+                if estimate > 2 * max_msecs {
+                    // seems like we are going to timeout, break
+                    elapsed = max_msecs + 1;
+                }
+            }
+            if elapsed > max_msecs {
+                _mi_warning_message(format_args!("huge page allocation timed out\n"));
+                break;
+            }
+        }
+    }
+    debug_assert!(page * MI_HUGE_OS_PAGE_SIZE <= size);
+    if !pages_reserved.is_null() {
+        unsafe { *pages_reserved = page };
+    }
+    if !psize.is_null() {
+        unsafe { *psize = page * MI_HUGE_OS_PAGE_SIZE };
+    }
+    if page == 0 {
+        ptr::null_mut()
+    } else {
+        start.cast()
+    }
+}
+
+// free every huge page in a range individually (as we allocated per page)
+// note: needed with VirtualAlloc but could potentially be done in one go on mmap'd systems.
+pub fn _mi_os_free_huge_pages(p: *mut c_void, size: usize /*, mi_stats_t* stats */) {
+    if p.is_null() || size == 0 {
+        return;
+    }
+    let mut base = p.cast::<u8>();
+    let mut size = size;
+    while size >= MI_HUGE_OS_PAGE_SIZE {
+        _mi_os_free(base.cast(), MI_HUGE_OS_PAGE_SIZE);
+        size -= MI_HUGE_OS_PAGE_SIZE;
+        base = unsafe { base.add(MI_HUGE_OS_PAGE_SIZE) };
+    }
+}
+
+/* ----------------------------------------------------------------------------
+  Support NUMA aware allocation
+-----------------------------------------------------------------------------*/
+
+#[cfg(target_os = "linux")]
+fn mi_os_numa_nodex() -> usize {
+    let mut node: libc::c_uint = 0;
+    let mut ncpu: libc::c_uint = 0;
+    let err = unsafe {
+        libc::syscall(
+            libc::SYS_getcpu,
+            &mut ncpu as *mut libc::c_uint,
+            &mut node as *mut libc::c_uint,
+            ptr::null_mut::<c_void>(),
+        )
     };
+    if err != 0 {
+        return 0;
+    }
+    node as usize
+}
+
+#[cfg(target_os = "linux")]
+fn mi_os_numa_node_countx() -> usize {
+    // enumerate node entries -- todo: it there a more efficient way to do this? (but ensure there is no allocation)
+    let mut buf = [0u8; 128];
+    let mut node: usize = 0;
+    while node < 256 {
+        let mut w = std::io::Cursor::new(&mut buf[..127]);
+        if std::io::Write::write_fmt(
+            &mut w,
+            format_args!("/sys/devices/system/node/node{}\0", node + 1),
+        )
+        .is_err()
+        {
+            break;
+        }
+        if unsafe { libc::access(buf.as_ptr().cast(), libc::R_OK) } != 0 {
+            break;
+        }
+        node += 1;
+    }
+    node + 1
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mi_os_numa_nodex() -> usize {
+    0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mi_os_numa_node_countx() -> usize {
+    1
+}
 
-    use crate::os::{
-        MiMemExtendedParameter, MiMemExtendedParameterBindgenTy2, _mi_os_alloc_aligned_offset,
-        mi_os_mem_alloc,
+static MI_NUMA_NODE_COUNT: AtomicUsize = AtomicUsize::new(0); // = 0 // cache the node count
+
+pub fn _mi_os_numa_node_count() -> usize {
+    let count = MI_NUMA_NODE_COUNT.load(Ordering::Acquire);
+    if count > 0 {
+        return count;
+    }
+    _mi_os_numa_node_count_get()
+}
+
+fn _mi_os_numa_node_count_get() -> usize {
+    let mut count = MI_NUMA_NODE_COUNT.load(Ordering::Acquire);
+    if count == 0 {
+        let ncount = mi_option_get(MiOption::MiOptionUseNumaNodes); // given explicitly?
+        if ncount > 0 {
+            count = ncount as usize;
+        } else {
+            count = mi_os_numa_node_countx(); // or detect dynamically
+            if count == 0 {
+                count = 1;
+            }
+        }
+        MI_NUMA_NODE_COUNT.store(count, Ordering::Release); // save it
+        _mi_verbose_message(format_args!("using {} numa regions\n", count));
+    }
+    count
+}
+
+pub fn _mi_os_numa_node_get(_tld: *mut MiOsTLD) -> i32 {
+    let numa_count = _mi_os_numa_node_count();
+    if numa_count <= 1 {
+        return 0; // optimize on single numa node systems: always node 0
+    }
+    // never more than the node count and >= 0
+    let mut numa_node = mi_os_numa_nodex();
+    if numa_node >= numa_count {
+        numa_node %= numa_count;
+    }
+    numa_node as i32
+}
+
+/* -----------------------------------------------------------
+  Clock
+----------------------------------------------------------- */
+
+// Monotonic clock in milli-seconds
+#[cfg(windows)]
+pub fn _mi_clock_now() -> i64 {
+    unsafe { windows::Win32::System::SystemInformation::GetTickCount64() as i64 }
+}
+
+#[cfg(unix)]
+pub fn _mi_clock_now() -> i64 {
+    let mut t = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
     };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut t) };
+    (t.tv_sec * 1000) + (t.tv_nsec / 1000000)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    #[cfg(windows)]
+    use std::mem::size_of;
+
+    #[cfg(windows)]
+    use windows::Win32::System::Memory::{MEM_COMMIT, MEM_EXTENDED_PARAMETER, MEM_RESERVE};
+
+    #[cfg(windows)]
+    use crate::os::{mi_win_virtual_allocx, MiMemExtendedParameter};
+
+    use crate::os::{_mi_os_alloc_aligned_offset, mi_os_mem_alloc};
+
+    use crate::os_mock::{MiOsCallKind, MiOsMockBackend};
 
     use super::{
-        _mi_align_up, _mi_os_good_alloc_size, _mi_os_init, _mi_os_page_size, mi_win_virtual_allocx,
+        _mi_align_up, _mi_os_alloc_aligned, _mi_os_commit, _mi_os_decommit, _mi_os_free,
+        _mi_os_free_aligned, _mi_os_good_alloc_size, _mi_os_init, _mi_os_page_size, _mi_os_protect,
+        _mi_os_unprotect, _mi_os_with_backend, mi_os_mem_free,
     };
 
     #[test]
@@ -605,6 +1678,51 @@ mod tests {
         assert!(ptr.is_null());
     }
 
+    #[test]
+    fn test_mi_os_alloc_aligned_with_offset() {
+        _mi_os_init();
+        let alignment = 4 * 1024 * 1024;
+        let offset = 64 * 1024;
+        let size = 1024 * 1024;
+        let p = _mi_os_alloc_aligned_offset(size, alignment, offset, true, ptr::null_mut());
+        assert!(!p.is_null());
+        assert_eq!((p as usize + offset) % alignment, 0);
+        unsafe { (p as *mut u8).write_bytes(0xAB, size) };
+        _mi_os_free_aligned(p, size, alignment, offset, true);
+    }
+
+    #[test]
+    fn test_mi_os_commit_failure_is_reported() {
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        mock.fail_nth(MiOsCallKind::Commit, 1);
+        _mi_os_with_backend(mock, || {
+            let size = 256 * 1024;
+            let p = _mi_os_alloc_aligned(size, 64 * 1024, false, ptr::null_mut());
+            assert!(!p.is_null());
+            let mut is_zero = true;
+            assert!(_mi_os_commit(p, size, &mut is_zero));
+            assert!(!is_zero);
+            assert!(!_mi_os_commit(p, size, &mut is_zero));
+            assert!(_mi_os_protect(p, _mi_os_page_size()));
+            assert!(_mi_os_unprotect(p, _mi_os_page_size()));
+            _mi_os_free(p, size);
+            let kinds: Vec<_> = mock.calls().iter().map(|c| c.kind).collect();
+            assert_eq!(
+                kinds,
+                [
+                    MiOsCallKind::Reserve,
+                    MiOsCallKind::Commit,
+                    MiOsCallKind::Commit,
+                    MiOsCallKind::Protect,
+                    MiOsCallKind::Unprotect,
+                    MiOsCallKind::Free
+                ]
+            );
+            assert_eq!(mock.live_regions(), 0);
+        });
+    }
+
     #[test]
     fn test_mi_os_good_alloc_size() {
         let res = _mi_os_good_alloc_size(23);
@@ -618,6 +1736,7 @@ mod tests {
         assert_eq!(_mi_align_up(17, 4), 20);
     }
 
+    #[cfg(windows)]
     #[test]
     fn test_mi_win_virtual_allocx() {
         let addr = ptr::null_mut();
@@ -634,6 +1753,7 @@ mod tests {
         println!("page size: {page_size}");
     }
 
+    #[cfg(windows)]
     #[test]
     fn test_memory_layout() {
         assert_eq!(
@@ -647,6 +1767,41 @@ mod tests {
         assert!(!mi_os_mem_alloc(33554432, 33554432, true, true, &mut false).is_null());
     }
 
+    #[test]
+    fn test_mi_os_alloc_aligned() {
+        _mi_os_init();
+        let alignment = 32 * 1024 * 1024;
+        for _ in 0..4 {
+            let p = _mi_os_alloc_aligned(alignment, alignment, true, ptr::null_mut());
+            assert!(!p.is_null());
+            assert_eq!(p as usize % alignment, 0);
+            unsafe {
+                (p as *mut u8).write(1);
+                (p as *mut u8).add(alignment - 1).write(1);
+            }
+            _mi_os_free(p, alignment);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mi_os_commit_decommit() {
+        _mi_os_init();
+        let size = 4 * _mi_os_page_size();
+        // reserve only, the memory is not accessible until committed
+        let p = mi_os_mem_alloc(size, 0, false, false, &mut false);
+        assert!(!p.is_null());
+        let mut is_zero = true;
+        assert!(_mi_os_commit(p, size, &mut is_zero));
+        assert!(!is_zero);
+        unsafe {
+            (p as *mut u8).write_bytes(0xAB, size);
+            assert_eq!(*(p as *mut u8).add(size - 1), 0xAB);
+        }
+        assert!(_mi_os_decommit(p, size));
+        assert!(mi_os_mem_free(p, size, false));
+    }
+
     // #[test]
     // fn bindgen_test_layout_MiMemExtendedParameter__bindgen_ty_2() {
     //     assert_eq!(
@@ -0,0 +1,195 @@
+// A recording and failure injecting OS backend for tests.
+// Memory is served from the Rust global allocator so the segment and arena
+// logic can be exercised without real `mmap`/`VirtualAlloc` calls.
+
+use std::{
+    alloc::{alloc_zeroed, dealloc, Layout},
+    ffi::c_void,
+    ptr,
+    sync::Mutex,
+};
+
+use crate::os::{_mi_os_page_size, MiOsBackend};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MiOsCallKind {
+    Reserve,
+    Commit,
+    Decommit,
+    Reset,
+    Protect,
+    Unprotect,
+    Free,
+    ReserveHuge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MiOsCall {
+    pub kind: MiOsCallKind,
+    pub addr: usize,
+    pub size: usize,
+    pub ok: bool,
+}
+
+#[derive(Default)]
+pub struct MiOsMockBackend {
+    calls: Mutex<Vec<MiOsCall>>,
+    // (kind, n): fail the n-th (0-based) call of the given kind
+    failures: Mutex<Vec<(MiOsCallKind, usize)>>,
+    regions: Mutex<Vec<MiOsMockRegion>>,
+}
+
+// A reserved region and the byte ranges of it that were freed already
+struct MiOsMockRegion {
+    start: usize,
+    layout: Layout,
+    freed: Vec<(usize, usize)>,
+}
+
+impl MiOsMockBackend {
+    // Create a mock with a 'static lifetime so it can be installed with `_mi_os_with_backend`.
+    pub fn leak() -> &'static MiOsMockBackend {
+        Box::leak(Box::default())
+    }
+
+    // Let the `nth` call (counting from 0) of `kind` fail.
+    pub fn fail_nth(&self, kind: MiOsCallKind, nth: usize) {
+        self.failures.lock().unwrap().push((kind, nth));
+    }
+
+    pub fn calls(&self) -> Vec<MiOsCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    pub fn count(&self, kind: MiOsCallKind) -> usize {
+        self.calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| c.kind == kind)
+            .count()
+    }
+
+    // Number of reserved regions that were not (completely) freed yet.
+    pub fn live_regions(&self) -> usize {
+        self.regions.lock().unwrap().len()
+    }
+
+    fn should_fail(&self, kind: MiOsCallKind) -> bool {
+        let nth = self.count(kind);
+        self.failures
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(k, n)| k == kind && n == nth)
+    }
+
+    fn record(&self, kind: MiOsCallKind, addr: *mut c_void, size: usize, ok: bool) -> bool {
+        self.calls.lock().unwrap().push(MiOsCall {
+            kind,
+            addr: addr as usize,
+            size,
+            ok,
+        });
+        ok
+    }
+
+    fn simple(&self, kind: MiOsCallKind, addr: *mut c_void, size: usize) -> bool {
+        let ok = !self.should_fail(kind);
+        self.record(kind, addr, size, ok)
+    }
+}
+
+impl MiOsBackend for MiOsMockBackend {
+    fn reserve(
+        &self,
+        size: usize,
+        try_alignment: usize,
+        _commit: bool,
+        _allow_large: bool,
+        is_large: &mut bool,
+    ) -> *mut c_void {
+        *is_large = false;
+        if self.should_fail(MiOsCallKind::Reserve) {
+            self.record(MiOsCallKind::Reserve, ptr::null_mut(), size, false);
+            return ptr::null_mut();
+        }
+        let align = try_alignment.max(_mi_os_page_size());
+        let p = match Layout::from_size_align(size, align) {
+            Ok(layout) => {
+                let p = unsafe { alloc_zeroed(layout) };
+                if !p.is_null() {
+                    self.regions.lock().unwrap().push(MiOsMockRegion {
+                        start: p as usize,
+                        layout,
+                        freed: Vec::new(),
+                    });
+                }
+                p.cast()
+            }
+            Err(_) => ptr::null_mut(),
+        };
+        self.record(MiOsCallKind::Reserve, p, size, !p.is_null());
+        p
+    }
+
+    fn commit(&self, addr: *mut c_void, size: usize) -> bool {
+        self.simple(MiOsCallKind::Commit, addr, size)
+    }
+
+    fn decommit(&self, addr: *mut c_void, size: usize) -> bool {
+        self.simple(MiOsCallKind::Decommit, addr, size)
+    }
+
+    fn reset(&self, addr: *mut c_void, size: usize) -> bool {
+        self.simple(MiOsCallKind::Reset, addr, size)
+    }
+
+    fn protect(&self, addr: *mut c_void, size: usize, protect: bool) -> bool {
+        let kind = if protect {
+            MiOsCallKind::Protect
+        } else {
+            MiOsCallKind::Unprotect
+        };
+        self.simple(kind, addr, size)
+    }
+
+    fn free(&self, addr: *mut c_void, size: usize) -> bool {
+        if self.should_fail(MiOsCallKind::Free) {
+            return self.record(MiOsCallKind::Free, addr, size, false);
+        }
+        // a free can release a part of a region (like `munmap`); the backing
+        // allocation is only released once all of the region is freed
+        let (start, end) = (addr as usize, addr as usize + size);
+        let mut regions = self.regions.lock().unwrap();
+        let ok = match regions
+            .iter()
+            .position(|r| r.start <= start && end <= r.start + r.layout.size())
+        {
+            Some(idx) => {
+                let region = &mut regions[idx];
+                assert!(
+                    region.freed.iter().all(|&(s, e)| end <= s || e <= start),
+                    "mock: range {start:#x}-{end:#x} was freed already"
+                );
+                region.freed.push((start, end));
+                let freed: usize = region.freed.iter().map(|&(s, e)| e - s).sum();
+                if freed == region.layout.size() {
+                    let region = regions.swap_remove(idx);
+                    unsafe { dealloc(region.start as *mut u8, region.layout) };
+                }
+                true
+            }
+            None => false,
+        };
+        drop(regions);
+        self.record(MiOsCallKind::Free, addr, size, ok)
+    }
+
+    fn reserve_huge(&self, addr: *mut c_void, size: usize, _numa_node: i32) -> *mut c_void {
+        // the global allocator cannot serve memory at a fixed address, so behave
+        // like a system without huge OS pages
+        self.record(MiOsCallKind::ReserveHuge, addr, size, false);
+        ptr::null_mut()
+    }
+}
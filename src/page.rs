@@ -1,12 +1,1264 @@
-use std::{ffi::c_void, ptr};
+/* -----------------------------------------------------------
+  The core of the allocator. Every segment contains
+  pages of a certain block size. The main function
+  exported is `mi_malloc_page`.
+----------------------------------------------------------- */
 
-use crate::mimalloc_types::MiHeap;
+use std::{
+    ffi::c_void,
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
 
-fn _mi_malloc_generic(
-    heap: Box<MiHeap>,
+use crate::{
+    alloc::{_mi_free_delayed_block, _mi_page_malloc},
+    heap::{_mi_heap_random_next, mi_heap_collect},
+    init::mi_thread_init,
+    mimalloc_internal::{
+        _mi_page_segment, _mi_page_start, get_default_heap, mi_atomic_yield, mi_block_next,
+        mi_block_nextx, mi_block_set_next, mi_block_set_nextx, mi_heap_is_initialized,
+        mi_page_all_free, mi_page_block_size, mi_page_has_aligned, mi_page_heap,
+        mi_page_immediate_available, mi_page_is_in_full, mi_page_set_has_aligned, mi_page_set_heap,
+        mi_page_set_in_full, mi_page_thread_free, mi_page_thread_free_flag,
+        mi_page_usable_block_size, mi_tf_block, mi_tf_delayed, mi_tf_set_block, mi_tf_set_delayed,
+    },
+    mimalloc_types::{
+        MiBlock, MiDelayed, MiHeap, MiPage, MiPageQueue, MiSegmentKind, MiTLD, MI_BIN_FULL,
+        MI_BIN_HUGE, MI_ENCODE_FREELIST, MI_HUGE_BLOCK_SIZE, MI_INTPTR_SIZE, MI_LARGE_OBJ_SIZE_MAX,
+        MI_MEDIUM_OBJ_SIZE_MAX, MI_PADDING_SIZE, MI_SECURE, MI_SEGMENT_SLICE_SIZE,
+        MI_SMALL_OBJ_SIZE_MAX, MI_STAT,
+    },
+    options::_mi_error_message,
+    os::_mi_os_good_alloc_size,
+    page_queue::{
+        _mi_bin, mi_heap_page_queue_of, mi_page_queue, mi_page_queue_enqueue_from,
+        mi_page_queue_is_huge, mi_page_queue_is_special, mi_page_queue_of, mi_page_queue_push,
+        mi_page_queue_remove,
+    },
+    random::_mi_random_shuffle,
+    segment::{
+        _mi_segment_page_abandon, _mi_segment_page_alloc, _mi_segment_page_free,
+        _mi_segment_page_start,
+    },
+    stats::{_mi_stat_counter_increase, _mi_stat_increase, _mi_stats_main},
+};
+
+#[cfg(debug_assertions)]
+use crate::page_queue::mi_heap_contains_queue;
+
+#[inline]
+fn mi_page_block_at(page_start: *mut u8, block_size: usize, i: usize) -> *mut MiBlock {
+    unsafe { page_start.add(i * block_size).cast() }
+}
+
+pub fn _mi_page_reclaim(heap: *mut MiHeap, page: *mut MiPage) {
+    debug_assert!(mi_page_heap(page) == heap);
+    debug_assert!(mi_page_thread_free_flag(page) != MiDelayed::MiNeverDelayedFree);
+    debug_assert!(unsafe { (*page).is_reset() } == 0);
+    // TODO: push on full queue immediately if it is full?
+    let pq = mi_page_queue(heap, mi_page_block_size(page));
+    mi_page_queue_push(heap, pq, page);
+}
+
+// allocate a fresh page from a segment
+fn mi_page_fresh_alloc(
+    heap: *mut MiHeap,
+    pq: *mut MiPageQueue,
+    block_size: usize,
+    page_alignment: usize,
+) -> *mut MiPage {
+    #[cfg(debug_assertions)]
+    debug_assert!(pq.is_null() || mi_heap_contains_queue(heap, pq));
+    let page = unsafe {
+        _mi_segment_page_alloc(
+            heap,
+            block_size,
+            page_alignment,
+            ptr::addr_of_mut!((*(*heap).tld).segments),
+            ptr::addr_of_mut!((*(*heap).tld).os),
+        )
+    };
+    if page.is_null() {
+        // this may be out-of-memory, or an abandoned page was reclaimed (and in our queue)
+        return ptr::null_mut();
+    }
+    debug_assert!(pq.is_null() || unsafe { (*page).xblock_size } != 0);
+    // a fresh page was found, initialize it
+    let full_block_size = if pq.is_null() || mi_page_queue_is_huge(pq) {
+        mi_page_block_size(page) // see also: mi_segment_huge_page_alloc
+    } else {
+        block_size
+    };
+    debug_assert!(full_block_size >= block_size);
+    mi_page_init(heap, page, full_block_size, unsafe { (*heap).tld });
+    _mi_stat_increase(unsafe { ptr::addr_of_mut!((*(*heap).tld).stats.pages) }, 1);
+    if !pq.is_null() {
+        mi_page_queue_push(heap, pq, page);
+    }
+    page
+}
+
+// Get a fresh page to use
+fn mi_page_fresh(heap: *mut MiHeap, pq: *mut MiPageQueue) -> *mut MiPage {
+    let page = mi_page_fresh_alloc(heap, pq, unsafe { (*pq).block_size }, 0);
+    if page.is_null() {
+        return ptr::null_mut();
+    }
+    debug_assert!(unsafe { (*pq).block_size } == mi_page_block_size(page));
+    debug_assert!(pq == mi_page_queue(heap, mi_page_block_size(page)));
+    page
+}
+
+/* -----------------------------------------------------------
+   Do any delayed frees
+   (put there by other threads if they deallocated in a full page)
+----------------------------------------------------------- */
+
+pub fn _mi_heap_delayed_free_all(heap: *mut MiHeap) {
+    while !_mi_heap_delayed_free_partial(heap) {
+        mi_atomic_yield();
+    }
+}
+
+// returns true if all delayed frees were processed
+pub fn _mi_heap_delayed_free_partial(heap: *mut MiHeap) -> bool {
+    // take over the list (note: no atomic exchange since it is often NULL)
+    let delayed = unsafe { &(*heap).thread_delayed_free };
+    let mut block = delayed.load(Ordering::Relaxed);
+    while !block.is_null() {
+        match delayed.compare_exchange_weak(
+            block,
+            ptr::null_mut(),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => break,
+            Err(current) => block = current,
+        }
+    }
+    let mut all_freed = true;
+
+    // and free them all
+    let keys = unsafe { ptr::addr_of!((*heap).keys).cast::<usize>() };
+    while !block.is_null() {
+        let next = mi_block_nextx(heap.cast(), block, keys);
+        // use internal free instead of regular one to keep stats etc correct
+        if !_mi_free_delayed_block(block) {
+            // we might already start delayed freeing while another thread has not yet
+            // reset the delayed_freeing flag; in that case delay it further by reinserting the current block
+            // into the delayed free list
+            all_freed = false;
+            let mut dfree = delayed.load(Ordering::Relaxed);
+            loop {
+                mi_block_set_nextx(heap.cast(), block, dfree, keys);
+                match delayed.compare_exchange_weak(
+                    dfree,
+                    block,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(current) => dfree = current,
+                }
+            }
+        }
+        block = next;
+    }
+    all_freed
+}
+
+pub fn _mi_page_use_delayed_free(page: *mut MiPage, delay: MiDelayed, override_never: bool) {
+    while !_mi_page_try_use_delayed_free(page, delay, override_never) {
+        mi_atomic_yield();
+    }
+}
+
+pub fn _mi_page_try_use_delayed_free(
+    page: *mut MiPage,
+    delay: MiDelayed,
+    override_never: bool,
+) -> bool {
+    let mut yield_count = 0;
+    loop {
+        let tfree = unsafe { (*page).xthread_free.load(Ordering::Acquire) }; // note: must acquire as we can break/repeat this loop and not do a CAS;
+        let tfreex = mi_tf_set_delayed(tfree, delay);
+        let old_delay = mi_tf_delayed(tfree);
+        if old_delay == MiDelayed::MiDelayedFreeing {
+            if yield_count >= 4 {
+                return false; // give up after 4 tries
+            }
+            yield_count += 1;
+            mi_atomic_yield(); // delay until outstanding MI_DELAYED_FREEING are done.
+            continue;
+        } else if delay == old_delay {
+            break; // avoid atomic operation if already equal
+        } else if !override_never && old_delay == MiDelayed::MiNeverDelayedFree {
+            break; // leave never-delayed flag set
+        }
+        if unsafe {
+            (*page)
+                .xthread_free
+                .compare_exchange_weak(tfree, tfreex, Ordering::Release, Ordering::Relaxed)
+                .is_ok()
+        } {
+            break;
+        }
+    }
+
+    true // success
+}
+
+/* -----------------------------------------------------------
+  Page collect the `local_free` and `thread_free` lists
+----------------------------------------------------------- */
+
+// Collect the local `thread_free` list using an atomic exchange.
+// Note: The exchange must be done atomically as this is used right after
+// moving to the full list in `mi_page_collect_ex` and we need to
+// ensure that there was no race where the page became unfull just before the move.
+fn _mi_page_thread_free_collect(page: *mut MiPage) {
+    let mut head: *mut MiBlock;
+    let mut tfree = unsafe { (*page).xthread_free.load(Ordering::Relaxed) };
+    loop {
+        head = mi_tf_block(tfree);
+        let tfreex = mi_tf_set_block(tfree, ptr::null_mut());
+        match unsafe {
+            (*page).xthread_free.compare_exchange_weak(
+                tfree,
+                tfreex,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+        } {
+            Ok(_) => break,
+            Err(current) => tfree = current,
+        }
+    }
+
+    // return if the list is empty
+    if head.is_null() {
+        return;
+    }
+
+    // find the tail -- also to get a proper count (without data races)
+    let max_count = unsafe { (*page).capacity } as u32; // cannot collect more than capacity
+    let mut count = 1;
+    let mut tail = head;
+    loop {
+        let next = mi_block_next(page, tail);
+        if next.is_null() || count > max_count {
+            break;
+        }
+        count += 1;
+        tail = next;
+    }
+    // if `count > max_count` there was a memory corruption (possibly infinite list due to double multi-threaded free)
+    if count > max_count {
+        _mi_error_message(libc::EFAULT, format_args!("corrupted thread-free list\n"));
+        return; // the thread-free items cannot be freed
+    }
+
+    // and append the current local free list
+    unsafe {
+        mi_block_set_next(page, tail, (*page).local_free);
+        (*page).local_free = head;
+
+        // update counts now
+        (*page).used -= count;
+    }
+}
+
+pub fn _mi_page_free_collect(page: *mut MiPage, force: bool) {
+    debug_assert!(!page.is_null());
+
+    // collect the thread free list
+    if force || !mi_page_thread_free(page).is_null() {
+        // quick test to avoid an atomic operation
+        _mi_page_thread_free_collect(page);
+    }
+
+    // and the local free list
+    unsafe {
+        if !(*page).local_free.is_null() {
+            if (*page).free.is_null() {
+                // usual case
+                (*page).free = (*page).local_free;
+                (*page).local_free = ptr::null_mut();
+                (*page).set_is_zero(0);
+            } else if force {
+                // append -- only on shutdown (force) as this is a linear operation
+                let mut tail = (*page).local_free;
+                loop {
+                    let next = mi_block_next(page, tail);
+                    if next.is_null() {
+                        break;
+                    }
+                    tail = next;
+                }
+                mi_block_set_next(page, tail, (*page).free);
+                (*page).free = (*page).local_free;
+                (*page).local_free = ptr::null_mut();
+                (*page).set_is_zero(0);
+            }
+        }
+
+        debug_assert!(!force || (*page).local_free.is_null());
+    }
+}
+
+/* -----------------------------------------------------------
+  Page fresh and retire
+----------------------------------------------------------- */
+
+// Move a page from the full list back to a regular list
+pub fn _mi_page_unfull(page: *mut MiPage) {
+    debug_assert!(!page.is_null());
+    debug_assert!(mi_page_is_in_full(page));
+    if !mi_page_is_in_full(page) {
+        return;
+    }
+
+    let heap = mi_page_heap(page);
+    let pqfull = unsafe { ptr::addr_of_mut!((*heap).pages[MI_BIN_FULL]) };
+    mi_page_set_in_full(page, false); // to get the right queue
+    let pq = mi_heap_page_queue_of(heap, page);
+    mi_page_set_in_full(page, true);
+    mi_page_queue_enqueue_from(pq, pqfull, page);
+}
+
+fn mi_page_to_full(page: *mut MiPage, pq: *mut MiPageQueue) {
+    debug_assert!(pq == mi_page_queue_of(page));
+    debug_assert!(!mi_page_immediate_available(page));
+    debug_assert!(!mi_page_is_in_full(page));
+
+    if mi_page_is_in_full(page) {
+        return;
+    }
+    mi_page_queue_enqueue_from(
+        unsafe { ptr::addr_of_mut!((*mi_page_heap(page)).pages[MI_BIN_FULL]) },
+        pq,
+        page,
+    );
+    _mi_page_free_collect(page, false); // try to collect right away in case another thread freed just before MI_USE_DELAYED_FREE was set
+}
+
+// Free a page with no more free blocks
+pub fn _mi_page_free(page: *mut MiPage, pq: *mut MiPageQueue, force: bool) {
+    debug_assert!(!page.is_null());
+    debug_assert!(pq == mi_page_queue_of(page));
+    debug_assert!(mi_page_all_free(page));
+    debug_assert!(mi_page_thread_free_flag(page) != MiDelayed::MiDelayedFreeing);
+
+    // no more aligned blocks in here
+    mi_page_set_has_aligned(page, false);
+
+    let heap = mi_page_heap(page);
+
+    // remove from the page list
+    // (no need to do _mi_heap_delayed_free first as all blocks are already free)
+    let segments_tld = unsafe { ptr::addr_of_mut!((*(*heap).tld).segments) };
+    mi_page_queue_remove(pq, page);
+
+    // and free it
+    mi_page_set_heap(page, ptr::null_mut());
+    _mi_segment_page_free(page, force, segments_tld);
+}
+
+// Abandon a page with used blocks at the end of a thread.
+// Note: only call if it is ensured that no references exist from
+// the `page->heap->thread_delayed_free` into this page.
+// Currently only called through `mi_heap_collect_ex` which ensures this.
+pub fn _mi_page_abandon(page: *mut MiPage, pq: *mut MiPageQueue) {
+    debug_assert!(!page.is_null());
+    debug_assert!(pq == mi_page_queue_of(page));
+    debug_assert!(!mi_page_heap(page).is_null());
+
+    let pheap = mi_page_heap(page);
+
+    // remove from our page list
+    let segments_tld = unsafe { ptr::addr_of_mut!((*(*pheap).tld).segments) };
+    mi_page_queue_remove(pq, page);
+
+    // page is no longer associated with our heap
+    debug_assert!(mi_page_thread_free_flag(page) == MiDelayed::MiNeverDelayedFree);
+    mi_page_set_heap(page, ptr::null_mut());
+
+    // and abandon it
+    debug_assert!(mi_page_heap(page).is_null());
+    _mi_segment_page_abandon(page, segments_tld);
+}
+
+// Retire parameters
+const MI_MAX_RETIRE_SIZE: usize = MI_MEDIUM_OBJ_SIZE_MAX;
+const MI_RETIRE_CYCLES: u8 = 16;
+
+// Retire a page with no more used blocks
+// Important to not retire too quickly though as new
+// allocations might coming.
+// Note: called from `mi_free` and benchmarks often
+// trigger this due to freeing everything and then
+// allocating again so careful when changing this.
+pub fn _mi_page_retire(page: *mut MiPage) {
+    debug_assert!(!page.is_null());
+    debug_assert!(mi_page_all_free(page));
+
+    mi_page_set_has_aligned(page, false);
+
+    // don't retire too often..
+    // (or we end up retiring and re-allocating most of the time)
+    // NOTE: refine this more: we should not retire if this
+    // is the only page left with free blocks. It is not clear
+    // how to check this efficiently though...
+    // for now, we don't retire if it is the only page left of this size class.
+    let pq = mi_page_queue_of(page);
+    let bsize = unsafe { (*page).xblock_size } as usize;
+    if bsize <= MI_MAX_RETIRE_SIZE && !mi_page_queue_is_special(pq) {
+        // not too large && not full or huge queue?
+        unsafe {
+            if (*pq).last == page && (*pq).first == page {
+                // the only page in the queue?
+                if MI_STAT > 0 {
+                    _mi_stat_counter_increase(ptr::addr_of_mut!(_mi_stats_main.page_no_retire), 1);
+                }
+                (*page).set_retire_expire(
+                    1 + if bsize <= MI_SMALL_OBJ_SIZE_MAX {
+                        MI_RETIRE_CYCLES
+                    } else {
+                        MI_RETIRE_CYCLES / 4
+                    },
+                );
+                let heap = mi_page_heap(page);
+                let index =
+                    pq.offset_from(ptr::addr_of!((*heap).pages).cast::<MiPageQueue>()) as usize;
+                debug_assert!(index < MI_BIN_HUGE);
+                if index < (*heap).page_retired_min {
+                    (*heap).page_retired_min = index;
+                }
+                if index > (*heap).page_retired_max {
+                    (*heap).page_retired_max = index;
+                }
+                debug_assert!(mi_page_all_free(page));
+                return; // dont't free after all
+            }
+        }
+    }
+    _mi_page_free(page, pq, false);
+}
+
+// Free retired pages: we don't need to look at the entire queues
+// since we only retire pages that are at the head position in a queue.
+pub fn _mi_heap_collect_retired(heap: *mut MiHeap, force: bool) {
+    let mut min = MI_BIN_FULL;
+    let mut max = 0;
+    unsafe {
+        for bin in (*heap).page_retired_min..=(*heap).page_retired_max {
+            let pq = ptr::addr_of_mut!((*heap).pages[bin]);
+            let page = (*pq).first;
+            if !page.is_null() && (*page).retire_expire() != 0 {
+                if mi_page_all_free(page) {
+                    (*page).set_retire_expire((*page).retire_expire() - 1);
+                    if force || (*page).retire_expire() == 0 {
+                        _mi_page_free((*pq).first, pq, force);
+                    } else {
+                        // keep retired, update min/max
+                        if bin < min {
+                            min = bin;
+                        }
+                        if bin > max {
+                            max = bin;
+                        }
+                    }
+                } else {
+                    (*page).set_retire_expire(0);
+                }
+            }
+        }
+        (*heap).page_retired_min = min;
+        (*heap).page_retired_max = max;
+    }
+}
+
+/* -----------------------------------------------------------
+  Initialize the initial free list in a page.
+  In secure mode we initialize a randomized list by
+  alternating between slices.
+----------------------------------------------------------- */
+
+const MI_MAX_SLICE_SHIFT: usize = 6; // at most 64 slices
+const MI_MAX_SLICES: usize = 1 << MI_MAX_SLICE_SHIFT;
+const MI_MIN_SLICES: usize = 2;
+
+// Randomize the order of the initial free list in secure mode 3 and up
+#[allow(clippy::absurd_extreme_comparisons)] // `MI_SECURE` is a build configuration constant
+const MI_SECURE_EXTEND: bool = MI_SECURE >= 3;
+
+fn mi_page_free_list_extend_secure(
+    heap: *mut MiHeap,
+    page: *mut MiPage,
+    bsize: usize,
+    extend: usize,
+) {
+    unsafe {
+        debug_assert!((*page).free.is_null());
+        debug_assert!((*page).local_free.is_null());
+        debug_assert!((*page).capacity as usize + extend <= (*page).reserved as usize);
+        debug_assert!(bsize == mi_page_block_size(page));
+        let page_area = _mi_page_start(_mi_page_segment(page), page, ptr::null_mut());
+
+        // initialize a randomized free list
+        // set up `slice_count` slices to alternate between
+        let mut shift = MI_MAX_SLICE_SHIFT;
+        while (extend >> shift) == 0 {
+            shift -= 1;
+        }
+        let slice_count = 1usize << shift;
+        let slice_extend = extend / slice_count;
+        debug_assert!(slice_extend >= 1);
+        let mut blocks = [ptr::null_mut::<MiBlock>(); MI_MAX_SLICES]; // current start of the slice
+        let mut counts = [0usize; MI_MAX_SLICES]; // available objects in the slice
+        for i in 0..slice_count {
+            blocks[i] = mi_page_block_at(
+                page_area,
+                bsize,
+                (*page).capacity as usize + i * slice_extend,
+            );
+            counts[i] = slice_extend;
+        }
+        counts[slice_count - 1] += extend % slice_count; // final slice holds the modulus too (todo: distribute evenly?)
+
+        // and initialize the free list by randomly threading through them
+        // set up first element
+        let r = _mi_heap_random_next(heap);
+        let mut current = r % slice_count;
+        counts[current] -= 1;
+        let free_start = blocks[current];
+        // and iterate through the rest; use `random_shuffle` for performance
+        let mut rnd = _mi_random_shuffle(r | 1); // ensure not 0
+        for i in 1..extend {
+            // call random_shuffle only every INTPTR_SIZE rounds
+            let round = i % MI_INTPTR_SIZE;
+            if round == 0 {
+                rnd = _mi_random_shuffle(rnd);
+            }
+            // select a random next slice index
+            let mut next = (rnd >> (8 * round)) & (slice_count - 1);
+            while counts[next] == 0 {
+                // ensure it still has space
+                next += 1;
+                if next == slice_count {
+                    next = 0;
+                }
+            }
+            // and link the current block to it
+            counts[next] -= 1;
+            let block = blocks[current];
+            blocks[current] = block.cast::<u8>().add(bsize).cast(); // bump to the following block
+            mi_block_set_next(page, block, blocks[next]); // and set next; note: we may have `current == next`
+            current = next;
+        }
+        // prepend to the free list (usually NULL)
+        mi_block_set_next(page, blocks[current], (*page).free); // end of the list
+        (*page).free = free_start;
+    }
+}
+
+fn mi_page_free_list_extend(page: *mut MiPage, bsize: usize, extend: usize) {
+    unsafe {
+        debug_assert!((*page).free.is_null());
+        debug_assert!((*page).local_free.is_null());
+        debug_assert!((*page).capacity as usize + extend <= (*page).reserved as usize);
+        debug_assert!(bsize == mi_page_block_size(page));
+        let page_area = _mi_page_start(_mi_page_segment(page), page, ptr::null_mut());
+
+        let start = mi_page_block_at(page_area, bsize, (*page).capacity as usize);
+
+        // initialize a sequential free list
+        let last = mi_page_block_at(page_area, bsize, (*page).capacity as usize + extend - 1);
+        let mut block = start;
+        while block <= last {
+            let next = block.cast::<u8>().add(bsize).cast::<MiBlock>();
+            mi_block_set_next(page, block, next);
+            block = next;
+        }
+        // prepend to free list (usually `NULL`)
+        mi_block_set_next(page, last, (*page).free);
+        (*page).free = start;
+    }
+}
+
+/* -----------------------------------------------------------
+  Page initialize and extend the capacity
+----------------------------------------------------------- */
+
+const MI_MAX_EXTEND_SIZE: usize = 4 * 1024; // heuristic, one OS page seems to work well.
+const MI_MIN_EXTEND: usize = if MI_SECURE != 0 {
+    8 * MI_SECURE as usize // extend at least by this many
+} else {
+    4
+};
+
+// Extend the capacity (up to reserved) by initializing a free list
+// We do at most `MI_MAX_EXTEND` to avoid touching too much memory
+// Note: we also experimented with "bump" allocation on the first
+// allocations but this did not speed up any benchmark (due to an
+// extra test in malloc? or cache effects?)
+fn mi_page_extend_free(heap: *mut MiHeap, page: *mut MiPage, tld: *mut MiTLD) {
+    unsafe {
+        debug_assert!((*page).free.is_null());
+        debug_assert!((*page).local_free.is_null());
+        if !(*page).free.is_null() {
+            return;
+        }
+        if (*page).capacity >= (*page).reserved {
+            return;
+        }
+
+        let mut page_size: usize = 0;
+        _mi_page_start(_mi_page_segment(page), page, &mut page_size);
+
+        // calculate the extend count
+        let bsize = if ((*page).xblock_size as usize) < MI_HUGE_BLOCK_SIZE {
+            (*page).xblock_size as usize
+        } else {
+            page_size
+        };
+        let mut extend = (*page).reserved as usize - (*page).capacity as usize;
+        debug_assert!(extend > 0);
+
+        let mut max_extend = if bsize >= MI_MAX_EXTEND_SIZE {
+            MI_MIN_EXTEND
+        } else {
+            MI_MAX_EXTEND_SIZE / bsize
+        };
+        if max_extend < MI_MIN_EXTEND {
+            max_extend = MI_MIN_EXTEND;
+        }
+        debug_assert!(max_extend > 0);
+
+        if extend > max_extend {
+            // ensure we don't touch memory beyond the page to reduce page commit.
+            // the `lean` benchmark tests this. Going from 1 to 8 increases rss by 50%.
+            extend = max_extend;
+        }
+
+        debug_assert!(
+            extend > 0 && extend + (*page).capacity as usize <= (*page).reserved as usize
+        );
+        debug_assert!(extend < (1 << 16));
+
+        // and append the extend the free list
+        if extend < MI_MIN_SLICES || !MI_SECURE_EXTEND {
+            mi_page_free_list_extend(page, bsize, extend);
+        } else {
+            mi_page_free_list_extend_secure(heap, page, bsize, extend);
+        }
+        if MI_STAT > 0 {
+            _mi_stat_counter_increase(ptr::addr_of_mut!((*tld).stats.pages_extended), 1);
+        }
+        _mi_stat_increase(
+            ptr::addr_of_mut!((*tld).stats.page_committed),
+            extend * bsize,
+        );
+        // enable the new free list
+        (*page).capacity += extend as u16;
+
+        // extension into zero initialized memory preserves the zero'd free list
+        if (*page).is_zero_init() == 0 {
+            (*page).set_is_zero(0);
+        }
+    }
+}
+
+// Initialize a fresh page
+fn mi_page_init(heap: *mut MiHeap, page: *mut MiPage, block_size: usize, tld: *mut MiTLD) {
+    debug_assert!(!page.is_null());
+    let segment = _mi_page_segment(page);
+    debug_assert!(!segment.is_null());
+    debug_assert!(block_size > 0);
+    // set fields
+    mi_page_set_heap(page, heap);
+    unsafe {
+        (*page).xblock_size = if block_size < MI_HUGE_BLOCK_SIZE {
+            block_size
+        } else {
+            MI_HUGE_BLOCK_SIZE
+        } as u32; // initialize before _mi_segment_page_start
+        let mut page_size: usize = 0;
+        _mi_segment_page_start(segment, page, &mut page_size);
+        debug_assert!(mi_page_block_size(page) <= page_size);
+        debug_assert!(page_size <= (*page).slice_count as usize * MI_SEGMENT_SLICE_SIZE);
+        debug_assert!(page_size / block_size < (1 << 16));
+        (*page).reserved = (page_size / block_size) as u16;
+        debug_assert!((*page).reserved > 0);
+        if cfg!(debug_assertions) {
+            (*page).set_is_zero(0); // ensure in debug mode we initialize with MI_DEBUG_UNINIT, see issue #501
+        } else {
+            (*page).set_is_zero((*page).is_zero_init());
+        }
+        if MI_ENCODE_FREELIST {
+            (*page).keys[0] = _mi_heap_random_next(heap);
+            (*page).keys[1] = _mi_heap_random_next(heap);
+        }
+
+        debug_assert!((*page).is_committed() != 0);
+        debug_assert!((*page).is_reset() == 0);
+        debug_assert!((*page).capacity == 0);
+        debug_assert!((*page).free.is_null());
+        debug_assert!((*page).used == 0);
+        debug_assert!((*page).xthread_free.load(Ordering::Relaxed) == 0);
+        debug_assert!((*page).next.is_null());
+        debug_assert!((*page).prev.is_null());
+        debug_assert!((*page).retire_expire() == 0);
+        debug_assert!(!mi_page_has_aligned(page));
+        if MI_ENCODE_FREELIST {
+            debug_assert!((*page).keys[0] != 0);
+            debug_assert!((*page).keys[1] != 0);
+        }
+    }
+
+    // initialize an initial free list
+    mi_page_extend_free(heap, page, tld);
+    debug_assert!(mi_page_immediate_available(page));
+}
+
+/* -----------------------------------------------------------
+  Find pages with free blocks
+-------------------------------------------------------------*/
+
+// Find a page with free blocks of `page->block_size`.
+fn mi_page_queue_find_free_ex(
+    heap: *mut MiHeap,
+    pq: *mut MiPageQueue,
+    first_try: bool,
+) -> *mut MiPage {
+    // search through the pages in "next fit" order
+    let mut count: usize = 0;
+    let mut page = unsafe { (*pq).first };
+    while !page.is_null() {
+        let next = unsafe { (*page).next }; // remember next
+        count += 1;
+
+        // 0. collect freed blocks by us and other threads
+        _mi_page_free_collect(page, false);
+
+        // 1. if the page contains free blocks, we are done
+        if mi_page_immediate_available(page) {
+            break; // pick this one
+        }
+
+        // 2. Try to extend
+        if unsafe { (*page).capacity < (*page).reserved } {
+            mi_page_extend_free(heap, page, unsafe { (*heap).tld });
+            debug_assert!(mi_page_immediate_available(page));
+            break;
+        }
+
+        // 3. If the page is completely full, move it to the `mi_pages_full`
+        // queue so we don't visit long-lived pages too often.
+        debug_assert!(!mi_page_is_in_full(page) && !mi_page_immediate_available(page));
+        mi_page_to_full(page, pq);
+
+        page = next;
+    } // for each page
+
+    if MI_STAT > 0 {
+        _mi_stat_counter_increase(
+            unsafe { ptr::addr_of_mut!((*(*heap).tld).stats.searches) },
+            count,
+        );
+    }
+
+    if page.is_null() {
+        _mi_heap_collect_retired(heap, false); // perhaps make a page available?
+        page = mi_page_fresh(heap, pq);
+        if page.is_null() && first_try {
+            // out-of-memory _or_ an abandoned page with free blocks was reclaimed, try once again
+            page = mi_page_queue_find_free_ex(heap, pq, false);
+        }
+    } else {
+        debug_assert!(unsafe { (*pq).first } == page);
+        unsafe { (*page).set_retire_expire(0) };
+    }
+    debug_assert!(page.is_null() || mi_page_immediate_available(page));
+    page
+}
+
+// Find a page with free blocks of `size`.
+#[inline]
+fn mi_find_free_page(heap: *mut MiHeap, size: usize) -> *mut MiPage {
+    let pq = mi_page_queue(heap, size);
+    let page = unsafe { (*pq).first };
+    if !page.is_null() {
+        _mi_page_free_collect(page, false);
+
+        if mi_page_immediate_available(page) {
+            unsafe { (*page).set_retire_expire(0) };
+            return page; // fast path
+        }
+    }
+    mi_page_queue_find_free_ex(heap, pq, true)
+}
+
+/* -----------------------------------------------------------
+  Users can register a deferred free function called
+  when the `free` list is empty. Since the `local_free`
+  is separate this is deterministically called after
+  a certain number of allocations.
+----------------------------------------------------------- */
+
+pub type MiDeferredFreeFun = extern "C" fn(force: bool, heartbeat: u64, arg: *mut c_void);
+
+static DEFERRED_FREE: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static DEFERRED_ARG: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+
+pub fn _mi_deferred_free(heap: *mut MiHeap, force: bool) {
+    unsafe {
+        let tld = (*heap).tld;
+        (*tld).heartbeat += 1;
+        let deferred_free = DEFERRED_FREE.load(Ordering::Acquire);
+        if !deferred_free.is_null() && !(*tld).recurse {
+            let deferred_free: MiDeferredFreeFun = std::mem::transmute(deferred_free);
+            (*tld).recurse = true;
+            deferred_free(
+                force,
+                (*tld).heartbeat,
+                DEFERRED_ARG.load(Ordering::Relaxed),
+            );
+            (*tld).recurse = false;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mi_register_deferred_free(
+    deferred_free: Option<MiDeferredFreeFun>,
+    arg: *mut c_void,
+) {
+    DEFERRED_FREE.store(
+        deferred_free.map_or(ptr::null_mut(), |f| f as *mut c_void),
+        Ordering::Release,
+    );
+    DEFERRED_ARG.store(arg, Ordering::Release);
+}
+
+/* -----------------------------------------------------------
+  General allocation
+----------------------------------------------------------- */
+
+/* -----------------------------------------------------------
+  Large and huge page allocation.
+  Large pages are single-block pages in the huge queue; huge pages
+  have their own segment and an `xblock_size` of at most `MI_HUGE_BLOCK_SIZE`.
+----------------------------------------------------------- */
+
+// A large or huge page is allocated in a fresh segment page and never reused from the free queue.
+fn mi_large_huge_page_alloc(heap: *mut MiHeap, size: usize, page_alignment: usize) -> *mut MiPage {
+    let block_size = _mi_os_good_alloc_size(size);
+    debug_assert!(_mi_bin(block_size) == MI_BIN_HUGE || page_alignment > 0);
+    let is_huge = block_size > MI_LARGE_OBJ_SIZE_MAX || page_alignment > 0;
+    // not block_size as that can be low if the page_alignment > 0
+    let pq = mi_page_queue(
+        heap,
+        if is_huge {
+            MI_HUGE_BLOCK_SIZE
+        } else {
+            block_size
+        },
+    );
+    debug_assert!(!is_huge || mi_page_queue_is_huge(pq));
+    let page = mi_page_fresh_alloc(heap, pq, block_size, page_alignment);
+    if !page.is_null() {
+        debug_assert!(mi_page_immediate_available(page));
+        if is_huge {
+            debug_assert!(
+                unsafe { (*_mi_page_segment(page)).kind } == MiSegmentKind::MiSegmentHuge
+            );
+            debug_assert!(unsafe { (*_mi_page_segment(page)).used } == 1);
+        } else {
+            debug_assert!(
+                unsafe { (*_mi_page_segment(page)).kind } != MiSegmentKind::MiSegmentHuge
+            );
+        }
+
+        if MI_STAT > 0 {
+            let bsize = mi_page_usable_block_size(page); // note: not `mi_page_block_size` to account for padding
+            let stats = unsafe { ptr::addr_of_mut!((*(*heap).tld).stats) };
+            unsafe {
+                if bsize <= MI_LARGE_OBJ_SIZE_MAX {
+                    _mi_stat_increase(ptr::addr_of_mut!((*stats).large), bsize);
+                    _mi_stat_counter_increase(ptr::addr_of_mut!((*stats).large_count), 1);
+                } else {
+                    _mi_stat_increase(ptr::addr_of_mut!((*stats).huge), bsize);
+                    _mi_stat_counter_increase(ptr::addr_of_mut!((*stats).huge_count), 1);
+                }
+            }
+        }
+    }
+    page
+}
+
+// Allocate a page
+// Note: in debug mode the size includes MI_PADDING_SIZE and might have overflowed.
+fn mi_find_page(heap: *mut MiHeap, size: usize, huge_alignment: usize) -> *mut MiPage {
+    // huge allocation?
+    let req_size = size.wrapping_sub(MI_PADDING_SIZE); // correct for padding_size in case of an overflow on `size`
+    if req_size > (MI_MEDIUM_OBJ_SIZE_MAX - MI_PADDING_SIZE) || huge_alignment > 0 {
+        if req_size > isize::MAX as usize {
+            // we don't allocate more than PTRDIFF_MAX (see <https://sourceware.org/ml/libc-announce/2019/msg00001.html>)
+            _mi_error_message(
+                libc::EOVERFLOW,
+                format_args!("allocation request is too large ({} bytes)\n", req_size),
+            );
+            ptr::null_mut()
+        } else {
+            mi_large_huge_page_alloc(heap, size, huge_alignment)
+        }
+    } else {
+        // otherwise find a page with free blocks in our size segregated queues
+        debug_assert!(size >= MI_PADDING_SIZE);
+        mi_find_free_page(heap, size)
+    }
+}
+
+// Generic allocation routine if the fast path (`alloc.rs:mi_page_malloc`) does not succeed.
+// Note: in debug mode the size includes MI_PADDING_SIZE and might have overflowed.
+// The `huge_alignment` is normally 0 but is set to a multiple of MI_SEGMENT_SIZE for
+// very large requested alignments in which case we use a huge segment.
+pub fn _mi_malloc_generic(
+    mut heap: *mut MiHeap,
     size: usize,
     zero: bool,
     huge_alignment: usize,
 ) -> *mut c_void {
-    ptr::null_mut()
+    debug_assert!(!heap.is_null());
+
+    // initialize if necessary
+    if !mi_heap_is_initialized(heap) {
+        mi_thread_init();
+        heap = get_default_heap();
+        if !mi_heap_is_initialized(heap) {
+            return ptr::null_mut();
+        }
+    }
+    debug_assert!(mi_heap_is_initialized(heap));
+
+    // call potential deferred free routines
+    _mi_deferred_free(heap, false);
+
+    // free delayed frees from other threads (but skip contended ones)
+    _mi_heap_delayed_free_partial(heap);
+
+    // find (or allocate) a page of the right size
+    let mut page = mi_find_page(heap, size, huge_alignment);
+    if page.is_null() {
+        // first time out of memory, try to collect and retry the allocation once more
+        mi_heap_collect(heap, true);
+        page = mi_find_page(heap, size, huge_alignment);
+    }
+
+    if page.is_null() {
+        // out of memory
+        let req_size = size.wrapping_sub(MI_PADDING_SIZE); // correct for padding_size in case of an overflow on `size`
+        _mi_error_message(
+            libc::ENOMEM,
+            format_args!("unable to allocate memory ({} bytes)\n", req_size),
+        );
+        return ptr::null_mut();
+    }
+
+    debug_assert!(mi_page_immediate_available(page));
+    debug_assert!(mi_page_block_size(page) >= size);
+
+    // and try again, this time succeeding! (i.e. this should never recurse through _mi_page_malloc)
+    if zero && unsafe { (*page).xblock_size } == 0 {
+        // note: we cannot call _mi_page_malloc with zeroing for huge blocks; we zero it afterwards in that case.
+        let p = _mi_page_malloc(heap, page, size, false);
+        debug_assert!(!p.is_null());
+        unsafe { ptr::write_bytes(p.cast::<u8>(), 0, mi_page_usable_block_size(page)) };
+        p
+    } else {
+        _mi_page_malloc(heap, page, size, zero)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{ffi::c_void, ptr, thread};
+
+    use crate::{
+        alloc::{mi_free, mi_heap_malloc},
+        heap::{_mi_heap_random_next, mi_heap_collect},
+        mimalloc_internal::{
+            _mi_page_segment, _mi_page_start, _mi_ptr_segment, _mi_segment_page_of, _mi_thread_id,
+            mi_block_next, mi_page_block_size, mi_page_is_in_full,
+        },
+        mimalloc_types::{
+            MiHeap, MiPage, MiSegmentKind, MiTLD, MI_BIN_FULL, MI_HUGE_BLOCK_SIZE,
+            MI_LARGE_OBJ_SIZE_MAX, MI_MEDIUM_OBJ_SIZE_MAX, MI_MEDIUM_PAGE_SIZE, MI_PADDING_SIZE,
+            MI_SEGMENT_SIZE, MI_SEGMENT_SLICE_SIZE, MI_SMALL_OBJ_SIZE_MAX,
+        },
+        random::_mi_random_init,
+        segment::_mi_segment_page_start,
+    };
+
+    use super::{_mi_heap_delayed_free_partial, mi_page_free_list_extend_secure};
+    use crate::stats::_mi_stats_done;
+
+    // A heap with its own thread local data, owned by the current thread.
+    pub(crate) struct TestHeap {
+        pub(crate) heap: Box<MiHeap>,
+        pub(crate) tld: Box<MiTLD>,
+    }
+
+    // merge the statistics so the main statistics stay balanced
+    impl Drop for TestHeap {
+        fn drop(&mut self) {
+            _mi_stats_done(&mut self.tld.stats);
+        }
+    }
+
+    impl TestHeap {
+        pub(crate) fn new() -> TestHeap {
+            let mut th = TestHeap {
+                heap: Box::new(MiHeap::new()),
+                tld: Box::default(),
+            };
+            th.heap.thread_id = _mi_thread_id();
+            _mi_random_init(&mut th.heap.random);
+            th.heap.cookie = _mi_heap_random_next(&mut *th.heap) | 1;
+            th.heap.tld = &mut *th.tld;
+            th.tld.heap_backing = &mut *th.heap;
+            th.tld.heaps = &mut *th.heap;
+            th.tld.segments.os = &mut th.tld.os;
+            th.tld.segments.stats = &mut th.tld.stats;
+            th.tld.os.stats = &mut th.tld.stats;
+            th
+        }
+
+        pub(crate) fn ptr(&mut self) -> *mut MiHeap {
+            &mut *self.heap
+        }
+    }
+
+    #[test]
+    fn test_mi_malloc_generic_allocates_fresh_pages() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        // enough blocks to fill several 64KiB pages
+        let blocks: Vec<*mut c_void> = (0..4000).map(|_| mi_heap_malloc(heap, 48)).collect();
+        for (i, &p) in blocks.iter().enumerate() {
+            assert!(!p.is_null());
+            assert_eq!(p as usize % 8, 0);
+            unsafe { ptr::write_bytes(p.cast::<u8>(), i as u8, 48) };
+        }
+        let mut sorted: Vec<usize> = blocks.iter().map(|&p| p as usize).collect();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), blocks.len());
+        assert!(th.heap.page_count > 1);
+        assert!(!th.heap.pages[MI_BIN_FULL].first.is_null());
+        assert_eq!(th.tld.segments.count, 1);
+
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        assert_eq!(th.heap.page_count, 0);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_page_and_segment_stats() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let blocks: Vec<*mut c_void> = (0..4000).map(|_| mi_heap_malloc(heap, 48)).collect();
+        let pages = th.heap.page_count as i64;
+        let stats = &th.tld.stats;
+        assert_eq!(stats.segments.current, 1);
+        assert_eq!(stats.pages.current, pages);
+        assert_eq!(stats.pages.peak, pages);
+        assert!(stats.page_committed.current >= 4000 * 48);
+
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        let stats = &th.tld.stats;
+        assert_eq!(stats.pages.current, 0);
+        assert_eq!(stats.pages.freed, pages);
+        assert_eq!(stats.page_committed.current, 0);
+        assert_eq!(stats.segments.current, 0);
+        assert_eq!(stats.segments.allocated, 1);
+    }
+
+    #[test]
+    fn test_mi_malloc_generic_reuses_freed_blocks() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let p = mi_heap_malloc(heap, 100);
+        let q = mi_heap_malloc(heap, 100);
+        mi_free(p);
+        mi_free(q);
+        // collected from the local free list once the free list is exhausted
+        let blocks: Vec<*mut c_void> = (0..2000).map(|_| mi_heap_malloc(heap, 100)).collect();
+        assert!(blocks.contains(&p) && blocks.contains(&q));
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_heap_delayed_free_unfulls_page() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let blocks: Vec<*mut c_void> = (0..3000).map(|_| mi_heap_malloc(heap, 32)).collect();
+        let page = _mi_segment_page_of(_mi_ptr_segment(blocks[0]), blocks[0]);
+        assert!(mi_page_is_in_full(page));
+
+        // the first free from another thread in a full page goes to the heap delayed free list
+        let p = blocks[0] as usize;
+        thread::spawn(move || mi_free(p as *mut c_void))
+            .join()
+            .unwrap();
+        assert!(!th
+            .heap
+            .thread_delayed_free
+            .load(std::sync::atomic::Ordering::Relaxed)
+            .is_null());
+
+        assert!(_mi_heap_delayed_free_partial(heap));
+        assert!(th
+            .heap
+            .thread_delayed_free
+            .load(std::sync::atomic::Ordering::Relaxed)
+            .is_null());
+        assert!(!mi_page_is_in_full(page));
+        assert!(unsafe { (*page).local_free } == blocks[0].cast());
+
+        for p in blocks.into_iter().skip(1) {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    fn page_of(p: *mut c_void) -> *mut MiPage {
+        _mi_segment_page_of(_mi_ptr_segment(p), p)
+    }
+
+    #[test]
+    fn test_mi_malloc_medium_and_large() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let sizes = [
+            MI_SMALL_OBJ_SIZE_MAX + 1,
+            100_000,
+            MI_MEDIUM_OBJ_SIZE_MAX - MI_PADDING_SIZE,
+            MI_MEDIUM_OBJ_SIZE_MAX,
+            1 << 20,
+            MI_LARGE_OBJ_SIZE_MAX - 4096,
+        ];
+        let blocks: Vec<*mut c_void> = sizes.iter().map(|&n| mi_heap_malloc(heap, n)).collect();
+        for (&p, &n) in blocks.iter().zip(sizes.iter()) {
+            assert!(!p.is_null(), "size {n}");
+            let page = page_of(p);
+            assert!(mi_page_block_size(page) >= n);
+            let slice_count = unsafe { (*page).slice_count } as usize;
+            if n <= MI_MEDIUM_OBJ_SIZE_MAX - MI_PADDING_SIZE {
+                assert_eq!(slice_count * MI_SEGMENT_SLICE_SIZE, MI_MEDIUM_PAGE_SIZE);
+            } else {
+                // a single block page
+                assert_eq!(unsafe { (*page).reserved }, 1);
+            }
+            assert_eq!(
+                unsafe { (*_mi_ptr_segment(p)).kind },
+                MiSegmentKind::MiSegmentNormal
+            );
+            unsafe { ptr::write_bytes(p.cast::<u8>(), 0xAB, n) };
+        }
+        for p in blocks {
+            mi_free(p);
+        }
+        mi_heap_collect(heap, true);
+        assert_eq!(th.heap.page_count, 0);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_malloc_huge() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let size = 2 * MI_SEGMENT_SIZE + 12345;
+        let p = mi_heap_malloc(heap, size);
+        assert!(!p.is_null());
+        let segment = _mi_ptr_segment(p);
+        let page = page_of(p);
+        unsafe {
+            assert_eq!((*segment).kind, MiSegmentKind::MiSegmentHuge);
+            assert_eq!((*segment).used, 1);
+            assert!((*segment).segment_slices > (*segment).slice_entries);
+            assert_eq!((*page).reserved, 1);
+            ptr::write_bytes(p.cast::<u8>(), 0xCD, size);
+
+            // the block size of a huge page is the page size
+            let mut psize: usize = 0;
+            _mi_segment_page_start(segment, page, &mut psize);
+            assert!(psize >= size);
+            assert_eq!((*page).xblock_size as usize, psize);
+            assert_eq!(mi_page_block_size(page), psize);
+
+            // and it is taken from the segment once the size no longer fits `xblock_size`
+            (*page).xblock_size = MI_HUGE_BLOCK_SIZE as u32;
+            assert_eq!(mi_page_block_size(page), psize);
+            (*page).xblock_size = psize as u32;
+        }
+        assert_eq!(th.tld.segments.count, 1);
+
+        // a huge page is freed with its segment right away
+        mi_free(p);
+        assert_eq!(th.heap.page_count, 0);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_page_free_list_extend_secure() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let p = mi_heap_malloc(heap, 64);
+        assert!(!p.is_null());
+        let page = page_of(p);
+        let bsize = mi_page_block_size(page);
+        let extend = 100;
+        let mut blocks = Vec::new();
+        let capacity = unsafe { (*page).capacity } as usize;
+        unsafe {
+            // drop the current free list and extend with a randomized one
+            assert!(capacity + extend <= (*page).reserved as usize);
+            (*page).free = ptr::null_mut();
+            mi_page_free_list_extend_secure(heap, page, bsize, extend);
+            (*page).capacity += extend as u16;
+
+            let start = _mi_page_start(_mi_page_segment(page), page, ptr::null_mut()) as usize;
+            let mut block = (*page).free;
+            while !block.is_null() {
+                let ofs = block as usize - start;
+                assert_eq!(ofs % bsize, 0);
+                blocks.push(ofs / bsize);
+                block = mi_block_next(page, block);
+            }
+        }
+        // every new block is on the free list exactly once, in a random order
+        let mut sorted = blocks.clone();
+        sorted.sort_unstable();
+        sorted.dedup();
+        assert_eq!(sorted.len(), extend);
+        assert_eq!(sorted, (capacity..capacity + extend).collect::<Vec<_>>());
+        assert_ne!(blocks, sorted);
+
+        mi_free(p);
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
 }
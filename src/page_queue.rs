@@ -0,0 +1,378 @@
+/* -----------------------------------------------------------
+  Definition of page queues for each block size
+----------------------------------------------------------- */
+
+use std::{ptr, sync::atomic::Ordering};
+
+use crate::{
+    init::_mi_page_empty,
+    mimalloc_internal::{
+        _mi_wsize_from_size, mi_bsr, mi_page_heap, mi_page_is_in_full, mi_page_set_in_full,
+    },
+    mimalloc_types::{
+        MiDelayed, MiHeap, MiPage, MiPageQueue, MI_BIN_FULL, MI_BIN_HUGE, MI_LARGE_OBJ_SIZE_MAX,
+        MI_MEDIUM_OBJ_SIZE_MAX, MI_MEDIUM_OBJ_WSIZE_MAX, MI_PAGE_QUEUES_EMPTY, MI_SMALL_SIZE_MAX,
+    },
+    page::_mi_page_use_delayed_free,
+};
+
+/* -----------------------------------------------------------
+  Queue query
+----------------------------------------------------------- */
+
+#[inline]
+pub fn mi_page_queue_is_huge(pq: *const MiPageQueue) -> bool {
+    unsafe { (*pq).block_size == (MI_MEDIUM_OBJ_SIZE_MAX + std::mem::size_of::<usize>()) }
+}
+
+#[inline]
+pub fn mi_page_queue_is_full(pq: *const MiPageQueue) -> bool {
+    unsafe { (*pq).block_size == (MI_MEDIUM_OBJ_SIZE_MAX + (2 * std::mem::size_of::<usize>())) }
+}
+
+#[inline]
+pub fn mi_page_queue_is_special(pq: *const MiPageQueue) -> bool {
+    unsafe { (*pq).block_size > MI_MEDIUM_OBJ_SIZE_MAX }
+}
+
+/* -----------------------------------------------------------
+  Bins
+----------------------------------------------------------- */
+
+// Return the bin for a given field size.
+// Returns MI_BIN_HUGE if the size is too large.
+// We use `wsize` for the size in "machine word sizes",
+// i.e. byte size == `wsize*sizeof(void*)`.
+#[inline]
+fn mi_bin(size: usize) -> usize {
+    let mut wsize = _mi_wsize_from_size(size);
+    let bin;
+    if wsize <= 1 {
+        bin = 1;
+    } else if wsize <= 8 {
+        bin = wsize;
+    } else if wsize > MI_MEDIUM_OBJ_WSIZE_MAX {
+        bin = MI_BIN_HUGE;
+    } else {
+        wsize -= 1;
+        // find the highest bit
+        let b = mi_bsr(wsize); // note: wsize != 0
+
+        // and use the top 3 bits to determine the bin (~12.5% worst internal fragmentation).
+        // - adjust with 3 because we use do not round the first 8 sizes
+        //   which each get an exact bin
+        bin = ((b << 2) + ((wsize >> (b - 2)) & 0x03)) - 3;
+        debug_assert!(bin < MI_BIN_HUGE);
+    }
+    debug_assert!(bin > 0 && bin <= MI_BIN_HUGE);
+    bin
+}
+
+/* -----------------------------------------------------------
+  Queue of pages with free blocks
+----------------------------------------------------------- */
+
+pub fn _mi_bin(size: usize) -> usize {
+    mi_bin(size)
+}
+
+pub fn _mi_bin_size(bin: usize) -> usize {
+    MI_PAGE_QUEUES_EMPTY[bin].block_size
+}
+
+#[cfg(debug_assertions)]
+fn mi_page_queue_contains(queue: *const MiPageQueue, page: *const MiPage) -> bool {
+    debug_assert!(!page.is_null());
+    let mut list = unsafe { (*queue).first };
+    while !list.is_null() {
+        unsafe {
+            debug_assert!((*list).next.is_null() || (*(*list).next).prev == list);
+            debug_assert!((*list).prev.is_null() || (*(*list).prev).next == list);
+        }
+        if ptr::eq(list, page) {
+            break;
+        }
+        list = unsafe { (*list).next };
+    }
+    ptr::eq(list, page)
+}
+
+#[cfg(debug_assertions)]
+pub fn mi_heap_contains_queue(heap: *const MiHeap, pq: *const MiPageQueue) -> bool {
+    unsafe {
+        let pages = ptr::addr_of!((*heap).pages).cast::<MiPageQueue>();
+        pq >= pages && pq <= pages.add(MI_BIN_FULL)
+    }
+}
+
+pub fn mi_page_queue_of(page: *const MiPage) -> *mut MiPageQueue {
+    let bin = if mi_page_is_in_full(page) {
+        MI_BIN_FULL
+    } else {
+        mi_bin(unsafe { (*page).xblock_size } as usize)
+    };
+    let heap = mi_page_heap(page);
+    debug_assert!(!heap.is_null() && bin <= MI_BIN_FULL);
+    let pq = unsafe { ptr::addr_of_mut!((*heap).pages[bin]) };
+    debug_assert!(
+        bin >= MI_BIN_HUGE || unsafe { (*page).xblock_size as usize == (*pq).block_size }
+    );
+    #[cfg(debug_assertions)]
+    debug_assert!(mi_page_queue_contains(pq, page));
+    pq
+}
+
+pub fn mi_page_queue(heap: *const MiHeap, size: usize) -> *mut MiPageQueue {
+    unsafe { ptr::addr_of!((*heap).pages[_mi_bin(size)]).cast_mut() }
+}
+
+pub fn mi_heap_page_queue_of(heap: *mut MiHeap, page: *const MiPage) -> *mut MiPageQueue {
+    let bin = if mi_page_is_in_full(page) {
+        MI_BIN_FULL
+    } else {
+        mi_bin(unsafe { (*page).xblock_size } as usize)
+    };
+    debug_assert!(bin <= MI_BIN_FULL);
+    let pq = unsafe { ptr::addr_of_mut!((*heap).pages[bin]) };
+    debug_assert!(
+        mi_page_is_in_full(page) || unsafe { (*page).xblock_size as usize == (*pq).block_size }
+    );
+    pq
+}
+
+// The current small page array is for efficiency and for each
+// small size (up to 256) it points directly to the page for that
+// size without having to compute the bin. This means when the
+// current free page queue is updated for a small bin, we need to update a
+// range of entries in `_mi_page_small_free`.
+#[inline]
+fn mi_heap_queue_first_update(heap: *mut MiHeap, pq: *const MiPageQueue) {
+    #[cfg(debug_assertions)]
+    debug_assert!(mi_heap_contains_queue(heap, pq));
+    let size = unsafe { (*pq).block_size };
+    if size > MI_SMALL_SIZE_MAX {
+        return;
+    }
+
+    let mut page = unsafe { (*pq).first };
+    if page.is_null() {
+        page = ptr::addr_of_mut!(_mi_page_empty);
+    }
+
+    // find index in the right direct page array
+    let idx = _mi_wsize_from_size(size);
+    let pages_free = unsafe { ptr::addr_of_mut!((*heap).pages_free_direct).cast::<*mut MiPage>() };
+
+    if unsafe { *pages_free.add(idx) } == page {
+        return; // already set
+    }
+
+    // find start slot
+    let start = if idx <= 1 {
+        0
+    } else {
+        // find previous size; due to minimal alignment upto 3 previous bins may need to be skipped
+        let bin = mi_bin(size);
+        let first = unsafe { ptr::addr_of!((*heap).pages).cast::<MiPageQueue>() };
+        let mut prev = unsafe { pq.sub(1) };
+        while bin == mi_bin(unsafe { (*prev).block_size }) && prev > first {
+            prev = unsafe { prev.sub(1) };
+        }
+        let start = 1 + _mi_wsize_from_size(unsafe { (*prev).block_size });
+        if start > idx {
+            idx
+        } else {
+            start
+        }
+    };
+
+    // set size range to the right page
+    debug_assert!(start <= idx);
+    for sz in start..=idx {
+        unsafe { *pages_free.add(sz) = page };
+    }
+}
+
+pub fn mi_page_queue_remove(queue: *mut MiPageQueue, page: *mut MiPage) {
+    debug_assert!(!page.is_null());
+    #[cfg(debug_assertions)]
+    debug_assert!(mi_page_queue_contains(queue, page));
+    unsafe {
+        debug_assert!(
+            (*page).xblock_size as usize == (*queue).block_size
+                || ((*page).xblock_size as usize > MI_MEDIUM_OBJ_SIZE_MAX
+                    && mi_page_queue_is_huge(queue))
+                || (mi_page_is_in_full(page) && mi_page_queue_is_full(queue))
+        );
+        let heap = mi_page_heap(page);
+
+        if !(*page).prev.is_null() {
+            (*(*page).prev).next = (*page).next;
+        }
+        if !(*page).next.is_null() {
+            (*(*page).next).prev = (*page).prev;
+        }
+        if page == (*queue).last {
+            (*queue).last = (*page).prev;
+        }
+        if page == (*queue).first {
+            (*queue).first = (*page).next;
+            // update first
+            mi_heap_queue_first_update(heap, queue);
+        }
+        (*heap).page_count -= 1;
+        (*page).next = ptr::null_mut();
+        (*page).prev = ptr::null_mut();
+        mi_page_set_in_full(page, false);
+    }
+}
+
+pub fn mi_page_queue_push(heap: *mut MiHeap, queue: *mut MiPageQueue, page: *mut MiPage) {
+    debug_assert!(mi_page_heap(page) == heap);
+    #[cfg(debug_assertions)]
+    debug_assert!(!mi_page_queue_contains(queue, page));
+    unsafe {
+        debug_assert!(
+            (*page).xblock_size as usize == (*queue).block_size
+                || (*page).xblock_size as usize > MI_MEDIUM_OBJ_SIZE_MAX
+                || (mi_page_is_in_full(page) && mi_page_queue_is_full(queue))
+        );
+
+        mi_page_set_in_full(page, mi_page_queue_is_full(queue));
+        (*page).next = (*queue).first;
+        (*page).prev = ptr::null_mut();
+        if !(*queue).first.is_null() {
+            debug_assert!((*(*queue).first).prev.is_null());
+            (*(*queue).first).prev = page;
+            (*queue).first = page;
+        } else {
+            (*queue).first = page;
+            (*queue).last = page;
+        }
+
+        // update direct
+        mi_heap_queue_first_update(heap, queue);
+        (*heap).page_count += 1;
+    }
+}
+
+pub fn mi_page_queue_enqueue_from(to: *mut MiPageQueue, from: *mut MiPageQueue, page: *mut MiPage) {
+    debug_assert!(!page.is_null());
+    #[cfg(debug_assertions)]
+    debug_assert!(mi_page_queue_contains(from, page));
+    #[cfg(debug_assertions)]
+    debug_assert!(!mi_page_queue_contains(to, page));
+    unsafe {
+        let bsize = (*page).xblock_size as usize;
+        debug_assert!(
+            (bsize == (*to).block_size
+                && (bsize == (*from).block_size || mi_page_queue_is_full(from)))
+                || (bsize == (*from).block_size && mi_page_queue_is_full(to))
+                || (bsize > MI_LARGE_OBJ_SIZE_MAX
+                    && (mi_page_queue_is_huge(to) || mi_page_queue_is_full(to)))
+        );
+
+        let heap = mi_page_heap(page);
+        if !(*page).prev.is_null() {
+            (*(*page).prev).next = (*page).next;
+        }
+        if !(*page).next.is_null() {
+            (*(*page).next).prev = (*page).prev;
+        }
+        if page == (*from).last {
+            (*from).last = (*page).prev;
+        }
+        if page == (*from).first {
+            (*from).first = (*page).next;
+            // update first
+            mi_heap_queue_first_update(heap, from);
+        }
+
+        (*page).prev = (*to).last;
+        (*page).next = ptr::null_mut();
+        if !(*to).last.is_null() {
+            debug_assert!(heap == mi_page_heap((*to).last));
+            (*(*to).last).next = page;
+            (*to).last = page;
+        } else {
+            (*to).first = page;
+            (*to).last = page;
+            mi_heap_queue_first_update(heap, to);
+        }
+
+        mi_page_set_in_full(page, mi_page_queue_is_full(to));
+    }
+}
+
+pub fn _mi_page_queue_append(
+    heap: *mut MiHeap,
+    pq: *mut MiPageQueue,
+    append: *mut MiPageQueue,
+) -> usize {
+    #[cfg(debug_assertions)]
+    debug_assert!(mi_heap_contains_queue(heap, pq));
+    unsafe {
+        debug_assert!((*pq).block_size == (*append).block_size);
+
+        if (*append).first.is_null() {
+            return 0;
+        }
+
+        // set append pages to new heap and count
+        let mut count = 0;
+        let mut page = (*append).first;
+        while !page.is_null() {
+            // inline `mi_page_set_heap` to avoid wrong assertion during absorption;
+            // in this case it is ok to be delayed freeing since both "to" and "from" heap are still alive.
+            (*page).xheap.store(heap as usize, Ordering::Release);
+            // set the flag to delayed free (not overriding NEVER_DELAYED_FREE) which has as a
+            // side effect that it spins until any DELAYED_FREEING is finished. This ensures
+            // that after appending only the new heap will be used for delayed free operations.
+            _mi_page_use_delayed_free(page, MiDelayed::MiUseDelayedFree, false);
+            count += 1;
+            page = (*page).next;
+        }
+
+        if (*pq).last.is_null() {
+            // take over afresh
+            debug_assert!((*pq).first.is_null());
+            (*pq).first = (*append).first;
+            (*pq).last = (*append).last;
+            mi_heap_queue_first_update(heap, pq);
+        } else {
+            // append to end
+            debug_assert!(!(*pq).last.is_null());
+            debug_assert!(!(*append).first.is_null());
+            (*(*pq).last).next = (*append).first;
+            (*(*append).first).prev = (*pq).last;
+            (*pq).last = (*append).last;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mimalloc_types::{MI_BIN_HUGE, MI_MEDIUM_OBJ_SIZE_MAX, MI_PAGE_QUEUES_EMPTY};
+
+    use super::_mi_bin;
+
+    #[test]
+    fn test_mi_bin_matches_queue_sizes() {
+        // every queue block size maps back onto its own bin
+        for (bin, pq) in MI_PAGE_QUEUES_EMPTY
+            .iter()
+            .enumerate()
+            .take(MI_BIN_HUGE)
+            .skip(1)
+        {
+            let size = pq.block_size;
+            if size > MI_MEDIUM_OBJ_SIZE_MAX {
+                break;
+            }
+            assert_eq!(_mi_bin(size), bin, "size {size}");
+            assert!(_mi_bin(size + 1) > bin);
+        }
+        assert_eq!(_mi_bin(MI_MEDIUM_OBJ_SIZE_MAX + 1), MI_BIN_HUGE);
+    }
+}
@@ -1,5 +1,369 @@
-use crate::mimalloc_types::MiRandomCtx;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn _mi_heap_random_next(ctx: *mut MiRandomCtx) -> usize {
-    0
+use crate::{mimalloc_types::MiRandomCtx, options::_mi_warning_message, os::_mi_clock_now};
+
+/* ----------------------------------------------------------------------------
+We use our own PRNG to keep predictable performance of random number generation
+and to avoid implementations that use a lock. We only use the OS provided
+random source to initialize the initial seeds. Since we do not need ultimate
+performance but we do rely on the security (for secret cookies in secure mode)
+we use a cryptographically secure generator (chacha20).
+-----------------------------------------------------------------------------*/
+
+const MI_CHACHA_ROUNDS: usize = 20; // perhaps use 12 for better performance?
+
+/* ----------------------------------------------------------------------------
+Chacha20 implementation as the original algorithm with a 64-bit nonce
+and counter: https://en.wikipedia.org/wiki/Salsa20
+The input matrix has sixteen 32-bit values:
+Position  0 to  3: constant key
+Position  4 to 11: the key
+Position 12 to 13: the counter.
+Position 14 to 15: the nonce.
+
+The implementation uses regular C code which compiles very well on modern compilers.
+(gcc x64 has no register spills, and clang 6+ uses SSE instructions)
+-----------------------------------------------------------------------------*/
+
+#[inline]
+fn rotl(x: u32, shift: u32) -> u32 {
+    x.rotate_left(shift)
+}
+
+#[inline]
+fn qround(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = rotl(x[d] ^ x[a], 16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = rotl(x[b] ^ x[c], 12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = rotl(x[d] ^ x[a], 8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = rotl(x[b] ^ x[c], 7);
+}
+
+fn chacha_block(ctx: &mut MiRandomCtx) {
+    // scramble into `x`
+    let mut x = ctx.input;
+    for _ in (0..MI_CHACHA_ROUNDS).step_by(2) {
+        qround(&mut x, 0, 4, 8, 12);
+        qround(&mut x, 1, 5, 9, 13);
+        qround(&mut x, 2, 6, 10, 14);
+        qround(&mut x, 3, 7, 11, 15);
+        qround(&mut x, 0, 5, 10, 15);
+        qround(&mut x, 1, 6, 11, 12);
+        qround(&mut x, 2, 7, 8, 13);
+        qround(&mut x, 3, 4, 9, 14);
+    }
+
+    // add scrambled data to the initial state
+    for (out, (x, input)) in ctx.output.iter_mut().zip(x.iter().zip(ctx.input)) {
+        *out = x.wrapping_add(input);
+    }
+    ctx.output_available = 16;
+
+    // increment the counter for the next round
+    ctx.input[12] = ctx.input[12].wrapping_add(1);
+    if ctx.input[12] == 0 {
+        ctx.input[13] = ctx.input[13].wrapping_add(1);
+        if ctx.input[13] == 0 {
+            // and keep increasing into the nonce
+            ctx.input[14] = ctx.input[14].wrapping_add(1);
+        }
+    }
+}
+
+fn chacha_next32(ctx: &mut MiRandomCtx) -> u32 {
+    if ctx.output_available <= 0 {
+        chacha_block(ctx);
+    }
+    let i = (16 - ctx.output_available) as usize;
+    let x = ctx.output[i];
+    ctx.output[i] = 0; // reset once the data is handed out
+    ctx.output_available -= 1;
+    x
+}
+
+#[inline]
+fn read32(p: &[u8], idx32: usize) -> u32 {
+    let i = 4 * idx32;
+    u32::from_le_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]])
+}
+
+fn chacha_init(ctx: &mut MiRandomCtx, key: &[u8; 32], nonce: u64) {
+    // since we only use chacha for randomness (and not encryption) we
+    // do not _need_ to read 32-bit values as little endian but we do anyways
+    // just for being compatible :-)
+    *ctx = MiRandomCtx::default();
+    let sigma = b"expand 32-byte k";
+    for i in 0..4 {
+        ctx.input[i] = read32(sigma, i);
+    }
+    for i in 0..8 {
+        ctx.input[i + 4] = read32(key, i);
+    }
+    ctx.input[12] = 0;
+    ctx.input[13] = 0;
+    ctx.input[14] = nonce as u32;
+    ctx.input[15] = (nonce >> 32) as u32;
+}
+
+fn chacha_split(ctx: &MiRandomCtx, nonce: u64, ctx_new: &mut MiRandomCtx) {
+    *ctx_new = MiRandomCtx::default();
+    ctx_new.input = ctx.input;
+    ctx_new.input[12] = 0;
+    ctx_new.input[13] = 0;
+    ctx_new.input[14] = nonce as u32;
+    ctx_new.input[15] = (nonce >> 32) as u32;
+    debug_assert!(ctx.input[14] != ctx_new.input[14] || ctx.input[15] != ctx_new.input[15]); // do not reuse nonces!
+    chacha_block(ctx_new);
+}
+
+/* ----------------------------------------------------------------------------
+Random interface
+-----------------------------------------------------------------------------*/
+
+fn mi_random_is_initialized(ctx: &MiRandomCtx) -> bool {
+    ctx.input[0] != 0
+}
+
+pub fn _mi_random_split(ctx: *mut MiRandomCtx, ctx_new: *mut MiRandomCtx) {
+    debug_assert!(mi_random_is_initialized(unsafe { &*ctx }));
+    debug_assert!(ctx != ctx_new);
+    chacha_split(
+        unsafe { &*ctx },
+        ctx_new as u64, /*nonce*/
+        unsafe { &mut *ctx_new },
+    );
+    unsafe { (*ctx_new).weak = (*ctx).weak };
+}
+
+pub fn _mi_random_next(ctx: *mut MiRandomCtx) -> usize {
+    let ctx = unsafe { &mut *ctx };
+    debug_assert!(mi_random_is_initialized(ctx));
+    #[cfg(target_pointer_width = "32")]
+    {
+        chacha_next32(ctx) as usize
+    }
+    #[cfg(target_pointer_width = "64")]
+    {
+        ((chacha_next32(ctx) as usize) << 32) | chacha_next32(ctx) as usize
+    }
+}
+
+/* ----------------------------------------------------------------------------
+To initialize a fresh random context we rely on the OS:
+- Windows     : BCryptGenRandom (or RtlGenRandom)
+- osX,bsd,wasi: arc4random_buf
+- Linux       : getrandom,/dev/urandom
+If we cannot get good randomness, we fall back to weak randomness based on a timer and ASLR.
+-----------------------------------------------------------------------------*/
+
+#[cfg(windows)]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    use windows::Win32::Security::Cryptography::{
+        BCryptGenRandom, BCRYPT_ALG_HANDLE, BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+    };
+    unsafe {
+        BCryptGenRandom(
+            BCRYPT_ALG_HANDLE::default(),
+            buf,
+            BCRYPT_USE_SYSTEM_PREFERRED_RNG,
+        )
+    }
+    .is_ok()
+}
+
+#[cfg(any(
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    unsafe { libc::arc4random_buf(buf.as_mut_ptr().cast(), buf.len()) };
+    true
+}
+
+#[cfg(target_os = "linux")]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    // Modern Linux provides `getrandom` but different distributions either use `sys/random.h` or `linux/random.h`
+    // and for the latter the actual `getrandom` call is not always defined.
+    // (see <https://stackoverflow.com/questions/45237324/why-doesnt-getrandom-compile>)
+    // We therefore use a syscall directly and fall back dynamically to /dev/urandom when needed.
+    static NO_GETRANDOM: AtomicBool = AtomicBool::new(false);
+    if !NO_GETRANDOM.load(Ordering::Acquire) {
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_getrandom,
+                buf.as_mut_ptr(),
+                buf.len(),
+                libc::GRND_NONBLOCK,
+            )
+        };
+        if ret >= 0 {
+            return buf.len() == ret as usize;
+        }
+        if io::Error::last_os_error().raw_os_error() != Some(libc::ENOSYS) {
+            return false;
+        }
+        NO_GETRANDOM.store(true, Ordering::Release); // don't call again, and fall back to /dev/urandom
+    }
+    let fd = unsafe { libc::open(c"/dev/urandom".as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return false;
+    }
+    let mut count = 0;
+    while count < buf.len() {
+        let ret = unsafe { libc::read(fd, buf[count..].as_mut_ptr().cast(), buf.len() - count) };
+        if ret <= 0 {
+            let err = io::Error::last_os_error().raw_os_error();
+            if err != Some(libc::EAGAIN) && err != Some(libc::EINTR) {
+                break;
+            }
+        } else {
+            count += ret as usize;
+        }
+    }
+    unsafe { libc::close(fd) };
+    count == buf.len()
+}
+
+#[cfg(not(any(
+    windows,
+    target_os = "linux",
+    target_vendor = "apple",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+fn os_random_buf(buf: &mut [u8]) -> bool {
+    false
+}
+
+pub fn _mi_random_shuffle(mut x: usize) -> usize {
+    if x == 0 {
+        x = 17; // ensure we don't get stuck in generating zeros
+    }
+    #[cfg(target_pointer_width = "64")]
+    {
+        // by Sebastiano Vigna, see: <http://xoshiro.di.unimi.it/splitmix64.c>
+        x ^= x >> 30;
+        x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+        x ^= x >> 27;
+        x = x.wrapping_mul(0x94d049bb133111eb);
+        x ^= x >> 31;
+    }
+    #[cfg(target_pointer_width = "32")]
+    {
+        // by Chris Wellons, see: <https://nullprogram.com/blog/2018/07/31/>
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x7feb352d);
+        x ^= x >> 15;
+        x = x.wrapping_mul(0x846ca68b);
+        x ^= x >> 16;
+    }
+    x
+}
+
+pub fn _mi_os_random_weak(extra_seed: usize) -> usize {
+    let mut x = (_mi_os_random_weak as fn(usize) -> usize as usize) ^ extra_seed; // ASLR makes the address random
+    x ^= _mi_clock_now() as usize;
+    // and do a few randomization steps
+    let max = ((x ^ (x >> 17)) & 0x0F) + 1;
+    for _ in 0..max {
+        x = _mi_random_shuffle(x);
+    }
+    debug_assert!(x != 0);
+    x
+}
+
+fn mi_random_init_ex(ctx: *mut MiRandomCtx, use_weak: bool) {
+    let mut key = [0u8; 32];
+    let weak = use_weak || !os_random_buf(&mut key);
+    if weak {
+        // if we fail to get random data from the OS, we fall back to a
+        // weak random source based on the current time
+        if !use_weak {
+            _mi_warning_message(format_args!("unable to use secure randomness\n"));
+        }
+        let mut x = _mi_os_random_weak(0);
+        for i in 0..8 {
+            // key is eight 32-bit words.
+            x = _mi_random_shuffle(x);
+            key[4 * i..4 * i + 4].copy_from_slice(&(x as u32).to_le_bytes());
+        }
+    }
+    let nonce = ctx as u64;
+    let ctx = unsafe { &mut *ctx };
+    chacha_init(ctx, &key, nonce);
+    ctx.weak = weak;
+}
+
+pub fn _mi_random_init(ctx: *mut MiRandomCtx) {
+    mi_random_init_ex(ctx, false);
+}
+
+pub fn _mi_random_init_weak(ctx: *mut MiRandomCtx) {
+    mi_random_init_ex(ctx, true);
+}
+
+pub fn _mi_random_reinit_if_weak(ctx: *mut MiRandomCtx) {
+    if unsafe { (*ctx).weak } {
+        _mi_random_init(ctx);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mimalloc_types::MiRandomCtx;
+
+    use super::{
+        _mi_random_init, _mi_random_init_weak, _mi_random_next, _mi_random_reinit_if_weak,
+        _mi_random_split, chacha_block, chacha_init, os_random_buf,
+    };
+
+    #[test]
+    fn test_chacha20_block() {
+        // test vector from RFC 7539, section 2.3.2 (with its 96-bit nonce mapped onto counter and nonce)
+        let key: Vec<u8> = (0u8..32).collect();
+        let mut ctx = MiRandomCtx::default();
+        chacha_init(&mut ctx, key.as_slice().try_into().unwrap(), 0x4a000000);
+        ctx.input[12] = 1;
+        ctx.input[13] = 0x09000000;
+        chacha_block(&mut ctx);
+        assert_eq!(ctx.output[0], 0xe4e7f110);
+        assert_eq!(ctx.output[1], 0x15593bd1);
+        assert_eq!(ctx.output[15], 0x4e3c50a2);
+        assert_eq!(ctx.input[12], 2); // the counter is incremented
+    }
+
+    #[test]
+    fn test_mi_random_init_and_split() {
+        let mut buf = [0u8; 32];
+        if cfg!(any(unix, windows)) {
+            assert!(os_random_buf(&mut buf));
+            assert!(buf.iter().any(|&b| b != 0));
+        }
+
+        let mut ctx = MiRandomCtx::default();
+        _mi_random_init(&mut ctx);
+        assert!(!ctx.weak);
+        let (x, y) = (_mi_random_next(&mut ctx), _mi_random_next(&mut ctx));
+        assert_ne!(x, y);
+
+        // a split context continues independently
+        let mut ctx_new = MiRandomCtx::default();
+        _mi_random_split(&mut ctx, &mut ctx_new);
+        assert_ne!(_mi_random_next(&mut ctx_new), _mi_random_next(&mut ctx));
+
+        let mut weak = MiRandomCtx::default();
+        _mi_random_init_weak(&mut weak);
+        assert!(weak.weak);
+        _mi_random_reinit_if_weak(&mut weak);
+        assert!(!weak.weak);
+    }
 }
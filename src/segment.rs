@@ -1,28 +1,43 @@
 use std::mem::size_of;
-use std::sync::atomic::Ordering;
-use std::{ptr, sync::atomic::AtomicU32};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
 use libc::{c_void, memset};
 use memoffset::offset_of;
 
-use crate::arena::{self, _mi_arena_alloc_aligned};
+use crate::arena::{_mi_arena_alloc_aligned, _mi_arena_free, _mi_arena_memid_is_suitable};
 use crate::mimalloc_internal::{
-    _mi_divide_up, mi_commit_mask_create_empty, mi_commit_mask_create_full, mi_commit_mask_is_full,
+    _mi_align_down, _mi_divide_up, _mi_page_segment, _mi_ptr_cookie, _mi_ptr_segment,
+    _mi_thread_id, mi_atomic_yield, mi_bsr, mi_commit_mask_create_empty,
+    mi_commit_mask_create_full, mi_commit_mask_is_empty, mi_commit_mask_is_full, mi_page_all_free,
+    mi_page_block_size, mi_page_has_any_available, mi_page_heap, mi_page_set_heap,
+    mi_page_thread_free_flag, mi_page_to_slice, mi_segment_size, mi_slice_first, mi_slice_to_page,
 };
 use crate::mimalloc_types::MiOption::{self, MiOptionEagerCommitDelay};
 use crate::mimalloc_types::{
-    MiCommitMask, MiSlice, MI_COMMIT_MASK_BITS, MI_COMMIT_MASK_FIELD_BITS,
-    MI_COMMIT_MASK_FIELD_COUNT, MI_COMMIT_SIZE, MI_HUGE_BLOCK_SIZE, MI_LARGE_OBJ_SIZE_MAX,
-    MI_SECURE, MI_SEGMENT_ALIGN, MI_SEGMENT_SIZE, MI_SEGMENT_SLICE_SIZE,
+    MiBlock, MiCommitMask, MiDelayed, MiPageKind, MiSegmentKind, MiSlice, MiSpanQueue,
+    MI_ALIGNMENT_MAX, MI_COMMIT_MASK_BITS, MI_COMMIT_MASK_FIELD_BITS, MI_COMMIT_MASK_FIELD_COUNT,
+    MI_COMMIT_SIZE, MI_HUGE_BLOCK_SIZE, MI_INTPTR_SIZE, MI_LARGE_OBJ_SIZE_MAX,
+    MI_MAX_ALIGN_GUARANTEE, MI_MAX_SLICE_OFFSET, MI_MEDIUM_OBJ_SIZE_MAX, MI_MEDIUM_PAGE_SIZE,
+    MI_MINIMAL_COMMIT_SIZE, MI_SECURE, MI_SEGMENT_ALIGN, MI_SEGMENT_BIN_MAX, MI_SEGMENT_MASK,
+    MI_SEGMENT_SIZE, MI_SEGMENT_SLICE_SIZE, MI_SLICES_PER_SEGMENT, MI_SMALL_OBJ_SIZE_MAX,
 };
-use crate::options::{mi_option_get_clamp, mi_option_is_enabled};
-use crate::os::{_mi_align_up, _mi_os_commit};
-use crate::segment::MiOption::MiOptionMaxSegmentReclaim;
-use crate::segment_cache::{_mi_segment_cache_pop, _mi_segment_map_allocated_at};
+use crate::options::{_mi_warning_message, mi_option_get_clamp, mi_option_is_enabled};
+use crate::os::{
+    _mi_align_up, _mi_clock_now, _mi_os_commit, _mi_os_decommit, _mi_os_page_size, _mi_os_protect,
+    _mi_os_reset, _mi_os_unprotect,
+};
+use crate::segment_cache::{
+    _mi_segment_cache_pop, _mi_segment_cache_push, _mi_segment_map_allocated_at,
+    _mi_segment_map_freed_at,
+};
+use crate::stats::{_mi_stat_decrease, _mi_stat_increase, _mi_stats_main};
 use crate::{
+    heap::_mi_heap_memid_is_suitable,
     init::_mi_current_thread_count,
     mimalloc_types::{MiArenaIdT, MiHeap, MiOsTLD, MiPage, MiSegment, MiSegmentsTLD},
     options::mi_option_get,
+    page::{_mi_page_free_collect, _mi_page_reclaim, _mi_page_use_delayed_free},
 };
 
 // Allocate a segment from the OS aligned to `MI_SEGMENT_SIZE` .
@@ -34,7 +49,7 @@ pub fn mi_segment_alloc(
     os_tld: *mut MiOsTLD,
     huge_page: *mut *mut MiPage,
 ) -> *mut MiSegment {
-    debug_assert!((required == 0 && huge_page.is_null()) || (required > 0 && huge_page.is_null()));
+    debug_assert!((required == 0 && huge_page.is_null()) || (required > 0 && !huge_page.is_null()));
 
     // calculate needed sizes first
     let mut info_slices: usize = 0;
@@ -50,7 +65,7 @@ pub fn mi_segment_alloc(
     _mi_current_thread_count() > 1 &&       // do not delay for the first N threads
     unsafe { (*tld).count} < mi_option_get(MiOptionEagerCommitDelay) as u64;
 
-    let eager: bool = !eager_delay && mi_option_is_enabled(MiOptionEagerCommitDelay);
+    let eager: bool = !eager_delay && mi_option_is_enabled(MiOption::MiOptionEagerCommit);
     let mut commit = eager || (required > 0);
     let mut is_zero = false;
 
@@ -94,7 +109,7 @@ pub fn mi_segment_alloc(
             memset(
                 (segment as usize + ofs) as *mut c_void,
                 0,
-                prefix + size_of::<MiSlice>() * (segment_slices + 1),
+                prefix + size_of::<MiSlice>() * (segment_slices.min(MI_SLICES_PER_SEGMENT) + 1),
             ); // one more
         }
     }
@@ -112,12 +127,12 @@ pub fn mi_segment_alloc(
                 &(*segment).decommit_mask
             ));
             // #if MI_DEBUG>2
-            if cfg!(Debug) {
+            if cfg!(debug_assertions) {
                 let commit_needed =
                     _mi_divide_up(info_slices * MI_SEGMENT_SLICE_SIZE, MI_COMMIT_SIZE);
                 let mut commit_needed_mask = MiCommitMask { mask: [0; 8] };
                 mi_commit_mask_create(0, commit_needed, &mut commit_needed_mask);
-                debug_assert!(mi_commit_mask_any_set(
+                debug_assert!(!mi_commit_mask_any_set(
                     &(*segment).decommit_mask,
                     &commit_needed_mask,
                 ));
@@ -125,11 +140,84 @@ pub fn mi_segment_alloc(
 
             // #endif
         }
+
+        // initialize segment info
+        let slice_entries = if segment_slices > MI_SLICES_PER_SEGMENT {
+            MI_SLICES_PER_SEGMENT
+        } else {
+            segment_slices
+        };
+        (*segment).segment_slices = segment_slices as u64;
+        (*segment).segment_info_slices = info_slices as u64;
+        (*segment)
+            .thread_id
+            .store(_mi_thread_id(), Ordering::Relaxed);
+        (*segment).cookie = _mi_ptr_cookie(segment.cast());
+        (*segment).slice_entries = slice_entries as u64;
+        (*segment).kind = if required == 0 {
+            MiSegmentKind::MiSegmentNormal
+        } else {
+            MiSegmentKind::MiSegmentHuge
+        };
+
+        let mut guard_slices = 0;
+        if MI_SECURE != 0 {
+            // in secure mode, we set up a protected page in between the segment info
+            // and the page data, and at the end of the segment.
+            let os_pagesize = _mi_os_page_size();
+            debug_assert!(mi_segment_info_size(segment) - os_pagesize >= pre_size);
+            _mi_os_protect(
+                (segment as *mut u8)
+                    .add(mi_segment_info_size(segment) - os_pagesize)
+                    .cast(),
+                os_pagesize,
+            );
+            let end = (segment as *mut u8).add(mi_segment_size(segment) - os_pagesize);
+            mi_segment_ensure_committed(segment, end, os_pagesize);
+            _mi_os_protect(end.cast(), os_pagesize);
+            if slice_entries == segment_slices {
+                (*segment).slice_entries -= 1; // don't use the last slice :-(
+            }
+            guard_slices = 1;
+        }
+
+        // reserve first slices for segment info
+        let page0 = mi_segment_span_allocate(segment, 0, info_slices);
+        debug_assert!(!page0.is_null());
+        if page0.is_null() {
+            return ptr::null_mut(); // cannot fail as we always commit in advance
+        }
+        debug_assert!((*segment).used == 1);
+        (*segment).used = 0; // don't count our internal slices towards usage
+
+        // initialize initial free pages
+        if (*segment).kind == MiSegmentKind::MiSegmentNormal {
+            // not a huge page
+            debug_assert!(huge_page.is_null());
+            mi_segment_span_free(
+                segment,
+                info_slices,
+                (*segment).slice_entries as usize - info_slices,
+                false, // don't decommit
+                tld,
+            );
+        } else {
+            debug_assert!(!huge_page.is_null());
+            debug_assert!(mi_commit_mask_is_empty(&(*segment).decommit_mask));
+            debug_assert!(mi_commit_mask_is_full(&(*segment).commit_mask));
+            *huge_page = mi_segment_span_allocate(
+                segment,
+                info_slices,
+                segment_slices - info_slices - guard_slices,
+            );
+            debug_assert!(!(*huge_page).is_null()); // cannot fail as we commit in advance
+        }
     }
 
-    ptr::null_mut()
+    segment
 }
 
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
 fn mi_segment_os_alloc(
     required: usize,
     page_alignment: usize,
@@ -213,7 +301,7 @@ fn mi_segment_os_alloc(
             }
         }
     }
-    debug_assert!(!segment.is_null() && segment as usize % MI_SEGMENT_SIZE == 0);
+    debug_assert!(!segment.is_null() && (segment as usize).is_multiple_of(MI_SEGMENT_SIZE));
 
     let commit_needed =
         unsafe { _mi_divide_up((*pinfo_slices) * MI_SEGMENT_SLICE_SIZE, MI_COMMIT_SIZE) };
@@ -231,7 +319,16 @@ fn mi_segment_os_alloc(
             is_zero, /*unsafe { (*tld).stats }*/
         );
         if !ok {
-            return ptr::null_mut(); // failed to commit
+            // failed to commit: release the reserved memory again
+            _mi_arena_free(
+                segment.cast(),
+                segment_size,
+                alignment,
+                align_offset,
+                memid,
+                unsafe { *pcommit },
+            );
+            return ptr::null_mut();
         }
         mi_commit_mask_set(pcommit_mask, &commit_needed_mask);
     }
@@ -244,9 +341,9 @@ fn mi_segment_os_alloc(
         (*segment).mem_alignment = alignment;
         (*segment).mem_align_offset = align_offset;
     }
-    mi_segments_track_size(segment_size as i32, tld);
+    mi_segments_track_size(segment_size as i64, tld);
     _mi_segment_map_allocated_at(segment);
-    return segment;
+    segment
 }
 
 fn mi_segment_try_reclaim(
@@ -256,40 +353,40 @@ fn mi_segment_try_reclaim(
     reclaimed: *mut bool,
     tld: *mut MiSegmentsTLD,
 ) -> *mut MiSegment {
-    //   unsafe { *reclaimed = false ;
-    //   let segment;
-    //   let mut max_tries = mi_option_get_clamp(MiOptionMaxSegmentReclaim, 8, 1024);     // limit the work to bound allocation times
-    //   while ((max_tries-- > 0) && (!(segment = mi_abandoned_pop()).is_null())) {
-    //     (*segment).abandoned_visits += 1;
-    //     // todo: an arena exclusive heap will potentially visit many abandoned unsuitable segments
-    //     // and push them into the visited list and use many tries. Perhaps we can skip non-suitable ones in a better way?
-    //     let is_suitable = _mi_heap_memid_is_suitable(heap, (*segment).memid);
-    //     let has_page = mi_segment_check_free(segment,needed_slices,block_size,tld); // try to free up pages (due to concurrent frees)
-    //     if (*segment).used == 0 {
-    //       // free the segment (by forced reclaim) to make it available to other threads.
-    //       // note1: we prefer to free a segment as that might lead to reclaiming another
-    //       // segment that is still partially used.
-    //       // note2: we could in principle optimize this by skipping reclaim and directly
-    //       // freeing but that would violate some invariants temporarily)
-    //       mi_segment_reclaim(segment, heap, 0, ptr::null_mut(), tld);
-    //     }
-    //     else if (has_page && is_suitable) {
-    //       // found a large enough free span, or a page of the right block_size with free space
-    //       // we return the result of reclaim (which is usually `segment`) as it might free
-    //       // the segment due to concurrent frees (in which case `NULL` is returned).
-    //       return mi_segment_reclaim(segment, heap, block_size, reclaimed, tld);
-    //     }
-    //     else if (*segment).abandoned_visits > 3 && is_suitable {
-    //       // always reclaim on 3rd visit to limit the abandoned queue length.
-    //       mi_segment_reclaim(segment, heap, 0, NULL, tld);
-    //     }
-    //     else {
-    //       // otherwise, push on the visited list so it gets not looked at too quickly again
-    //     //   mi_segment_delayed_decommit(segment, true /* force? */, (*tld).stats); // forced decommit if needed as we may not visit soon again
-    //     //   mi_abandoned_visited_push(segment);
-    //     }
-    //   }
-    //   }
+    unsafe { *reclaimed = false };
+    let mut max_tries = mi_option_get_clamp(MiOption::MiOptionMaxSegmentReclaim, 8, 1024); // limit the work to bound allocation times
+    while max_tries > 0 {
+        max_tries -= 1;
+        let segment = mi_abandoned_pop();
+        if segment.is_null() {
+            break;
+        }
+        unsafe { (*segment).abandoned_visits += 1 };
+        // todo: an arena exclusive heap will potentially visit many abandoned unsuitable segments
+        // and push them into the visited list and use many tries. Perhaps we can skip non-suitable ones in a better way?
+        let is_suitable = _mi_heap_memid_is_suitable(heap, unsafe { (*segment).memid });
+        let has_page = mi_segment_check_free(segment, needed_slices, block_size, tld); // try to free up pages (due to concurrent frees)
+        if unsafe { (*segment).used } == 0 {
+            // free the segment (by forced reclaim) to make it available to other threads.
+            // note1: we prefer to free a segment as that might lead to reclaiming another
+            // segment that is still partially used.
+            // note2: we could in principle optimize this by skipping reclaim and directly
+            // freeing but that would violate some invariants temporarily)
+            mi_segment_reclaim(segment, heap, 0, ptr::null_mut(), tld);
+        } else if has_page && is_suitable {
+            // found a large enough free span, or a page of the right block_size with free space
+            // we return the result of reclaim (which is usually `segment`) as it might free
+            // the segment due to concurrent frees (in which case `NULL` is returned).
+            return mi_segment_reclaim(segment, heap, block_size, reclaimed, tld);
+        } else if unsafe { (*segment).abandoned_visits } > 3 && is_suitable {
+            // always reclaim on 3rd visit to limit the abandoned queue length.
+            mi_segment_reclaim(segment, heap, 0, ptr::null_mut(), tld);
+        } else {
+            // otherwise, push on the visited list so it gets not looked at too quickly again
+            mi_segment_delayed_decommit(segment, true /* force? */); // forced decommit if needed as we may not visit soon again
+            mi_abandoned_visited_push(segment);
+        }
+    }
     ptr::null_mut()
 }
 
@@ -333,108 +430,487 @@ fn mi_segment_calculate_slices(
     pre_size: *mut usize,
     info_slices: *mut usize,
 ) -> usize {
-    0
-}
-
-type MiTaggedSegment = u64;
-
-// // Pop from the abandoned list
-// fn  mi_abandoned_pop() -> *mut MiSegment {
-//     let segment;
-//     // Check efficiently if it is empty (or if the visited list needs to be moved)
-//     let ts = mi_atomic_load_relaxed(&abandoned);
-//     segment = mi_tagged_segment_ptr(ts);
-//     if mi_likely(segment == NULL) {
-//       if mi_likely(!mi_abandoned_visited_revisit()) { // try to swap in the visited list on NULL
-//         return NULL;
-//       }
-//     }
-
-//     // Do a pop. We use a reader count to prevent
-//     // a segment to be decommitted while a read is still pending,
-//     // and a tagged pointer to prevent A-B-A link corruption.
-//     // (this is called from `region.c:_mi_mem_free` for example)
-//     mi_atomic_increment_relaxed(&abandoned_readers);  // ensure no segment gets decommitted
-//     mi_tagged_segment_t next = 0;
-//     ts = mi_atomic_load_acquire(&abandoned);
-//     do {
-//       segment = mi_tagged_segment_ptr(ts);
-//       if (segment != NULL) {
-//         mi_segment_t* anext = mi_atomic_load_ptr_relaxed(mi_segment_t, &segment->abandoned_next);
-//         next = mi_tagged_segment(anext, ts); // note: reads the segment's `abandoned_next` field so should not be decommitted
-//       }
-//     } while (segment != NULL && !mi_atomic_cas_weak_acq_rel(&abandoned, &ts, next));
-//     mi_atomic_decrement_relaxed(&abandoned_readers);  // release reader lock
-//     if (segment != NULL) {
-//       mi_atomic_store_ptr_release(mi_segment_t, &segment->abandoned_next, NULL);
-//       mi_atomic_decrement_relaxed(&abandoned_count);
-//     }
-//     return segment;
-//   }
+    let page_size = _mi_os_page_size();
+    let mut isize = _mi_align_up(size_of::<MiSegment>(), page_size);
+    let mut guardsize = 0;
+    let mut required = required;
+
+    if MI_SECURE != 0 {
+        // in secure mode, we set up a protected page in between the segment info
+        // and the page data (and one at the end of the segment)
+        guardsize = page_size;
+        if required > 0 {
+            required = _mi_align_up(required, MI_SEGMENT_SLICE_SIZE) + page_size;
+        }
+    }
+
+    if !pre_size.is_null() {
+        unsafe { *pre_size = isize };
+    }
+    isize = _mi_align_up(isize + guardsize, MI_SEGMENT_SLICE_SIZE);
+    if !info_slices.is_null() {
+        unsafe { *info_slices = isize / MI_SEGMENT_SLICE_SIZE };
+    }
+    let segment_size = if required == 0 {
+        MI_SEGMENT_SIZE
+    } else {
+        _mi_align_up(required + isize + guardsize, MI_SEGMENT_SLICE_SIZE)
+    };
+    debug_assert!(segment_size % MI_SEGMENT_SLICE_SIZE == 0);
+    segment_size / MI_SEGMENT_SLICE_SIZE
+}
+
+/* -----------------------------------------------------------
+   Abandonment
+
+   When threads terminate, they can leave segments with
+   live blocks (reachable through other threads). Such segments
+   are "abandoned" and will be reclaimed by other threads to
+   reuse their pages and/or free them eventually
+
+   We maintain a global list of abandoned segments that are
+   reclaimed on demand. Since this is shared among threads
+   the implementation needs to avoid the A-B-A problem on
+   popping abandoned segments: <https://en.wikipedia.org/wiki/ABA_problem>
+   We use tagged pointers to avoid accidentially identifying
+   reused segments, much like stamped references in Java.
+   Secondly, we maintain a reader counter to avoid resetting
+   or decommitting segments that have a pending read operation.
+
+   Note: the current implementation is one possible design;
+   another way might be to keep track of abandoned segments
+   in the arenas/segment_cache's. This would have the advantage of keeping
+   all concurrent code in one place and not needing to deal
+   with ABA issues. The drawback is that it is unclear how to
+   scan abandoned segments efficiently in that case as they
+   would be spread among all other segments in the arenas.
+----------------------------------------------------------- */
+
+// Use the bottom 20-bits (on 64-bit) of the aligned segment pointers
+// to put in a tag that increments on update to avoid the A-B-A problem.
+const MI_TAGGED_MASK: usize = MI_SEGMENT_MASK;
+type MiTaggedSegment = usize;
+
+fn mi_tagged_segment_ptr(ts: MiTaggedSegment) -> *mut MiSegment {
+    (ts & !MI_TAGGED_MASK) as *mut MiSegment
+}
+
+fn mi_tagged_segment(segment: *mut MiSegment, ts: MiTaggedSegment) -> MiTaggedSegment {
+    debug_assert!((segment as usize & MI_TAGGED_MASK) == 0);
+    let tag = ((ts & MI_TAGGED_MASK) + 1) & MI_TAGGED_MASK;
+    segment as usize | tag
+}
+
+// This is a list of visited abandoned pages that were full at the time.
+// this list migrates to `abandoned` when that becomes NULL. The use of
+// this list reduces contention and the rate at which segments are visited.
+static ABANDONED_VISITED: AtomicPtr<MiSegment> = AtomicPtr::new(ptr::null_mut());
+
+// The abandoned page list (tagged as it supports pop)
+static ABANDONED: AtomicUsize = AtomicUsize::new(0);
+
+// Maintain these for debug purposes (these counts may be a bit off)
+static ABANDONED_COUNT: AtomicUsize = AtomicUsize::new(0);
+static ABANDONED_VISITED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// We also maintain a count of current readers of the abandoned list
+// in order to prevent resetting/decommitting segment memory if it might
+// still be read.
+static ABANDONED_READERS: AtomicUsize = AtomicUsize::new(0);
+
+// Push on the visited list
+fn mi_abandoned_visited_push(segment: *mut MiSegment) {
+    unsafe {
+        debug_assert!((*segment).thread_id.load(Ordering::Relaxed) == 0);
+        debug_assert!((*segment).abandoned_next.load(Ordering::Relaxed).is_null());
+        debug_assert!((*segment).next.is_null());
+        debug_assert!((*segment).used > 0);
+        let mut anext = ABANDONED_VISITED.load(Ordering::Relaxed);
+        loop {
+            (*segment).abandoned_next.store(anext, Ordering::Release);
+            match ABANDONED_VISITED.compare_exchange_weak(
+                anext,
+                segment,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current) => anext = current,
+            }
+        }
+    }
+    ABANDONED_VISITED_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Move the visited list to the abandoned list.
+fn mi_abandoned_visited_revisit() -> bool {
+    // quick check if the visited list is empty
+    if ABANDONED_VISITED.load(Ordering::Relaxed).is_null() {
+        return false;
+    }
+
+    // grab the whole visited list
+    let first = ABANDONED_VISITED.swap(ptr::null_mut(), Ordering::AcqRel);
+    if first.is_null() {
+        return false;
+    }
+
+    // first try to swap directly if the abandoned list happens to be NULL
+    let ts = ABANDONED.load(Ordering::Relaxed);
+    if mi_tagged_segment_ptr(ts).is_null() {
+        let count = ABANDONED_VISITED_COUNT.load(Ordering::Relaxed);
+        let afirst = mi_tagged_segment(first, ts);
+        if ABANDONED
+            .compare_exchange(ts, afirst, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            ABANDONED_COUNT.fetch_add(count, Ordering::Relaxed);
+            ABANDONED_VISITED_COUNT.fetch_sub(count, Ordering::Relaxed);
+            return true;
+        }
+    }
+
+    // find the last element of the visited list: O(n)
+    let mut last = first;
+    loop {
+        let next = unsafe { (*last).abandoned_next.load(Ordering::Relaxed) };
+        if next.is_null() {
+            break;
+        }
+        last = next;
+    }
+
+    // and atomically prepend to the abandoned list
+    // (no need to increase the readers as we don't access the abandoned segments)
+    let mut anext = ABANDONED.load(Ordering::Relaxed);
+    let mut count;
+    loop {
+        count = ABANDONED_VISITED_COUNT.load(Ordering::Relaxed);
+        unsafe {
+            (*last)
+                .abandoned_next
+                .store(mi_tagged_segment_ptr(anext), Ordering::Release);
+        }
+        let afirst = mi_tagged_segment(first, anext);
+        match ABANDONED.compare_exchange_weak(anext, afirst, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => break,
+            Err(current) => anext = current,
+        }
+    }
+    ABANDONED_COUNT.fetch_add(count, Ordering::Relaxed);
+    ABANDONED_VISITED_COUNT.fetch_sub(count, Ordering::Relaxed);
+    true
+}
+
+// Push on the abandoned list.
+fn mi_abandoned_push(segment: *mut MiSegment) {
+    unsafe {
+        debug_assert!((*segment).thread_id.load(Ordering::Relaxed) == 0);
+        debug_assert!((*segment).abandoned_next.load(Ordering::Relaxed).is_null());
+        debug_assert!((*segment).next.is_null());
+        debug_assert!((*segment).used > 0);
+        let mut ts = ABANDONED.load(Ordering::Relaxed);
+        loop {
+            (*segment)
+                .abandoned_next
+                .store(mi_tagged_segment_ptr(ts), Ordering::Release);
+            let next = mi_tagged_segment(segment, ts);
+            match ABANDONED.compare_exchange_weak(ts, next, Ordering::Release, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(current) => ts = current,
+            }
+        }
+    }
+    ABANDONED_COUNT.fetch_add(1, Ordering::Relaxed);
+}
+
+// Wait until there are no more pending reads on segments that used to be in the abandoned list
+// called for example from `arena.rs` before decommitting
+pub fn _mi_abandoned_await_readers() {
+    while ABANDONED_READERS.load(Ordering::Acquire) != 0 {
+        mi_atomic_yield();
+    }
+}
+
+// Pop from the abandoned list
+fn mi_abandoned_pop() -> *mut MiSegment {
+    // Check efficiently if it is empty (or if the visited list needs to be moved)
+    let ts = ABANDONED.load(Ordering::Relaxed);
+    if mi_tagged_segment_ptr(ts).is_null() && !mi_abandoned_visited_revisit() {
+        // try to swap in the visited list on NULL
+        return ptr::null_mut();
+    }
+
+    // Do a pop. We use a reader count to prevent
+    // a segment to be decommitted while a read is still pending,
+    // and a tagged pointer to prevent A-B-A link corruption.
+    // (this is called from `arena.rs:_mi_arena_free` for example)
+    ABANDONED_READERS.fetch_add(1, Ordering::Relaxed); // ensure no segment gets decommitted
+    let mut ts = ABANDONED.load(Ordering::Acquire);
+    let mut segment;
+    loop {
+        segment = mi_tagged_segment_ptr(ts);
+        if segment.is_null() {
+            break;
+        }
+        // note: reads the segment's `abandoned_next` field so should not be decommitted
+        let anext = unsafe { (*segment).abandoned_next.load(Ordering::Relaxed) };
+        let next = mi_tagged_segment(anext, ts);
+        match ABANDONED.compare_exchange_weak(ts, next, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => break,
+            Err(current) => ts = current,
+        }
+    }
+    ABANDONED_READERS.fetch_sub(1, Ordering::Relaxed); // release reader lock
+    if !segment.is_null() {
+        unsafe {
+            (*segment)
+                .abandoned_next
+                .store(ptr::null_mut(), Ordering::Release)
+        };
+        ABANDONED_COUNT.fetch_sub(1, Ordering::Relaxed);
+    }
+    segment
+}
+
+/* -----------------------------------------------------------
+   Abandon segment/page
+----------------------------------------------------------- */
+
+fn mi_segment_abandon(segment: *mut MiSegment, tld: *mut MiSegmentsTLD) {
+    unsafe {
+        debug_assert!((*segment).used == (*segment).abandoned);
+        debug_assert!((*segment).used > 0);
+        debug_assert!((*segment).abandoned_next.load(Ordering::Relaxed).is_null());
+        debug_assert!((*segment).abandoned_visits == 0);
+
+        // remove the free pages from the free page queues
+        let mut slice = ptr::addr_of_mut!((*segment).slices[0]);
+        let end = mi_segment_slices_end(segment);
+        while slice < end {
+            debug_assert!((*slice).slice_count > 0);
+            debug_assert!((*slice).slice_offset == 0);
+            if (*slice).xblock_size == 0 {
+                // a free page
+                mi_segment_span_remove_from_queue(slice, tld);
+                (*slice).xblock_size = 0; // but keep it free
+            }
+            slice = slice.add((*slice).slice_count as usize);
+        }
+
+        // perform delayed decommits
+        mi_segment_delayed_decommit(
+            segment,
+            mi_option_is_enabled(MiOption::MiOptionAbandonedPageDecommit), /* force? */
+        );
+
+        // all pages in the segment are abandoned; add it to the abandoned list
+        _mi_stat_increase(ptr::addr_of_mut!((*(*tld).stats).segments_abandoned), 1);
+        mi_segments_track_size(-(mi_segment_size(segment) as i64), tld);
+        (*segment).thread_id.store(0, Ordering::Relaxed);
+        (*segment)
+            .abandoned_next
+            .store(ptr::null_mut(), Ordering::Release);
+        (*segment).abandoned_visits = 1; // from 0 to 1 to signify it is abandoned
+    }
+    mi_abandoned_push(segment);
+}
+
+pub fn _mi_segment_page_abandon(page: *mut MiPage, tld: *mut MiSegmentsTLD) {
+    debug_assert!(!page.is_null());
+    debug_assert!(mi_page_thread_free_flag(page) == MiDelayed::MiNeverDelayedFree);
+    debug_assert!(mi_page_heap(page).is_null());
+    let segment = _mi_page_segment(page);
+
+    unsafe {
+        (*segment).abandoned += 1;
+        _mi_stat_increase(ptr::addr_of_mut!((*(*tld).stats).pages_abandoned), 1);
+        debug_assert!((*segment).abandoned <= (*segment).used);
+        if (*segment).used == (*segment).abandoned {
+            // all pages are abandoned, abandon the entire segment
+            mi_segment_abandon(segment, tld);
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+  Reclaim abandoned pages
+----------------------------------------------------------- */
+
+fn mi_slices_start_iterate(segment: *mut MiSegment, end: *mut *const MiSlice) -> *mut MiSlice {
+    unsafe {
+        let slice = ptr::addr_of_mut!((*segment).slices[0]);
+        *end = mi_segment_slices_end(segment);
+        debug_assert!((*slice).slice_count > 0 && (*slice).xblock_size > 0); // segment allocated page
+        slice.add((*slice).slice_count as usize) // skip the first segment allocated page
+    }
+}
+
+// Possibly free pages and check if free space is available
+fn mi_segment_check_free(
+    segment: *mut MiSegment,
+    slices_needed: usize,
+    block_size: usize,
+    tld: *mut MiSegmentsTLD,
+) -> bool {
+    debug_assert!(block_size < MI_HUGE_BLOCK_SIZE);
+    debug_assert!(mi_segment_is_abandoned(segment));
+    let mut has_page = false;
+
+    // for all slices
+    let mut end: *const MiSlice = ptr::null();
+    let mut slice = mi_slices_start_iterate(segment, &mut end);
+    unsafe {
+        while (slice as *const MiSlice) < end {
+            debug_assert!((*slice).slice_count > 0);
+            debug_assert!((*slice).slice_offset == 0);
+            if mi_slice_is_used(slice) {
+                // used page
+                // ensure used count is up to date and collect potential concurrent frees
+                let page = mi_slice_to_page(slice);
+                _mi_page_free_collect(page, false);
+                if mi_page_all_free(page) {
+                    // if this page is all free now, free it without adding to any queues (yet)
+                    debug_assert!((*page).next.is_null() && (*page).prev.is_null());
+                    _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).pages_abandoned), 1);
+                    (*segment).abandoned -= 1;
+                    slice = mi_segment_page_clear(page, tld); // re-assign slice due to coalesce!
+                    debug_assert!(!mi_slice_is_used(slice));
+                    if (*slice).slice_count as usize >= slices_needed {
+                        has_page = true;
+                    }
+                } else if (*page).xblock_size as usize == block_size
+                    && mi_page_has_any_available(page)
+                {
+                    // a page has available free blocks of the right size
+                    has_page = true;
+                }
+            } else {
+                // empty span
+                if (*slice).slice_count as usize >= slices_needed {
+                    has_page = true;
+                }
+            }
+            slice = slice.add((*slice).slice_count as usize);
+        }
+    }
+    has_page
+}
 
 // Reclaim an abandoned segment; returns NULL if the segment was freed
 // set `right_page_reclaimed` to `true` if it reclaimed a page of the right `block_size` that was not full.
-// static mi_segment_t* mi_segment_reclaim(mi_segment_t* segment, mi_heap_t* heap, size_t requested_block_size, bool* right_page_reclaimed, mi_segments_tld_t* tld) {
-//     mi_assert_internal(mi_atomic_load_ptr_relaxed(mi_segment_t, &segment->abandoned_next) == NULL);
-//     mi_assert_expensive(mi_segment_is_valid(segment, tld));
-//     if (right_page_reclaimed != NULL) { *right_page_reclaimed = false; }
-
-//     segment->thread_id = _mi_thread_id();
-//     segment->abandoned_visits = 0;
-//     mi_segments_track_size((long)mi_segment_size(segment), tld);
-//     mi_assert_internal(segment->next == NULL);
-//     _mi_stat_decrease(&tld->stats->segments_abandoned, 1);
-
-//     // for all slices
-//     const mi_slice_t* end;
-//     mi_slice_t* slice = mi_slices_start_iterate(segment, &end);
-//     while (slice < end) {
-//       mi_assert_internal(slice->slice_count > 0);
-//       mi_assert_internal(slice->slice_offset == 0);
-//       if (mi_slice_is_used(slice)) {
-//         // in use: reclaim the page in our heap
-//         mi_page_t* page = mi_slice_to_page(slice);
-//         mi_assert_internal(!page->is_reset);
-//         mi_assert_internal(page->is_committed);
-//         mi_assert_internal(mi_page_thread_free_flag(page)==MI_NEVER_DELAYED_FREE);
-//         mi_assert_internal(mi_page_heap(page) == NULL);
-//         mi_assert_internal(page->next == NULL && page->prev==NULL);
-//         _mi_stat_decrease(&tld->stats->pages_abandoned, 1);
-//         segment->abandoned--;
-//         // set the heap again and allow delayed free again
-//         mi_page_set_heap(page, heap);
-//         _mi_page_use_delayed_free(page, MI_USE_DELAYED_FREE, true); // override never (after heap is set)
-//         _mi_page_free_collect(page, false); // ensure used count is up to date
-//         if (mi_page_all_free(page)) {
-//           // if everything free by now, free the page
-//           slice = mi_segment_page_clear(page, tld);   // set slice again due to coalesceing
-//         }
-//         else {
-//           // otherwise reclaim it into the heap
-//           _mi_page_reclaim(heap, page);
-//           if (requested_block_size == page->xblock_size && mi_page_has_any_available(page)) {
-//             if (right_page_reclaimed != NULL) { *right_page_reclaimed = true; }
-//           }
-//         }
-//       }
-//       else {
-//         // the span is free, add it to our page queues
-//         slice = mi_segment_span_free_coalesce(slice, tld); // set slice again due to coalesceing
-//       }
-//       mi_assert_internal(slice->slice_count>0 && slice->slice_offset==0);
-//       slice = slice + slice->slice_count;
-//     }
-
-//     mi_assert(segment->abandoned == 0);
-//     if (segment->used == 0) {  // due to page_clear
-//       mi_assert_internal(right_page_reclaimed == NULL || !(*right_page_reclaimed));
-//       mi_segment_free(segment, false, tld);
-//       return NULL;
-//     }
-//     else {
-//       return segment;
-//     }
-//   }
+fn mi_segment_reclaim(
+    segment: *mut MiSegment,
+    heap: *mut MiHeap,
+    requested_block_size: usize,
+    right_page_reclaimed: *mut bool,
+    tld: *mut MiSegmentsTLD,
+) -> *mut MiSegment {
+    unsafe {
+        debug_assert!((*segment).abandoned_next.load(Ordering::Relaxed).is_null());
+        if !right_page_reclaimed.is_null() {
+            *right_page_reclaimed = false;
+        }
+
+        (*segment)
+            .thread_id
+            .store(_mi_thread_id(), Ordering::Relaxed);
+        (*segment).abandoned_visits = 0;
+        mi_segments_track_size(mi_segment_size(segment) as i64, tld);
+        debug_assert!((*segment).next.is_null());
+        _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).segments_abandoned), 1);
+
+        // for all slices
+        let mut end: *const MiSlice = ptr::null();
+        let mut slice = mi_slices_start_iterate(segment, &mut end);
+        while (slice as *const MiSlice) < end {
+            debug_assert!((*slice).slice_count > 0);
+            debug_assert!((*slice).slice_offset == 0);
+            if mi_slice_is_used(slice) {
+                // in use: reclaim the page in our heap
+                let page = mi_slice_to_page(slice);
+                debug_assert!((*page).is_reset() == 0);
+                debug_assert!((*page).is_committed() != 0);
+                debug_assert!(mi_page_thread_free_flag(page) == MiDelayed::MiNeverDelayedFree);
+                debug_assert!(mi_page_heap(page).is_null());
+                debug_assert!((*page).next.is_null() && (*page).prev.is_null());
+                _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).pages_abandoned), 1);
+                (*segment).abandoned -= 1;
+                // set the heap again and allow delayed free again
+                mi_page_set_heap(page, heap);
+                _mi_page_use_delayed_free(page, MiDelayed::MiUseDelayedFree, true); // override never (after heap is set)
+                _mi_page_free_collect(page, false); // ensure used count is up to date
+                if mi_page_all_free(page) {
+                    // if everything free by now, free the page
+                    slice = mi_segment_page_clear(page, tld); // set slice again due to coalesceing
+                } else {
+                    // otherwise reclaim it into the heap
+                    _mi_page_reclaim(heap, page);
+                    if requested_block_size == (*page).xblock_size as usize
+                        && mi_page_has_any_available(page)
+                        && !right_page_reclaimed.is_null()
+                    {
+                        *right_page_reclaimed = true;
+                    }
+                }
+            } else {
+                // the span is free, add it to our page queues
+                slice = mi_segment_span_free_coalesce(slice, tld); // set slice again due to coalesceing
+            }
+            debug_assert!((*slice).slice_count > 0 && (*slice).slice_offset == 0);
+            slice = slice.add((*slice).slice_count as usize);
+        }
+
+        debug_assert!((*segment).abandoned == 0);
+        if (*segment).used == 0 {
+            // due to page_clear
+            debug_assert!(right_page_reclaimed.is_null() || !*right_page_reclaimed);
+            mi_segment_free(segment, false, tld);
+            ptr::null_mut()
+        } else {
+            segment
+        }
+    }
+}
+
+// Reclaim all abandoned segments into `heap` (used when the main thread terminates)
+pub fn _mi_abandoned_reclaim_all(heap: *mut MiHeap, tld: *mut MiSegmentsTLD) {
+    loop {
+        let segment = mi_abandoned_pop();
+        if segment.is_null() {
+            break;
+        }
+        mi_segment_reclaim(segment, heap, 0, ptr::null_mut(), tld);
+    }
+}
+
+// Collect abandoned segments: free the ones that have no more used pages
+// and decommit expired parts of the others.
+pub fn _mi_abandoned_collect(heap: *mut MiHeap, force: bool, tld: *mut MiSegmentsTLD) {
+    let mut max_tries = if force { 16 * 1024 } else { 1024 }; // limit latency
+    if force {
+        mi_abandoned_visited_revisit();
+    }
+    while max_tries > 0 {
+        max_tries -= 1;
+        let segment = mi_abandoned_pop();
+        if segment.is_null() {
+            break;
+        }
+        mi_segment_check_free(segment, 0, 0, tld); // try to free up pages (due to concurrent frees)
+        if unsafe { (*segment).used } == 0 {
+            // free the segment (by forced reclaim) to make it available to other threads.
+            // note: we could in principle optimize this by skipping reclaim and directly
+            // freeing but that would violate some invariants temporarily)
+            mi_segment_reclaim(segment, heap, 0, ptr::null_mut(), tld);
+        } else {
+            // otherwise, decommit if needed and push on the visited list
+            // note: forced decommit can be expensive if many threads are destroyed/created as in mstress.
+            mi_segment_delayed_decommit(segment, force);
+            mi_abandoned_visited_push(segment);
+        }
+    }
+}
 
 // -------------------------------------------------------------------
 // commit mask
@@ -523,8 +999,8 @@ fn mi_commit_mask_create(bitidx: usize, mut bitcount: usize, cm: *mut MiCommitMa
     }
 }
 
-fn _mi_commit_mask_committed_size(cm: *const MiCommitMask, total: usize) -> usize {
-    debug_assert!((total % MI_COMMIT_MASK_BITS) == 0);
+pub fn _mi_commit_mask_committed_size(cm: *const MiCommitMask, total: usize) -> usize {
+    debug_assert!(total.is_multiple_of(MI_COMMIT_MASK_BITS));
     let mut count = 0;
     for i in 0..MI_COMMIT_MASK_FIELD_COUNT {
         let mut mask = unsafe { (*cm).mask[i] };
@@ -548,7 +1024,7 @@ fn _mi_commit_mask_committed_size(cm: *const MiCommitMask, total: usize) -> usiz
     (total / MI_COMMIT_MASK_BITS) * count
 }
 
-fn _mi_commit_mask_next_run(cm: *const MiCommitMask, idx: *mut usize) -> usize {
+pub fn _mi_commit_mask_next_run(cm: *const MiCommitMask, idx: *mut usize) -> usize {
     let mut i = unsafe { (*idx) / MI_COMMIT_MASK_FIELD_BITS };
     let mut ofs = unsafe { (*idx) % MI_COMMIT_MASK_FIELD_BITS };
     let mut mask = 0;
@@ -605,38 +1081,1393 @@ fn _mi_commit_mask_next_run(cm: *const MiCommitMask, idx: *mut usize) -> usize {
     }
 }
 
-/* ----------------------------------------------------------------------------
-Segment caches
-We keep a small segment cache per thread to increase local
-reuse and avoid setting/clearing guard pages in secure mode.
-------------------------------------------------------------------------------- */
+/* -----------------------------------------------------------
+  Slices
+----------------------------------------------------------- */
+
+fn mi_segment_slices_end(segment: *mut MiSegment) -> *mut MiSlice {
+    unsafe { ptr::addr_of_mut!((*segment).slices[(*segment).slice_entries as usize]) }
+}
+
+fn mi_slice_start(slice: *const MiSlice) -> *mut u8 {
+    let segment = _mi_ptr_segment(slice.cast());
+    debug_assert!(
+        slice >= unsafe { ptr::addr_of!((*segment).slices[0]) }
+            && slice < mi_segment_slices_end(segment)
+    );
+    unsafe { (segment as *mut u8).add(mi_slice_index(slice) * MI_SEGMENT_SLICE_SIZE) }
+}
+
+/* -----------------------------------------------------------
+  Bins
+----------------------------------------------------------- */
+// Use bit scan forward to quickly find the first zero bit if it is available
+
+fn mi_slice_bin8(slice_count: usize) -> usize {
+    if slice_count <= 1 {
+        return slice_count;
+    }
+    debug_assert!(slice_count <= MI_SLICES_PER_SEGMENT);
+    let slice_count = slice_count - 1;
+    let s = mi_bsr(slice_count); // slice_count > 1
+    if s <= 2 {
+        return slice_count + 1;
+    }
+    ((s << 2) | ((slice_count >> (s - 2)) & 0x03)) - 4
+}
+
+fn mi_slice_bin(slice_count: usize) -> usize {
+    debug_assert!(slice_count * MI_SEGMENT_SLICE_SIZE <= MI_SEGMENT_SIZE);
+    debug_assert!(mi_slice_bin8(MI_SLICES_PER_SEGMENT) <= MI_SEGMENT_BIN_MAX);
+    let bin = mi_slice_bin8(slice_count);
+    debug_assert!(bin <= MI_SEGMENT_BIN_MAX);
+    bin
+}
+
+fn mi_slice_index(slice: *const MiSlice) -> usize {
+    let segment = _mi_ptr_segment(slice.cast());
+    let index = unsafe { slice.offset_from(ptr::addr_of!((*segment).slices[0])) };
+    debug_assert!(index >= 0 && index < unsafe { (*segment).slice_entries } as isize);
+    index as usize
+}
+
+/* -----------------------------------------------------------
+  Slice span queues
+----------------------------------------------------------- */
 
-fn mi_segments_track_size(segment_size: i32, tld: *mut MiSegmentsTLD) {
-    // if (segment_size>=0) _mi_stat_increase(&tld->stats->segments,1);
-    //                 else _mi_stat_decrease(&tld->stats->segments,1);
+fn mi_span_queue_push(sq: *mut MiSpanQueue, slice: *mut MiSlice) {
+    // todo: or push to the end?
     unsafe {
-        if segment_size >= 0 {
-            (*tld).count += 1
+        debug_assert!((*slice).prev.is_null() && (*slice).next.is_null());
+        (*slice).prev = ptr::null_mut(); // paranoia
+        (*slice).next = (*sq).first;
+        (*sq).first = slice;
+        if !(*slice).next.is_null() {
+            (*(*slice).next).prev = slice;
         } else {
-            (*tld).count -= 1
+            (*sq).last = slice;
+        }
+        (*slice).xblock_size = 0; // free
+    }
+}
+
+fn mi_span_queue_for(slice_count: usize, tld: *mut MiSegmentsTLD) -> *mut MiSpanQueue {
+    let bin = mi_slice_bin(slice_count);
+    let sq = unsafe { ptr::addr_of_mut!((*tld).spans[bin]) };
+    debug_assert!(unsafe { (*sq).slice_count } as usize >= slice_count);
+    sq
+}
+
+fn mi_span_queue_delete(sq: *mut MiSpanQueue, slice: *mut MiSlice) {
+    unsafe {
+        debug_assert!(
+            (*slice).xblock_size == 0 && (*slice).slice_count > 0 && (*slice).slice_offset == 0
+        );
+        // should work too if the queue does not contain slice (which can happen during reclaim)
+        if !(*slice).prev.is_null() {
+            (*(*slice).prev).next = (*slice).next;
+        }
+        if slice == (*sq).first {
+            (*sq).first = (*slice).next;
+        }
+        if !(*slice).next.is_null() {
+            (*(*slice).next).prev = (*slice).prev;
+        }
+        if slice == (*sq).last {
+            (*sq).last = (*slice).prev;
+        }
+        (*slice).prev = ptr::null_mut();
+        (*slice).next = ptr::null_mut();
+        (*slice).xblock_size = 1; // no more free
+    }
+}
+
+fn mi_slice_is_used(slice: *const MiSlice) -> bool {
+    unsafe { (*slice).xblock_size > 0 }
+}
+
+/* -----------------------------------------------------------
+ Segment size calculations
+----------------------------------------------------------- */
+
+// In secure mode 4 every page in a normal segment ends with a guard page
+#[allow(clippy::absurd_extreme_comparisons)] // `MI_SECURE` is a build configuration constant
+const MI_PAGE_GUARD: bool = MI_SECURE >= 4;
+
+fn mi_segment_info_size(segment: *mut MiSegment) -> usize {
+    unsafe { (*segment).segment_info_slices as usize * MI_SEGMENT_SLICE_SIZE }
+}
+
+fn _mi_segment_page_start_from_slice(
+    segment: *const MiSegment,
+    slice: *const MiSlice,
+    xblock_size: usize,
+    page_size: *mut usize,
+) -> *mut u8 {
+    let idx = unsafe { slice.offset_from(ptr::addr_of!((*segment).slices[0])) } as usize;
+    let psize = unsafe { (*slice).slice_count } as usize * MI_SEGMENT_SLICE_SIZE;
+    // make the start not OS page aligned for smaller blocks to avoid page/cache effects
+    let start_offset = if (MI_INTPTR_SIZE..=1024).contains(&xblock_size) {
+        3 * MI_MAX_ALIGN_GUARANTEE
+    } else {
+        0
+    };
+    // in secure mode the last OS page of a page in a normal segment is a guard page
+    let guard_size =
+        if MI_PAGE_GUARD && unsafe { (*segment).kind } == MiSegmentKind::MiSegmentNormal {
+            _mi_os_page_size()
+        } else {
+            0
         };
-        if (*tld).count > (*tld).peak_count {
-            (*tld).peak_count = (*tld).count;
+    if !page_size.is_null() {
+        unsafe { *page_size = psize - start_offset - guard_size };
+    }
+    unsafe { (segment as *mut u8).add((idx * MI_SEGMENT_SLICE_SIZE) + start_offset) }
+}
+
+// Protect (or unprotect) the guard page at the end of a page in a normal segment (in secure mode 4)
+fn mi_segment_page_protect(page: *mut MiPage, protect: bool) {
+    let segment = _mi_page_segment(page);
+    if !MI_PAGE_GUARD || unsafe { (*segment).kind } != MiSegmentKind::MiSegmentNormal {
+        return;
+    }
+    let os_psize = _mi_os_page_size();
+    let end = mi_slice_start(mi_page_to_slice(page))
+        .wrapping_add(unsafe { (*page).slice_count } as usize * MI_SEGMENT_SLICE_SIZE);
+    let guard = end.wrapping_sub(os_psize).cast();
+    if protect {
+        _mi_os_protect(guard, os_psize);
+    } else {
+        _mi_os_unprotect(guard, os_psize);
+    }
+}
+
+// Protect the guard pages (of the segment and of live pages) again that lie in a
+// range of the segment that was just (re)committed.
+fn mi_segment_guards_reprotect(segment: *mut MiSegment, start: *mut u8, size: usize) {
+    if MI_SECURE == 0 {
+        return;
+    }
+    let os_psize = _mi_os_page_size();
+    let in_range = |guard: *mut u8| guard >= start && guard < start.wrapping_add(size);
+    let protect = |guard: *mut u8| {
+        if in_range(guard) {
+            _mi_os_protect(guard.cast(), os_psize);
         }
-        (*tld).current_size += segment_size as u64;
-        if (*tld).current_size > (*tld).peak_size {
-            (*tld).peak_size = (*tld).current_size;
+    };
+    unsafe {
+        let base = segment as *mut u8;
+        protect(base.add(mi_segment_info_size(segment) - os_psize));
+        protect(base.add(mi_segment_size(segment) - os_psize));
+        if !MI_PAGE_GUARD || (*segment).kind != MiSegmentKind::MiSegmentNormal {
+            return;
+        }
+        let mut slice = ptr::addr_of_mut!((*segment).slices[0]);
+        let end = mi_segment_slices_end(segment);
+        while slice < end && (*slice).slice_count > 0 {
+            // (no slice count yet while the segment is being initialized)
+            if mi_slice_is_used(slice) {
+                protect(
+                    mi_slice_start(slice)
+                        .add((*slice).slice_count as usize * MI_SEGMENT_SLICE_SIZE - os_psize),
+                );
+            }
+            slice = slice.add((*slice).slice_count as usize);
         }
     }
 }
 
-pub fn mi_segments_page_find_and_allocate(
-    slice_count: usize,
-    req_arena_id: MiArenaIdT,
-    tld: MiSegmentsTLD,
-) -> *mut MiPage {
-    debug_assert!(slice_count * MI_SEGMENT_SLICE_SIZE <= MI_LARGE_OBJ_SIZE_MAX);
-    // search from best fit up
+// Start of the page available memory; can be used on uninitialized pages
+pub fn _mi_segment_page_start(
+    segment: *const MiSegment,
+    page: *const MiPage,
+    page_size: *mut usize,
+) -> *mut u8 {
+    let p = _mi_segment_page_start_from_slice(
+        segment,
+        page,
+        unsafe { (*page).xblock_size } as usize,
+        page_size,
+    );
+    debug_assert!(ptr::eq(_mi_ptr_segment(p.cast()), segment));
+    p
+}
 
-    ptr::null_mut()
+/* -----------------------------------------------------------
+  Segment commit/decommit
+----------------------------------------------------------- */
+
+fn mi_segment_commit_mask(
+    segment: *mut MiSegment,
+    conservative: bool,
+    p: *mut u8,
+    size: usize,
+    start_p: *mut *mut u8,
+    full_size: *mut usize,
+    cm: *mut MiCommitMask,
+) {
+    debug_assert!(_mi_ptr_segment(p.wrapping_add(1).cast()) == segment);
+    debug_assert!(unsafe { (*segment).kind } != MiSegmentKind::MiSegmentHuge);
+    mi_commit_mask_create_empty(cm);
+    if size == 0
+        || size > MI_SEGMENT_SIZE
+        || unsafe { (*segment).kind } == MiSegmentKind::MiSegmentHuge
+    {
+        return;
+    }
+    let segstart = mi_segment_info_size(segment);
+    let segsize = mi_segment_size(segment);
+    if p >= unsafe { (segment as *mut u8).add(segsize) } {
+        return;
+    }
+
+    let pstart = p as usize - segment as usize;
+    debug_assert!(pstart + size <= segsize);
+
+    let mut start;
+    let mut end;
+    if conservative {
+        // decommit conservative
+        start = _mi_align_up(pstart, MI_COMMIT_SIZE);
+        end = _mi_align_down(pstart + size, MI_COMMIT_SIZE);
+        debug_assert!(start >= segstart);
+        debug_assert!(end <= segsize);
+    } else {
+        // commit liberal
+        start = _mi_align_down(pstart, MI_MINIMAL_COMMIT_SIZE);
+        end = _mi_align_up(pstart + size, MI_MINIMAL_COMMIT_SIZE);
+    }
+    if pstart >= segstart && start < segstart {
+        // note: the mask is also calculated for an initial commit of the info area
+        start = segstart;
+    }
+    if end > segsize {
+        end = segsize;
+    }
+
+    debug_assert!(start <= pstart && (pstart + size) <= end);
+    debug_assert!(start % MI_COMMIT_SIZE == 0 && end % MI_COMMIT_SIZE == 0);
+    unsafe {
+        *start_p = (segment as *mut u8).add(start);
+        *full_size = end.saturating_sub(start);
+        if *full_size == 0 {
+            return;
+        }
+    }
+
+    let bitidx = start / MI_COMMIT_SIZE;
+    debug_assert!(bitidx < MI_COMMIT_MASK_BITS);
+
+    let bitcount = unsafe { *full_size } / MI_COMMIT_SIZE; // can be 0
+    if bitidx + bitcount > MI_COMMIT_MASK_BITS {
+        _mi_warning_message(format_args!(
+            "commit mask overflow: idx={} count={} start={:x} end={:x} p={:p} size={} fullsize={}\n",
+            bitidx,
+            bitcount,
+            start,
+            end,
+            p,
+            size,
+            unsafe { *full_size }
+        ));
+    }
+    debug_assert!((bitidx + bitcount) <= MI_COMMIT_MASK_BITS);
+    mi_commit_mask_create(bitidx, bitcount, cm);
+}
+
+fn mi_segment_commitx(segment: *mut MiSegment, commit: bool, p: *mut u8, size: usize) -> bool {
+    debug_assert!(mi_commit_mask_all_set(
+        unsafe { ptr::addr_of!((*segment).commit_mask) },
+        unsafe { ptr::addr_of!((*segment).decommit_mask) }
+    ));
+
+    // commit liberal, but decommit conservative
+    let mut start: *mut u8 = ptr::null_mut();
+    let mut full_size: usize = 0;
+    let mut mask = MiCommitMask { mask: [0; 8] };
+    mi_segment_commit_mask(
+        segment,
+        !commit, /*conservative*/
+        p,
+        size,
+        &mut start,
+        &mut full_size,
+        &mut mask,
+    );
+    if mi_commit_mask_is_empty(&mask) || full_size == 0 {
+        return true;
+    }
+
+    unsafe {
+        if commit && !mi_commit_mask_all_set(&(*segment).commit_mask, &mask) {
+            let mut is_zero = false;
+            if !_mi_os_commit(start.cast(), full_size, &mut is_zero) {
+                return false;
+            }
+            mi_commit_mask_set(&mut (*segment).commit_mask, &mask);
+            // committing makes the whole range accessible again (also the guard pages in it)
+            mi_segment_guards_reprotect(segment, start, full_size);
+        } else if !commit && mi_commit_mask_any_set(&(*segment).commit_mask, &mask) {
+            debug_assert!(start as *mut MiSegment != segment);
+            if (*segment).allow_decommit {
+                _mi_os_decommit(start.cast(), full_size); // ok if this fails
+            }
+            mi_commit_mask_clear(&mut (*segment).commit_mask, &mask);
+        }
+        // increase expiration of reusing part of the delayed decommit
+        if commit && mi_commit_mask_any_set(&(*segment).decommit_mask, &mask) {
+            (*segment).decommit_expire =
+                _mi_clock_now() + mi_option_get(MiOption::MiOptionDecommitDelay) as i64;
+        }
+        // always undo delayed decommits
+        mi_commit_mask_clear(&mut (*segment).decommit_mask, &mask);
+    }
+    true
+}
+
+fn mi_segment_ensure_committed(segment: *mut MiSegment, p: *mut u8, size: usize) -> bool {
+    unsafe {
+        debug_assert!(mi_commit_mask_all_set(
+            &(*segment).commit_mask,
+            &(*segment).decommit_mask
+        ));
+        // note: assumes commit_mask is always full for huge segments as otherwise the commit mask bits can overflow
+        if mi_commit_mask_is_full(&(*segment).commit_mask)
+            && mi_commit_mask_is_empty(&(*segment).decommit_mask)
+        {
+            return true; // fully committed
+        }
+        debug_assert!((*segment).kind != MiSegmentKind::MiSegmentHuge);
+    }
+    mi_segment_commitx(segment, true, p, size)
+}
+
+fn mi_segment_perhaps_decommit(segment: *mut MiSegment, p: *mut u8, size: usize) {
+    unsafe {
+        if !(*segment).allow_decommit {
+            return;
+        }
+        if mi_option_get(MiOption::MiOptionDecommitDelay) == 0 {
+            mi_segment_commitx(segment, false, p, size);
+        } else {
+            // register for future decommit in the decommit mask
+            let mut start: *mut u8 = ptr::null_mut();
+            let mut full_size: usize = 0;
+            let mut mask = MiCommitMask { mask: [0; 8] };
+            mi_segment_commit_mask(
+                segment,
+                true, /*conservative*/
+                p,
+                size,
+                &mut start,
+                &mut full_size,
+                &mut mask,
+            );
+            if mi_commit_mask_is_empty(&mask) || full_size == 0 {
+                return;
+            }
+
+            // update delayed commit
+            debug_assert!(
+                (*segment).decommit_expire > 0
+                    || mi_commit_mask_is_empty(&(*segment).decommit_mask)
+            );
+            let mut cmask = MiCommitMask { mask: [0; 8] };
+            mi_commit_mask_create_intersect(&(*segment).commit_mask, &mask, &mut cmask); // only decommit what is committed; span_free may try to decommit more
+            mi_commit_mask_set(&mut (*segment).decommit_mask, &cmask);
+            let now = _mi_clock_now();
+            if (*segment).decommit_expire == 0 {
+                // no previous decommits, initialize now
+                (*segment).decommit_expire =
+                    now + mi_option_get(MiOption::MiOptionDecommitDelay) as i64;
+            } else if (*segment).decommit_expire <= now {
+                // previous decommit mask already expired
+                if (*segment).decommit_expire
+                    + mi_option_get(MiOption::MiOptionDecommitExtendDelay) as i64
+                    <= now
+                {
+                    mi_segment_delayed_decommit(segment, true);
+                } else {
+                    (*segment).decommit_expire =
+                        now + mi_option_get(MiOption::MiOptionDecommitExtendDelay) as i64;
+                    // (mi_option_get(mi_option_decommit_delay) / 8); // wait a tiny bit longer in case there is a series of free's
+                }
+            } else {
+                // previous decommit mask is not yet expired, increase the expiration by a bit.
+                (*segment).decommit_expire +=
+                    mi_option_get(MiOption::MiOptionDecommitExtendDelay) as i64;
+            }
+        }
+    }
+}
+
+fn mi_segment_delayed_decommit(segment: *mut MiSegment, force: bool) {
+    unsafe {
+        if !(*segment).allow_decommit || mi_commit_mask_is_empty(&(*segment).decommit_mask) {
+            return;
+        }
+        let now = _mi_clock_now();
+        if !force && now < (*segment).decommit_expire {
+            return;
+        }
+
+        let mask = (*segment).decommit_mask;
+        (*segment).decommit_expire = 0;
+        mi_commit_mask_create_empty(&mut (*segment).decommit_mask);
+
+        let mut idx = 0;
+        loop {
+            let count = _mi_commit_mask_next_run(&mask, &mut idx);
+            if count == 0 {
+                break;
+            }
+            // if found, decommit that sequence
+            let p = (segment as *mut u8).add(idx * MI_COMMIT_SIZE);
+            let size = count * MI_COMMIT_SIZE;
+            mi_segment_commitx(segment, false, p, size);
+            idx += count;
+        }
+        debug_assert!(mi_commit_mask_is_empty(&(*segment).decommit_mask));
+    }
+}
+
+/* -----------------------------------------------------------
+   Span free
+----------------------------------------------------------- */
+
+fn mi_segment_is_abandoned(segment: *mut MiSegment) -> bool {
+    unsafe { (*segment).thread_id.load(Ordering::Relaxed) == 0 }
+}
+
+// note: can be called on abandoned segments
+fn mi_segment_span_free(
+    segment: *mut MiSegment,
+    slice_index: usize,
+    mut slice_count: usize,
+    allow_decommit: bool,
+    tld: *mut MiSegmentsTLD,
+) {
+    debug_assert!(slice_index < unsafe { (*segment).slice_entries } as usize);
+    let sq = if unsafe { (*segment).kind } == MiSegmentKind::MiSegmentHuge
+        || mi_segment_is_abandoned(segment)
+    {
+        ptr::null_mut()
+    } else {
+        mi_span_queue_for(slice_count, tld)
+    };
+    if slice_count == 0 {
+        slice_count = 1;
+    }
+    debug_assert!(slice_index + slice_count - 1 < unsafe { (*segment).slice_entries } as usize);
+
+    // set first and last slice (the intermediates can be undetermined)
+    unsafe {
+        let slice = ptr::addr_of_mut!((*segment).slices[slice_index]);
+        (*slice).slice_count = slice_count as u32;
+        (*slice).slice_offset = 0;
+        if slice_count > 1 {
+            let last = ptr::addr_of_mut!((*segment).slices[slice_index + slice_count - 1]);
+            (*last).slice_count = 0;
+            (*last).slice_offset = (size_of::<MiPage>() * (slice_count - 1)) as u32;
+            (*last).xblock_size = 0;
+        }
+
+        // perhaps decommit
+        if allow_decommit {
+            mi_segment_perhaps_decommit(
+                segment,
+                mi_slice_start(slice),
+                slice_count * MI_SEGMENT_SLICE_SIZE,
+            );
+        }
+
+        // and push it on the free page queue (if it was not a huge page)
+        if !sq.is_null() {
+            mi_span_queue_push(sq, slice);
+        } else {
+            (*slice).xblock_size = 0; // mark huge page as free anyways
+        }
+    }
+}
+
+fn mi_segment_span_remove_from_queue(slice: *mut MiSlice, tld: *mut MiSegmentsTLD) {
+    unsafe {
+        debug_assert!(
+            (*slice).slice_count > 0 && (*slice).slice_offset == 0 && (*slice).xblock_size == 0
+        );
+        debug_assert!((*_mi_ptr_segment(slice.cast())).kind != MiSegmentKind::MiSegmentHuge);
+        let sq = mi_span_queue_for((*slice).slice_count as usize, tld);
+        mi_span_queue_delete(sq, slice);
+    }
+}
+
+// free a page, and coalesce with neighbours.
+fn mi_segment_span_free_coalesce(mut slice: *mut MiSlice, tld: *mut MiSegmentsTLD) -> *mut MiSlice {
+    unsafe {
+        debug_assert!(!slice.is_null() && (*slice).slice_count > 0 && (*slice).slice_offset == 0);
+        let segment = _mi_ptr_segment(slice.cast());
+        let is_abandoned = mi_segment_is_abandoned(segment);
+
+        // for huge pages, just mark as free but don't add to the queues
+        if (*segment).kind == MiSegmentKind::MiSegmentHuge {
+            // issue #691: segment->used can be 0 if the huge page block was freed while abandoned (reclaim will get here in that case)
+            debug_assert!(
+                ((*segment).used == 0 && (*slice).xblock_size == 0) || (*segment).used == 1
+            ); // decreased right after this call in `mi_segment_page_clear`
+            (*slice).xblock_size = 0; // mark as free anyways
+                                      // we should mark the last slice `xblock_size=0` now to maintain invariants but we skip it to
+                                      // avoid a possible cache miss (and the segment is about to be freed)
+            return slice;
+        }
+
+        // otherwise coalesce the span and add to the free span queues
+        let mut slice_count = (*slice).slice_count as usize;
+        let next = slice.add((*slice).slice_count as usize);
+        debug_assert!(next <= mi_segment_slices_end(segment));
+        if next < mi_segment_slices_end(segment) && (*next).xblock_size == 0 {
+            // free next block -- remove it from free and merge
+            debug_assert!((*next).slice_count > 0 && (*next).slice_offset == 0);
+            slice_count += (*next).slice_count as usize; // extend
+            if !is_abandoned {
+                mi_segment_span_remove_from_queue(next, tld);
+            }
+        }
+        if slice > ptr::addr_of_mut!((*segment).slices[0]) {
+            let prev = mi_slice_first(slice.sub(1));
+            debug_assert!(prev >= ptr::addr_of_mut!((*segment).slices[0]));
+            if (*prev).xblock_size == 0 {
+                // free previous slice -- remove it from free and merge
+                debug_assert!((*prev).slice_count > 0 && (*prev).slice_offset == 0);
+                slice_count += (*prev).slice_count as usize;
+                if !is_abandoned {
+                    mi_segment_span_remove_from_queue(prev, tld);
+                }
+                slice = prev;
+            }
+        }
+
+        // and add the new free page
+        mi_segment_span_free(segment, mi_slice_index(slice), slice_count, true, tld);
+        slice
+    }
+}
+
+/* -----------------------------------------------------------
+   Page allocation
+----------------------------------------------------------- */
+
+// Note: may still return NULL if committing the memory failed
+fn mi_segment_span_allocate(
+    segment: *mut MiSegment,
+    slice_index: usize,
+    slice_count: usize,
+) -> *mut MiPage {
+    unsafe {
+        debug_assert!(slice_index < (*segment).slice_entries as usize);
+        let slice = ptr::addr_of_mut!((*segment).slices[slice_index]);
+        debug_assert!((*slice).xblock_size == 0 || (*slice).xblock_size == 1);
+
+        // commit before changing the slice data
+        if !mi_segment_ensure_committed(
+            segment,
+            _mi_segment_page_start_from_slice(segment, slice, 0, ptr::null_mut()),
+            slice_count * MI_SEGMENT_SLICE_SIZE,
+        ) {
+            return ptr::null_mut(); // commit failed!
+        }
+
+        // convert the slices to a page
+        (*slice).slice_offset = 0;
+        (*slice).slice_count = slice_count as u32;
+        debug_assert!((*slice).slice_count as usize == slice_count);
+        let bsize = slice_count * MI_SEGMENT_SLICE_SIZE;
+        (*slice).xblock_size = if bsize >= MI_HUGE_BLOCK_SIZE {
+            MI_HUGE_BLOCK_SIZE
+        } else {
+            bsize
+        } as u32;
+        let page = mi_slice_to_page(slice);
+
+        // set slice back pointers for the first MI_MAX_SLICE_OFFSET entries
+        let mut extra = slice_count - 1;
+        if extra > MI_MAX_SLICE_OFFSET {
+            extra = MI_MAX_SLICE_OFFSET;
+        }
+        if slice_index + extra >= (*segment).slice_entries as usize {
+            extra = (*segment).slice_entries as usize - slice_index - 1; // huge objects may have more slices than avaiable entries in the segment->slices
+        }
+
+        let mut slice_next = slice.add(1);
+        for i in 1..=extra {
+            (*slice_next).slice_offset = (size_of::<MiSlice>() * i) as u32;
+            (*slice_next).slice_count = 0;
+            (*slice_next).xblock_size = 1;
+            slice_next = slice_next.add(1);
+        }
+
+        // and also for the last one (if not set already) (the last one is needed for coalescing and for large alignments)
+        // note: the index can be larger than MI_SLICES_PER_SEGMENT for huge allocations (see #543)
+        let end = mi_segment_slices_end(segment);
+        let mut last = slice.wrapping_add(slice_count - 1);
+        if last > end {
+            last = end;
+        }
+        if last > slice {
+            (*last).slice_offset = (size_of::<MiSlice>() * last.offset_from(slice) as usize) as u32;
+            (*last).slice_count = 0;
+            (*last).xblock_size = 1;
+        }
+
+        // and initialize the page
+        (*page).set_is_reset(0);
+        (*page).set_is_committed(1);
+        (*segment).used += 1;
+        page
+    }
+}
+
+fn mi_segment_slice_split(
+    segment: *mut MiSegment,
+    slice: *mut MiSlice,
+    slice_count: usize,
+    tld: *mut MiSegmentsTLD,
+) {
+    unsafe {
+        debug_assert!(_mi_ptr_segment(slice.cast()) == segment);
+        debug_assert!((*slice).slice_count as usize >= slice_count);
+        debug_assert!((*slice).xblock_size > 0); // no more in free queue
+        if (*slice).slice_count as usize <= slice_count {
+            return;
+        }
+        debug_assert!((*segment).kind != MiSegmentKind::MiSegmentHuge);
+        let next_index = mi_slice_index(slice) + slice_count;
+        let next_count = (*slice).slice_count as usize - slice_count;
+        mi_segment_span_free(segment, next_index, next_count, false, tld); // don't decommit left-over part
+        (*slice).slice_count = slice_count as u32;
+    }
+}
+
+fn mi_segments_page_find_and_allocate(
+    mut slice_count: usize,
+    req_arena_id: MiArenaIdT,
+    tld: *mut MiSegmentsTLD,
+) -> *mut MiPage {
+    // (in secure mode 4 a large page can be one medium page larger to make room for its guard page)
+    debug_assert!(
+        slice_count * MI_SEGMENT_SLICE_SIZE
+            <= MI_LARGE_OBJ_SIZE_MAX
+                + if MI_PAGE_GUARD {
+                    MI_MEDIUM_PAGE_SIZE
+                } else {
+                    0
+                }
+    );
+    // search from best fit up
+    let mut sq = mi_span_queue_for(slice_count, tld);
+    if slice_count == 0 {
+        slice_count = 1;
+    }
+    let sq_last = unsafe { ptr::addr_of_mut!((*tld).spans[MI_SEGMENT_BIN_MAX]) };
+    while sq <= sq_last {
+        let mut slice = unsafe { (*sq).first };
+        while !slice.is_null() {
+            if unsafe { (*slice).slice_count } as usize >= slice_count {
+                // found one
+                let segment = _mi_ptr_segment(slice.cast());
+                if _mi_arena_memid_is_suitable(unsafe { (*segment).memid }, req_arena_id) {
+                    // found a suitable page span
+                    mi_span_queue_delete(sq, slice);
+
+                    if unsafe { (*slice).slice_count } as usize > slice_count {
+                        mi_segment_slice_split(segment, slice, slice_count, tld);
+                    }
+                    debug_assert!(
+                        !slice.is_null()
+                            && unsafe { (*slice).slice_count } as usize == slice_count
+                            && unsafe { (*slice).xblock_size } > 0
+                    );
+                    let page = mi_segment_span_allocate(segment, mi_slice_index(slice), unsafe {
+                        (*slice).slice_count
+                    }
+                        as usize);
+                    if page.is_null() {
+                        // commit failed; return NULL but first restore the slice
+                        mi_segment_span_free_coalesce(slice, tld);
+                        return ptr::null_mut();
+                    }
+                    return page;
+                }
+            }
+            slice = unsafe { (*slice).next };
+        }
+        sq = unsafe { sq.add(1) };
+    }
+    // could not find a page..
+    ptr::null_mut()
+}
+
+/* -----------------------------------------------------------
+   Segment free
+----------------------------------------------------------- */
+
+fn mi_segment_os_free(segment: *mut MiSegment, tld: *mut MiSegmentsTLD) {
+    unsafe {
+        (*segment).thread_id.store(0, Ordering::Relaxed);
+        _mi_segment_map_freed_at(segment);
+        mi_segments_track_size(-(mi_segment_size(segment) as i64), tld);
+
+        if MI_SECURE != 0 {
+            // unprotect the guard pages; we cannot just unprotect the whole segment size as part may be decommitted
+            let os_pagesize = _mi_os_page_size();
+            _mi_os_unprotect(
+                (segment as *mut u8)
+                    .add(mi_segment_info_size(segment) - os_pagesize)
+                    .cast(),
+                os_pagesize,
+            );
+            let end = (segment as *mut u8).add(mi_segment_size(segment) - os_pagesize);
+            _mi_os_unprotect(end.cast(), os_pagesize);
+        }
+
+        // purge delayed decommits now? (no, leave it to the cache)
+        // mi_segment_delayed_decommit(segment,true,tld->stats);
+
+        let size = mi_segment_size(segment);
+        if size != MI_SEGMENT_SIZE
+            || (*segment).mem_align_offset != 0
+            || (*segment).kind == MiSegmentKind::MiSegmentHuge // only push regular segments on the cache
+            || !_mi_segment_cache_push(
+                segment.cast(),
+                size,
+                (*segment).memid,
+                &(*segment).commit_mask,
+                &(*segment).decommit_mask,
+                (*segment).mem_is_large,
+                (*segment).mem_is_pinned,
+                (*tld).os,
+            )
+        {
+            if !(*segment).mem_is_pinned {
+                let csize = _mi_commit_mask_committed_size(&(*segment).commit_mask, size);
+                if csize > 0 {
+                    _mi_stat_decrease(ptr::addr_of_mut!(_mi_stats_main.committed), csize);
+                }
+            }
+            _mi_abandoned_await_readers(); // wait until safe to free
+            _mi_arena_free(
+                segment.cast(),
+                mi_segment_size(segment),
+                (*segment).mem_alignment,
+                (*segment).mem_align_offset,
+                (*segment).memid,
+                (*segment).mem_is_pinned, /* pretend not committed to not double count decommits */
+            );
+        }
+    }
+}
+
+fn mi_segment_free(segment: *mut MiSegment, _force: bool, tld: *mut MiSegmentsTLD) {
+    debug_assert!(!segment.is_null());
+    unsafe {
+        debug_assert!((*segment).next.is_null());
+        debug_assert!((*segment).used == 0);
+
+        // Remove the free pages
+        let mut slice = ptr::addr_of_mut!((*segment).slices[0]);
+        let end = mi_segment_slices_end(segment);
+        let mut page_count = 0;
+        while slice < end {
+            debug_assert!((*slice).slice_count > 0);
+            debug_assert!((*slice).slice_offset == 0);
+            debug_assert!(mi_slice_index(slice) == 0 || (*slice).xblock_size == 0); // no more used pages ..
+            if (*slice).xblock_size == 0 && (*segment).kind != MiSegmentKind::MiSegmentHuge {
+                mi_segment_span_remove_from_queue(slice, tld);
+            }
+            page_count += 1;
+            slice = slice.wrapping_add((*slice).slice_count as usize);
+        }
+        debug_assert!(page_count == 2); // first page is allocated by the segment itself
+    }
+
+    // return it to the OS
+    mi_segment_os_free(segment, tld);
+}
+
+/* -----------------------------------------------------------
+   Page free
+----------------------------------------------------------- */
+
+// note: can be called on abandoned pages
+fn mi_segment_page_clear(page: *mut MiPage, tld: *mut MiSegmentsTLD) -> *mut MiSlice {
+    unsafe {
+        debug_assert!((*page).xblock_size > 0);
+        debug_assert!(mi_page_all_free(page));
+        let segment = _mi_ptr_segment(page.cast());
+        debug_assert!((*segment).used > 0);
+
+        let inuse = (*page).capacity as usize * mi_page_block_size(page);
+        _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).page_committed), inuse);
+        _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).pages), 1);
+
+        // reset the page memory to reduce memory pressure?
+        if !(*segment).mem_is_pinned
+            && (*page).is_committed() != 0
+            && mi_option_is_enabled(MiOption::MiOptionPageReset)
+        {
+            let mut psize: usize = 0;
+            let start = _mi_segment_page_start(segment, page, &mut psize);
+            (*page).set_is_reset(1);
+            _mi_os_reset(start.cast(), psize);
+        }
+
+        // remove the guard page before the memory is reused for other pages
+        mi_segment_page_protect(page, false);
+
+        // zero the page data, but not the segment fields
+        (*page).set_is_zero_init(0);
+        let ofs = offset_of!(MiPage, capacity);
+        memset(
+            (page as *mut u8).add(ofs).cast(),
+            0,
+            size_of::<MiPage>() - ofs,
+        );
+        (*page).xblock_size = 1;
+
+        // and free it
+        let slice = mi_segment_span_free_coalesce(mi_page_to_slice(page), tld);
+        (*segment).used -= 1;
+        // cannot assert segment valid as it is called during reclaim
+        slice
+    }
+}
+
+pub fn _mi_segment_page_free(page: *mut MiPage, force: bool, tld: *mut MiSegmentsTLD) {
+    debug_assert!(!page.is_null());
+
+    let segment = _mi_page_segment(page);
+
+    // mark it as free now
+    mi_segment_page_clear(page, tld);
+
+    if unsafe { (*segment).used } == 0 {
+        // no more used pages; remove from the free list and free the segment
+        mi_segment_free(segment, force, tld);
+    } else if unsafe { (*segment).used == (*segment).abandoned } {
+        // only abandoned pages; remove from free list and abandon
+        mi_segment_abandon(segment, tld);
+    }
+}
+
+/* ----------------------------------------------------------------------------
+Segment caches
+We keep a small segment cache per thread to increase local
+reuse and avoid setting/clearing guard pages in secure mode.
+------------------------------------------------------------------------------- */
+
+fn mi_segments_track_size(segment_size: i64, tld: *mut MiSegmentsTLD) {
+    unsafe {
+        if segment_size >= 0 {
+            _mi_stat_increase(ptr::addr_of_mut!((*(*tld).stats).segments), 1);
+        } else {
+            _mi_stat_decrease(ptr::addr_of_mut!((*(*tld).stats).segments), 1);
+        }
+        if segment_size >= 0 {
+            (*tld).count += 1
+        } else {
+            (*tld).count -= 1
+        };
+        if (*tld).count > (*tld).peak_count {
+            (*tld).peak_count = (*tld).count;
+        }
+        (*tld).current_size = (*tld).current_size.wrapping_add(segment_size as u64);
+        if (*tld).current_size > (*tld).peak_size {
+            (*tld).peak_size = (*tld).current_size;
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+   Page allocation
+----------------------------------------------------------- */
+
+fn mi_segments_page_alloc(
+    heap: *mut MiHeap,
+    page_kind: MiPageKind,
+    required: usize,
+    block_size: usize,
+    tld: *mut MiSegmentsTLD,
+    os_tld: *mut MiOsTLD,
+) -> *mut MiPage {
+    debug_assert!(required <= MI_LARGE_OBJ_SIZE_MAX && page_kind != MiPageKind::MiPageHuge);
+
+    // make room for the guard page at the end of a large page (in secure mode 4)
+    let guard_size = if MI_PAGE_GUARD && page_kind == MiPageKind::MiPageLarge {
+        _mi_os_page_size()
+    } else {
+        0
+    };
+
+    // find a free page
+    let page_size = _mi_align_up(
+        required + guard_size,
+        if required > MI_MEDIUM_PAGE_SIZE {
+            MI_MEDIUM_PAGE_SIZE
+        } else {
+            MI_SEGMENT_SLICE_SIZE
+        },
+    );
+    let slices_needed = page_size / MI_SEGMENT_SLICE_SIZE;
+    debug_assert!(slices_needed * MI_SEGMENT_SLICE_SIZE == page_size);
+    let page = mi_segments_page_find_and_allocate(slices_needed, unsafe { (*heap).arena_id }, tld); //(required <= MI_SMALL_SIZE_MAX ? 0 : slices_needed), tld);
+    if page.is_null() {
+        // no free page, allocate a new segment and try again
+        if mi_segment_reclaim_or_alloc(heap, slices_needed, block_size, tld, os_tld).is_null() {
+            // OOM or reclaimed a good page in the heap
+            return ptr::null_mut();
+        } else {
+            // otherwise try again
+            return mi_segments_page_alloc(heap, page_kind, required, block_size, tld, os_tld);
+        }
+    }
+    debug_assert!(
+        !page.is_null()
+            && unsafe { (*page).slice_count } as usize * MI_SEGMENT_SLICE_SIZE == page_size
+    );
+    debug_assert!(
+        unsafe {
+            (*_mi_ptr_segment(page.cast()))
+                .thread_id
+                .load(Ordering::Relaxed)
+        } == _mi_thread_id()
+    );
+    mi_segment_delayed_decommit(_mi_ptr_segment(page.cast()), false);
+    mi_segment_page_protect(page, true);
+    if guard_size > 0 {
+        // large pages in the huge queue take their block size from the page, which excludes the guard page
+        unsafe { (*page).xblock_size -= guard_size as u32 };
+    }
+    page
+}
+
+/* -----------------------------------------------------------
+   Huge page allocation
+----------------------------------------------------------- */
+
+fn mi_segment_huge_page_alloc(
+    size: usize,
+    page_alignment: usize,
+    req_arena_id: MiArenaIdT,
+    tld: *mut MiSegmentsTLD,
+    os_tld: *mut MiOsTLD,
+) -> *mut MiPage {
+    let mut page: *mut MiPage = ptr::null_mut();
+    let segment = mi_segment_alloc(size, page_alignment, req_arena_id, tld, os_tld, &mut page);
+    if segment.is_null() || page.is_null() {
+        return ptr::null_mut();
+    }
+    unsafe {
+        debug_assert!((*segment).used == 1);
+        debug_assert!(mi_page_block_size(page) >= size);
+
+        // for huge pages we initialize the xblock_size as we may
+        // overallocate to accommodate large alignments.
+        let mut psize: usize = 0;
+        let start = _mi_segment_page_start(segment, page, &mut psize);
+        (*page).xblock_size = if psize > MI_HUGE_BLOCK_SIZE {
+            MI_HUGE_BLOCK_SIZE
+        } else {
+            psize
+        } as u32;
+
+        // decommit the part of the prefix of a page that will not be used; this can be quite large (close to MI_SEGMENT_SIZE)
+        if page_alignment > 0 && (*segment).allow_decommit {
+            let aligned_p = _mi_align_up(start as usize, page_alignment) as *mut u8;
+            debug_assert!((aligned_p as usize).is_multiple_of(page_alignment));
+            debug_assert!(psize - (aligned_p.offset_from(start) as usize) >= size);
+            let decommit_start = start.add(size_of::<MiBlock>()); // for the free list
+            let decommit_size = aligned_p.offset_from(decommit_start) as usize;
+            _mi_os_decommit(decommit_start.cast(), decommit_size); // note: cannot use segment_decommit on huge segments
+        }
+    }
+    page
+}
+
+// reset memory of a huge block from another thread
+pub fn _mi_segment_huge_page_reset(
+    segment: *mut MiSegment,
+    page: *mut MiPage,
+    block: *mut MiBlock,
+) {
+    unsafe {
+        debug_assert!((*segment).kind == MiSegmentKind::MiSegmentHuge);
+        debug_assert!(segment == _mi_page_segment(page));
+        debug_assert!((*page).used == 1); // this is called just before the free
+        debug_assert!((*page).free.is_null());
+        if (*segment).allow_decommit {
+            let usize = mi_page_block_size(page) - size_of::<MiBlock>();
+            let p = block.cast::<u8>().add(size_of::<MiBlock>());
+            _mi_os_reset(p.cast(), usize); // note: cannot use segment_decommit on huge segments
+        }
+    }
+}
+
+pub fn _mi_segment_page_alloc(
+    heap: *mut MiHeap,
+    block_size: usize,
+    page_alignment: usize,
+    tld: *mut MiSegmentsTLD,
+    os_tld: *mut MiOsTLD,
+) -> *mut MiPage {
+    let page = if page_alignment > MI_ALIGNMENT_MAX {
+        debug_assert!(page_alignment.is_power_of_two());
+        debug_assert!(page_alignment >= MI_SEGMENT_SIZE);
+        let page_alignment = page_alignment.max(MI_SEGMENT_SIZE);
+        mi_segment_huge_page_alloc(
+            block_size,
+            page_alignment,
+            unsafe { (*heap).arena_id },
+            tld,
+            os_tld,
+        )
+    } else if block_size <= MI_SMALL_OBJ_SIZE_MAX {
+        mi_segments_page_alloc(
+            heap,
+            MiPageKind::MiPageSmall,
+            block_size,
+            block_size,
+            tld,
+            os_tld,
+        )
+    } else if block_size <= MI_MEDIUM_OBJ_SIZE_MAX {
+        mi_segments_page_alloc(
+            heap,
+            MiPageKind::MiPageMedium,
+            MI_MEDIUM_PAGE_SIZE,
+            block_size,
+            tld,
+            os_tld,
+        )
+    } else if block_size <= MI_LARGE_OBJ_SIZE_MAX {
+        mi_segments_page_alloc(
+            heap,
+            MiPageKind::MiPageLarge,
+            block_size,
+            block_size,
+            tld,
+            os_tld,
+        )
+    } else {
+        mi_segment_huge_page_alloc(
+            block_size,
+            page_alignment,
+            unsafe { (*heap).arena_id },
+            tld,
+            os_tld,
+        )
+    };
+    debug_assert!(
+        page.is_null()
+            || _mi_arena_memid_is_suitable(unsafe { (*_mi_page_segment(page)).memid }, unsafe {
+                (*heap).arena_id
+            })
+    );
+    page
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{ffi::c_void, mem::size_of, ptr, thread};
+
+    use crate::{
+        alloc::{mi_free, mi_heap_malloc},
+        arena::_mi_arena_free,
+        heap::mi_heap_collect,
+        init::mi_thread_init,
+        mimalloc_internal::{
+            _mi_ptr_segment, _mi_segment_page_of, mi_page_block_size, mi_segment_size,
+        },
+        mimalloc_types::{
+            MiBlock, MiCommitMask, MiHeap, MiSegment, MiSegmentsTLD, MI_COMMIT_SIZE,
+            MI_LARGE_OBJ_SIZE_MAX, MI_MINIMAL_COMMIT_SIZE, MI_SECURE, MI_SEGMENT_ALIGN,
+            MI_SEGMENT_SIZE, MI_SEGMENT_SLICE_SIZE, MI_SLICES_PER_SEGMENT,
+        },
+        os::{_mi_os_page_size, _mi_os_with_backend},
+        os_mock::{MiOsCallKind, MiOsMockBackend},
+        page::tests::TestHeap,
+        segment_cache::{_mi_segment_map_freed_at, _mi_segment_of},
+    };
+
+    use super::{
+        _mi_segment_page_start, mi_abandoned_pop, mi_abandoned_visited_push,
+        mi_abandoned_visited_revisit, mi_segment_calculate_slices, mi_segment_commitx,
+        mi_segment_info_size, mi_segment_os_alloc, mi_segment_reclaim, mi_segments_track_size,
+        mi_slice_start, MI_PAGE_GUARD,
+    };
+
+    // Take `segment` off the abandoned list and reclaim it into `heap`; the other abandoned
+    // segments are pushed on the visited list. Other threads may have the segment popped
+    // temporarily, so this retries until it is found: the segment must not be suitable for
+    // the heaps of other threads (for example, by allocating it in an exclusive arena).
+    pub(crate) fn reclaim_abandoned(
+        segment: *mut MiSegment,
+        heap: *mut MiHeap,
+        tld: *mut MiSegmentsTLD,
+    ) {
+        loop {
+            mi_abandoned_visited_revisit();
+            loop {
+                let s = mi_abandoned_pop();
+                if s.is_null() {
+                    break;
+                }
+                if s == segment {
+                    mi_segment_reclaim(s, heap, 0, ptr::null_mut(), tld);
+                    return;
+                }
+                mi_abandoned_visited_push(s);
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn test_mi_segment_calculate_slices() {
+        let os_psize = _mi_os_page_size();
+        let guard_size = if MI_SECURE != 0 { os_psize } else { 0 };
+        let mut pre_size = 0;
+        let mut info_slices = 0;
+        let slices = mi_segment_calculate_slices(0, &mut pre_size, &mut info_slices);
+        assert_eq!(slices, MI_SLICES_PER_SEGMENT);
+        assert!(pre_size >= size_of::<MiSegment>() && pre_size % os_psize == 0);
+        // the guard page fits between the segment info and the first page
+        assert!(info_slices * MI_SEGMENT_SLICE_SIZE >= pre_size + guard_size);
+
+        // a huge page still fits after the info and the guard at the end of the segment
+        let required = 3 * MI_SEGMENT_SIZE + 1;
+        let slices = mi_segment_calculate_slices(required, ptr::null_mut(), ptr::null_mut());
+        let guard_slices = if MI_SECURE != 0 { 1 } else { 0 };
+        assert!((slices - info_slices - guard_slices) * MI_SEGMENT_SLICE_SIZE >= required);
+    }
+
+    // `true` if the byte at `p` can be read (the kernel reports `EFAULT` instead of faulting)
+    #[cfg(unix)]
+    fn is_readable(p: *const u8) -> bool {
+        let mut fds = [0; 2];
+        unsafe {
+            assert_eq!(libc::pipe(fds.as_mut_ptr()), 0);
+            let n = libc::write(fds[1], p.cast(), 1);
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+            n == 1
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mi_segment_guard_pages() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let p = mi_heap_malloc(heap, 64);
+        assert!(!p.is_null());
+        let segment = _mi_ptr_segment(p);
+        let page = _mi_segment_page_of(segment, p);
+        let os_psize = _mi_os_page_size();
+
+        // guard pages after the segment info and at the end of the segment
+        let info_guard = segment as usize + mi_segment_info_size(segment) - os_psize;
+        let end_guard = segment as usize + mi_segment_size(segment) - os_psize;
+        assert!(is_readable(segment.cast()) && is_readable(p.cast()));
+        if MI_SECURE != 0 {
+            assert!(!is_readable(info_guard as *const u8));
+            assert!(!is_readable(end_guard as *const u8));
+        } else {
+            assert!(is_readable(info_guard as *const u8));
+        }
+
+        // and at the end of every page (in secure mode 4)
+        let mut psize = 0;
+        let start = _mi_segment_page_start(segment, page, &mut psize) as usize;
+        let page_end = mi_slice_start(page) as usize
+            + unsafe { (*page).slice_count } as usize * MI_SEGMENT_SLICE_SIZE;
+        if MI_PAGE_GUARD {
+            assert_eq!(start + psize, page_end - os_psize);
+            assert!(!is_readable((page_end - os_psize) as *const u8));
+        } else {
+            assert_eq!(start + psize, page_end);
+        }
+
+        mi_free(p);
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mi_segment_recommit_keeps_guard_pages() {
+        if !MI_PAGE_GUARD {
+            return;
+        }
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let p = mi_heap_malloc(heap, 64);
+        assert!(!p.is_null());
+        let segment = _mi_ptr_segment(p);
+        let page = _mi_segment_page_of(segment, p);
+        let os_psize = _mi_os_page_size();
+        let info_guard = segment as usize + mi_segment_info_size(segment) - os_psize;
+        let page_end = mi_slice_start(page)
+            .wrapping_add(unsafe { (*page).slice_count } as usize * MI_SEGMENT_SLICE_SIZE);
+        let page_guard = page_end as usize - os_psize;
+        assert!(!is_readable(info_guard as *const u8));
+        assert!(!is_readable(page_guard as *const u8));
+
+        // decommit the free span right after the page and commit it again: the commit
+        // is widened to the start of the segment and covers the live page and its guard
+        assert!((page_end as usize - segment as usize) < MI_MINIMAL_COMMIT_SIZE);
+        assert!(mi_segment_commitx(segment, false, page_end, MI_COMMIT_SIZE));
+        assert!(mi_segment_commitx(segment, true, page_end, MI_COMMIT_SIZE));
+        assert!(is_readable(page_end));
+        assert!(is_readable(p.cast()));
+        assert!(!is_readable(info_guard as *const u8));
+        assert!(!is_readable(page_guard as *const u8));
+
+        mi_free(p);
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_segment_huge_page_reset_on_thread_free() {
+        let mut th = TestHeap::new();
+        let heap = th.ptr();
+        let size = MI_LARGE_OBJ_SIZE_MAX + 1;
+        let p = mi_heap_malloc(heap, size);
+        assert!(!p.is_null());
+        let page = _mi_segment_page_of(_mi_ptr_segment(p), p);
+        let bsize = mi_page_block_size(page);
+
+        // a free from another thread resets the memory of the block right away
+        let mock = MiOsMockBackend::leak();
+        let addr = p as usize;
+        thread::spawn(move || {
+            // the thread data must not come from the mock
+            mi_thread_init();
+            _mi_os_with_backend(mock, || mi_free(addr as *mut c_void));
+        })
+        .join()
+        .unwrap();
+        let resets: Vec<_> = mock
+            .calls()
+            .into_iter()
+            .filter(|c| c.kind == MiOsCallKind::Reset)
+            .collect();
+        assert_eq!(resets.len(), 1);
+        assert!(resets[0].ok);
+        assert!(resets[0].addr >= addr + size_of::<MiBlock>());
+        assert!(resets[0].addr + resets[0].size <= addr + bsize);
+        assert!(resets[0].size >= size - _mi_os_page_size());
+
+        // but the segment is only freed by the owning thread
+        assert_eq!(unsafe { (*page).used }, 1);
+        assert_eq!(th.tld.segments.count, 1);
+        mi_heap_collect(heap, true);
+        assert_eq!(th.tld.segments.count, 0);
+    }
+
+    #[test]
+    fn test_mi_segment_alloc_free_with_mock() {
+        // the thread data must not come from the mock
+        mi_thread_init();
+        let mock = MiOsMockBackend::leak();
+        _mi_os_with_backend(mock, || {
+            let mut th = TestHeap::new();
+            let heap = th.ptr();
+            let small = mi_heap_malloc(heap, 64);
+            let huge = mi_heap_malloc(heap, MI_LARGE_OBJ_SIZE_MAX + 1);
+            assert!(!small.is_null() && !huge.is_null());
+            assert_ne!(_mi_ptr_segment(small), _mi_ptr_segment(huge));
+            assert_eq!(th.tld.segments.count, 2);
+            assert_eq!(mock.live_regions(), 2);
+
+            mi_free(small);
+            mi_free(huge);
+            mi_heap_collect(heap, true);
+            assert_eq!(th.tld.segments.count, 0);
+        });
+        assert!(mock.count(MiOsCallKind::Reserve) >= 2);
+        assert_eq!(mock.live_regions(), 0);
+
+        // all guard pages are unprotected again before the memory is released
+        let calls = mock.calls();
+        assert_eq!(mock.count(MiOsCallKind::Protect) > 0, MI_SECURE != 0);
+        for guard in calls.iter().filter(|c| c.kind == MiOsCallKind::Protect) {
+            assert!(calls
+                .iter()
+                .any(|c| c.kind == MiOsCallKind::Unprotect && c.addr == guard.addr));
+        }
+    }
+
+    #[test]
+    fn test_mi_segment_os_alloc_commit_failure() {
+        let mock = MiOsMockBackend::leak();
+        mock.fail_nth(MiOsCallKind::Commit, 0);
+        _mi_os_with_backend(mock, || {
+            let mut th = TestHeap::new();
+            let alloc = |th: &mut TestHeap, commit: &mut bool| {
+                let mut pre_size = 0;
+                let mut info_slices = 0;
+                let mut segment_slices =
+                    mi_segment_calculate_slices(0, &mut pre_size, &mut info_slices);
+                let mut commit_mask = MiCommitMask { mask: [0; 8] };
+                let mut decommit_mask = MiCommitMask { mask: [0; 8] };
+                let mut is_zero = false;
+                let segment = mi_segment_os_alloc(
+                    0,
+                    0,
+                    true,
+                    0,
+                    &mut segment_slices,
+                    &mut pre_size,
+                    &mut info_slices,
+                    &mut commit_mask,
+                    &mut decommit_mask,
+                    &mut is_zero,
+                    commit,
+                    &mut th.tld.segments,
+                    &mut th.tld.os,
+                );
+                (segment, segment_slices * MI_SEGMENT_SLICE_SIZE)
+            };
+
+            // a lazily committed segment whose info slices cannot be committed is released again
+            let mut commit = false;
+            let (segment, _) = alloc(&mut th, &mut commit);
+            assert!(segment.is_null());
+            assert_eq!(mock.count(MiOsCallKind::Commit), 1);
+            assert_eq!(mock.live_regions(), 0);
+
+            // the next attempt succeeds
+            let mut commit = false;
+            let (segment, size) = alloc(&mut th, &mut commit);
+            assert!(!segment.is_null());
+            assert_eq!(mock.count(MiOsCallKind::Commit), 2);
+            assert_eq!(mock.live_regions(), 1);
+            // undo the segment map entry and statistics of the allocation before releasing the memory
+            _mi_segment_map_freed_at(segment);
+            mi_segments_track_size(-(size as i64), &mut th.tld.segments);
+            assert_eq!(th.tld.segments.count, 0);
+            assert!(_mi_segment_of(segment.cast()).is_null());
+            unsafe {
+                _mi_arena_free(
+                    segment.cast(),
+                    size,
+                    MI_SEGMENT_ALIGN,
+                    0,
+                    (*segment).memid,
+                    false,
+                )
+            };
+        });
+        assert_eq!(mock.live_regions(), 0);
+    }
 }
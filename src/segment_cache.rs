@@ -1,6 +1,6 @@
 use std::{
-    ptr::{self, addr_of},
-    sync::atomic::AtomicUsize,
+    ptr,
+    sync::atomic::{AtomicI64, AtomicUsize, Ordering},
 };
 
 use crate::mimalloc_internal::mi_segment_size;
@@ -8,28 +8,50 @@ use crate::mimalloc_internal::mi_segment_size;
 use libc::{c_void, size_t, uintptr_t};
 
 use crate::{
-    mimalloc_internal::{_mi_ptr_cookie, _mi_ptr_segment, mi_bsr},
+    arena::{
+        _mi_arena_free, _mi_arena_id_none, _mi_arena_is_os_allocated, _mi_arena_memid_is_suitable,
+    },
+    bitmap::{
+        _mi_bitmap_claim, _mi_bitmap_is_claimed, _mi_bitmap_try_find_from_claim,
+        _mi_bitmap_try_find_from_claim_pred, _mi_bitmap_unclaim, mi_bitmap_index_bit,
+        mi_bitmap_index_create_from_bit, MiBitmapIndex, MiBitmapPredFun, MI_BITMAP_FIELD_BITS,
+        MI_BITMAP_FIELD_FULL,
+    },
+    mimalloc_internal::{
+        _mi_ptr_cookie, _mi_ptr_segment, mi_bsr, mi_commit_mask_create_empty,
+        mi_commit_mask_is_empty, mi_commit_mask_is_full,
+    },
     mimalloc_types::{
-        MiArenaIdT, MiCommitMask, MiOsTLD, MiSegment, MI_INTPTR_BITS, MI_INTPTR_SIZE,
-        MI_SEGMENT_SIZE,
+        MiArenaIdT, MiCommitMask, MiOption, MiOsTLD, MiSegment, MI_COMMIT_MASK_BITS,
+        MI_INTPTR_BITS, MI_INTPTR_SIZE, MI_SEGMENT_ALIGN, MI_SEGMENT_SIZE,
+    },
+    options::{mi_option_get, mi_option_is_enabled},
+    os::{_mi_clock_now, _mi_os_decommit, _mi_os_numa_node_count, _mi_os_numa_node_get},
+    random::_mi_random_shuffle,
+    segment::{
+        _mi_abandoned_await_readers, _mi_commit_mask_committed_size, _mi_commit_mask_next_run,
     },
+    stats::{_mi_stat_decrease, _mi_stats_main},
 };
 
 // #if (MI_INTPTR_SIZE==8)
 // TODO support only 64bit for now
-const MI_MAX_ADDRESS: usize = 20 << 40; // 20TB
-                                        // #else
-                                        // #define MI_MAX_ADDRESS    ((size_t)2 << 30)   // 2Gb
-                                        // #endif
+// note: covers the full 47-bit user address space so `_mi_segment_of` can reject any foreign pointer
+const MI_MAX_ADDRESS: usize = 128 << 40; // 128TB
+                                         // #else
+                                         // #define MI_MAX_ADDRESS    ((size_t)2 << 30)   // 2Gb
+                                         // #endif
 
 const MI_SEGMENT_MAP_BITS: usize = MI_MAX_ADDRESS / MI_SEGMENT_SIZE;
 const MI_SEGMENT_MAP_SIZE: usize = MI_SEGMENT_MAP_BITS / 8;
 const MI_SEGMENT_MAP_WSIZE: usize = MI_SEGMENT_MAP_SIZE / MI_INTPTR_SIZE;
 
 // static _Atomic(uintptr_t) mi_segment_map[MI_SEGMENT_MAP_WSIZE + 1];  // 2KiB per TB with 64MiB segments
+#[allow(clippy::declare_interior_mutable_const)]
 const INIT: AtomicUsize = AtomicUsize::new(0);
 static mi_segment_map: [AtomicUsize; MI_SEGMENT_MAP_WSIZE + 1] = [INIT; MI_SEGMENT_MAP_WSIZE + 1];
 
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
 pub fn _mi_segment_cache_pop(
     size: usize,
     commit_mask: *mut MiCommitMask,
@@ -37,7 +59,7 @@ pub fn _mi_segment_cache_pop(
     large: *mut bool,
     is_pinned: *mut bool,
     is_zero: *mut bool,
-    _req_arena_id: MiArenaIdT,
+    req_arena_id: MiArenaIdT,
     memid: *mut usize,
     tld: *mut MiOsTLD,
 ) -> *mut c_void {
@@ -49,7 +71,7 @@ pub fn _mi_segment_cache_pop(
         large,
         is_pinned,
         is_zero,
-        _req_arena_id,
+        req_arena_id,
         memid,
         tld,
     )
@@ -61,7 +83,7 @@ fn mi_segment_map_index_of(segment: *const MiSegment, bitidx: *mut size_t) -> si
         unsafe {
             *bitidx = 0;
         }
-        return MI_SEGMENT_MAP_WSIZE;
+        MI_SEGMENT_MAP_WSIZE
     } else {
         let segindex = (segment) as usize / MI_SEGMENT_SIZE;
         unsafe {
@@ -69,7 +91,7 @@ fn mi_segment_map_index_of(segment: *const MiSegment, bitidx: *mut size_t) -> si
         }
         let mapindex = segindex / MI_INTPTR_BITS;
         debug_assert!(mapindex < MI_SEGMENT_MAP_WSIZE);
-        return mapindex;
+        mapindex
     }
 }
 
@@ -80,30 +102,105 @@ pub fn _mi_segment_map_allocated_at(segment: *const MiSegment) {
     if index == MI_SEGMENT_MAP_WSIZE {
         return;
     }
-    let mask = mi_segment_map[index].load(std::sync::atomic::Ordering::Relaxed);
+    let mut mask = mi_segment_map[index].load(Ordering::Relaxed);
     let mut newmask: uintptr_t;
-    // do {
-    //   newmask = (mask | ((uintptr_t)1 << bitidx));
-    // } while (!mi_atomic_cas_weak_release(&mi_segment_map[index], &mask, newmask));
-
     loop {
         newmask = mask | (1 << bitidx);
+        match mi_segment_map[index].compare_exchange_weak(
+            mask,
+            newmask,
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => mask = current,
+        }
+    }
+}
 
-        if mi_segment_map[index]
-            .compare_exchange_weak(
-                // Strange here
-                addr_of!(mask) as usize,
-                newmask,
-                std::sync::atomic::Ordering::Release,
-                std::sync::atomic::Ordering::Relaxed,
-            )
-            .is_ok()
-        {
-            break;
+pub fn _mi_segment_map_freed_at(segment: *const MiSegment) {
+    let mut bitidx: size_t = 0;
+    let index: size_t = mi_segment_map_index_of(segment, &mut bitidx);
+    debug_assert!(index <= MI_SEGMENT_MAP_WSIZE);
+    if index == MI_SEGMENT_MAP_WSIZE {
+        return;
+    }
+    let mut mask = mi_segment_map[index].load(Ordering::Relaxed);
+    let mut newmask: uintptr_t;
+    loop {
+        newmask = mask & !(1 << bitidx);
+        match mi_segment_map[index].compare_exchange_weak(
+            mask,
+            newmask,
+            Ordering::Release,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => break,
+            Err(current) => mask = current,
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+  The segment cache
+  Caches freed segments of `MI_SEGMENT_SIZE` for reuse and
+  decommits their memory after `segment_decommit_delay` milli-seconds.
+----------------------------------------------------------- */
+
+const MI_CACHE_FIELDS: usize = 16;
+const MI_CACHE_MAX: usize = MI_BITMAP_FIELD_BITS * MI_CACHE_FIELDS; // 1024 on 64-bit
+
+struct MiCacheSlot {
+    p: *mut c_void,
+    memid: usize,
+    is_pinned: bool,
+    commit_mask: MiCommitMask,
+    decommit_mask: MiCommitMask,
+    expire: AtomicI64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const MI_CACHE_SLOT_INIT: MiCacheSlot = MiCacheSlot {
+    p: ptr::null_mut(),
+    memid: 0,
+    is_pinned: false,
+    commit_mask: MiCommitMask { mask: [0; 8] },
+    decommit_mask: MiCommitMask { mask: [0; 8] },
+    expire: AtomicI64::new(0),
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const MI_CACHE_BITS_SET: AtomicUsize = AtomicUsize::new(MI_BITMAP_FIELD_FULL);
+
+static mut cache: [MiCacheSlot; MI_CACHE_MAX] = [MI_CACHE_SLOT_INIT; MI_CACHE_MAX]; // = 0
+
+static cache_available: [AtomicUsize; MI_CACHE_FIELDS] = [MI_CACHE_BITS_SET; MI_CACHE_FIELDS]; // zero bit = available!
+static cache_available_large: [AtomicUsize; MI_CACHE_FIELDS] = [MI_CACHE_BITS_SET; MI_CACHE_FIELDS];
+static cache_inuse: [AtomicUsize; MI_CACHE_FIELDS] = [INIT; MI_CACHE_FIELDS]; // zero bit = free
+
+fn mi_cache_slot(bitidx: MiBitmapIndex) -> *mut MiCacheSlot {
+    unsafe { ptr::addr_of_mut!(cache[mi_bitmap_index_bit(bitidx)]) }
+}
+
+// numa node determines start field
+fn mi_segment_cache_start_field(tld: *mut MiOsTLD) -> usize {
+    let numa_node = _mi_os_numa_node_get(tld);
+    let mut start_field = 0;
+    if numa_node > 0 {
+        start_field = (MI_CACHE_FIELDS / _mi_os_numa_node_count()) * numa_node as usize;
+        if start_field >= MI_CACHE_FIELDS {
+            start_field = 0;
         }
     }
+    start_field
 }
 
+fn mi_segment_cache_is_suitable(bitidx: MiBitmapIndex, arg: *mut c_void) -> bool {
+    let req_arena_id = unsafe { *arg.cast::<MiArenaIdT>() };
+    let slot = mi_cache_slot(bitidx);
+    _mi_arena_memid_is_suitable(unsafe { (*slot).memid }, req_arena_id)
+}
+
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
 fn mi_segment_cache_pop_ex(
     all_suitable: bool,
     size: usize,
@@ -112,15 +209,364 @@ fn mi_segment_cache_pop_ex(
     large: *mut bool,
     is_pinned: *mut bool,
     is_zero: *mut bool,
-    _req_arena_id: MiArenaIdT,
+    req_arena_id: MiArenaIdT,
     memid: *mut usize,
     tld: *mut MiOsTLD,
 ) -> *mut c_void {
-    ptr::null_mut()
+    // only segment blocks
+    if size != MI_SEGMENT_SIZE {
+        return ptr::null_mut();
+    }
+
+    // numa node determines start field
+    let start_field = mi_segment_cache_start_field(tld);
+
+    // find an available slot and make it unavailable
+    let mut bitidx: MiBitmapIndex = 0;
+    let mut claimed = false;
+    let mut req_arena_id = req_arena_id;
+    let pred_arg = ptr::addr_of_mut!(req_arena_id).cast();
+    // cannot pass None as the arena may be exclusive itself; todo: do not put exclusive arenas in the cache?
+    let pred_fun: Option<MiBitmapPredFun> = if all_suitable {
+        None
+    } else {
+        Some(mi_segment_cache_is_suitable)
+    };
+
+    unsafe {
+        if *large {
+            // large allowed?
+            claimed = _mi_bitmap_try_find_from_claim_pred(
+                cache_available_large.as_ptr(),
+                MI_CACHE_FIELDS,
+                start_field,
+                1,
+                pred_fun,
+                pred_arg,
+                &mut bitidx,
+            );
+            if claimed {
+                *large = true;
+            }
+        }
+        if !claimed {
+            claimed = _mi_bitmap_try_find_from_claim_pred(
+                cache_available.as_ptr(),
+                MI_CACHE_FIELDS,
+                start_field,
+                1,
+                pred_fun,
+                pred_arg,
+                &mut bitidx,
+            );
+            if claimed {
+                *large = false;
+            }
+        }
+
+        if !claimed {
+            return ptr::null_mut();
+        }
+
+        // found a slot
+        let slot = mi_cache_slot(bitidx);
+        let p = (*slot).p;
+        *memid = (*slot).memid;
+        *is_pinned = (*slot).is_pinned;
+        *is_zero = false;
+        *commit_mask = (*slot).commit_mask;
+        *decommit_mask = (*slot).decommit_mask;
+        (*slot).p = ptr::null_mut();
+        (*slot).expire.store(0, Ordering::Release);
+
+        // mark the slot as free again
+        debug_assert!(_mi_bitmap_is_claimed(
+            cache_inuse.as_ptr(),
+            MI_CACHE_FIELDS,
+            1,
+            bitidx
+        ));
+        _mi_bitmap_unclaim(cache_inuse.as_ptr(), MI_CACHE_FIELDS, 1, bitidx);
+        p
+    }
+}
+
+fn mi_commit_mask_decommit(cmask: *mut MiCommitMask, p: *mut c_void, total: usize) {
+    if mi_commit_mask_is_empty(cmask) {
+        // nothing
+    } else if mi_commit_mask_is_full(cmask) {
+        // decommit the whole in one call
+        _mi_os_decommit(p, total);
+    } else {
+        // decommit parts
+        debug_assert!(total.is_multiple_of(MI_COMMIT_MASK_BITS));
+        let part = total / MI_COMMIT_MASK_BITS;
+        let mut idx = 0;
+        loop {
+            let count = _mi_commit_mask_next_run(cmask, &mut idx);
+            if count == 0 {
+                break;
+            }
+            let start = unsafe { p.cast::<u8>().add(idx * part) };
+            let size = count * part;
+            _mi_os_decommit(start.cast(), size);
+            idx += count;
+        }
+    }
+    mi_commit_mask_create_empty(cmask);
+}
+
+const MI_MAX_PURGE_PER_PUSH: usize = 4;
+
+fn mi_segment_cache_purge(visit_all: bool, force: bool, _tld: *mut MiOsTLD) {
+    if !mi_option_is_enabled(MiOption::MiOptionAllowDecommit) {
+        return;
+    }
+    let now = _mi_clock_now();
+    let mut purged = 0;
+    let max_visits = if visit_all {
+        MI_CACHE_MAX // visit all
+    } else {
+        MI_CACHE_FIELDS // probe at most N (=16) slots
+    };
+    let mut idx = if visit_all {
+        0
+    } else {
+        _mi_random_shuffle(now as usize) % MI_CACHE_MAX // random start
+    };
+    for _ in 0..max_visits {
+        // visit N slots
+        if idx >= MI_CACHE_MAX {
+            idx = 0; // wrap
+        }
+        let bitidx = mi_bitmap_index_create_from_bit(idx);
+        let slot = mi_cache_slot(bitidx);
+        let mut expire = unsafe { (*slot).expire.load(Ordering::Relaxed) };
+        if expire != 0 && (force || now >= expire) {
+            // racy read
+            // seems expired, first claim it from available
+            purged += 1;
+            if _mi_bitmap_claim(
+                cache_available.as_ptr(),
+                MI_CACHE_FIELDS,
+                1,
+                bitidx,
+                ptr::null_mut(),
+            ) {
+                // no need to check large as those cannot be decommitted anyways
+                // it was available, we claimed it (and made it unavailable)
+                debug_assert!(_mi_bitmap_is_claimed(
+                    cache_available.as_ptr(),
+                    MI_CACHE_FIELDS,
+                    1,
+                    bitidx
+                ));
+                // we can now access it safely
+                expire = unsafe { (*slot).expire.load(Ordering::Acquire) };
+                if expire != 0 && (force || now >= expire) {
+                    // safe read
+                    debug_assert!(_mi_bitmap_is_claimed(
+                        cache_inuse.as_ptr(),
+                        MI_CACHE_FIELDS,
+                        1,
+                        bitidx
+                    ));
+                    // still expired, decommit it
+                    unsafe {
+                        (*slot).expire.store(0, Ordering::Relaxed);
+                        debug_assert!(!mi_commit_mask_is_empty(&(*slot).commit_mask));
+                        _mi_abandoned_await_readers(); // wait until safe to decommit
+                                                       // decommit committed parts
+                                                       // TODO: instead of decommit, we could also free to the OS?
+                        mi_commit_mask_decommit(
+                            ptr::addr_of_mut!((*slot).commit_mask),
+                            (*slot).p,
+                            MI_SEGMENT_SIZE,
+                        );
+                        mi_commit_mask_create_empty(ptr::addr_of_mut!((*slot).decommit_mask));
+                    }
+                }
+                _mi_bitmap_unclaim(cache_available.as_ptr(), MI_CACHE_FIELDS, 1, bitidx);
+                // make it available again for a pop
+            }
+            if !visit_all && purged > MI_MAX_PURGE_PER_PUSH {
+                break; // bound to no more than N purge tries per push
+            }
+        }
+        idx += 1;
+    }
+}
+
+pub fn _mi_segment_cache_collect(force: bool, tld: *mut MiOsTLD) {
+    if force {
+        // called on `mi_collect(true)` but not on thread termination
+        _mi_segment_cache_free_all(tld);
+    } else {
+        mi_segment_cache_purge(
+            true,  /* visit all */
+            false, /* don't force unexpired */
+            tld,
+        );
+    }
+}
+
+pub fn _mi_segment_cache_free_all(tld: *mut MiOsTLD) {
+    let mut commit_mask = MiCommitMask { mask: [0; 8] };
+    let mut decommit_mask = MiCommitMask { mask: [0; 8] };
+    let mut is_pinned = false;
+    let mut is_zero = false;
+    let mut memid = 0;
+    let size = MI_SEGMENT_SIZE;
+    // iterate twice: first large pages, then regular memory
+    for i in 0..2 {
+        loop {
+            // keep popping and freeing the memory
+            let mut large = i == 0;
+            let p = mi_segment_cache_pop_ex(
+                true, /* all */
+                size,
+                &mut commit_mask,
+                &mut decommit_mask,
+                &mut large,
+                &mut is_pinned,
+                &mut is_zero,
+                _mi_arena_id_none(),
+                &mut memid,
+                tld,
+            );
+            if p.is_null() {
+                break;
+            }
+            let csize = _mi_commit_mask_committed_size(&commit_mask, size);
+            if csize > 0 && !is_pinned {
+                _mi_stat_decrease(
+                    unsafe { ptr::addr_of_mut!(_mi_stats_main.committed) },
+                    csize,
+                );
+            }
+            _mi_arena_free(
+                p,
+                size,
+                MI_SEGMENT_ALIGN,
+                0,
+                memid,
+                is_pinned, /* pretend not committed to not double count decommits */
+            );
+        }
+    }
+}
+
+// Push a segment on the cache; returns `false` if the segment could not be cached
+// (and should be freed by the caller).
+#[allow(clippy::too_many_arguments)] // same arguments as the C function
+pub fn _mi_segment_cache_push(
+    start: *mut c_void,
+    size: usize,
+    memid: usize,
+    commit_mask: *const MiCommitMask,
+    decommit_mask: *const MiCommitMask,
+    is_large: bool,
+    is_pinned: bool,
+    tld: *mut MiOsTLD,
+) -> bool {
+    // purge expired entries
+    mi_segment_cache_purge(
+        false, /* limit purges to a constant N */
+        false, /* don't force unexpired */
+        tld,
+    );
+
+    // only cache normal segment blocks
+    if size != MI_SEGMENT_SIZE || !(start as usize).is_multiple_of(MI_SEGMENT_ALIGN) {
+        return false;
+    }
+
+    // Also do not cache arena allocated segments that cannot be decommitted. (as arena allocation is fast)
+    // This is a common case with reserved huge OS pages.
+    //
+    // (note: we could also allow segments that are already fully decommitted but that never happens
+    //  as the first slice is always committed (for the segment metadata))
+    if !_mi_arena_is_os_allocated(memid) && is_pinned {
+        return false;
+    }
+
+    // numa node determines start field
+    let start_field = mi_segment_cache_start_field(ptr::null_mut());
+
+    // find an available slot
+    let mut bitidx: MiBitmapIndex = 0;
+    let claimed = _mi_bitmap_try_find_from_claim(
+        cache_inuse.as_ptr(),
+        MI_CACHE_FIELDS,
+        start_field,
+        1,
+        &mut bitidx,
+    );
+    if !claimed {
+        return false;
+    }
+
+    debug_assert!(_mi_bitmap_is_claimed(
+        cache_available.as_ptr(),
+        MI_CACHE_FIELDS,
+        1,
+        bitidx
+    ));
+    debug_assert!(_mi_bitmap_is_claimed(
+        cache_available_large.as_ptr(),
+        MI_CACHE_FIELDS,
+        1,
+        bitidx
+    ));
+    debug_assert!(!(is_pinned || is_large) || mi_commit_mask_is_full(commit_mask));
+
+    // set the slot
+    let slot = mi_cache_slot(bitidx);
+    unsafe {
+        (*slot).p = start;
+        (*slot).memid = memid;
+        (*slot).is_pinned = is_pinned;
+        (*slot).expire.store(0, Ordering::Relaxed);
+        (*slot).commit_mask = *commit_mask;
+        (*slot).decommit_mask = *decommit_mask;
+        if !mi_commit_mask_is_empty(commit_mask)
+            && !is_large
+            && !is_pinned
+            && mi_option_is_enabled(MiOption::MiOptionAllowDecommit)
+        {
+            let delay = mi_option_get(MiOption::MiOptionSegmentDecommitDelay);
+            if delay == 0 {
+                _mi_abandoned_await_readers(); // wait until safe to decommit
+                mi_commit_mask_decommit(
+                    ptr::addr_of_mut!((*slot).commit_mask),
+                    start,
+                    MI_SEGMENT_SIZE,
+                );
+                mi_commit_mask_create_empty(ptr::addr_of_mut!((*slot).decommit_mask));
+            } else {
+                (*slot)
+                    .expire
+                    .store(_mi_clock_now() + delay as i64, Ordering::Release);
+            }
+        }
+    }
+
+    // make it available
+    _mi_bitmap_unclaim(
+        if is_large {
+            cache_available_large.as_ptr()
+        } else {
+            cache_available.as_ptr()
+        },
+        MI_CACHE_FIELDS,
+        1,
+        bitidx,
+    );
+    true
 }
 
 // Determine the segment belonging to a pointer or NULL if it is not in a valid segment.
-fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
+pub fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
     if p.is_null() {
         return ptr::null_mut();
     }
@@ -129,7 +575,7 @@ fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
     let mut bitidx: usize = 0;
     let index = mi_segment_map_index_of(segment, &mut bitidx);
     // fast path: for any pointer to valid small/medium/large object or first MI_SEGMENT_SIZE in huge
-    let mask = mi_segment_map[index].load(std::sync::atomic::Ordering::Relaxed);
+    let mask = mi_segment_map[index].load(Ordering::Relaxed);
     if (mask & (1 << bitidx)) != 0 {
         return segment; // yes, allocated by us
     }
@@ -143,7 +589,7 @@ fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
     // // could be slow but searches in MI_INTPTR_SIZE * MI_SEGMENT_SIZE (512MiB) steps trough
     // // valid huge objects
     // // note: we could maintain a lowest index to speed up the path for invalid pointers?
-    let mut lobitidx;
+    let lobitidx;
     let mut loindex;
     let lobits = mask & ((1 << bitidx) - 1);
     if lobits != 0 {
@@ -153,13 +599,13 @@ fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
         return ptr::null_mut();
     } else {
         debug_assert!(index > 0);
-        let mut lomask = mask;
+        let mut lomask;
         loindex = index;
 
         loop {
             loindex -= 1;
-            lomask = mi_segment_map[loindex].load(std::sync::atomic::Ordering::Relaxed);
-            if lomask != 0 && loindex > 0 {
+            lomask = mi_segment_map[loindex].load(Ordering::Relaxed);
+            if lomask == 0 && loindex > 0 {
                 continue;
             } else {
                 break;
@@ -199,11 +645,86 @@ fn _mi_segment_of(p: *const c_void) -> *mut MiSegment {
     segment
 }
 
-// Is this a valid pointer in our heap?
-fn mi_is_valid_pointer(p: *const c_void) -> bool {
-    !_mi_segment_of(p).is_null()
-}
+#[cfg(test)]
+mod tests {
+    use crate::{
+        arena::{_mi_arena_alloc_aligned, _mi_arena_id_none, mi_reserve_os_memory_ex, MI_MEMID_OS},
+        init::mi_thread_init,
+        mimalloc_internal::{mi_commit_mask_create_empty, mi_commit_mask_create_full},
+        mimalloc_types::{MiCommitMask, MiOption, MiOsTLD, MI_SEGMENT_ALIGN, MI_SEGMENT_SIZE},
+        options::{mi_option_get, mi_option_set},
+        os::{_mi_os_init, _mi_os_with_backend},
+        os_mock::{MiOsCallKind, MiOsMockBackend},
+    };
+
+    use super::_mi_segment_cache_push;
+
+    // Push a segment from a fresh exclusive arena (reserved from the current backend) on the
+    // cache and return the number of decommit calls for it. As the arena is exclusive other
+    // threads never pop the test segments (except when all segments are released).
+    fn push_segment(mock: &MiOsMockBackend) -> usize {
+        let mut arena_id = _mi_arena_id_none();
+        assert_eq!(
+            mi_reserve_os_memory_ex(MI_SEGMENT_SIZE, false, false, true, &mut arena_id),
+            0
+        );
+        let mut commit = true;
+        let mut large = false;
+        let mut is_pinned = false;
+        let mut is_zero = false;
+        let mut memid = MI_MEMID_OS;
+        let mut tld = MiOsTLD::default();
+        let p = _mi_arena_alloc_aligned(
+            MI_SEGMENT_SIZE,
+            MI_SEGMENT_ALIGN,
+            0,
+            &mut commit,
+            &mut large,
+            &mut is_pinned,
+            &mut is_zero,
+            arena_id,
+            &mut memid,
+            &mut tld,
+        );
+        assert!(!p.is_null() && memid != MI_MEMID_OS && !is_pinned);
+        let mut commit_mask = MiCommitMask { mask: [0; 8] };
+        let mut decommit_mask = MiCommitMask { mask: [0; 8] };
+        mi_commit_mask_create_full(&mut commit_mask);
+        mi_commit_mask_create_empty(&mut decommit_mask);
+        assert!(_mi_segment_cache_push(
+            p,
+            MI_SEGMENT_SIZE,
+            memid,
+            &commit_mask,
+            &decommit_mask,
+            false,
+            false,
+            &mut tld,
+        ));
+        let start = p as usize;
+        mock.calls()
+            .iter()
+            .filter(|c| c.kind == MiOsCallKind::Decommit)
+            .filter(|c| c.addr >= start && c.addr < start + MI_SEGMENT_SIZE)
+            .inspect(|c| assert!(c.ok && c.addr + c.size <= start + MI_SEGMENT_SIZE))
+            .count()
+    }
+
+    #[test]
+    fn test_mi_segment_cache_push_decommit_delay() {
+        mi_thread_init();
+        _mi_os_init();
+        let mock = MiOsMockBackend::leak();
+        _mi_os_with_backend(mock, || {
+            // the cached segment is decommitted only after the delay
+            assert_eq!(mi_option_get(MiOption::MiOptionSegmentDecommitDelay), 500);
+            assert_eq!(push_segment(mock), 0);
 
-fn mi_is_in_heap_region(p: *const c_void) -> bool {
-    mi_is_valid_pointer(p)
+            // without a delay it is decommitted right away
+            mi_option_set(MiOption::MiOptionSegmentDecommitDelay, 0);
+            let decommits = push_segment(mock);
+            mi_option_set(MiOption::MiOptionSegmentDecommitDelay, 500);
+            assert_eq!(decommits, 1);
+        });
+    }
 }
@@ -0,0 +1,1739 @@
+use std::ffi::{c_void, CStr};
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::mem::{self, size_of};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+use std::{env, fmt, fs, process, ptr, thread};
+
+use libc::c_char;
+
+use crate::{
+    heap::mi_heap_get_default,
+    mimalloc_types::MiOption,
+    mimalloc_types::{
+        MiHeap, MiSegmentsTLD, MiStatCount, MiStatCounter, MiStats, MI_BIN_HUGE, MI_STAT,
+    },
+    options::{_mi_fprintf, _mi_fputs, mi_option_get, MiMsgBuf, MiOutputFun},
+    os::{_mi_clock_now, _mi_os_numa_node_count, _mi_os_page_size},
+    page_queue::_mi_bin_size,
+};
+
+/* -----------------------------------------------------------
+  Statistics operations
+----------------------------------------------------------- */
+
+pub static mut _mi_stats_main: MiStats = MiStats::new();
+
+fn mi_is_in_main<T>(stat: *const T) -> bool {
+    let main = ptr::addr_of!(_mi_stats_main) as usize;
+    let stat = stat as usize;
+    stat >= main && stat < main + size_of::<MiStats>()
+}
+
+// atomically add to a field of a statistic that may be shared between threads
+fn mi_atomic_addi64_relaxed(p: *mut i64, amount: i64) -> i64 {
+    unsafe { AtomicI64::from_ptr(p) }.fetch_add(amount, Ordering::Relaxed)
+}
+
+fn mi_atomic_maxi64_relaxed(p: *mut i64, x: i64) {
+    unsafe { AtomicI64::from_ptr(p) }.fetch_max(x, Ordering::Relaxed);
+}
+
+fn mi_atomic_loadi64_relaxed(p: *const i64) -> i64 {
+    unsafe { AtomicI64::from_ptr(p.cast_mut()) }.load(Ordering::Relaxed)
+}
+
+fn mi_stat_update(stat: *mut MiStatCount, amount: i64) {
+    if amount == 0 {
+        return;
+    }
+    unsafe {
+        if mi_is_in_main(stat) {
+            // add atomically (for abandoned pages)
+            let current = mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).current), amount);
+            mi_atomic_maxi64_relaxed(ptr::addr_of_mut!((*stat).peak), current + amount);
+            if amount > 0 {
+                mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).allocated), amount);
+            } else {
+                mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).freed), -amount);
+            }
+        } else {
+            // add thread local
+            (*stat).current += amount;
+            if (*stat).current > (*stat).peak {
+                (*stat).peak = (*stat).current;
+            }
+            if amount > 0 {
+                (*stat).allocated += amount;
+            } else {
+                (*stat).freed += -amount;
+            }
+        }
+    }
+}
+
+pub fn _mi_stat_counter_increase(stat: *mut MiStatCounter, amount: usize) {
+    unsafe {
+        if mi_is_in_main(stat) {
+            mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).count), 1);
+            mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).total), amount as i64);
+        } else {
+            (*stat).count += 1;
+            (*stat).total += amount as i64;
+        }
+    }
+}
+
+pub fn _mi_stat_increase(stat: *mut MiStatCount, amount: usize) {
+    mi_stat_update(stat, amount as i64);
+}
+
+pub fn _mi_stat_decrease(stat: *mut MiStatCount, amount: usize) {
+    mi_stat_update(stat, -(amount as i64));
+}
+
+// The statistics of a heap; the (static) empty heap has no thread local data yet
+// and counts directly into the main statistics.
+pub fn _mi_heap_stats(heap: *mut MiHeap) -> *mut MiStats {
+    unsafe {
+        if heap.is_null() || (*heap).tld.is_null() {
+            ptr::addr_of_mut!(_mi_stats_main)
+        } else {
+            ptr::addr_of_mut!((*(*heap).tld).stats)
+        }
+    }
+}
+
+// must be thread safe as it is called from stats_merge
+fn mi_stat_add(stat: *mut MiStatCount, src: *const MiStatCount, unit: i64) {
+    if ptr::eq(stat, src) {
+        return;
+    }
+    unsafe {
+        // the source may be the main statistics that are updated concurrently
+        let allocated = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).allocated));
+        let freed = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).freed));
+        if allocated == 0 && freed == 0 {
+            return;
+        }
+        let current = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).current));
+        let peak = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).peak));
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).allocated), allocated * unit);
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).current), current * unit);
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).freed), freed * unit);
+        // peak scores do not work across threads..
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).peak), peak * unit);
+    }
+}
+
+fn mi_stat_counter_add(stat: *mut MiStatCounter, src: *const MiStatCounter, unit: i64) {
+    if ptr::eq(stat, src) {
+        return;
+    }
+    unsafe {
+        let total = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).total));
+        let count = mi_atomic_loadi64_relaxed(ptr::addr_of!((*src).count));
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).total), total * unit);
+        mi_atomic_addi64_relaxed(ptr::addr_of_mut!((*stat).count), count * unit);
+    }
+}
+
+// must be thread safe as it is called from stats_merge
+fn mi_stats_add(stats: *mut MiStats, src: *const MiStats) {
+    if ptr::eq(stats, src) {
+        return;
+    }
+    unsafe {
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).segments),
+            ptr::addr_of!((*src).segments),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).pages),
+            ptr::addr_of!((*src).pages),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).reserved),
+            ptr::addr_of!((*src).reserved),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).committed),
+            ptr::addr_of!((*src).committed),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).reset),
+            ptr::addr_of!((*src).reset),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).page_committed),
+            ptr::addr_of!((*src).page_committed),
+            1,
+        );
+
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).pages_abandoned),
+            ptr::addr_of!((*src).pages_abandoned),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).segments_abandoned),
+            ptr::addr_of!((*src).segments_abandoned),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).threads),
+            ptr::addr_of!((*src).threads),
+            1,
+        );
+
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).malloc),
+            ptr::addr_of!((*src).malloc),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).segments_cache),
+            ptr::addr_of!((*src).segments_cache),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).normal),
+            ptr::addr_of!((*src).normal),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).huge),
+            ptr::addr_of!((*src).huge),
+            1,
+        );
+        mi_stat_add(
+            ptr::addr_of_mut!((*stats).large),
+            ptr::addr_of!((*src).large),
+            1,
+        );
+
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).pages_extended),
+            ptr::addr_of!((*src).pages_extended),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).mmap_calls),
+            ptr::addr_of!((*src).mmap_calls),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).commit_calls),
+            ptr::addr_of!((*src).commit_calls),
+            1,
+        );
+
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).page_no_retire),
+            ptr::addr_of!((*src).page_no_retire),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).searches),
+            ptr::addr_of!((*src).searches),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).normal_count),
+            ptr::addr_of!((*src).normal_count),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).huge_count),
+            ptr::addr_of!((*src).huge_count),
+            1,
+        );
+        mi_stat_counter_add(
+            ptr::addr_of_mut!((*stats).large_count),
+            ptr::addr_of!((*src).large_count),
+            1,
+        );
+
+        if MI_STAT > 1 {
+            for i in 0..=MI_BIN_HUGE {
+                // (unused bins are skipped by `mi_stat_add`)
+                mi_stat_add(
+                    ptr::addr_of_mut!((*stats).normal_bins[i]),
+                    ptr::addr_of!((*src).normal_bins[i]),
+                    1,
+                );
+            }
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+  Display statistics
+----------------------------------------------------------- */
+
+// unit > 0 : size in binary bytes
+// unit == 0: count as decimal
+// unit < 0 : count in binary
+fn mi_printf_amount(n: i64, unit: i64, out: Option<MiOutputFun>, arg: *mut c_void, width: usize) {
+    let mut buf = MiMsgBuf::<32>::new();
+    let suffix = if unit <= 0 { " " } else { "B" };
+    let base: i64 = if unit == 0 { 1000 } else { 1024 };
+    let n = if unit > 0 { n * unit } else { n };
+
+    let pos = n.abs();
+    if pos < base {
+        if n != 1 || suffix != "B" {
+            // skip printing 1 B for the unit column
+            let _ = write!(buf, "{} {:<3}", n, if n == 0 { "" } else { suffix });
+        }
+    } else {
+        let mut divider = base;
+        let mut magnitude = "K";
+        if pos >= divider * base {
+            divider *= base;
+            magnitude = "M";
+        }
+        if pos >= divider * base {
+            divider *= base;
+            magnitude = "G";
+        }
+        let tens = n / (divider / 10);
+        let whole = tens / 10;
+        let frac1 = tens % 10;
+        let mut unitdesc = MiMsgBuf::<8>::new();
+        let _ = write!(
+            unitdesc,
+            "{}{}{}",
+            magnitude,
+            if base == 1024 { "i" } else { "" },
+            suffix
+        );
+        let _ = write!(buf, "{}.{} {:<3}", whole, frac1.abs(), unitdesc.as_str());
+    }
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!("{:>width$}", buf.as_str(), width = width),
+    );
+}
+
+fn mi_print_amount(n: i64, unit: i64, out: Option<MiOutputFun>, arg: *mut c_void) {
+    mi_printf_amount(n, unit, out, arg, 11);
+}
+
+fn mi_print_count(n: i64, unit: i64, out: Option<MiOutputFun>, arg: *mut c_void) {
+    if unit == 1 {
+        _mi_fprintf(out, arg, format_args!("{:>11}", " "));
+    } else {
+        mi_print_amount(n, 0, out, arg);
+    }
+}
+
+fn mi_stat_print(
+    stat: &MiStatCount,
+    msg: &str,
+    unit: i64,
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+) {
+    _mi_fprintf(out, arg, format_args!("{:>10}:", msg));
+    if unit > 0 {
+        mi_print_amount(stat.peak, unit, out, arg);
+        mi_print_amount(stat.allocated, unit, out, arg);
+        mi_print_amount(stat.freed, unit, out, arg);
+        mi_print_amount(stat.current, unit, out, arg);
+        mi_print_amount(unit, 1, out, arg);
+        mi_print_count(stat.allocated, unit, out, arg);
+        if stat.allocated > stat.freed {
+            _mi_fprintf(out, arg, format_args!("  not all freed!\n"));
+        } else {
+            _mi_fprintf(out, arg, format_args!("  ok\n"));
+        }
+    } else if unit < 0 {
+        mi_print_amount(stat.peak, -1, out, arg);
+        mi_print_amount(stat.allocated, -1, out, arg);
+        mi_print_amount(stat.freed, -1, out, arg);
+        mi_print_amount(stat.current, -1, out, arg);
+        if unit == -1 {
+            _mi_fprintf(out, arg, format_args!("{:>22}", ""));
+        } else {
+            mi_print_amount(-unit, 1, out, arg);
+            mi_print_count(stat.allocated / -unit, 0, out, arg);
+        }
+        if stat.allocated > stat.freed {
+            _mi_fprintf(out, arg, format_args!("  not all freed!\n"));
+        } else {
+            _mi_fprintf(out, arg, format_args!("  ok\n"));
+        }
+    } else {
+        mi_print_amount(stat.peak, 1, out, arg);
+        mi_print_amount(stat.allocated, 1, out, arg);
+        _mi_fprintf(out, arg, format_args!("{:>11}", " ")); // no freed
+        mi_print_amount(stat.current, 1, out, arg);
+        _mi_fprintf(out, arg, format_args!("\n"));
+    }
+}
+
+fn mi_stat_counter_print(
+    stat: &MiStatCounter,
+    msg: &str,
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+) {
+    _mi_fprintf(out, arg, format_args!("{:>10}:", msg));
+    mi_print_amount(stat.total, -1, out, arg);
+    _mi_fprintf(out, arg, format_args!("\n"));
+}
+
+fn mi_stat_counter_print_avg(
+    stat: &MiStatCounter,
+    msg: &str,
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+) {
+    let avg_tens = if stat.count == 0 {
+        0
+    } else {
+        stat.total * 10 / stat.count
+    };
+    let avg_whole = avg_tens / 10;
+    let avg_frac1 = avg_tens % 10;
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!("{:>10}: {:>5}.{} avg\n", msg, avg_whole, avg_frac1),
+    );
+}
+
+fn mi_print_header(out: Option<MiOutputFun>, arg: *mut c_void) {
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!(
+            "{:>10}: {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}\n",
+            "heap stats", "peak   ", "total   ", "freed   ", "current   ", "unit   ", "count   "
+        ),
+    );
+}
+
+fn mi_stats_print_bins(
+    bins: &[MiStatCount],
+    max: usize,
+    fmt: &str,
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+) {
+    let mut found = false;
+    for (i, bin) in bins.iter().enumerate().take(max + 1) {
+        if bin.allocated > 0 {
+            found = true;
+            let unit = _mi_bin_size(i) as i64;
+            let mut buf = MiMsgBuf::<64>::new();
+            let _ = write!(buf, "{} {:>3}", fmt, i);
+            mi_stat_print(bin, buf.as_str(), unit, out, arg);
+        }
+    }
+    if found {
+        _mi_fprintf(out, arg, format_args!("\n"));
+        mi_print_header(out, arg);
+    }
+}
+
+//------------------------------------------------------------
+// Use an output wrapper for line-buffered output
+// (which is nice when using loggers etc.)
+//------------------------------------------------------------
+
+struct MiBuffered {
+    out: Option<MiOutputFun>, // original output function
+    arg: *mut c_void,         // and state
+    buf: [u8; 256],           // local buffer of at least size `count+1`
+    used: usize,              // currently used chars `used <= count`
+    count: usize,             // total chars available for output
+}
+
+fn mi_buffered_flush(buf: &mut MiBuffered) {
+    buf.buf[buf.used] = 0;
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(&buf.buf[..=buf.used]) };
+    _mi_fputs(buf.out, buf.arg, None, msg);
+    buf.used = 0;
+}
+
+unsafe extern "C" fn mi_buffered_out(msg: *const c_char, arg: *mut c_void) {
+    let buf = arg.cast::<MiBuffered>();
+    if msg.is_null() || buf.is_null() {
+        return;
+    }
+    let buf = &mut *buf;
+    for &c in CStr::from_ptr(msg).to_bytes() {
+        if buf.used >= buf.count {
+            mi_buffered_flush(buf);
+        }
+        debug_assert!(buf.used < buf.count);
+        buf.buf[buf.used] = c;
+        buf.used += 1;
+        if c == b'\n' {
+            mi_buffered_flush(buf);
+        }
+    }
+}
+
+//------------------------------------------------------------
+// Print statistics
+//------------------------------------------------------------
+
+fn _mi_stats_print(stats: &MiStats, out0: Option<MiOutputFun>, arg0: *mut c_void) {
+    // wrap the output function to be line buffered
+    let mut buffer = MiBuffered {
+        out: out0,
+        arg: arg0,
+        buf: [0; 256],
+        used: 0,
+        count: 255,
+    };
+    let out: Option<MiOutputFun> = Some(mi_buffered_out);
+    let arg: *mut c_void = ptr::addr_of_mut!(buffer).cast();
+
+    // and print using that
+    mi_print_header(out, arg);
+    if MI_STAT > 1 {
+        mi_stats_print_bins(&stats.normal_bins, MI_BIN_HUGE, "normal", out, arg);
+    }
+    if MI_STAT > 0 {
+        let unit_of = |stat: &MiStatCount, count: &MiStatCounter| {
+            if count.count == 0 {
+                1
+            } else {
+                -(stat.allocated / count.count)
+            }
+        };
+        mi_stat_print(
+            &stats.normal,
+            "normal",
+            unit_of(&stats.normal, &stats.normal_count),
+            out,
+            arg,
+        );
+        mi_stat_print(
+            &stats.large,
+            "large",
+            unit_of(&stats.large, &stats.large_count),
+            out,
+            arg,
+        );
+        mi_stat_print(
+            &stats.huge,
+            "huge",
+            unit_of(&stats.huge, &stats.huge_count),
+            out,
+            arg,
+        );
+        let mut total = MiStatCount::new();
+        mi_stat_add(&mut total, &stats.normal, 1);
+        mi_stat_add(&mut total, &stats.large, 1);
+        mi_stat_add(&mut total, &stats.huge, 1);
+        mi_stat_print(&total, "total", 1, out, arg);
+    }
+    if MI_STAT > 1 {
+        mi_stat_print(&stats.malloc, "malloc req", 1, out, arg);
+        _mi_fprintf(out, arg, format_args!("\n"));
+    }
+    mi_stat_print(&stats.reserved, "reserved", 1, out, arg);
+    mi_stat_print(&stats.committed, "committed", 1, out, arg);
+    mi_stat_print(&stats.reset, "reset", 1, out, arg);
+    mi_stat_print(&stats.page_committed, "touched", 1, out, arg);
+    mi_stat_print(&stats.segments, "segments", -1, out, arg);
+    mi_stat_print(&stats.segments_abandoned, "-abandoned", -1, out, arg);
+    mi_stat_print(&stats.segments_cache, "-cached", -1, out, arg);
+    mi_stat_print(&stats.pages, "pages", -1, out, arg);
+    mi_stat_print(&stats.pages_abandoned, "-abandoned", -1, out, arg);
+    mi_stat_counter_print(&stats.pages_extended, "-extended", out, arg);
+    mi_stat_counter_print(&stats.page_no_retire, "-noretire", out, arg);
+    mi_stat_counter_print(&stats.mmap_calls, "mmaps", out, arg);
+    mi_stat_counter_print(&stats.commit_calls, "commits", out, arg);
+    mi_stat_print(&stats.threads, "threads", -1, out, arg);
+    mi_stat_counter_print_avg(&stats.searches, "searches", out, arg);
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!("{:>10}: {:>7}\n", "numa nodes", _mi_os_numa_node_count()),
+    );
+
+    let elapsed = _mi_clock_end(MI_PROCESS_START.load(Ordering::Relaxed));
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!(
+            "{:>10}: {:>7}.{:03} s\n",
+            "elapsed",
+            elapsed / 1000,
+            elapsed % 1000
+        ),
+    );
+    let info = mi_stat_process_info();
+    _mi_fprintf(
+        out,
+        arg,
+        format_args!(
+            "{:>10}: user: {}.{:03} s, system: {}.{:03} s, faults: {}, rss: ",
+            "process",
+            info.user_msecs / 1000,
+            info.user_msecs % 1000,
+            info.system_msecs / 1000,
+            info.system_msecs % 1000,
+            info.page_faults
+        ),
+    );
+    mi_printf_amount(info.peak_rss as i64, 1, out, arg, 0);
+    if info.peak_commit > 0 {
+        _mi_fprintf(out, arg, format_args!(", commit: "));
+        mi_printf_amount(info.peak_commit as i64, 1, out, arg, 0);
+    }
+    _mi_fprintf(out, arg, format_args!("\n"));
+}
+
+static MI_PROCESS_START: AtomicI64 = AtomicI64::new(0);
+
+fn _mi_clock_end(start: i64) -> i64 {
+    _mi_clock_now() - start
+}
+
+/* -----------------------------------------------------------
+  Process info
+----------------------------------------------------------- */
+
+/// Resource usage of the process, as returned by [`MiProcessInfo::get`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MiProcessInfo {
+    /// Milliseconds since the process started (or the statistics were reset).
+    pub elapsed_msecs: usize,
+    /// Milliseconds of user CPU time.
+    pub user_msecs: usize,
+    /// Milliseconds of system CPU time.
+    pub system_msecs: usize,
+    /// Resident set size in bytes.
+    pub current_rss: usize,
+    /// Peak resident set size in bytes.
+    pub peak_rss: usize,
+    /// Memory committed by the allocator in bytes.
+    pub current_commit: usize,
+    /// Peak memory committed by the allocator in bytes.
+    pub peak_commit: usize,
+    /// Major page faults.
+    pub page_faults: usize,
+}
+
+impl MiProcessInfo {
+    /// Returns the current resource usage of the process.
+    pub fn get() -> Self {
+        mi_stat_process_info()
+    }
+}
+
+#[cfg(unix)]
+fn timeval_msecs(tv: &libc::timeval) -> usize {
+    (tv.tv_sec as usize * 1000) + (tv.tv_usec as usize / 1000)
+}
+
+// The resident set size from `/proc/self/statm` (read without allocating)
+#[cfg(target_os = "linux")]
+fn mi_stat_current_rss() -> Option<usize> {
+    let fd = unsafe { libc::open(c"/proc/self/statm".as_ptr(), libc::O_RDONLY) };
+    if fd < 0 {
+        return None;
+    }
+    let mut buf = [0u8; 128];
+    let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+    unsafe { libc::close(fd) };
+    if n <= 0 {
+        return None;
+    }
+    // fields: size resident shared text lib data dt (in pages)
+    let statm = std::str::from_utf8(&buf[..n as usize]).ok()?;
+    let resident: usize = statm.split_ascii_whitespace().nth(1)?.parse().ok()?;
+    Some(resident * _mi_os_page_size())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn mi_stat_current_rss() -> Option<usize> {
+    None
+}
+
+fn mi_stat_process_info() -> MiProcessInfo {
+    let elapsed = _mi_clock_end(MI_PROCESS_START.load(Ordering::Relaxed));
+    let mut info = MiProcessInfo {
+        elapsed_msecs: elapsed.max(0) as usize,
+        ..Default::default()
+    };
+    // estimate commit using our stats
+    let (peak, current) = unsafe {
+        (
+            ptr::addr_of_mut!(_mi_stats_main.committed.peak),
+            ptr::addr_of_mut!(_mi_stats_main.committed.current),
+        )
+    };
+    info.peak_commit = mi_atomic_loadi64_relaxed(peak) as usize;
+    info.current_commit = mi_atomic_loadi64_relaxed(current) as usize;
+    info.current_rss = mi_stat_current_rss().unwrap_or(info.current_commit); // or estimate
+    info.peak_rss = info.peak_commit;
+
+    #[cfg(unix)]
+    {
+        let mut rusage: libc::rusage = unsafe { mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut rusage) } == 0 {
+            info.user_msecs = timeval_msecs(&rusage.ru_utime);
+            info.system_msecs = timeval_msecs(&rusage.ru_stime);
+            info.page_faults = rusage.ru_majflt as usize;
+            if cfg!(target_vendor = "apple") {
+                info.peak_rss = rusage.ru_maxrss as usize; // BSD reports in bytes
+            } else {
+                info.peak_rss = rusage.ru_maxrss as usize * 1024; // Linux reports in KiB
+            }
+        }
+    }
+    info
+}
+
+// Return process information (elapsed and cpu time, rss, commit and page faults);
+// any of the output pointers may be null.
+#[no_mangle]
+pub extern "C" fn mi_process_info(
+    elapsed_msecs: *mut usize,
+    user_msecs: *mut usize,
+    system_msecs: *mut usize,
+    current_rss: *mut usize,
+    peak_rss: *mut usize,
+    current_commit: *mut usize,
+    peak_commit: *mut usize,
+    page_faults: *mut usize,
+) {
+    let info = mi_stat_process_info();
+    for (p, value) in [
+        (elapsed_msecs, info.elapsed_msecs),
+        (user_msecs, info.user_msecs),
+        (system_msecs, info.system_msecs),
+        (current_rss, info.current_rss),
+        (peak_rss, info.peak_rss),
+        (current_commit, info.current_commit),
+        (peak_commit, info.peak_commit),
+        (page_faults, info.page_faults),
+    ] {
+        if !p.is_null() {
+            unsafe { *p = value };
+        }
+    }
+}
+
+/* -----------------------------------------------------------
+  Merging and resetting
+----------------------------------------------------------- */
+
+fn mi_stats_get_default() -> *mut MiStats {
+    _mi_heap_stats(mi_heap_get_default())
+}
+
+// A copy of (the main) statistics to print from: other threads may update
+// (or merge into) them concurrently so every field is read atomically.
+fn mi_stats_copy(src: *const MiStats) -> MiStats {
+    let mut stats = MiStats::new();
+    mi_stats_add(&mut stats, src);
+    stats
+}
+
+fn mi_stats_merge_from(stats: *mut MiStats) {
+    if !ptr::eq(stats, ptr::addr_of!(_mi_stats_main)) {
+        mi_stats_add(ptr::addr_of_mut!(_mi_stats_main), stats);
+        unsafe { stats.write(MiStats::new()) };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mi_stats_reset() {
+    let stats = mi_stats_get_default();
+    if !ptr::eq(stats, ptr::addr_of!(_mi_stats_main)) {
+        unsafe { stats.write(MiStats::new()) };
+    }
+    unsafe { ptr::addr_of_mut!(_mi_stats_main).write(MiStats::new()) };
+    if MI_PROCESS_START.load(Ordering::Relaxed) == 0 {
+        MI_PROCESS_START.store(_mi_clock_now(), Ordering::Relaxed);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn mi_stats_merge() {
+    mi_stats_merge_from(mi_stats_get_default());
+}
+
+// called from `_mi_thread_done`
+pub fn _mi_stats_done(stats: *mut MiStats) {
+    mi_stats_merge_from(stats);
+}
+
+// Print the statistics of all threads (merging the current thread's statistics first)
+#[no_mangle]
+pub extern "C" fn mi_stats_print_out(out: Option<MiOutputFun>, arg: *mut c_void) {
+    mi_stats_merge_from(mi_stats_get_default());
+    _mi_stats_print(&mi_stats_copy(ptr::addr_of!(_mi_stats_main)), out, arg);
+}
+
+// For compatibility there is an `out` parameter (which can be `stdout` or `stderr`);
+// the output always goes to the registered output.
+#[no_mangle]
+pub extern "C" fn mi_stats_print(_out: *mut c_void) {
+    mi_stats_print_out(None, ptr::null_mut());
+}
+
+// Print the statistics of the current thread only
+#[no_mangle]
+pub extern "C" fn mi_thread_stats_print_out(out: Option<MiOutputFun>, arg: *mut c_void) {
+    _mi_stats_print(unsafe { &*mi_stats_get_default() }, out, arg);
+}
+
+/* -----------------------------------------------------------
+  Export statistics as JSON
+----------------------------------------------------------- */
+
+// Writes JSON through an output function; keeps track of whether a separator is needed.
+struct MiJsonOut {
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+    first: bool, // no separator needed before the next member
+}
+
+impl MiJsonOut {
+    fn member(&mut self, name: &str) {
+        let sep = if self.first { "" } else { "," };
+        _mi_fprintf(self.out, self.arg, format_args!("{}\"{}\":", sep, name));
+        self.first = false;
+    }
+
+    fn begin(&mut self, name: Option<&str>, open: char) {
+        match name {
+            Some(name) => self.member(name),
+            None if !self.first => _mi_fprintf(self.out, self.arg, format_args!(",")),
+            None => {}
+        }
+        _mi_fprintf(self.out, self.arg, format_args!("{}", open));
+        self.first = true;
+    }
+
+    fn end(&mut self, close: char) {
+        _mi_fprintf(self.out, self.arg, format_args!("{}", close));
+        self.first = false;
+    }
+
+    fn int(&mut self, name: &str, value: i64) {
+        self.member(name);
+        _mi_fprintf(self.out, self.arg, format_args!("{}", value));
+    }
+
+    fn stat_count(&mut self, name: &str, stat: &MiStatCount) {
+        self.begin(Some(name), '{');
+        self.int("allocated", stat.allocated);
+        self.int("freed", stat.freed);
+        self.int("peak", stat.peak);
+        self.int("current", stat.current);
+        self.end('}');
+    }
+
+    fn stat_counter(&mut self, name: &str, stat: &MiStatCounter) {
+        self.begin(Some(name), '{');
+        self.int("total", stat.total);
+        self.int("count", stat.count);
+        self.end('}');
+    }
+}
+
+fn _mi_stats_print_json(
+    stats: &MiStats,
+    segments: Option<&MiSegmentsTLD>,
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+) {
+    let mut json = MiJsonOut {
+        out,
+        arg,
+        first: true,
+    };
+    json.begin(None, '{');
+    json.int(
+        "elapsed_msecs",
+        _mi_clock_end(MI_PROCESS_START.load(Ordering::Relaxed)),
+    );
+    json.int("stat_level", MI_STAT as i64);
+
+    let info = mi_stat_process_info();
+    json.begin(Some("process"), '{');
+    json.int("user_msecs", info.user_msecs as i64);
+    json.int("system_msecs", info.system_msecs as i64);
+    json.int("current_rss", info.current_rss as i64);
+    json.int("peak_rss", info.peak_rss as i64);
+    json.int("current_commit", info.current_commit as i64);
+    json.int("peak_commit", info.peak_commit as i64);
+    json.int("page_faults", info.page_faults as i64);
+    json.end('}');
+
+    // OS memory totals
+    json.begin(Some("os"), '{');
+    json.int("reserved", stats.reserved.current);
+    json.int("reserved_peak", stats.reserved.peak);
+    json.int("reserved_total", stats.reserved.allocated);
+    json.int("committed", stats.committed.current);
+    json.int("committed_peak", stats.committed.peak);
+    json.int("committed_total", stats.committed.allocated);
+    json.int("reset_total", stats.reset.allocated);
+    json.int("mmap_calls", stats.mmap_calls.total);
+    json.int("commit_calls", stats.commit_calls.total);
+    json.int("numa_nodes", _mi_os_numa_node_count() as i64);
+    json.end('}');
+
+    // segments of the current thread
+    if let Some(segments) = segments {
+        json.begin(Some("thread_segments"), '{');
+        json.int("count", segments.count as i64);
+        json.int("peak_count", segments.peak_count as i64);
+        json.int("current_size", segments.current_size as i64);
+        json.int("peak_size", segments.peak_size as i64);
+        json.end('}');
+    }
+
+    json.begin(Some("stats"), '{');
+    json.stat_count("segments", &stats.segments);
+    json.stat_count("pages", &stats.pages);
+    json.stat_count("reserved", &stats.reserved);
+    json.stat_count("committed", &stats.committed);
+    json.stat_count("reset", &stats.reset);
+    json.stat_count("page_committed", &stats.page_committed);
+    json.stat_count("segments_abandoned", &stats.segments_abandoned);
+    json.stat_count("pages_abandoned", &stats.pages_abandoned);
+    json.stat_count("threads", &stats.threads);
+    json.stat_count("normal", &stats.normal);
+    json.stat_count("huge", &stats.huge);
+    json.stat_count("large", &stats.large);
+    json.stat_count("malloc", &stats.malloc);
+    json.stat_count("segments_cache", &stats.segments_cache);
+    json.stat_counter("pages_extended", &stats.pages_extended);
+    json.stat_counter("mmap_calls", &stats.mmap_calls);
+    json.stat_counter("commit_calls", &stats.commit_calls);
+    json.stat_counter("page_no_retire", &stats.page_no_retire);
+    json.stat_counter("searches", &stats.searches);
+    json.stat_counter("normal_count", &stats.normal_count);
+    json.stat_counter("huge_count", &stats.huge_count);
+    json.stat_counter("large_count", &stats.large_count);
+    json.end('}');
+
+    // per size class (only maintained if `MI_STAT > 1`)
+    json.begin(Some("bins"), '[');
+    for (i, bin) in stats.normal_bins.iter().enumerate() {
+        if bin.allocated > 0 {
+            json.begin(None, '{');
+            json.int("bin", i as i64);
+            json.int("block_size", _mi_bin_size(i) as i64);
+            json.int("allocated", bin.allocated);
+            json.int("freed", bin.freed);
+            json.int("peak", bin.peak);
+            json.int("current", bin.current);
+            json.end('}');
+        }
+    }
+    json.end(']');
+    json.end('}');
+}
+
+// Print the merged statistics of all threads as a JSON object
+#[no_mangle]
+pub extern "C" fn mi_stats_print_json_out(out: Option<MiOutputFun>, arg: *mut c_void) {
+    let stats = mi_stats_get_default();
+    let heap = mi_heap_get_default();
+    mi_stats_merge_from(stats);
+    let segments = unsafe { (*heap).tld.as_ref().map(|tld| &tld.segments) };
+    _mi_stats_print_json(
+        &mi_stats_copy(ptr::addr_of!(_mi_stats_main)),
+        segments,
+        out,
+        arg,
+    );
+}
+
+// Output function that appends to the `String` given as argument
+unsafe extern "C" fn mi_out_string(msg: *const c_char, arg: *mut c_void) {
+    let s = &mut *arg.cast::<String>();
+    s.push_str(CStr::from_ptr(msg).to_str().unwrap_or(""));
+}
+
+/// Returns the merged allocator statistics of all threads as a JSON object.
+///
+/// This includes the OS reserve/commit totals, the segment counts of the
+/// calling thread, and the counts per size class (bin).
+pub fn mi_stats_json() -> String {
+    let mut json = String::new();
+    mi_stats_print_json_out(Some(mi_out_string), ptr::addr_of_mut!(json).cast());
+    json
+}
+
+/* -----------------------------------------------------------
+  Export statistics in the Prometheus text format
+----------------------------------------------------------- */
+
+// Writes metric families in the Prometheus text exposition format through an output function.
+struct MiPromOut {
+    out: Option<MiOutputFun>,
+    arg: *mut c_void,
+}
+
+impl MiPromOut {
+    // the `# HELP` and `# TYPE` lines that start a metric family
+    fn family(&self, name: fmt::Arguments, kind: &str, help: fmt::Arguments) {
+        _mi_fprintf(
+            self.out,
+            self.arg,
+            format_args!("# HELP mimalloc_{} {}\n", name, help),
+        );
+        _mi_fprintf(
+            self.out,
+            self.arg,
+            format_args!("# TYPE mimalloc_{} {}\n", name, kind),
+        );
+    }
+
+    // a sample, labeled with the block size if it belongs to a size class (bin)
+    fn sample(&self, name: fmt::Arguments, size_class: Option<usize>, value: fmt::Arguments) {
+        match size_class {
+            Some(size) => _mi_fprintf(
+                self.out,
+                self.arg,
+                format_args!("mimalloc_{}{{size_class=\"{}\"}} {}\n", name, size, value),
+            ),
+            None => _mi_fprintf(
+                self.out,
+                self.arg,
+                format_args!("mimalloc_{} {}\n", name, value),
+            ),
+        }
+    }
+
+    fn gauge(&self, name: &str, help: &str, value: i64) {
+        self.family(format_args!("{}", name), "gauge", format_args!("{}", help));
+        self.sample(format_args!("{}", name), None, format_args!("{}", value));
+    }
+
+    // e.g. `reserved` in `_bytes`: `mimalloc_reserved_bytes`, `mimalloc_reserved_peak_bytes`,
+    // `mimalloc_reserved_allocated_bytes_total` and `mimalloc_reserved_freed_bytes_total`
+    fn stat_count(&self, name: &str, unit: &str, help: &str, stat: &MiStatCount) {
+        self.family(
+            format_args!("{}{}", name, unit),
+            "gauge",
+            format_args!("Current {}.", help),
+        );
+        self.sample(
+            format_args!("{}{}", name, unit),
+            None,
+            format_args!("{}", stat.current),
+        );
+        self.family(
+            format_args!("{}_peak{}", name, unit),
+            "gauge",
+            format_args!("Peak {}.", help),
+        );
+        self.sample(
+            format_args!("{}_peak{}", name, unit),
+            None,
+            format_args!("{}", stat.peak),
+        );
+        self.family(
+            format_args!("{}_allocated{}_total", name, unit),
+            "counter",
+            format_args!("Cumulative increase of {}.", help),
+        );
+        self.sample(
+            format_args!("{}_allocated{}_total", name, unit),
+            None,
+            format_args!("{}", stat.allocated),
+        );
+        self.family(
+            format_args!("{}_freed{}_total", name, unit),
+            "counter",
+            format_args!("Cumulative decrease of {}.", help),
+        );
+        self.sample(
+            format_args!("{}_freed{}_total", name, unit),
+            None,
+            format_args!("{}", stat.freed),
+        );
+    }
+
+    fn stat_counter(&self, name: &str, help: &str, stat: &MiStatCounter) {
+        self.family(
+            format_args!("{}_total", name),
+            "counter",
+            format_args!("{}", help),
+        );
+        self.sample(
+            format_args!("{}_total", name),
+            None,
+            format_args!("{}", stat.total),
+        );
+    }
+
+    // one family over all used size classes
+    fn bins(
+        &self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        stats: &MiStats,
+        value: fn(&MiStatCount) -> i64,
+    ) {
+        self.family(format_args!("{}", name), kind, format_args!("{}", help));
+        for (i, bin) in stats.normal_bins.iter().enumerate() {
+            if bin.allocated > 0 {
+                self.sample(
+                    format_args!("{}", name),
+                    Some(_mi_bin_size(i)),
+                    format_args!("{}", value(bin)),
+                );
+            }
+        }
+    }
+}
+
+fn _mi_stats_print_prometheus(stats: &MiStats, out: Option<MiOutputFun>, arg: *mut c_void) {
+    let prom = MiPromOut { out, arg };
+    let elapsed = _mi_clock_end(MI_PROCESS_START.load(Ordering::Relaxed));
+    prom.family(
+        format_args!("elapsed_seconds"),
+        "gauge",
+        format_args!("Time since the statistics were reset."),
+    );
+    prom.sample(
+        format_args!("elapsed_seconds"),
+        None,
+        format_args!("{}.{:03}", elapsed / 1000, elapsed % 1000),
+    );
+    prom.gauge(
+        "stat_level",
+        "Level of detail of the statistics (MI_STAT).",
+        MI_STAT as i64,
+    );
+    prom.gauge(
+        "numa_nodes",
+        "Number of NUMA nodes in use.",
+        _mi_os_numa_node_count() as i64,
+    );
+
+    let info = mi_stat_process_info();
+    for (name, help, msecs) in [
+        (
+            "process_user_seconds_total",
+            "User CPU time of the process.",
+            info.user_msecs,
+        ),
+        (
+            "process_system_seconds_total",
+            "System CPU time of the process.",
+            info.system_msecs,
+        ),
+    ] {
+        prom.family(
+            format_args!("{}", name),
+            "counter",
+            format_args!("{}", help),
+        );
+        prom.sample(
+            format_args!("{}", name),
+            None,
+            format_args!("{}.{:03}", msecs / 1000, msecs % 1000),
+        );
+    }
+    prom.gauge(
+        "process_rss_bytes",
+        "Resident set size of the process.",
+        info.current_rss as i64,
+    );
+    prom.gauge(
+        "process_peak_rss_bytes",
+        "Peak resident set size of the process.",
+        info.peak_rss as i64,
+    );
+    prom.gauge(
+        "process_commit_bytes",
+        "Memory committed by the allocator.",
+        info.current_commit as i64,
+    );
+    prom.gauge(
+        "process_peak_commit_bytes",
+        "Peak memory committed by the allocator.",
+        info.peak_commit as i64,
+    );
+    prom.family(
+        format_args!("process_page_faults_total"),
+        "counter",
+        format_args!("Major page faults of the process."),
+    );
+    prom.sample(
+        format_args!("process_page_faults_total"),
+        None,
+        format_args!("{}", info.page_faults),
+    );
+
+    prom.stat_count(
+        "reserved",
+        "_bytes",
+        "memory reserved from the OS",
+        &stats.reserved,
+    );
+    prom.stat_count(
+        "committed",
+        "_bytes",
+        "memory committed from the OS",
+        &stats.committed,
+    );
+    prom.stat_count("reset", "_bytes", "memory reset to the OS", &stats.reset);
+    prom.stat_count(
+        "page_committed",
+        "_bytes",
+        "memory committed to pages",
+        &stats.page_committed,
+    );
+    prom.stat_count("segments", "", "segments", &stats.segments);
+    prom.stat_count(
+        "segments_abandoned",
+        "",
+        "abandoned segments",
+        &stats.segments_abandoned,
+    );
+    prom.stat_count(
+        "segments_cache",
+        "",
+        "cached segments",
+        &stats.segments_cache,
+    );
+    prom.stat_count("pages", "", "pages", &stats.pages);
+    prom.stat_count(
+        "pages_abandoned",
+        "",
+        "abandoned pages",
+        &stats.pages_abandoned,
+    );
+    prom.stat_count("threads", "", "threads", &stats.threads);
+    prom.stat_count(
+        "normal",
+        "_bytes",
+        "memory in small and medium blocks",
+        &stats.normal,
+    );
+    prom.stat_count("large", "_bytes", "memory in large blocks", &stats.large);
+    prom.stat_count("huge", "_bytes", "memory in huge blocks", &stats.huge);
+    prom.stat_count("malloc", "_bytes", "requested memory", &stats.malloc);
+
+    prom.stat_counter(
+        "pages_extended",
+        "Pages extended with more blocks.",
+        &stats.pages_extended,
+    );
+    prom.stat_counter(
+        "mmap_calls",
+        "Calls to mmap (or VirtualAlloc).",
+        &stats.mmap_calls,
+    );
+    prom.stat_counter(
+        "commit_calls",
+        "Calls to commit OS memory.",
+        &stats.commit_calls,
+    );
+    prom.stat_counter(
+        "page_no_retire",
+        "Pages kept instead of retired.",
+        &stats.page_no_retire,
+    );
+    prom.stat_counter(
+        "searches",
+        "Pages visited while searching for free blocks.",
+        &stats.searches,
+    );
+    prom.stat_counter(
+        "normal_count",
+        "Small and medium blocks allocated.",
+        &stats.normal_count,
+    );
+    prom.stat_counter("large_count", "Large blocks allocated.", &stats.large_count);
+    prom.stat_counter("huge_count", "Huge blocks allocated.", &stats.huge_count);
+
+    // per size class (only maintained if `MI_STAT > 1`)
+    if MI_STAT > 1 {
+        prom.bins(
+            "bin_blocks",
+            "gauge",
+            "Current blocks per size class.",
+            stats,
+            |bin| bin.current,
+        );
+        prom.bins(
+            "bin_blocks_peak",
+            "gauge",
+            "Peak blocks per size class.",
+            stats,
+            |bin| bin.peak,
+        );
+        prom.bins(
+            "bin_allocated_blocks_total",
+            "counter",
+            "Blocks allocated per size class.",
+            stats,
+            |bin| bin.allocated,
+        );
+        prom.bins(
+            "bin_freed_blocks_total",
+            "counter",
+            "Blocks freed per size class.",
+            stats,
+            |bin| bin.freed,
+        );
+    }
+}
+
+// Print the merged statistics of all threads in the Prometheus text format
+#[no_mangle]
+pub extern "C" fn mi_stats_print_prometheus_out(out: Option<MiOutputFun>, arg: *mut c_void) {
+    mi_stats_merge_from(mi_stats_get_default());
+    _mi_stats_print_prometheus(&mi_stats_copy(ptr::addr_of!(_mi_stats_main)), out, arg);
+}
+
+/// Returns the merged allocator statistics of all threads in the Prometheus
+/// text exposition format.
+///
+/// Counts per size class carry a `size_class` label with the block size.
+pub fn mi_stats_prometheus() -> String {
+    let mut metrics = String::new();
+    mi_stats_print_prometheus_out(Some(mi_out_string), ptr::addr_of_mut!(metrics).cast());
+    metrics
+}
+
+// Answer a scrape: the request itself is ignored as every path serves the metrics.
+fn mi_prometheus_respond<S: Read + io::Write>(mut stream: S) -> io::Result<()> {
+    let mut request = [0u8; 1024];
+    let _ = stream.read(&mut request)?;
+    let metrics = mi_stats_prometheus();
+    write!(
+        stream,
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        metrics.len()
+    )?;
+    stream.write_all(metrics.as_bytes())
+}
+
+// A scrape that does not send its request (or read the response) in time is dropped
+const MI_PROMETHEUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Wait between failed `accept` calls (e.g. when out of file descriptors)
+const MI_PROMETHEUS_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const MI_PROMETHEUS_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+fn mi_prometheus_spawn<S: Read + io::Write>(
+    mut accept: impl FnMut() -> io::Result<S> + Send + 'static,
+) -> io::Result<()> {
+    thread::Builder::new()
+        .name("mimalloc-prometheus".into())
+        .spawn(move || {
+            let mut backoff = MI_PROMETHEUS_BACKOFF_MIN;
+            loop {
+                match accept() {
+                    Ok(stream) => {
+                        backoff = MI_PROMETHEUS_BACKOFF_MIN;
+                        let _ = mi_prometheus_respond(stream);
+                    }
+                    Err(_) => {
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(MI_PROMETHEUS_BACKOFF_MAX);
+                    }
+                }
+            }
+        })
+        .map(|_| ())
+}
+
+/// Serves the statistics over HTTP in the Prometheus text format from a
+/// background thread, and returns the address the listener is bound to.
+pub fn mi_stats_prometheus_serve_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local = listener.local_addr()?;
+    mi_prometheus_spawn(move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(MI_PROMETHEUS_TIMEOUT))?;
+        stream.set_write_timeout(Some(MI_PROMETHEUS_TIMEOUT))?;
+        Ok(stream)
+    })?;
+    Ok(local)
+}
+
+/// Serves the statistics over HTTP in the Prometheus text format on a
+/// Unix-domain socket from a background thread.
+///
+/// An existing file at `path` is replaced.
+#[cfg(unix)]
+pub fn mi_stats_prometheus_serve_unix<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let _ = fs::remove_file(path.as_ref());
+    let listener = UnixListener::bind(path)?;
+    mi_prometheus_spawn(move || {
+        let (stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(MI_PROMETHEUS_TIMEOUT))?;
+        stream.set_write_timeout(Some(MI_PROMETHEUS_TIMEOUT))?;
+        Ok(stream)
+    })
+}
+
+// Where to listen for a value of the `prometheus_listen` option
+#[derive(Debug, PartialEq, Eq)]
+enum MiPrometheusListen {
+    Off,
+    Unix,     // 1 (`on`): the Unix-domain socket at `mi_prometheus_socket_path()`
+    Tcp(u16), // 1024..=65535: the TCP port on localhost
+}
+
+fn mi_prometheus_listen_parse(listen: i64) -> Option<MiPrometheusListen> {
+    match listen {
+        0 => Some(MiPrometheusListen::Off),
+        1 => Some(MiPrometheusListen::Unix),
+        1024..=65535 => Some(MiPrometheusListen::Tcp(listen as u16)),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+fn mi_prometheus_socket_path() -> PathBuf {
+    env::temp_dir().join(format!("mimalloc-{}.sock", process::id()))
+}
+
+fn mi_prometheus_listen_at(listen: i64) -> io::Result<()> {
+    match mi_prometheus_listen_parse(listen) {
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid prometheus_listen value {}", listen),
+        )),
+        Some(MiPrometheusListen::Off) => Ok(()),
+        #[cfg(unix)]
+        Some(MiPrometheusListen::Unix) => {
+            mi_stats_prometheus_serve_unix(mi_prometheus_socket_path())
+        }
+        #[cfg(not(unix))]
+        Some(MiPrometheusListen::Unix) => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        Some(MiPrometheusListen::Tcp(port)) => {
+            mi_stats_prometheus_serve_tcp((Ipv4Addr::LOCALHOST, port)).map(|_| ())
+        }
+    }
+}
+
+static MI_PROMETHEUS_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// Serves the statistics in the Prometheus text format from a background thread
+/// where the `prometheus_listen` option (`MIMALLOC_PROMETHEUS_LISTEN`) says, see
+/// [`MiOption::MiOptionPrometheusListen`].
+///
+/// Nothing is started if the option is off or the statistics are served already.
+/// The allocator never starts the exporter by itself; call this early in `main`.
+pub fn mi_stats_prometheus_listen() -> io::Result<()> {
+    #[allow(clippy::useless_conversion)] // `c_long` is 32-bit on windows
+    let listen = i64::from(mi_option_get(MiOption::MiOptionPrometheusListen));
+    if listen == 0 || MI_PROMETHEUS_STARTED.swap(true, Ordering::AcqRel) {
+        return Ok(());
+    }
+    let result = mi_prometheus_listen_at(listen);
+    if result.is_err() {
+        MI_PROMETHEUS_STARTED.store(false, Ordering::Release); // allow to try again
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_void, CStr},
+        io::{self, Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        ptr, thread,
+    };
+
+    #[cfg(unix)]
+    use std::os::unix::net::UnixStream;
+
+    use libc::c_char;
+
+    use crate::{
+        alloc::{mi_free, mi_malloc},
+        mimalloc_types::{MiStatCount, MiStatCounter, MiStats, MI_STAT},
+    };
+
+    use super::{
+        _mi_stat_counter_increase, _mi_stat_decrease, _mi_stat_increase, _mi_stats_main,
+        mi_printf_amount, mi_process_info, mi_prometheus_listen_at, mi_prometheus_listen_parse,
+        mi_stats_copy, mi_stats_json, mi_stats_print_out, mi_stats_prometheus,
+        mi_stats_prometheus_serve_tcp, MiProcessInfo, MiPrometheusListen,
+    };
+
+    #[cfg(unix)]
+    use super::mi_prometheus_socket_path;
+
+    unsafe extern "C" fn collect_output(msg: *const c_char, arg: *mut c_void) {
+        let out = &mut *arg.cast::<String>();
+        out.push_str(CStr::from_ptr(msg).to_str().unwrap());
+    }
+
+    fn amount(n: i64, unit: i64) -> String {
+        let mut out = String::new();
+        mi_printf_amount(
+            n,
+            unit,
+            Some(collect_output),
+            ptr::addr_of_mut!(out).cast(),
+            0,
+        );
+        out
+    }
+
+    #[test]
+    fn test_mi_stat_update() {
+        let mut stat = MiStatCount::default();
+        _mi_stat_increase(&mut stat, 100);
+        _mi_stat_increase(&mut stat, 50);
+        _mi_stat_decrease(&mut stat, 120);
+        assert_eq!(stat.allocated, 150);
+        assert_eq!(stat.freed, 120);
+        assert_eq!(stat.current, 30);
+        assert_eq!(stat.peak, 150);
+
+        let mut counter = MiStatCounter::default();
+        _mi_stat_counter_increase(&mut counter, 3);
+        _mi_stat_counter_increase(&mut counter, 4);
+        assert_eq!(counter.count, 2);
+        assert_eq!(counter.total, 7);
+    }
+
+    #[test]
+    fn test_thread_stats_merge_at_exit() {
+        // the main statistics are shared with concurrent tests, so only check monotonic counts
+        let main = ptr::addr_of!(_mi_stats_main);
+        let (threads, segments) =
+            unsafe { ((*main).threads.allocated, (*main).segments.allocated) };
+        thread::spawn(|| {
+            let p = mi_malloc(1000);
+            assert!(!p.is_null());
+            mi_free(p);
+        })
+        .join()
+        .unwrap();
+        unsafe {
+            assert!((*main).threads.allocated > threads);
+            assert!((*main).segments.allocated > segments); // merged from the thread statistics
+        }
+    }
+
+    #[test]
+    fn test_mi_printf_amount() {
+        assert_eq!(amount(0, 1), "0    ");
+        assert_eq!(amount(512, 1), "512 B  ");
+        assert_eq!(amount(1536, 1), "1.5 KiB");
+        assert_eq!(amount(3, 1024 * 1024), "3.0 MiB");
+        assert_eq!(amount(-2048, 1), "-2.0 KiB");
+        assert_eq!(amount(12, -1), "12    ");
+        assert_eq!(amount(25_000, 0), "25.0 K  ");
+    }
+
+    #[test]
+    fn test_mi_stats_print_out() {
+        let p = mi_malloc(100);
+        let mut out = String::new();
+        mi_stats_print_out(Some(collect_output), ptr::addr_of_mut!(out).cast());
+        mi_free(p);
+
+        let lines: Vec<&str> = out.lines().collect();
+        assert!(lines[0].starts_with("heap stats:"));
+        for name in [
+            "reserved",
+            "committed",
+            "segments",
+            "pages",
+            "threads",
+            "elapsed",
+        ] {
+            let prefix = format!("{:>10}:", name);
+            assert!(lines.iter().any(|l| l.starts_with(&prefix)), "{}", name);
+        }
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn test_mi_stats_json() {
+        let p = mi_malloc(100);
+        let json = mi_stats_json();
+        mi_free(p);
+
+        assert!(json.starts_with("{\"elapsed_msecs\":"));
+        assert!(json.ends_with("]}"));
+        let mut depth = 0;
+        for c in json.chars() {
+            match c {
+                '{' | '[' => depth += 1,
+                '}' | ']' => depth -= 1,
+                _ => {}
+            }
+            assert!(depth >= 0);
+        }
+        assert_eq!(depth, 0);
+        for bad in [",}", ",]", "{,", "[,", ",,"] {
+            assert!(!json.contains(bad), "{}", json);
+        }
+        assert!(json.contains("\"process\":{\"user_msecs\":"));
+        assert!(json.contains("\"os\":{\"reserved\":"));
+        assert!(json.contains("\"thread_segments\":{\"count\":"));
+        assert!(json.contains("\"segments\":{\"allocated\":"));
+        if MI_STAT > 1 {
+            assert!(json.contains("\"bins\":[{\"bin\":"));
+        }
+    }
+
+    #[test]
+    fn test_mi_stats_prometheus() {
+        let p = mi_malloc(100);
+        let metrics = mi_stats_prometheus();
+        mi_free(p);
+
+        // every sample follows the help and type lines of its family
+        let mut family = "";
+        for line in metrics.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                family = help.split(' ').next().unwrap();
+            } else if let Some(kind) = line.strip_prefix("# TYPE ") {
+                assert!(
+                    kind == format!("{} gauge", family) || kind == format!("{} counter", family)
+                );
+            } else {
+                let (name, value) = line.rsplit_once(' ').unwrap();
+                assert_eq!(name.split('{').next().unwrap(), family, "{}", line);
+                assert!(value.parse::<f64>().is_ok(), "{}", line);
+            }
+        }
+        assert!(metrics.contains("# TYPE mimalloc_reserved_bytes gauge\nmimalloc_reserved_bytes "));
+        assert!(metrics.contains("# TYPE mimalloc_mmap_calls_total counter\n"));
+        if MI_STAT > 1 {
+            assert!(metrics.contains("mimalloc_bin_allocated_blocks_total{size_class=\"112\"} "));
+        }
+    }
+
+    // Scrape the metrics and check the response
+    fn scrape(mut stream: impl Read + Write) {
+        stream.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}\r\n", body.len())));
+        assert!(body.starts_with("# HELP mimalloc_elapsed_seconds "));
+    }
+
+    #[test]
+    fn test_mi_stats_prometheus_serve_tcp() {
+        let addr = mi_stats_prometheus_serve_tcp((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        scrape(TcpStream::connect(addr).unwrap());
+    }
+
+    #[test]
+    fn test_mi_prometheus_listen_parse() {
+        assert_eq!(mi_prometheus_listen_parse(0), Some(MiPrometheusListen::Off));
+        assert_eq!(
+            mi_prometheus_listen_parse(1),
+            Some(MiPrometheusListen::Unix)
+        );
+        assert_eq!(
+            mi_prometheus_listen_parse(9100),
+            Some(MiPrometheusListen::Tcp(9100))
+        );
+        assert_eq!(
+            mi_prometheus_listen_parse(65535),
+            Some(MiPrometheusListen::Tcp(65535))
+        );
+        for invalid in [-1, 2, 80, 1023, 65536] {
+            assert_eq!(mi_prometheus_listen_parse(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_mi_prometheus_listen_at() {
+        // off and invalid values start nothing
+        assert!(mi_prometheus_listen_at(0).is_ok());
+        let err = mi_prometheus_listen_at(80).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        // a TCP port on localhost (find a free one first)
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        if port >= 1024 {
+            mi_prometheus_listen_at(port as i64).unwrap();
+            scrape(TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap());
+        }
+
+        // a Unix-domain socket
+        #[cfg(unix)]
+        {
+            mi_prometheus_listen_at(1).unwrap();
+            let path = mi_prometheus_socket_path();
+            scrape(UnixStream::connect(&path).unwrap());
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn test_mi_process_info() {
+        let p = mi_malloc(1000);
+        let info = MiProcessInfo::get();
+        mi_free(p);
+
+        assert!(info.peak_commit > 0);
+        assert!(info.peak_commit >= info.current_commit);
+        assert!(info.current_rss > 0);
+        if cfg!(unix) {
+            assert!(info.peak_rss > 0);
+        }
+
+        let (mut elapsed, mut rss) = (usize::MAX, 0);
+        mi_process_info(
+            &mut elapsed,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            &mut rss,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+        );
+        assert!(elapsed < usize::MAX);
+        assert!(rss > 0);
+    }
+
+    #[test]
+    fn test_mi_stats_copy() {
+        let mut stats = MiStats::new();
+        _mi_stat_increase(&mut stats.segments, 3);
+        _mi_stat_decrease(&mut stats.segments, 1);
+        if MI_STAT > 1 {
+            // (the bins are only maintained and merged with detailed statistics)
+            _mi_stat_increase(&mut stats.normal_bins[5], 64);
+        }
+        _mi_stat_counter_increase(&mut stats.searches, 7);
+        let copy = mi_stats_copy(&stats);
+        assert_eq!(format!("{:?}", copy), format!("{:?}", stats));
+    }
+}
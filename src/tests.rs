@@ -5,6 +5,7 @@
 
 use crate::init::_mi_heap_init;
 
+#[allow(unused_macros)]
 macro_rules! test_layout {
     ($type: ty, $size: expr, $align: expr) => {
         paste! {